@@ -0,0 +1,485 @@
+use core::{mem, ptr, slice};
+
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The reason an ACPI structure could not be used.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AcpiError {
+    /// No Root System Description Pointer was found in the BIOS areas.
+    RsdpNotFound,
+    /// The structure with the signature has an incorrect checksum.
+    InvalidChecksum([u8; 4]),
+    /// No table with the signature is listed in the root table.
+    TableNotFound([u8; 4]),
+}
+
+/// The Root System Description Pointer, which locates the root table.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The fields below only exist when `revision` is at least 2.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl Rsdp {
+    const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    /// The size of the structure defined by ACPI 1.0, which has no fields after `rsdt_address`.
+    const V1_LENGTH: usize = 20;
+}
+
+/// The header shared by every System Description Table.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    /// The four-character signature of the table, such as `APIC` for the MADT.
+    pub signature: [u8; 4],
+    /// The length of the table in bytes, including the header.
+    pub length: u32,
+    /// The revision of the structure of the table.
+    pub revision: u8,
+    /// The byte which makes every byte of the table sum to 0.
+    pub checksum: u8,
+    /// The ID of the OEM which supplied the table.
+    pub oem_id: [u8; 6],
+    /// The OEM's ID of the table.
+    pub oem_table_id: [u8; 8],
+    /// The OEM's revision of the table.
+    pub oem_revision: u32,
+    /// The vendor ID of the utility which created the table.
+    pub creator_id: u32,
+    /// The revision of the utility which created the table.
+    pub creator_revision: u32,
+}
+
+/// Access to the ACPI tables provided by the firmware.
+#[derive(Clone, Copy, Debug)]
+pub struct Acpi {
+    physical_memory_offset: VirtAddr,
+    root: PhysAddr,
+    extended: bool,
+}
+
+impl Acpi {
+    /// Find the ACPI tables by searching for the Root System Description Pointer in the first KiB
+    /// of the Extended BIOS Data Area and in the BIOS ROM between `0xE_0000` and `0xF_FFFF`.
+    ///
+    /// # Safety
+    /// All of physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn new(physical_memory_offset: VirtAddr) -> Result<Self, AcpiError> {
+        let ebda_segment =
+            ptr::read_volatile(physical_memory_offset.as_ptr::<u8>().add(0x40E) as *const u16);
+        let ebda = (ebda_segment as u64) << 4;
+        let mut acpi = Self {
+            physical_memory_offset,
+            root: PhysAddr::new(0),
+            extended: false,
+        };
+        let rsdp = (ebda..ebda + 0x400)
+            .chain(0xE_0000..0x10_0000)
+            .step_by(16)
+            .map(PhysAddr::new)
+            .find(|&addr| acpi.is_rsdp(addr))
+            .ok_or(AcpiError::RsdpNotFound)?;
+        let rsdp: Rsdp = acpi.read(rsdp);
+        if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            acpi.root = PhysAddr::new(rsdp.xsdt_address);
+            acpi.extended = true;
+        } else {
+            acpi.root = PhysAddr::new(rsdp.rsdt_address as u64);
+        }
+        acpi.validate(acpi.root)?;
+        Ok(acpi)
+    }
+
+    /// The offset at which all of physical memory is mapped.
+    pub fn physical_memory_offset(&self) -> VirtAddr {
+        self.physical_memory_offset
+    }
+
+    /// Read a `T` from the physical address `addr`.
+    ///
+    /// # Safety
+    /// `addr` must contain a valid `T`.
+    pub unsafe fn read<T: Copy>(&self, addr: PhysAddr) -> T {
+        ptr::read_unaligned(self.virt_addr(addr).as_ptr())
+    }
+
+    /// Get the bytes in physical memory starting at `addr`.
+    ///
+    /// # Safety
+    /// The bytes must not be modified while the slice exists.
+    pub unsafe fn bytes(&self, addr: PhysAddr, len: usize) -> &[u8] {
+        slice::from_raw_parts(self.virt_addr(addr).as_ptr(), len)
+    }
+
+    fn virt_addr(&self, addr: PhysAddr) -> VirtAddr {
+        self.physical_memory_offset + addr.as_u64()
+    }
+
+    unsafe fn is_rsdp(&self, addr: PhysAddr) -> bool {
+        let bytes = self.bytes(addr, Rsdp::V1_LENGTH);
+        bytes.starts_with(Rsdp::SIGNATURE) && checksum(bytes) == 0
+    }
+
+    /// Check the checksum of the table at `addr`.
+    unsafe fn validate(&self, addr: PhysAddr) -> Result<SdtHeader, AcpiError> {
+        let header: SdtHeader = self.read(addr);
+        if checksum(self.bytes(addr, header.length as usize)) == 0 {
+            Ok(header)
+        } else {
+            Err(AcpiError::InvalidChecksum(header.signature))
+        }
+    }
+
+    /// Find the table with the signature `signature` and check its checksum. On success, returns
+    /// the physical address and header of the table.
+    pub fn find_table(&self, signature: &[u8; 4]) -> Result<(PhysAddr, SdtHeader), AcpiError> {
+        unsafe {
+            let root: SdtHeader = self.read(self.root);
+            let entry_size = if self.extended { 8 } else { 4 };
+            let entries_start = self.root + mem::size_of::<SdtHeader>();
+            let entry_count = (root.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+            (0..entry_count)
+                .map(|i| {
+                    let entry = entries_start + i * entry_size;
+                    if self.extended {
+                        PhysAddr::new(self.read::<u64>(entry))
+                    } else {
+                        PhysAddr::new(self.read::<u32>(entry) as u64)
+                    }
+                })
+                .find(|&addr| self.read::<SdtHeader>(addr).signature == *signature)
+                .ok_or(AcpiError::TableNotFound(*signature))
+                .and_then(|addr| self.validate(addr).map(|header| (addr, header)))
+        }
+    }
+
+    /// Parse the Multiple APIC Description Table.
+    pub fn madt(&self) -> Result<Madt, AcpiError> {
+        let (addr, header) = self.find_table(b"APIC")?;
+        unsafe { Ok(Madt::parse(self, addr, header)) }
+    }
+
+    /// Parse the Fixed ACPI Description Table.
+    pub fn fadt(&self) -> Result<Fadt, AcpiError> {
+        let (addr, header) = self.find_table(b"FACP")?;
+        unsafe { Ok(Fadt::parse(self, addr, header)) }
+    }
+
+    /// Parse the High Precision Event Timer Description Table.
+    pub fn hpet(&self) -> Result<HpetInfo, AcpiError> {
+        let (addr, _) = self.find_table(b"HPET")?;
+        unsafe { Ok(HpetInfo::parse(self, addr)) }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+/// The maximum number of I/O APICs that are recorded from the MADT.
+pub const MAX_IO_APICS: usize = 8;
+
+/// The maximum number of interrupt source overrides that are recorded from the MADT.
+pub const MAX_OVERRIDES: usize = 16;
+
+/// The maximum number of processors that are recorded from the MADT.
+pub const MAX_PROCESSORS: usize = 32;
+
+/// An I/O APIC described by the MADT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IoApicInfo {
+    /// The APIC ID of the I/O APIC.
+    pub id: u8,
+    /// The physical address of the registers of the I/O APIC.
+    pub address: PhysAddr,
+    /// The first Global System Interrupt handled by the I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// The polarity of an interrupt signal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Polarity {
+    /// The interrupt is raised while the signal is high.
+    ActiveHigh,
+    /// The interrupt is raised while the signal is low.
+    ActiveLow,
+}
+
+/// How an interrupt signal is recognized.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TriggerMode {
+    /// The interrupt is raised when the signal changes.
+    Edge,
+    /// The interrupt is raised as long as the signal is at its active level.
+    Level,
+}
+
+/// A remapping of an ISA IRQ to a different Global System Interrupt, polarity, or trigger mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InterruptSourceOverride {
+    /// The ISA IRQ that is remapped.
+    pub source: u8,
+    /// The Global System Interrupt that the IRQ is delivered on.
+    pub gsi: u32,
+    /// The polarity of the interrupt signal.
+    pub polarity: Polarity,
+    /// The trigger mode of the interrupt signal.
+    pub trigger_mode: TriggerMode,
+}
+
+impl InterruptSourceOverride {
+    /// The routing of `irq` when the MADT has no override for it. ISA IRQs are active high and
+    /// edge triggered.
+    pub fn identity(irq: u8) -> Self {
+        Self {
+            source: irq,
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }
+    }
+
+    fn from_flags(source: u8, gsi: u32, flags: u16) -> Self {
+        // A value of 0b00 in either field means that the bus's default is used.
+        Self {
+            source,
+            gsi,
+            polarity: match flags & 0b11 {
+                0b11 => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            },
+            trigger_mode: match (flags >> 2) & 0b11 {
+                0b11 => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            },
+        }
+    }
+}
+
+/// The interrupt controllers described by the Multiple APIC Description Table.
+#[derive(Clone, Copy, Debug)]
+pub struct Madt {
+    /// The physical address of the registers of the local APIC of each processor.
+    pub local_apic_address: PhysAddr,
+    /// Whether the system also has dual 8259 PICs which must be disabled to use the APICs.
+    pub has_legacy_pics: bool,
+    io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    overrides: [Option<InterruptSourceOverride>; MAX_OVERRIDES],
+    processors: [Option<u8>; MAX_PROCESSORS],
+}
+
+impl Madt {
+    const PROCESSOR_LOCAL_APIC: u8 = 0;
+    const IO_APIC: u8 = 1;
+    const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+    const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+    unsafe fn parse(acpi: &Acpi, addr: PhysAddr, header: SdtHeader) -> Self {
+        let body = addr + mem::size_of::<SdtHeader>();
+        let mut madt = Self {
+            local_apic_address: PhysAddr::new(acpi.read::<u32>(body) as u64),
+            has_legacy_pics: acpi.read::<u32>(body + 4u64) & 1 != 0,
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+            processors: [None; MAX_PROCESSORS],
+        };
+        let entries = acpi.bytes(
+            body + 8u64,
+            header.length as usize - mem::size_of::<SdtHeader>() - 8,
+        );
+        let mut offset = 0;
+        while offset + 2 <= entries.len() {
+            let (entry_type, length) = (entries[offset], entries[offset + 1] as usize);
+            if length < 2 || offset + length > entries.len() {
+                break;
+            }
+            let entry = &entries[offset..offset + length];
+            let u32_at =
+                |i: usize| u32::from_le_bytes([entry[i], entry[i + 1], entry[i + 2], entry[i + 3]]);
+            match entry_type {
+                Self::PROCESSOR_LOCAL_APIC if length >= 8 => {
+                    // Bit 0 is set if the processor is enabled and bit 1 is set if it can be
+                    // enabled.
+                    if u32_at(4) & 0b11 != 0 {
+                        insert(&mut madt.processors, entry[3]);
+                    }
+                }
+                Self::IO_APIC if length >= 12 => insert(
+                    &mut madt.io_apics,
+                    IoApicInfo {
+                        id: entry[2],
+                        address: PhysAddr::new(u32_at(4) as u64),
+                        gsi_base: u32_at(8),
+                    },
+                ),
+                Self::INTERRUPT_SOURCE_OVERRIDE if length >= 10 => insert(
+                    &mut madt.overrides,
+                    InterruptSourceOverride::from_flags(
+                        entry[3],
+                        u32_at(4),
+                        u16::from_le_bytes([entry[8], entry[9]]),
+                    ),
+                ),
+                Self::LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+                    madt.local_apic_address =
+                        PhysAddr::new(u32_at(4) as u64 | (u32_at(8) as u64) << 32);
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        madt
+    }
+
+    /// The I/O APICs of the system.
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApicInfo> {
+        self.io_apics.iter().flatten()
+    }
+
+    /// The interrupt source overrides of the system.
+    pub fn overrides(&self) -> impl Iterator<Item = &InterruptSourceOverride> {
+        self.overrides.iter().flatten()
+    }
+
+    /// The local APIC IDs of the processors which are or can be enabled.
+    pub fn processors(&self) -> impl Iterator<Item = u8> + '_ {
+        self.processors.iter().flatten().copied()
+    }
+
+    /// The routing of the ISA IRQ `irq`, taking any override into account. Returns `None` if
+    /// `irq` has no override and the GSI it would be identity mapped to is claimed by the override
+    /// of another IRQ, like GSI 2 is by the PIT on most systems.
+    pub fn isa_irq_routing(&self, irq: u8) -> Option<InterruptSourceOverride> {
+        match self.overrides().find(|o| o.source == irq) {
+            Some(o) => Some(*o),
+            None if self.overrides().any(|o| o.gsi == irq as u32) => None,
+            None => Some(InterruptSourceOverride::identity(irq)),
+        }
+    }
+}
+
+/// The fields of the Fixed ACPI Description Table which are used by the kernel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fadt {
+    /// The index of the CMOS register which holds the century of the real-time clock, if the
+    /// firmware provides one.
+    pub century_register: Option<u8>,
+}
+
+impl Fadt {
+    /// The offset of the century field from the start of the table.
+    const CENTURY_OFFSET: u64 = 108;
+
+    unsafe fn parse(acpi: &Acpi, addr: PhysAddr, header: SdtHeader) -> Self {
+        // ACPI 1.0 tables may end before the century field.
+        let century = if header.length as u64 > Self::CENTURY_OFFSET {
+            acpi.read::<u8>(addr + Self::CENTURY_OFFSET)
+        } else {
+            0
+        };
+        Self {
+            century_register: if century == 0 { None } else { Some(century) },
+        }
+    }
+}
+
+/// The High Precision Event Timer described by the HPET table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HpetInfo {
+    /// The physical address of the registers of the HPET.
+    pub address: PhysAddr,
+    /// The sequence number of the HPET, which is 0 for the first HPET.
+    pub number: u8,
+    /// The smallest number of counts that a periodic timer can be programmed with without losing
+    /// interrupts.
+    pub minimum_tick: u16,
+}
+
+impl HpetInfo {
+    unsafe fn parse(acpi: &Acpi, addr: PhysAddr) -> Self {
+        let body = addr + mem::size_of::<SdtHeader>();
+        // The address is the last field of a 12 byte Generic Address Structure, which follows the
+        // 4 byte event timer block ID.
+        Self {
+            address: PhysAddr::new(acpi.read::<u64>(body + 8u64)),
+            number: acpi.read::<u8>(body + 16u64),
+            minimum_tick: acpi.read::<u16>(body + 17u64),
+        }
+    }
+}
+
+/// Put `value` in the first empty slot of `slots`. If there is no empty slot, `value` is dropped.
+fn insert<T>(slots: &mut [Option<T>], value: T) {
+    if let Some(slot) = slots.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::acpi]";
+
+    #[test_case]
+    fn test_checksum() {
+        serial_print!("{} test_checksum... ", TEST_PREFIX);
+        assert_eq!(checksum(&[0x01, 0x02, 0xFD]), 0);
+        assert_eq!(checksum(&[0xFF, 0x02]), 0x01);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_override_flags() {
+        serial_print!("{} test_override_flags... ", TEST_PREFIX);
+        let o = InterruptSourceOverride::from_flags(9, 9, 0b1111);
+        assert_eq!(o.polarity, Polarity::ActiveLow);
+        assert_eq!(o.trigger_mode, TriggerMode::Level);
+        let o = InterruptSourceOverride::from_flags(0, 2, 0);
+        assert_eq!(o.polarity, Polarity::ActiveHigh);
+        assert_eq!(o.trigger_mode, TriggerMode::Edge);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_isa_irq_routing() {
+        serial_print!("{} test_isa_irq_routing... ", TEST_PREFIX);
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(0xFEE0_0000),
+            has_legacy_pics: true,
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+            processors: [None; MAX_PROCESSORS],
+        };
+        insert(
+            &mut madt.overrides,
+            InterruptSourceOverride::from_flags(0, 2, 0),
+        );
+        insert(
+            &mut madt.overrides,
+            InterruptSourceOverride::from_flags(9, 9, 0b1111),
+        );
+        assert_eq!(madt.isa_irq_routing(0).map(|o| o.gsi), Some(2));
+        // The PIT's override claims GSI 2.
+        assert_eq!(madt.isa_irq_routing(2), None);
+        assert_eq!(
+            madt.isa_irq_routing(1),
+            Some(InterruptSourceOverride::identity(1))
+        );
+        assert_eq!(
+            madt.isa_irq_routing(9).map(|o| o.trigger_mode),
+            Some(TriggerMode::Level)
+        );
+        serial_println!("[ok]");
+    }
+}
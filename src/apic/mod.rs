@@ -0,0 +1,523 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+
+use x86_64::{registers::model_specific::Msr, VirtAddr};
+
+use crate::{
+    acpi::{Acpi, AcpiError, InterruptSourceOverride, Madt, Polarity, TriggerMode, MAX_IO_APICS},
+    memory::paging::{self, PagingError},
+    pic::{Irq, PIC_1_OFFSET},
+};
+
+/// The vector that the local APIC delivers spurious interrupts on. The local APIC never expects an
+/// end of interrupt for this vector.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The vector that the local APIC delivers internal errors on.
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+/// The vector that the local APIC timer interrupts are delivered on.
+pub const TIMER_VECTOR: u8 = 0xFD;
+
+/// The reason the APICs could not be set up.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ApicError {
+    /// The ACPI tables describing the APICs could not be read.
+    Acpi(AcpiError),
+    /// The MADT doesn't describe any I/O APIC.
+    NoIoApic,
+    /// The APICs have not been set up.
+    Inactive,
+    /// No I/O APIC handles the Global System Interrupt.
+    UnhandledGsi(u32),
+    /// The registers of an APIC could not be mapped.
+    Map(PagingError),
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(e: AcpiError) -> Self {
+        Self::Acpi(e)
+    }
+}
+
+impl From<PagingError> for ApicError {
+    fn from(e: PagingError) -> Self {
+        Self::Map(e)
+    }
+}
+
+/// The mode of the local APIC timer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimerMode {
+    /// Raise a single interrupt once the count reaches 0.
+    OneShot,
+    /// Raise an interrupt and reload the initial count every time the count reaches 0.
+    Periodic,
+}
+
+/// The value that the bus frequency is divided by to get the frequency of the local APIC timer.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimerDivide {
+    /// Divide by 1.
+    By1 = 0b1011,
+    /// Divide by 2.
+    By2 = 0b0000,
+    /// Divide by 4.
+    By4 = 0b0001,
+    /// Divide by 8.
+    By8 = 0b0010,
+    /// Divide by 16.
+    By16 = 0b0011,
+    /// Divide by 32.
+    By32 = 0b1000,
+    /// Divide by 64.
+    By64 = 0b1001,
+    /// Divide by 128.
+    By128 = 0b1010,
+}
+
+/// The local APIC of the current processor.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    /// The size of the registers of the local APIC.
+    const REGISTERS_SIZE: u64 = 0x400;
+
+    const IA32_APIC_BASE: u32 = 0x1B;
+    const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+    const ID: usize = 0x020;
+    const TASK_PRIORITY: usize = 0x080;
+    const END_OF_INTERRUPT: usize = 0x0B0;
+    const SPURIOUS_INTERRUPT_VECTOR: usize = 0x0F0;
+    const ERROR_STATUS: usize = 0x280;
+    const LVT_TIMER: usize = 0x320;
+    const LVT_LINT0: usize = 0x350;
+    const LVT_LINT1: usize = 0x360;
+    const LVT_ERROR: usize = 0x370;
+    const TIMER_INITIAL_COUNT: usize = 0x380;
+    const TIMER_CURRENT_COUNT: usize = 0x390;
+    const TIMER_DIVIDE: usize = 0x3E0;
+
+    const SOFTWARE_ENABLE: u32 = 1 << 8;
+    const LVT_MASKED: u32 = 1 << 16;
+    const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+    /// Get a handle to the local APIC whose registers are mapped at `base`.
+    ///
+    /// # Safety
+    /// The registers of the local APIC must be mapped at `base` and there must not be any other
+    /// handle to the local APIC.
+    pub unsafe fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    unsafe fn read(&self, register: usize) -> u32 {
+        ptr::read_volatile((self.base + register).as_ptr())
+    }
+
+    unsafe fn write(&mut self, register: usize, value: u32) {
+        ptr::write_volatile((self.base + register).as_mut_ptr(), value)
+    }
+
+    /// Enable the local APIC, delivering spurious interrupts on [`SPURIOUS_VECTOR`] and errors on
+    /// [`ERROR_VECTOR`]. The timer and the local interrupt pins are masked.
+    ///
+    /// [`ERROR_VECTOR`]: constant.ERROR_VECTOR.html
+    /// [`SPURIOUS_VECTOR`]: constant.SPURIOUS_VECTOR.html
+    pub fn enable(&mut self) {
+        unsafe {
+            let mut apic_base = Msr::new(Self::IA32_APIC_BASE);
+            let value = apic_base.read();
+            apic_base.write(value | Self::APIC_GLOBAL_ENABLE);
+
+            self.write(Self::LVT_TIMER, Self::LVT_MASKED);
+            self.write(Self::LVT_LINT0, Self::LVT_MASKED);
+            self.write(Self::LVT_LINT1, Self::LVT_MASKED);
+            self.write(Self::LVT_ERROR, ERROR_VECTOR as u32);
+            // The error status register must be written before it is read.
+            self.write(Self::ERROR_STATUS, 0);
+            self.write(Self::ERROR_STATUS, 0);
+            self.write(Self::TASK_PRIORITY, 0);
+            self.write(
+                Self::SPURIOUS_INTERRUPT_VECTOR,
+                Self::SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+            );
+        }
+    }
+
+    /// The APIC ID of the current processor.
+    pub fn id(&self) -> u8 {
+        unsafe { (self.read(Self::ID) >> 24) as u8 }
+    }
+
+    /// Signal the end of the interrupt that is currently being handled.
+    pub fn end_of_interrupt(&mut self) {
+        unsafe { self.write(Self::END_OF_INTERRUPT, 0) }
+    }
+
+    /// Read and clear the errors the local APIC has detected since the last time they were read.
+    pub fn error_status(&mut self) -> u32 {
+        unsafe {
+            self.write(Self::ERROR_STATUS, 0);
+            self.read(Self::ERROR_STATUS)
+        }
+    }
+
+    /// Start the timer counting down from `initial_count` at the bus frequency divided by
+    /// `divide`. The timer interrupt is delivered on [`TIMER_VECTOR`].
+    ///
+    /// [`TIMER_VECTOR`]: constant.TIMER_VECTOR.html
+    pub fn start_timer(&mut self, mode: TimerMode, divide: TimerDivide, initial_count: u32) {
+        let mode = match mode {
+            TimerMode::OneShot => 0,
+            TimerMode::Periodic => Self::LVT_TIMER_PERIODIC,
+        };
+        unsafe {
+            self.write(Self::TIMER_DIVIDE, divide as u32);
+            self.write(Self::LVT_TIMER, mode | TIMER_VECTOR as u32);
+            self.write(Self::TIMER_INITIAL_COUNT, initial_count);
+        }
+    }
+
+    /// Stop the timer and mask its interrupt.
+    pub fn stop_timer(&mut self) {
+        unsafe {
+            self.write(Self::LVT_TIMER, Self::LVT_MASKED);
+            self.write(Self::TIMER_INITIAL_COUNT, 0);
+        }
+    }
+
+    /// The current count of the timer.
+    pub fn timer_count(&self) -> u32 {
+        unsafe { self.read(Self::TIMER_CURRENT_COUNT) }
+    }
+}
+
+/// An entry of the redirection table of an I/O APIC, which determines how a Global System
+/// Interrupt is delivered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RedirectionEntry {
+    /// The vector to deliver the interrupt on.
+    pub vector: u8,
+    /// The polarity of the interrupt signal.
+    pub polarity: Polarity,
+    /// The trigger mode of the interrupt signal.
+    pub trigger_mode: TriggerMode,
+    /// Whether the interrupt is masked.
+    pub masked: bool,
+    /// The APIC ID of the processor to deliver the interrupt to.
+    pub destination: u8,
+}
+
+impl RedirectionEntry {
+    const ACTIVE_LOW: u64 = 1 << 13;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const MASKED: u64 = 1 << 16;
+
+    fn to_bits(self) -> u64 {
+        // Fixed delivery mode and physical destination mode are both 0.
+        let mut bits = self.vector as u64 | (self.destination as u64) << 56;
+        if self.polarity == Polarity::ActiveLow {
+            bits |= Self::ACTIVE_LOW;
+        }
+        if self.trigger_mode == TriggerMode::Level {
+            bits |= Self::LEVEL_TRIGGERED;
+        }
+        if self.masked {
+            bits |= Self::MASKED;
+        }
+        bits
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            vector: bits as u8,
+            polarity: if bits & Self::ACTIVE_LOW != 0 {
+                Polarity::ActiveLow
+            } else {
+                Polarity::ActiveHigh
+            },
+            trigger_mode: if bits & Self::LEVEL_TRIGGERED != 0 {
+                TriggerMode::Level
+            } else {
+                TriggerMode::Edge
+            },
+            masked: bits & Self::MASKED != 0,
+            destination: (bits >> 56) as u8,
+        }
+    }
+}
+
+/// An I/O APIC, which routes Global System Interrupts to local APICs.
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    /// The size of the registers of an I/O APIC.
+    const REGISTERS_SIZE: u64 = 0x20;
+
+    const REGISTER_SELECT: u64 = 0x00;
+    const REGISTER_WINDOW: u64 = 0x10;
+    const VERSION: u32 = 0x01;
+    const REDIRECTION_TABLE: u32 = 0x10;
+
+    /// Get a handle to the I/O APIC whose registers are mapped at `base` and which handles the
+    /// Global System Interrupts starting at `gsi_base`.
+    ///
+    /// # Safety
+    /// The registers of the I/O APIC must be mapped at `base` and there must not be any other
+    /// handle to the I/O APIC.
+    pub unsafe fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        let mut io_apic = Self {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(Self::VERSION) >> 16) & 0xFF) + 1;
+        io_apic
+    }
+
+    unsafe fn read(&mut self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + Self::REGISTER_SELECT).as_mut_ptr(), register);
+        ptr::read_volatile((self.base + Self::REGISTER_WINDOW).as_ptr())
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + Self::REGISTER_SELECT).as_mut_ptr(), register);
+        ptr::write_volatile((self.base + Self::REGISTER_WINDOW).as_mut_ptr(), value);
+    }
+
+    /// Whether the I/O APIC handles the Global System Interrupt `gsi`.
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    /// Get the redirection entry for `gsi`. `gsi` must be handled by the I/O APIC.
+    pub fn redirection(&mut self, gsi: u32) -> RedirectionEntry {
+        assert!(
+            self.handles(gsi),
+            "GSI {} is not handled by this I/O APIC",
+            gsi
+        );
+        let register = Self::REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        unsafe {
+            let low = self.read(register) as u64;
+            let high = self.read(register + 1) as u64;
+            RedirectionEntry::from_bits(low | high << 32)
+        }
+    }
+
+    /// Set the redirection entry for `gsi`. `gsi` must be handled by the I/O APIC.
+    pub fn set_redirection(&mut self, gsi: u32, entry: RedirectionEntry) {
+        assert!(
+            self.handles(gsi),
+            "GSI {} is not handled by this I/O APIC",
+            gsi
+        );
+        let register = Self::REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        let bits = entry.to_bits();
+        unsafe {
+            // Mask the entry while it is being changed so that a half-written entry is never used.
+            self.write(register, RedirectionEntry::MASKED as u32);
+            self.write(register + 1, (bits >> 32) as u32);
+            self.write(register, bits as u32);
+        }
+    }
+}
+
+/// The state of the APICs once they have been set up.
+struct Apics {
+    local: LocalApic,
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    madt: Madt,
+}
+
+impl Apics {
+    fn io_apic_for(&mut self, gsi: u32) -> Option<&mut IoApic> {
+        self.io_apics
+            .iter_mut()
+            .flatten()
+            .find(|io_apic| io_apic.handles(gsi))
+    }
+
+    /// The I/O APIC and GSI that `irq` is delivered through, if it has a redirection entry of its
+    /// own. The cascade never raises an interrupt, so it has none.
+    fn route_of(&mut self, irq: Irq) -> Option<(&mut IoApic, u32)> {
+        if irq == Irq::Cascade {
+            return None;
+        }
+        let InterruptSourceOverride { gsi, .. } = self.madt.isa_irq_routing(irq.line())?;
+        self.io_apic_for(gsi).map(|io_apic| (io_apic, gsi))
+    }
+}
+
+static APICS: Mutex<Option<Apics>> = Mutex::new(None);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Set up the local APIC of the current processor and every I/O APIC described by the MADT. Every
+/// ISA IRQ except the cascade is routed to the same vector the PICs would deliver it on,
+/// respecting the interrupt source overrides, and starts out masked. A GSI which an override
+/// claims for another IRQ is left alone. The PICs are not touched.
+///
+/// # Safety
+/// Interrupts must be disabled and must not be called more than once. The registers of the APICs
+/// are mapped into the MMIO region.
+pub unsafe fn init(acpi: &Acpi) -> Result<(), ApicError> {
+    let madt = acpi.madt()?;
+    if madt.io_apics().next().is_none() {
+        return Err(ApicError::NoIoApic);
+    }
+    let base = paging::map_mmio(madt.local_apic_address, LocalApic::REGISTERS_SIZE)?;
+    let mut local = LocalApic::new(base);
+    local.enable();
+    let mut apics = Apics {
+        io_apics: Default::default(),
+        local,
+        madt,
+    };
+    for (slot, info) in apics.io_apics.iter_mut().zip(madt.io_apics()) {
+        let base = paging::map_mmio(info.address, IoApic::REGISTERS_SIZE)?;
+        *slot = Some(IoApic::new(base, info.gsi_base));
+    }
+    let destination = apics.local.id();
+    for irq in Irq::lines() {
+        let routing = match madt.isa_irq_routing(irq.line()) {
+            Some(routing) => routing,
+            None => continue,
+        };
+        if let Some((io_apic, gsi)) = apics.route_of(irq) {
+            io_apic.set_redirection(
+                gsi,
+                RedirectionEntry {
+                    vector: PIC_1_OFFSET + irq.line(),
+                    polarity: routing.polarity,
+                    trigger_mode: routing.trigger_mode,
+                    masked: true,
+                    destination,
+                },
+            );
+        }
+    }
+    *APICS.lock() = Some(apics);
+    ACTIVE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Whether the APICs have been set up and are delivering interrupts instead of the PICs.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Signal the end of the interrupt that is currently being handled to the local APIC. Does
+/// nothing if the APICs are not active.
+pub fn end_of_interrupt() {
+    if let Some(apics) = APICS.lock().as_mut() {
+        apics.local.end_of_interrupt();
+    }
+}
+
+/// Mask or unmask the ISA IRQ `irq` at the I/O APIC that handles it. Does nothing if the APICs are
+/// not active or `irq` isn't routed through them.
+pub fn set_masked(irq: Irq, masked: bool) {
+    if let Some((io_apic, gsi)) = APICS.lock().as_mut().and_then(|apics| apics.route_of(irq)) {
+        let entry = io_apic.redirection(gsi);
+        io_apic.set_redirection(gsi, RedirectionEntry { masked, ..entry });
+    }
+}
+
+/// Whether the ISA IRQ `irq` is masked at the I/O APIC that handles it. An IRQ which isn't routed
+/// through the APICs is always masked.
+pub fn is_masked(irq: Irq) -> bool {
+    APICS
+        .lock()
+        .as_mut()
+        .and_then(|apics| apics.route_of(irq))
+        .map_or(true, |(io_apic, gsi)| io_apic.redirection(gsi).masked)
+}
+
+/// Deliver the Global System Interrupt `gsi` on `vector` to the current processor, replacing
+/// whatever the redirection entry held before. The interrupt is unmasked.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+) -> Result<(), ApicError> {
+    let mut apics = APICS.lock();
+    let apics = apics.as_mut().ok_or(ApicError::Inactive)?;
+    let destination = apics.local.id();
+    let io_apic = apics.io_apic_for(gsi).ok_or(ApicError::UnhandledGsi(gsi))?;
+    io_apic.set_redirection(
+        gsi,
+        RedirectionEntry {
+            vector,
+            polarity,
+            trigger_mode,
+            masked: false,
+            destination,
+        },
+    );
+    Ok(())
+}
+
+/// The address and data of a Message Signaled Interrupt which is delivered on `vector` to the
+/// current processor, as a fixed, edge triggered interrupt.
+pub fn msi_message(vector: u8) -> Result<(u32, u32), ApicError> {
+    const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+    let destination = with_local_apic(|local| local.id()).ok_or(ApicError::Inactive)?;
+    Ok((MSI_ADDRESS_BASE | (destination as u32) << 12, vector as u32))
+}
+
+/// Run `f` with the local APIC of the current processor, if the APICs are active.
+pub fn with_local_apic<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&mut LocalApic) -> T,
+{
+    APICS.lock().as_mut().map(|apics| f(&mut apics.local))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::apic]";
+
+    #[test_case]
+    fn test_redirection_entry_bits() {
+        serial_print!("{} test_redirection_entry_bits... ", TEST_PREFIX);
+        let entry = RedirectionEntry {
+            vector: 0x29,
+            polarity: Polarity::ActiveLow,
+            trigger_mode: TriggerMode::Level,
+            masked: true,
+            destination: 3,
+        };
+        assert_eq!(entry.to_bits(), 0x0300_0000_0001_A029);
+        assert_eq!(RedirectionEntry::from_bits(entry.to_bits()), entry);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_inactive() {
+        serial_print!("{} test_inactive... ", TEST_PREFIX);
+        // The library tests run with the PICs.
+        assert_eq!(msi_message(0x60), Err(ApicError::Inactive));
+        assert_eq!(
+            route_gsi(16, 0x60, Polarity::ActiveHigh, TriggerMode::Edge),
+            Err(ApicError::Inactive)
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,213 @@
+use core::fmt::{self, Display, Formatter};
+
+use crate::cpu_exception::fixup;
+
+/// The symbol table embedded in the kernel image.
+pub mod symbols;
+use symbols::Symbol;
+
+/// The maximum number of frames walked before giving up, in case the chain of frame pointers is
+/// corrupt.
+pub const MAX_FRAMES: usize = 32;
+
+/// A single call frame found by walking the chain of saved frame pointers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Frame {
+    /// The address execution returns to, or the saved instruction for the first frame of an
+    /// exception.
+    pub address: u64,
+    /// The function containing the address, if it could be resolved.
+    pub symbol: Option<Symbol>,
+}
+
+impl Frame {
+    /// Create a frame for the instruction at `address`.
+    pub fn new(address: u64) -> Self {
+        Self {
+            address,
+            symbol: symbols::resolve(address),
+        }
+    }
+
+    /// Create a frame for the return address `address`. The return address is the instruction
+    /// after the call, which is in a different function if the call was the last instruction of
+    /// the caller, so the call itself is resolved instead.
+    pub fn from_return_address(address: u64) -> Self {
+        Self {
+            address,
+            symbol: symbols::resolve(address.wrapping_sub(1)),
+        }
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.symbol {
+            Some(symbol) => write!(
+                f,
+                "{:#018X} {}+{:#X}",
+                self.address,
+                symbol.name,
+                self.address - symbol.address,
+            ),
+            None => write!(f, "{:#018X} <unknown>", self.address),
+        }
+    }
+}
+
+/// Whether `frame_pointer` could point to a saved frame pointer. This only rejects values that
+/// are certainly wrong, before the frame is probed.
+fn is_plausible(frame_pointer: u64) -> bool {
+    let canonical = frame_pointer < 0x0000_8000_0000_0000 || frame_pointer >= 0xFFFF_8000_0000_0000;
+    frame_pointer != 0 && frame_pointer % 8 == 0 && canonical
+}
+
+/// An iterator over the frames on the stack, from the innermost call outwards. It relies on every
+/// function saving its caller's `RBP` at `[RBP]` and its return address at `[RBP + 8]`, which the
+/// kernel is compiled to do with `-C force-frame-pointers=yes`.
+#[derive(Clone, Copy, Debug)]
+pub struct Backtrace {
+    first: Option<u64>,
+    frame_pointer: u64,
+    frames: usize,
+}
+
+impl Backtrace {
+    /// Walk the stack of the caller.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let frame_pointer: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack));
+        }
+        Self::from_frame_pointer(frame_pointer)
+    }
+
+    /// Walk the stack starting from the frame that `frame_pointer` points to.
+    pub fn from_frame_pointer(frame_pointer: u64) -> Self {
+        Self {
+            first: None,
+            frame_pointer,
+            frames: 0,
+        }
+    }
+
+    /// Walk the stack of code that was interrupted at `instruction_pointer` with `frame_pointer`
+    /// in `RBP`. The interrupted instruction is the first frame.
+    pub fn from_exception(instruction_pointer: u64, frame_pointer: u64) -> Self {
+        Self {
+            first: Some(instruction_pointer),
+            frame_pointer,
+            frames: 0,
+        }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(address) = self.first.take() {
+            self.frames += 1;
+            return Some(Frame::new(address));
+        }
+        if self.frames >= MAX_FRAMES || !is_plausible(self.frame_pointer) {
+            return None;
+        }
+        // A corrupt chain can point anywhere, so the frame is probed rather than read. Faulting
+        // here would lose the backtrace of a fault that is being reported.
+        let [caller_frame_pointer, return_address] =
+            match unsafe { fixup::probe_read::<[u64; 2]>(self.frame_pointer) } {
+                Ok(frame) => frame,
+                Err(_) => return None,
+            };
+        if return_address == 0 {
+            return None;
+        }
+        let frame = Frame::from_return_address(return_address);
+        // The stack grows down, so every caller's frame is above its callee's. Anything else
+        // means the chain is corrupt or has reached the frame that the bootloader called into.
+        self.frame_pointer = if caller_frame_pointer > self.frame_pointer {
+            caller_frame_pointer
+        } else {
+            0
+        };
+        self.frames += 1;
+        // Every kernel function is in the symbol table, so an address outside all of them means
+        // the walk has left the kernel's stack.
+        if frame.symbol.is_none() && symbols::is_loaded() {
+            self.frame_pointer = 0;
+        }
+        Some(frame)
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+        if !symbols::is_loaded() {
+            write!(f, " (no symbol table embedded)")?;
+        }
+        let frames = *self;
+        for (index, frame) in frames.enumerate() {
+            write!(f, "\n  {:2}: {}", index, frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use x86_64::VirtAddr;
+
+    use crate::memory::paging;
+
+    const TEST_PREFIX: &'static str = "[rust_os::backtrace]";
+
+    #[inline(never)]
+    fn backtrace_from_callee() -> Backtrace {
+        Backtrace::current()
+    }
+
+    #[test_case]
+    fn test_current_backtrace() {
+        serial_print!("{} test_current_backtrace... ", TEST_PREFIX);
+        let frames = backtrace_from_callee().count();
+        assert!(frames > 0 && frames <= MAX_FRAMES);
+        if symbols::is_loaded() {
+            let frame = backtrace_from_callee().next().unwrap();
+            let symbol = frame.symbol.expect("Return address was not resolved");
+            assert!(symbol.name.ends_with("test_current_backtrace"));
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_implausible_frame_pointer() {
+        serial_print!("{} test_implausible_frame_pointer... ", TEST_PREFIX);
+        assert_eq!(Backtrace::from_frame_pointer(0).count(), 0);
+        assert_eq!(Backtrace::from_frame_pointer(0x1003).count(), 0);
+        assert_eq!(
+            Backtrace::from_frame_pointer(0x0000_8000_0000_0000).count(),
+            0
+        );
+        let mut backtrace = Backtrace::from_exception(0x1234, 0);
+        assert_eq!(backtrace.next().map(|frame| frame.address), Some(0x1234));
+        assert_eq!(backtrace.next(), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_unmapped_frame_pointer() {
+        serial_print!("{} test_unmapped_frame_pointer... ", TEST_PREFIX);
+        let unmapped = 0xFFFF_FD80_7FFF_F000;
+        assert_eq!(paging::translate(VirtAddr::new(unmapped)), None);
+        assert_eq!(Backtrace::from_frame_pointer(unmapped).count(), 0);
+        let mut backtrace = Backtrace::from_exception(0x1234, unmapped);
+        assert_eq!(backtrace.next().map(|frame| frame.address), Some(0x1234));
+        assert_eq!(backtrace.next(), None);
+        serial_println!("[ok]");
+    }
+}
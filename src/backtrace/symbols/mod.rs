@@ -0,0 +1,129 @@
+use core::{cell::UnsafeCell, convert::TryInto, slice, str};
+
+/// The number of bytes reserved in the kernel image for the symbol table.
+pub const SYMBOL_TABLE_SIZE: usize = 0x8_0000;
+
+/// The magic number at the start of the section before `tools/embed_symbols.py` has filled it.
+const EMPTY_MAGIC: [u8; 8] = *b"NOSYMTAB";
+
+/// The magic number at the start of a filled symbol table.
+const MAGIC: [u8; 8] = *b"SYMTAB01";
+
+/// The size of the header: the magic number, the number of symbols and the offset of the names.
+const HEADER_SIZE: usize = 16;
+
+/// The size of each symbol: its address, its size, and the offset and length of its name.
+const ENTRY_SIZE: usize = 24;
+
+/// The bytes reserved for the symbol table. The initial magic number keeps the section out of
+/// `.bss` so that it takes up space in the image, and the `UnsafeCell` stops the compiler from
+/// assuming the section still holds its initial value.
+#[repr(C)]
+struct SymbolSection(UnsafeCell<[u8; SYMBOL_TABLE_SIZE]>);
+
+unsafe impl Sync for SymbolSection {}
+
+const fn initial_section() -> [u8; SYMBOL_TABLE_SIZE] {
+    let mut bytes = [0; SYMBOL_TABLE_SIZE];
+    let mut i = 0;
+    while i < EMPTY_MAGIC.len() {
+        bytes[i] = EMPTY_MAGIC[i];
+        i += 1;
+    }
+    bytes
+}
+
+/// The symbol table, filled in after linking by `tools/embed_symbols.py`. The table starts with
+/// a header holding [`MAGIC`], the number of symbols and the offset of the names from the start of
+/// the table, each number in little-endian byte order. The symbols follow, sorted by address, and
+/// then their names.
+#[used]
+#[link_section = ".kernel_symbols"]
+static SYMBOL_TABLE: SymbolSection = SymbolSection(UnsafeCell::new(initial_section()));
+
+/// A function in the kernel image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    /// The demangled name of the function.
+    pub name: &'static str,
+    /// The address of the first instruction of the function.
+    pub address: u64,
+    /// The size of the function in bytes.
+    pub size: u64,
+}
+
+impl Symbol {
+    /// Whether `address` is inside the function.
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.address && address - self.address < self.size
+    }
+}
+
+fn table() -> &'static [u8] {
+    unsafe { slice::from_raw_parts(SYMBOL_TABLE.0.get() as *const u8, SYMBOL_TABLE_SIZE) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Whether a symbol table was embedded in the kernel image. Without one, addresses can't be
+/// resolved to functions.
+pub fn is_loaded() -> bool {
+    table()[..MAGIC.len()] == MAGIC
+}
+
+/// The number of symbols in the table.
+pub fn count() -> usize {
+    if is_loaded() {
+        read_u32(table(), 8)
+    } else {
+        0
+    }
+}
+
+/// The `index`th symbol in order of address.
+fn symbol(index: usize) -> Symbol {
+    let table = table();
+    let names = read_u32(table, 12);
+    let entry = HEADER_SIZE + index * ENTRY_SIZE;
+    let name_offset = names + read_u32(table, entry + 16);
+    let name_len = read_u32(table, entry + 20);
+    let name = table
+        .get(name_offset..name_offset + name_len)
+        .and_then(|name| str::from_utf8(name).ok())
+        .unwrap_or("<invalid symbol name>");
+    Symbol {
+        name,
+        address: read_u64(table, entry),
+        size: read_u64(table, entry + 8),
+    }
+}
+
+/// The function containing `address`, if the symbol table has one.
+pub fn resolve(address: u64) -> Option<Symbol> {
+    let count = count();
+    if count > (SYMBOL_TABLE_SIZE - HEADER_SIZE) / ENTRY_SIZE {
+        return None;
+    }
+    // Find the last symbol which starts at or before `address`.
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if symbol(middle).address <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let candidate = symbol(low.checked_sub(1)?);
+    if candidate.contains(address) {
+        Some(candidate)
+    } else {
+        None
+    }
+}
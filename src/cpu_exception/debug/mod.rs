@@ -0,0 +1,443 @@
+use spin::Mutex;
+
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+use super::interrupts::trap::TrapFrame;
+
+/// The number of watchpoints the CPU supports, one for each of `DR0` through `DR3`.
+pub const WATCHPOINT_COUNT: usize = 4;
+
+/// The resume flag in `RFLAGS`. While it is set, instruction breakpoints are ignored for one
+/// instruction, so that returning to an instruction that hit a breakpoint doesn't hit it again.
+const RESUME_FLAG: u64 = 1 << 16;
+
+/// The value of `DR6` with no debug conditions recorded. The reserved bits must be written as
+/// ones.
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+/// The `DR7` bit that makes the CPU report the exact instruction which hit a data watchpoint.
+const DR7_LOCAL_EXACT: u64 = 1 << 8;
+
+/// The access that triggers a watchpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Condition {
+    /// Executing the instruction at the address. The exception is raised before the instruction
+    /// runs.
+    Execute,
+    /// Writing to the watched bytes. The exception is raised after the instruction runs.
+    Write,
+    /// Reading from or writing to the watched bytes, but not fetching instructions from them. The
+    /// exception is raised after the instruction runs.
+    ReadWrite,
+}
+
+impl Condition {
+    /// The encoding of the condition in the `R/W` fields of `DR7`.
+    fn bits(&self) -> u64 {
+        match self {
+            Self::Execute => 0b00,
+            Self::Write => 0b01,
+            Self::ReadWrite => 0b11,
+        }
+    }
+}
+
+/// The number of bytes a watchpoint covers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Length {
+    /// A single byte.
+    One,
+    /// Two bytes, aligned to two bytes.
+    Two,
+    /// Four bytes, aligned to four bytes.
+    Four,
+    /// Eight bytes, aligned to eight bytes.
+    Eight,
+}
+
+impl Length {
+    /// The number of bytes covered.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Self::One => 1,
+            Self::Two => 2,
+            Self::Four => 4,
+            Self::Eight => 8,
+        }
+    }
+
+    /// The encoding of the length in the `LEN` fields of `DR7`.
+    fn bits(&self) -> u64 {
+        match self {
+            Self::One => 0b00,
+            Self::Two => 0b01,
+            Self::Eight => 0b10,
+            Self::Four => 0b11,
+        }
+    }
+}
+
+/// A hardware watchpoint on a range of kernel addresses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+    /// The first address watched.
+    pub address: VirtAddr,
+    /// The access that triggers the watchpoint.
+    pub condition: Condition,
+    /// The number of bytes watched.
+    pub length: Length,
+}
+
+/// The reason a watchpoint could not be set.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchpointError {
+    /// The address is not aligned to the length of the watchpoint.
+    Unaligned(VirtAddr),
+    /// An [`Execute`] watchpoint was given a length other than [`One`].
+    ///
+    /// [`Execute`]: enum.Condition.html#variant.Execute
+    /// [`One`]: enum.Length.html#variant.One
+    InvalidLength(Length),
+    /// Every debug register is already in use.
+    NoFreeRegister,
+    /// The index does not name a debug register or no watchpoint is set in it.
+    NotSet(usize),
+}
+
+/// A watchpoint which fired.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WatchpointHit {
+    /// The index of the debug register holding the watchpoint.
+    pub index: usize,
+    /// The watchpoint.
+    pub watchpoint: Watchpoint,
+    /// The address of the saved instruction. For [`Execute`] watchpoints this is the watched
+    /// instruction; for data watchpoints it is the instruction after the access.
+    ///
+    /// [`Execute`]: enum.Condition.html#variant.Execute
+    pub instruction_pointer: VirtAddr,
+}
+
+/// A function which is called from the [`Debug`] handler for every watchpoint that fires. It runs
+/// with interrupts disabled in the middle of the code that made the access, so it must not take
+/// any lock that code might hold; printing to the serial port is safe while watching the VGA
+/// buffer, for example.
+///
+/// [`Debug`]: ../enum.CpuException.html#variant.Debug
+pub type WatchpointHandler = fn(hit: WatchpointHit);
+
+/// The decoded value of `DR6`, which records why the last [`Debug`] exception was raised.
+///
+/// [`Debug`]: ../enum.CpuException.html#variant.Debug
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DebugStatus(pub u64);
+
+impl DebugStatus {
+    /// Read `DR6`.
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe {
+            asm!("mov {}, dr6", out(reg) value, options(nomem, nostack));
+        }
+        Self(value)
+    }
+
+    /// Whether the condition of the watchpoint in debug register `index` was met. The CPU
+    /// reports this even for watchpoints that aren't enabled.
+    pub fn is_triggered(&self, index: usize) -> bool {
+        index < WATCHPOINT_COUNT && self.0 & (1 << index) != 0
+    }
+
+    /// Whether an access to a debug register was detected.
+    pub fn is_register_access(&self) -> bool {
+        self.0 & (1 << 13) != 0
+    }
+
+    /// Whether the exception was raised by the trap flag after a single instruction.
+    pub fn is_single_step(&self) -> bool {
+        self.0 & (1 << 14) != 0
+    }
+
+    /// Whether the exception was raised by a task switch to a task with the debug trap flag set.
+    pub fn is_task_switch(&self) -> bool {
+        self.0 & (1 << 15) != 0
+    }
+}
+
+struct DebugRegisters {
+    watchpoints: [Option<Watchpoint>; WATCHPOINT_COUNT],
+    handler: Option<WatchpointHandler>,
+}
+
+/// The watchpoints in the debug registers and the handler for them. The [`Debug`] handler locks
+/// them, so interrupts must be disabled while they are locked.
+///
+/// [`Debug`]: ../enum.CpuException.html#variant.Debug
+static DEBUG_REGISTERS: Mutex<DebugRegisters> = Mutex::new(DebugRegisters {
+    watchpoints: [None; WATCHPOINT_COUNT],
+    handler: None,
+});
+
+/// Write `address` to debug register `index`.
+unsafe fn write_address(index: usize, address: u64) {
+    match index {
+        0 => asm!("mov dr0, {}", in(reg) address, options(nomem, nostack)),
+        1 => asm!("mov dr1, {}", in(reg) address, options(nomem, nostack)),
+        2 => asm!("mov dr2, {}", in(reg) address, options(nomem, nostack)),
+        3 => asm!("mov dr3, {}", in(reg) address, options(nomem, nostack)),
+        _ => unreachable!("There are only four address debug registers"),
+    }
+}
+
+unsafe fn write_dr6(value: u64) {
+    asm!("mov dr6, {}", in(reg) value, options(nomem, nostack));
+}
+
+unsafe fn write_dr7(value: u64) {
+    asm!("mov dr7, {}", in(reg) value, options(nomem, nostack));
+}
+
+/// Build the value of `DR7` which enables exactly `watchpoints`.
+fn control_value(watchpoints: &[Option<Watchpoint>; WATCHPOINT_COUNT]) -> u64 {
+    let value = watchpoints
+        .iter()
+        .enumerate()
+        .filter_map(|(index, watchpoint)| Some((index, (*watchpoint)?)))
+        .fold(0, |value, (index, watchpoint)| {
+            let fields = watchpoint.condition.bits() | watchpoint.length.bits() << 2;
+            value | 1 << (2 * index) | fields << (16 + 4 * index)
+        });
+    if value == 0 {
+        0
+    } else {
+        value | DR7_LOCAL_EXACT
+    }
+}
+
+/// Set a watchpoint in the first free debug register. Returns the index of the register.
+pub fn set_watchpoint(watchpoint: Watchpoint) -> Result<usize, WatchpointError> {
+    if watchpoint.condition == Condition::Execute && watchpoint.length != Length::One {
+        return Err(WatchpointError::InvalidLength(watchpoint.length));
+    }
+    if watchpoint.address.as_u64() % watchpoint.length.bytes() != 0 {
+        return Err(WatchpointError::Unaligned(watchpoint.address));
+    }
+    without_interrupts(|| {
+        let mut registers = DEBUG_REGISTERS.lock();
+        let index = registers
+            .watchpoints
+            .iter()
+            .position(Option::is_none)
+            .ok_or(WatchpointError::NoFreeRegister)?;
+        registers.watchpoints[index] = Some(watchpoint);
+        unsafe {
+            write_address(index, watchpoint.address.as_u64());
+            write_dr7(control_value(&registers.watchpoints));
+        }
+        Ok(index)
+    })
+}
+
+/// Remove the watchpoint in debug register `index`. Returns the watchpoint that was removed.
+pub fn clear_watchpoint(index: usize) -> Result<Watchpoint, WatchpointError> {
+    without_interrupts(|| {
+        let mut registers = DEBUG_REGISTERS.lock();
+        let watchpoint = registers
+            .watchpoints
+            .get_mut(index)
+            .and_then(Option::take)
+            .ok_or(WatchpointError::NotSet(index))?;
+        unsafe {
+            write_dr7(control_value(&registers.watchpoints));
+            write_address(index, 0);
+        }
+        Ok(watchpoint)
+    })
+}
+
+/// The watchpoint in debug register `index`, if any.
+pub fn watchpoint(index: usize) -> Option<Watchpoint> {
+    without_interrupts(|| {
+        DEBUG_REGISTERS
+            .lock()
+            .watchpoints
+            .get(index)
+            .copied()
+            .flatten()
+    })
+}
+
+/// Call `handler` for every watchpoint that fires from now on, instead of the previous handler.
+/// Without a handler, each hit is printed to the serial port.
+pub fn set_handler(handler: WatchpointHandler) {
+    without_interrupts(|| DEBUG_REGISTERS.lock().handler = Some(handler));
+}
+
+/// Stop calling the handler set with [`set_handler`].
+///
+/// [`set_handler`]: fn.set_handler.html
+pub fn clear_handler() {
+    without_interrupts(|| DEBUG_REGISTERS.lock().handler = None);
+}
+
+fn print_hit(hit: WatchpointHit) {
+    serial_println!(
+        "Watchpoint {} ({:?} of {} bytes at {:?}) hit at {:?}",
+        hit.index,
+        hit.watchpoint.condition,
+        hit.watchpoint.length.bytes(),
+        hit.watchpoint.address,
+        hit.instruction_pointer,
+    );
+}
+
+/// Decode `DR6` for the [`Debug`] exception described by `frame`, clear it, and call the handler
+/// for every enabled watchpoint that fired. Returns whether the exception was caused only by
+/// watchpoints, in which case it has been fully handled.
+///
+/// [`Debug`]: ../enum.CpuException.html#variant.Debug
+pub(super) fn handle_debug_exception(frame: &mut TrapFrame) -> bool {
+    let status = DebugStatus::read();
+    unsafe {
+        write_dr6(DR6_CLEAR);
+    }
+    let (watchpoints, handler) = {
+        let registers = DEBUG_REGISTERS.lock();
+        (registers.watchpoints, registers.handler)
+    };
+    let handler = handler.unwrap_or(print_hit);
+    let mut any_hit = false;
+    for (index, watchpoint) in watchpoints.iter().enumerate() {
+        if let (Some(watchpoint), true) = (watchpoint, status.is_triggered(index)) {
+            any_hit = true;
+            if watchpoint.condition == Condition::Execute {
+                frame.rflags |= RESUME_FLAG;
+            }
+            handler(WatchpointHit {
+                index,
+                watchpoint: *watchpoint,
+                instruction_pointer: VirtAddr::new(frame.rip),
+            });
+        }
+    }
+    any_hit && !status.is_single_step() && !status.is_register_access() && !status.is_task_switch()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::{
+        ptr,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    };
+
+    const TEST_PREFIX: &'static str = "[rust_os::cpu_exception::debug]";
+
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+    static LAST_INDEX: AtomicUsize = AtomicUsize::new(WATCHPOINT_COUNT);
+    static mut WATCHED: u64 = 0;
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+
+    fn count_hit(hit: WatchpointHit) {
+        HITS.fetch_add(1, Ordering::SeqCst);
+        LAST_INDEX.store(hit.index, Ordering::SeqCst);
+    }
+
+    #[inline(never)]
+    fn watched_function() {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn test_control_value() {
+        serial_print!("{} test_control_value... ", TEST_PREFIX);
+        let watchpoint = Watchpoint {
+            address: VirtAddr::new(0x1000),
+            condition: Condition::Write,
+            length: Length::Four,
+        };
+        let watchpoints = [None, Some(watchpoint), None, None];
+        assert_eq!(
+            control_value(&watchpoints),
+            1 << 2 | 0b1101 << 20 | DR7_LOCAL_EXACT
+        );
+        assert_eq!(control_value(&[None; WATCHPOINT_COUNT]), 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_invalid_watchpoints() {
+        serial_print!("{} test_invalid_watchpoints... ", TEST_PREFIX);
+        let unaligned = Watchpoint {
+            address: VirtAddr::new(0x1002),
+            condition: Condition::ReadWrite,
+            length: Length::Eight,
+        };
+        assert_eq!(
+            set_watchpoint(unaligned),
+            Err(WatchpointError::Unaligned(unaligned.address))
+        );
+        let long_execute = Watchpoint {
+            address: VirtAddr::new(0x1000),
+            condition: Condition::Execute,
+            length: Length::Two,
+        };
+        assert_eq!(
+            set_watchpoint(long_execute),
+            Err(WatchpointError::InvalidLength(Length::Two))
+        );
+        assert_eq!(clear_watchpoint(4), Err(WatchpointError::NotSet(4)));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_write_watchpoint() {
+        serial_print!("{} test_write_watchpoint... ", TEST_PREFIX);
+        set_handler(count_hit);
+        let address = unsafe { &mut WATCHED as *mut u64 };
+        let index = set_watchpoint(Watchpoint {
+            address: VirtAddr::from_ptr(address),
+            condition: Condition::Write,
+            length: Length::Eight,
+        })
+        .expect("Failed to set watchpoint");
+        let hits = HITS.load(Ordering::SeqCst);
+        unsafe {
+            let _ = ptr::read_volatile(address);
+        }
+        assert_eq!(HITS.load(Ordering::SeqCst), hits);
+        unsafe {
+            ptr::write_volatile(address, 7);
+        }
+        assert_eq!(HITS.load(Ordering::SeqCst), hits + 1);
+        assert_eq!(LAST_INDEX.load(Ordering::SeqCst), index);
+        assert!(clear_watchpoint(index).is_ok());
+        unsafe {
+            ptr::write_volatile(address, 8);
+        }
+        assert_eq!(HITS.load(Ordering::SeqCst), hits + 1);
+        clear_handler();
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_execute_watchpoint() {
+        serial_print!("{} test_execute_watchpoint... ", TEST_PREFIX);
+        set_handler(count_hit);
+        let index = set_watchpoint(Watchpoint {
+            address: VirtAddr::new(watched_function as usize as u64),
+            condition: Condition::Execute,
+            length: Length::One,
+        })
+        .expect("Failed to set watchpoint");
+        let hits = HITS.load(Ordering::SeqCst);
+        let calls = CALLS.load(Ordering::SeqCst);
+        watched_function();
+        assert_eq!(HITS.load(Ordering::SeqCst), hits + 1);
+        assert_eq!(CALLS.load(Ordering::SeqCst), calls + 1);
+        assert!(clear_watchpoint(index).is_ok());
+        clear_handler();
+        serial_println!("[ok]");
+    }
+}
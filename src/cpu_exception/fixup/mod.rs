@@ -0,0 +1,239 @@
+use core::{
+    mem::{self, MaybeUninit},
+    slice,
+};
+
+use spin::Mutex;
+
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+use super::CpuException;
+
+/// An entry of the exception table. If an instruction in `start..end` faults, execution resumes
+/// at `fixup` instead of the fault being treated as a kernel bug.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct Fixup {
+    /// The address of the first marked instruction.
+    pub start: u64,
+    /// The address one past the last marked instruction.
+    pub end: u64,
+    /// The address to resume at.
+    pub fixup: u64,
+}
+
+impl Fixup {
+    /// Whether the instruction at `address` is marked by the entry.
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        (self.start..self.end).contains(&address.as_u64())
+    }
+}
+
+/// A fault which was recovered from through the exception table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fault {
+    /// The exception that was thrown: [`PageFault`] or [`GeneralProtectionFault`].
+    ///
+    /// [`PageFault`]: ../enum.CpuException.html#variant.PageFault
+    /// [`GeneralProtectionFault`]: ../enum.CpuException.html#variant.GeneralProtectionFault
+    pub exception: CpuException,
+    /// The error code of the exception.
+    pub error_code: u64,
+    /// The address whose access faulted, if the CPU reported it.
+    pub address: Option<VirtAddr>,
+}
+
+extern "C" {
+    /// The first entry of the exception table. The linker defines this symbol for the
+    /// `rust_os_fixups` section.
+    static __start_rust_os_fixups: Fixup;
+    /// The address one past the last entry of the exception table.
+    static __stop_rust_os_fixups: Fixup;
+    /// Copy `len` bytes from `src` to `dest` and return 0, or return 1 if the copy faults.
+    fn rust_os_probe_copy(dest: *mut u8, src: *const u8, len: usize) -> u64;
+}
+
+// `rep movsb` is the only marked instruction, so a fault anywhere in the copy resumes at the
+// fixup, which reports the failure to the caller. Each entry of the table is three quad words in
+// the order of the fields of `Fixup`.
+global_asm!(
+    "
+    .intel_syntax noprefix
+    .global rust_os_probe_copy
+    rust_os_probe_copy:
+        mov rcx, rdx
+    rust_os_probe_copy_start:
+        rep movsb
+    rust_os_probe_copy_end:
+        xor eax, eax
+        ret
+    rust_os_probe_copy_fixup:
+        mov eax, 1
+        ret
+    .pushsection rust_os_fixups, \"a\"
+    .balign 8
+        .quad rust_os_probe_copy_start
+        .quad rust_os_probe_copy_end
+        .quad rust_os_probe_copy_fixup
+    .popsection
+    .att_syntax prefix
+    "
+);
+
+/// The fault most recently recovered from, which is taken by the code it resumes at.
+static LAST_FAULT: Mutex<Option<Fault>> = Mutex::new(None);
+
+/// Every entry of the exception table.
+pub fn fixups() -> &'static [Fixup] {
+    unsafe {
+        let start = &__start_rust_os_fixups as *const Fixup;
+        let end = &__stop_rust_os_fixups as *const Fixup;
+        let len = (end as usize - start as usize) / mem::size_of::<Fixup>();
+        slice::from_raw_parts(start, len)
+    }
+}
+
+/// The address to resume at if the instruction at `instruction_pointer` faults, if it is marked in
+/// the exception table.
+pub fn search(instruction_pointer: VirtAddr) -> Option<VirtAddr> {
+    fixups()
+        .iter()
+        .find(|fixup| fixup.contains(instruction_pointer))
+        .map(|fixup| VirtAddr::new(fixup.fixup))
+}
+
+/// Record `fault`, thrown at `instruction_pointer`, and return the address to resume at if the
+/// instruction is marked in the exception table. This is called by the handlers of the exceptions
+/// that can be recovered from.
+pub(crate) fn recover(instruction_pointer: VirtAddr, fault: Fault) -> Option<VirtAddr> {
+    let fixup = search(instruction_pointer)?;
+    *LAST_FAULT.lock() = Some(fault);
+    Some(fixup)
+}
+
+/// Read a `T` from `addr`, returning the fault instead of panicking if the address is not mapped,
+/// not readable, or not canonical.
+///
+/// # Safety
+/// Reading the memory must not have side effects, as reading device memory might, and every bit
+/// pattern must be a valid `T`.
+pub unsafe fn probe_read<T: Copy>(addr: u64) -> Result<T, Fault> {
+    let mut value = MaybeUninit::<T>::uninit();
+    probe_copy(
+        value.as_mut_ptr() as *mut u8,
+        addr as *const u8,
+        mem::size_of::<T>(),
+    )?;
+    Ok(value.assume_init())
+}
+
+/// Write `value` to `addr`, returning the fault instead of panicking if the address is not
+/// mapped, not writable, or not canonical.
+///
+/// # Safety
+/// Writing the memory must not break the kernel or have side effects, as writing device memory
+/// might.
+pub unsafe fn probe_write<T: Copy>(addr: u64, value: T) -> Result<(), Fault> {
+    probe_copy(
+        addr as *mut u8,
+        &value as *const T as *const u8,
+        mem::size_of::<T>(),
+    )
+}
+
+/// Copy `len` bytes from `src` to `dest`, returning the fault if either faults.
+unsafe fn probe_copy(dest: *mut u8, src: *const u8, len: usize) -> Result<(), Fault> {
+    // Interrupts are disabled so that the fault taken afterwards is the one the copy caused.
+    without_interrupts(|| {
+        if rust_os_probe_copy(dest, src, len) == 0 {
+            Ok(())
+        } else {
+            Err(LAST_FAULT
+                .lock()
+                .take()
+                .expect("A probe failed without recording the fault"))
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    const TEST_PREFIX: &'static str = "[rust_os::cpu_exception::fixup]";
+
+    #[test_case]
+    fn test_table() {
+        serial_print!("{} test_table... ", TEST_PREFIX);
+        let copy = VirtAddr::new(rust_os_probe_copy as usize as u64);
+        // The marked instruction follows the 3 byte `mov rcx, rdx`.
+        let fixup = fixups()
+            .iter()
+            .find(|fixup| fixup.start == copy.as_u64() + 3)
+            .copied()
+            .unwrap();
+        assert!(fixup.start < fixup.end && fixup.end < fixup.fixup);
+        assert_eq!(
+            search(VirtAddr::new(fixup.start)),
+            Some(VirtAddr::new(fixup.fixup))
+        );
+        assert_eq!(search(copy), None);
+        assert_eq!(search(VirtAddr::new(fixup.end)), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_probe_mapped() {
+        serial_print!("{} test_probe_mapped... ", TEST_PREFIX);
+        let value: u64 = 0x0123_4567_89AB_CDEF;
+        let addr = &value as *const u64 as u64;
+        unsafe {
+            assert_eq!(probe_read::<u64>(addr), Ok(value));
+            assert_eq!(probe_read::<u8>(addr + 1), Ok(0xCD));
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_probe_unmapped() {
+        serial_print!("{} test_probe_unmapped... ", TEST_PREFIX);
+        // The guard page below the kernel stack is never mapped.
+        let guard = crate::gdt::KERNEL_STACK_ADDRESS;
+        let fault = unsafe { probe_read::<u64>(guard) }.unwrap_err();
+        assert_eq!(fault.exception, CpuException::PageFault);
+        assert_eq!(fault.address, Some(VirtAddr::new(guard)));
+        let error_code = PageFaultErrorCode::from_bits_truncate(fault.error_code);
+        assert!(!error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+        assert!(!error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_probe_write() {
+        serial_print!("{} test_probe_write... ", TEST_PREFIX);
+        let mut value: u64 = 0;
+        let addr = &mut value as *mut u64 as u64;
+        unsafe {
+            assert_eq!(probe_write::<u16>(addr + 2, 0xBEEF), Ok(()));
+        }
+        assert_eq!(value, 0xBEEF_0000);
+        let guard = crate::gdt::KERNEL_STACK_ADDRESS;
+        let fault = unsafe { probe_write::<u8>(guard, 0) }.unwrap_err();
+        assert_eq!(fault.exception, CpuException::PageFault);
+        assert_eq!(fault.address, Some(VirtAddr::new(guard)));
+        let error_code = PageFaultErrorCode::from_bits_truncate(fault.error_code);
+        assert!(error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_probe_non_canonical() {
+        serial_print!("{} test_probe_non_canonical... ", TEST_PREFIX);
+        let fault = unsafe { probe_read::<u32>(0x8000_0000_0000_0000) }.unwrap_err();
+        assert_eq!(fault.exception, CpuException::GeneralProtectionFault);
+        assert_eq!(fault.address, None);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,305 @@
+use core::convert::TryFrom;
+
+use spin::Mutex;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use crate::{
+    apic,
+    pic::{self, Irq},
+};
+
+/// The first vector which isn't reserved for CPU exceptions.
+pub const FIRST_INTERRUPT_VECTOR: u8 = 0x20;
+
+/// The number of vectors which can have handlers registered.
+const VECTOR_COUNT: usize = 0x100 - FIRST_INTERRUPT_VECTOR as usize;
+
+/// The maximum number of handlers which can share a single vector.
+pub const MAX_HANDLERS_PER_VECTOR: usize = 4;
+
+/// A function which handles an interrupt. The argument is the vector the interrupt was delivered
+/// on. When several handlers share a vector, every handler is called for every interrupt, so each
+/// handler must check whether its own device raised the interrupt. The return value is whether it
+/// did.
+pub type InterruptHandler = fn(vector: u8) -> bool;
+
+/// The reason a handler could not be registered or unregistered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegisterError {
+    /// The vector is reserved for CPU exceptions.
+    ReservedVector(u8),
+    /// The vector already has [`MAX_HANDLERS_PER_VECTOR`] handlers.
+    ///
+    /// [`MAX_HANDLERS_PER_VECTOR`]: constant.MAX_HANDLERS_PER_VECTOR.html
+    VectorFull(u8),
+    /// The handler is already registered for the vector.
+    AlreadyRegistered(u8),
+    /// The handler is not registered for the vector.
+    NotRegistered(u8),
+}
+
+#[derive(Clone, Copy)]
+struct VectorEntry {
+    handlers: [Option<InterruptHandler>; MAX_HANDLERS_PER_VECTOR],
+    count: u64,
+    unhandled: u64,
+}
+
+impl VectorEntry {
+    const EMPTY: Self = Self {
+        handlers: [None; MAX_HANDLERS_PER_VECTOR],
+        count: 0,
+        unhandled: 0,
+    };
+}
+
+/// The handlers and counters of every vector from `FIRST_INTERRUPT_VECTOR` up. The interrupt
+/// stubs lock the table, so interrupts must be disabled while it is locked.
+static DISPATCH_TABLE: Mutex<[VectorEntry; VECTOR_COUNT]> =
+    Mutex::new([VectorEntry::EMPTY; VECTOR_COUNT]);
+
+fn table_index(vector: u8) -> Result<usize, RegisterError> {
+    if vector < FIRST_INTERRUPT_VECTOR {
+        Err(RegisterError::ReservedVector(vector))
+    } else {
+        Ok((vector - FIRST_INTERRUPT_VECTOR) as usize)
+    }
+}
+
+/// Register `handler` to be called whenever an interrupt is delivered on `vector`. Up to
+/// [`MAX_HANDLERS_PER_VECTOR`] handlers can share a vector and they are called in the order in
+/// which they were registered. Registering a handler does not unmask the interrupt line at the
+/// interrupt controller.
+///
+/// [`MAX_HANDLERS_PER_VECTOR`]: constant.MAX_HANDLERS_PER_VECTOR.html
+pub fn register_irq(vector: u8, handler: InterruptHandler) -> Result<(), RegisterError> {
+    let index = table_index(vector)?;
+    without_interrupts(|| {
+        let mut table = DISPATCH_TABLE.lock();
+        let handlers = &mut table[index].handlers;
+        if handlers
+            .iter()
+            .flatten()
+            .any(|&h| h as usize == handler as usize)
+        {
+            return Err(RegisterError::AlreadyRegistered(vector));
+        }
+        let slot = handlers
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(RegisterError::VectorFull(vector))?;
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Stop calling `handler` for interrupts delivered on `vector`.
+pub fn unregister_irq(vector: u8, handler: InterruptHandler) -> Result<(), RegisterError> {
+    let index = table_index(vector)?;
+    without_interrupts(|| {
+        let mut table = DISPATCH_TABLE.lock();
+        let handlers = &mut table[index].handlers;
+        let position = handlers
+            .iter()
+            .position(|h| h.map(|h| h as usize) == Some(handler as usize))
+            .ok_or(RegisterError::NotRegistered(vector))?;
+        // Keep the remaining handlers contiguous so that they keep being called in order.
+        handlers[position..].rotate_left(1);
+        handlers[MAX_HANDLERS_PER_VECTOR - 1] = None;
+        Ok(())
+    })
+}
+
+/// The number of interrupts that have been delivered on `vector`, including spurious interrupts
+/// and interrupts that no handler recognized. Vectors reserved for CPU exceptions are not
+/// counted.
+pub fn interrupt_count(vector: u8) -> u64 {
+    table_index(vector)
+        .map(|index| without_interrupts(|| DISPATCH_TABLE.lock()[index].count))
+        .unwrap_or(0)
+}
+
+/// The number of interrupts delivered on `vector` that no registered handler recognized.
+pub fn unhandled_count(vector: u8) -> u64 {
+    table_index(vector)
+        .map(|index| without_interrupts(|| DISPATCH_TABLE.lock()[index].unhandled))
+        .unwrap_or(0)
+}
+
+/// Acknowledge the interrupt on `vector` to the interrupt controller that delivered it.
+fn end_of_interrupt(vector: u8) {
+    if apic::is_active() {
+        if vector != apic::SPURIOUS_VECTOR {
+            apic::end_of_interrupt();
+        }
+    } else if let Some(irq) = pic_irq(vector) {
+        unsafe {
+            pic::PICS.lock().notify_end_of_interrupt(irq);
+        }
+    }
+}
+
+/// Whether the interrupt on `vector` is spurious and must not be passed to the handlers.
+fn is_spurious(vector: u8) -> bool {
+    if apic::is_active() {
+        vector == apic::SPURIOUS_VECTOR
+    } else {
+        pic_irq(vector).map_or(false, |irq| pic::PICS.lock().is_spurious(irq))
+    }
+}
+
+/// The PIC line that delivers interrupts on `vector`, if any.
+fn pic_irq(vector: u8) -> Option<Irq> {
+    vector
+        .checked_sub(pic::PIC_1_OFFSET)
+        .and_then(|line| Irq::try_from(line).ok())
+}
+
+/// Call every handler registered for `vector`. This is called by the interrupt stubs with
+/// interrupts disabled.
+fn dispatch(vector: u8) {
+    let index = (vector - FIRST_INTERRUPT_VECTOR) as usize;
+    let spurious = is_spurious(vector);
+    let handlers = {
+        let mut table = DISPATCH_TABLE.lock();
+        table[index].count += 1;
+        table[index].handlers
+    };
+    if !spurious {
+        let handled = handlers
+            .iter()
+            .flatten()
+            .fold(false, |handled, handler| handler(vector) | handled);
+        if !handled {
+            DISPATCH_TABLE.lock()[index].unhandled += 1;
+        }
+    }
+    end_of_interrupt(vector);
+}
+
+macro_rules! stub {
+    ($vector:expr) => {{
+        extern "x86-interrupt" fn stub(_: &mut InterruptStackFrame) {
+            dispatch($vector)
+        }
+        stub as HandlerFunc
+    }};
+}
+
+macro_rules! stub_row {
+    ($base:expr) => {
+        [
+            stub!($base + 0x0),
+            stub!($base + 0x1),
+            stub!($base + 0x2),
+            stub!($base + 0x3),
+            stub!($base + 0x4),
+            stub!($base + 0x5),
+            stub!($base + 0x6),
+            stub!($base + 0x7),
+            stub!($base + 0x8),
+            stub!($base + 0x9),
+            stub!($base + 0xA),
+            stub!($base + 0xB),
+            stub!($base + 0xC),
+            stub!($base + 0xD),
+            stub!($base + 0xE),
+            stub!($base + 0xF),
+        ]
+    };
+}
+
+/// Install a stub into `idt` for every vector from [`FIRST_INTERRUPT_VECTOR`] up which calls the
+/// handlers registered for that vector.
+///
+/// [`FIRST_INTERRUPT_VECTOR`]: constant.FIRST_INTERRUPT_VECTOR.html
+pub(super) fn set_interrupt_stubs(idt: &mut InterruptDescriptorTable) {
+    let stubs: [[HandlerFunc; 0x10]; VECTOR_COUNT / 0x10] = [
+        stub_row!(0x20),
+        stub_row!(0x30),
+        stub_row!(0x40),
+        stub_row!(0x50),
+        stub_row!(0x60),
+        stub_row!(0x70),
+        stub_row!(0x80),
+        stub_row!(0x90),
+        stub_row!(0xA0),
+        stub_row!(0xB0),
+        stub_row!(0xC0),
+        stub_row!(0xD0),
+        stub_row!(0xE0),
+        stub_row!(0xF0),
+    ];
+    for (index, &stub) in stubs.iter().flatten().enumerate() {
+        idt[FIRST_INTERRUPT_VECTOR as usize + index].set_handler_fn(stub);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const TEST_PREFIX: &'static str = "[rust_os::cpu_exception::interrupts::dispatch]";
+
+    static FIRST_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static SECOND_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn first_handler(_: u8) -> bool {
+        FIRST_CALLS.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    fn second_handler(_: u8) -> bool {
+        SECOND_CALLS.fetch_add(1, Ordering::SeqCst);
+        false
+    }
+
+    #[test_case]
+    fn test_register_reserved_vector() {
+        serial_print!("{} test_register_reserved_vector... ", TEST_PREFIX);
+        assert_eq!(
+            register_irq(0x0E, first_handler),
+            Err(RegisterError::ReservedVector(0x0E))
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_shared_vector() {
+        serial_print!("{} test_shared_vector... ", TEST_PREFIX);
+        assert_eq!(register_irq(0x50, first_handler), Ok(()));
+        assert_eq!(register_irq(0x50, second_handler), Ok(()));
+        assert_eq!(
+            register_irq(0x50, second_handler),
+            Err(RegisterError::AlreadyRegistered(0x50))
+        );
+        let count = interrupt_count(0x50);
+        unsafe {
+            asm!("int 0x50");
+        }
+        assert_eq!(interrupt_count(0x50), count + 1);
+        assert_eq!(FIRST_CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(SECOND_CALLS.load(Ordering::SeqCst), 1);
+
+        assert_eq!(unregister_irq(0x50, first_handler), Ok(()));
+        assert_eq!(
+            unregister_irq(0x50, first_handler),
+            Err(RegisterError::NotRegistered(0x50))
+        );
+        let unhandled = unhandled_count(0x50);
+        unsafe {
+            asm!("int 0x50");
+        }
+        assert_eq!(FIRST_CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(SECOND_CALLS.load(Ordering::SeqCst), 2);
+        assert_eq!(unhandled_count(0x50), unhandled + 1);
+        assert_eq!(unregister_irq(0x50, second_handler), Ok(()));
+        serial_println!("[ok]");
+    }
+}
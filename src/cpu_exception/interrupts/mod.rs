@@ -1,4 +1,38 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use core::fmt::{self, Display, Formatter};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use super::{
+    debug,
+    fixup::{self, Fault},
+    report::ExceptionReport,
+    CpuException,
+};
+use crate::{
+    acpi::Acpi,
+    apic::{self, ApicError},
+    gdb, gdt,
+    memory::{
+        demand::{self, DemandError},
+        protection, user,
+    },
+    pic::{self, Irq},
+};
+
+mod dispatch;
+/// Entry stubs which save the complete register state of the code interrupted by an exception.
+pub mod trap;
+use trap::TrapFrame;
+
+pub use dispatch::{
+    interrupt_count, register_irq, unhandled_count, unregister_irq, InterruptHandler,
+    RegisterError, FIRST_INTERRUPT_VECTOR, MAX_HANDLERS_PER_VECTOR,
+};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = make_idt();
@@ -9,45 +43,304 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Remap the PICs and start accepting hardware interrupts. The IDT must already be loaded.
+pub fn enable() {
+    unsafe {
+        pic::PICS.lock().initialize();
+    }
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Switch from the PICs to the local APIC and I/O APICs described by the ACPI tables. Every ISA
+/// IRQ keeps its vector and its mask from the PICs, and the PICs are then masked off entirely.
+///
+/// # Safety
+/// All of physical memory must be mapped at the offset used by `acpi`.
+pub unsafe fn enable_apic(acpi: &Acpi) -> Result<(), ApicError> {
+    without_interrupts(|| {
+        apic::init(acpi)?;
+        match register_irq(apic::ERROR_VECTOR, apic_error_handler) {
+            Ok(()) | Err(RegisterError::AlreadyRegistered(_)) => {}
+            Err(e) => panic!("Failed to register the APIC error handler: {:?}", e),
+        }
+        let mut pics = pic::PICS.lock();
+        Irq::lines()
+            .filter(|&irq| irq != Irq::Cascade)
+            .for_each(|irq| apic::set_masked(irq, pics.is_masked(irq)));
+        pics.mask_all();
+        Ok(())
+    })
+}
+
+/// Mask or unmask `irq` at whichever interrupt controller is active.
+pub fn set_irq_masked(irq: Irq, masked: bool) {
+    without_interrupts(|| {
+        if apic::is_active() {
+            apic::set_masked(irq, masked);
+        } else {
+            pic::PICS.lock().set_masked(irq, masked);
+        }
+    })
+}
+
+/// Whether `irq` is masked at whichever interrupt controller is active.
+pub fn is_irq_masked(irq: Irq) -> bool {
+    without_interrupts(|| {
+        if apic::is_active() {
+            apic::is_masked(irq)
+        } else {
+            pic::PICS.lock().is_masked(irq)
+        }
+    })
+}
+
+fn apic_error_handler(_: u8) -> bool {
+    let status = apic::with_local_apic(|local| local.error_status()).unwrap_or(0);
+    serial_println!("APIC error: {:#010X}", status);
+    true
+}
+
 fn make_idt() -> InterruptDescriptorTable {
     let mut idt = InterruptDescriptorTable::new();
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
-    unsafe {
-        idt.double_fault.set_handler_fn(double_fault_handler)
-            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+    CpuException::exceptions().for_each(|exception| set_exception_handler(&mut idt, exception));
+    dispatch::set_interrupt_stubs(&mut idt);
+    idt
+}
+
+/// The index of the stack in the Interrupt Stack Table that the CPU should switch to before
+/// calling the handler for `exception`, if any.
+fn stack_index(exception: CpuException) -> Option<u16> {
+    match exception {
+        CpuException::DoubleFault => Some(crate::gdt::DOUBLE_FAULT_IST_INDEX),
+        CpuException::StackSegmentFault => Some(crate::gdt::STACK_SEGMENT_FAULT_IST_INDEX),
+        CpuException::PageFault => Some(crate::gdt::PAGE_FAULT_IST_INDEX),
+        _ => None,
     }
-    unsafe {
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler)
-            .set_stack_index(crate::gdt::STACK_SEGMENT_FAULT_IST_INDEX);
+}
+
+/// Install the entry stub for `exception` into `idt`. The match is exhaustive so that adding a
+/// variant to `CpuException` without also giving it an entry is a compile error.
+fn set_exception_handler(idt: &mut InterruptDescriptorTable, exception: CpuException) {
+    let options = match exception {
+        CpuException::DivideByZero => idt.divide_error.set_handler_fn(trap::entry(exception)),
+        CpuException::Debug => idt.debug.set_handler_fn(trap::entry(exception)),
+        CpuException::NonMaskableInterrupt => idt
+            .non_maskable_interrupt
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::Breakpoint => idt.breakpoint.set_handler_fn(trap::entry(exception)),
+        CpuException::Overflow => idt.overflow.set_handler_fn(trap::entry(exception)),
+        CpuException::BoundRangeExceeded => idt
+            .bound_range_exceeded
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::InvalidOpcode => idt.invalid_opcode.set_handler_fn(trap::entry(exception)),
+        CpuException::DeviceNotAvailable => idt
+            .device_not_available
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::DoubleFault => idt.double_fault.set_handler_fn(trap::entry(exception)),
+        #[allow(deprecated)]
+        CpuException::CoprocessorSegmentOverrun => {
+            idt[exception.vector() as usize].set_handler_fn(trap::entry(exception))
+        }
+        CpuException::InvalidTss => idt.invalid_tss.set_handler_fn(trap::entry(exception)),
+        CpuException::SegmentNotPresent => idt
+            .segment_not_present
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::StackSegmentFault => idt
+            .stack_segment_fault
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::GeneralProtectionFault => idt
+            .general_protection_fault
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::PageFault => idt.page_fault.set_handler_fn(trap::entry(exception)),
+        CpuException::X87FloatingPointException => idt
+            .x87_floating_point
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::AlignmentCheck => idt.alignment_check.set_handler_fn(trap::entry(exception)),
+        CpuException::MachineCheck => idt.machine_check.set_handler_fn(trap::entry(exception)),
+        CpuException::SimdFloatingPointException => idt
+            .simd_floating_point
+            .set_handler_fn(trap::entry(exception)),
+        CpuException::VirtualizationException => {
+            idt.virtualization.set_handler_fn(trap::entry(exception))
+        }
+        CpuException::SecurityException => idt
+            .security_exception
+            .set_handler_fn(trap::entry(exception)),
+    };
+    if let Some(index) = stack_index(exception) {
+        unsafe {
+            options.set_stack_index(index);
+        }
     }
-    unsafe {
-        idt.page_fault.set_handler_fn(page_fault_handler)
-            .set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
+}
+
+/// Record and display the report of an exception. Only [`Breakpoint`] can be resumed from;
+/// every other exception panics.
+///
+/// [`Breakpoint`]: ../enum.CpuException.html#variant.Breakpoint
+fn handle_exception(report: ExceptionReport) {
+    report.record();
+    report.render();
+    match report.exception {
+        CpuException::Breakpoint => {}
+        exception => panic!("EXCEPTION: {:?}", exception),
     }
-    idt
 }
 
-extern "x86-interrupt" fn breakpoint_handler(frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", frame)
+/// Handle any exception, given the frame saved by its entry stub.
+fn handle_trap(frame: &mut TrapFrame) {
+    match frame.exception() {
+        CpuException::Breakpoint | CpuException::Debug => handle_debug_trap(frame),
+        CpuException::GeneralProtectionFault => handle_general_protection_fault(frame),
+        CpuException::PageFault => handle_page_fault(frame),
+        CpuException::DoubleFault => {
+            let report = ExceptionReport::from_trap_frame(frame);
+            check_stack_overflow(&report);
+            handle_exception(report)
+        }
+        _ => handle_exception(ExceptionReport::from_trap_frame(frame)),
+    }
 }
 
-extern "x86-interrupt" fn double_fault_handler(frame: &mut InterruptStackFrame, _: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", frame)
+/// Handle a [`Breakpoint`] or [`Debug`] trap. A [`Debug`] trap caused only by watchpoints is
+/// handled by the watchpoint handler. Otherwise, while the GDB stub is enabled it takes over both
+/// traps; when it isn't, they are handled like any other exception.
+///
+/// [`Breakpoint`]: ../enum.CpuException.html#variant.Breakpoint
+/// [`Debug`]: ../enum.CpuException.html#variant.Debug
+fn handle_debug_trap(frame: &mut TrapFrame) {
+    if frame.exception() == CpuException::Debug && debug::handle_debug_exception(frame) {
+        return;
+    }
+    if gdb::is_enabled() {
+        gdb::handle_trap(frame);
+    } else {
+        handle_exception(ExceptionReport::from_trap_frame(frame));
+    }
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler(frame: &mut InterruptStackFrame, _: u64) {
-    panic!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}", frame)
+fn handle_general_protection_fault(frame: &mut TrapFrame) {
+    let fault = Fault {
+        exception: CpuException::GeneralProtectionFault,
+        error_code: frame.error_code,
+        address: None,
+    };
+    if !resume_at_fixup(frame, fault) {
+        handle_exception(ExceptionReport::from_trap_frame(frame))
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    frame: &mut InterruptStackFrame,
-    _: PageFaultErrorCode,
-) {
-    panic!("EXCEPTION: PAGE FAULT\n{:#?}", frame)
+/// Make the interrupted code resume at its fixup if the faulting instruction is marked in the
+/// exception table. Returns whether it was.
+fn resume_at_fixup(frame: &mut TrapFrame, fault: Fault) -> bool {
+    match fixup::recover(VirtAddr::new(frame.rip), fault) {
+        Some(address) => {
+            frame.rip = address.as_u64();
+            true
+        }
+        None => false,
+    }
+}
+
+fn handle_page_fault(frame: &mut TrapFrame) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let fault_address = Cr2::read();
+    let reason = match demand::handle_page_fault(fault_address, error_code) {
+        Ok(()) => return,
+        Err(reason) => reason,
+    };
+    let fault = Fault {
+        exception: CpuException::PageFault,
+        error_code: frame.error_code,
+        address: Some(fault_address),
+    };
+    if resume_at_fixup(frame, fault) {
+        return;
+    }
+    let report = ExceptionReport::from_trap_frame(frame);
+    check_stack_overflow(&report);
+    report.record();
+    report.render();
+    let diagnosis = if let Some(violation) = user::violation(fault_address, error_code) {
+        PageFaultDiagnosis::User(violation)
+    } else if let Some(violation) = protection::violation(fault_address, error_code) {
+        PageFaultDiagnosis::WXorX(violation)
+    } else {
+        PageFaultDiagnosis::Unhandled(reason)
+    };
+    panic!(
+        "page fault on {} at {:#x}: {}",
+        describe_access(error_code),
+        fault_address.as_u64(),
+        diagnosis
+    )
+}
+
+/// Why a page fault was fatal, from the most specific cause to the least.
+enum PageFaultDiagnosis {
+    /// The kernel accessed a user page in a way SMEP or SMAP forbids.
+    User(user::Violation),
+    /// The access broke the W^X protection of the page.
+    WXorX(protection::Violation),
+    /// Demand paging couldn't resolve the fault.
+    Unhandled(DemandError),
+}
+
+impl Display for PageFaultDiagnosis {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(violation) => write!(f, "{}", violation),
+            Self::WXorX(violation) => write!(f, "{}", violation),
+            Self::Unhandled(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Describe the access which caused a page fault, such as "user write" or "kernel instruction
+/// fetch".
+fn describe_access(error_code: PageFaultErrorCode) -> &'static str {
+    let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        if user {
+            "user instruction fetch"
+        } else {
+            "kernel instruction fetch"
+        }
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        if user {
+            "user write"
+        } else {
+            "kernel write"
+        }
+    } else if user {
+        "user read"
+    } else {
+        "kernel read"
+    }
+}
+
+/// Panic with a precise diagnosis if the fault described by `report` was caused by overflowing
+/// one of the guarded stacks. Both [`PageFault`] and [`DoubleFault`] run on their own stacks, so
+/// they can report an overflow of any other stack.
+///
+/// [`DoubleFault`]: ../enum.CpuException.html#variant.DoubleFault
+/// [`PageFault`]: ../enum.CpuException.html#variant.PageFault
+fn check_stack_overflow(report: &ExceptionReport) {
+    let fault_address = VirtAddr::new_truncate(report.control_registers.cr2);
+    if let Some(stack) = gdt::overflowed_stack(report.frame.stack_pointer, fault_address) {
+        report.record();
+        report.render();
+        panic!("stack overflow on {}", stack.name);
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::cpu_exception::{
+        report::{self, ErrorCode},
+        CpuException,
+    };
+
     const TEST_PREFIX: &'static str = "[rust_os::cpu_exception::interrupts]";
 
     #[test_case]
@@ -56,4 +349,41 @@ mod test {
         x86_64::instructions::interrupts::int3();
         serial_println!("[ok]");
     }
+
+    #[test_case]
+    fn test_breakpoint_report() {
+        serial_print!("{} test_breakpoint_report... ", TEST_PREFIX);
+        x86_64::instructions::interrupts::int3();
+        let report = report::last_report().expect("Breakpoint was not recorded");
+        assert_eq!(report.exception, CpuException::Breakpoint);
+        assert_eq!(report.error_code, ErrorCode::None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_breakpoint_registers() {
+        serial_print!("{} test_breakpoint_registers... ", TEST_PREFIX);
+        unsafe {
+            asm!("int3", in("rax") 0x0123_4567_89AB_CDEFu64, in("r15") 0xFEDC_BA98_7654_3210u64);
+        }
+        let report = report::last_report().expect("Breakpoint was not recorded");
+        assert_eq!(report.registers.rax, 0x0123_4567_89AB_CDEF);
+        assert_eq!(report.registers.r15, 0xFEDC_BA98_7654_3210);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_breakpoint_backtrace() {
+        serial_print!("{} test_breakpoint_backtrace... ", TEST_PREFIX);
+        let frame_pointer: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack));
+        }
+        x86_64::instructions::interrupts::int3();
+        let report = report::last_report().expect("Breakpoint was not recorded");
+        assert_eq!(report.registers.rbp, frame_pointer);
+        let first = report.backtrace().next().expect("Backtrace is empty");
+        assert_eq!(first.address, report.frame.instruction_pointer.as_u64());
+        serial_println!("[ok]");
+    }
 }
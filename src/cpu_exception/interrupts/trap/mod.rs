@@ -0,0 +1,322 @@
+use core::{convert::TryFrom, mem};
+
+use x86_64::VirtAddr;
+
+use crate::cpu_exception::{
+    report::{GeneralRegisters, StackFrame},
+    CpuException,
+};
+
+/// The trap flag in `RFLAGS`. While it is set, the CPU raises [`Debug`] after every instruction.
+///
+/// [`Debug`]: ../../enum.CpuException.html#variant.Debug
+pub const TRAP_FLAG: u64 = 1 << 8;
+
+/// The complete state of the interrupted code, saved by the entry stubs of every exception. Unlike
+/// the state given to an `x86-interrupt` handler, the general-purpose registers are saved before
+/// any handler code runs, so they are exactly the values of the interrupted code. They are
+/// restored from the frame when the handler returns, so changes to any field take effect in the
+/// interrupted code.
+///
+/// The fields are in the order the stubs push them, from the lowest address up.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct TrapFrame {
+    /// The value of `R15`.
+    pub r15: u64,
+    /// The value of `R14`.
+    pub r14: u64,
+    /// The value of `R13`.
+    pub r13: u64,
+    /// The value of `R12`.
+    pub r12: u64,
+    /// The value of `R11`.
+    pub r11: u64,
+    /// The value of `R10`.
+    pub r10: u64,
+    /// The value of `R9`.
+    pub r9: u64,
+    /// The value of `R8`.
+    pub r8: u64,
+    /// The value of `RBP`.
+    pub rbp: u64,
+    /// The value of `RDI`.
+    pub rdi: u64,
+    /// The value of `RSI`.
+    pub rsi: u64,
+    /// The value of `RDX`.
+    pub rdx: u64,
+    /// The value of `RCX`.
+    pub rcx: u64,
+    /// The value of `RBX`.
+    pub rbx: u64,
+    /// The value of `RAX`.
+    pub rax: u64,
+    /// The vector of the exception.
+    pub vector: u64,
+    /// The error code of the exception, or 0 if it doesn't have one.
+    pub error_code: u64,
+    /// The address of the saved instruction.
+    pub rip: u64,
+    /// The code segment selector.
+    pub cs: u64,
+    /// The value of `RFLAGS`.
+    pub rflags: u64,
+    /// The value of `RSP`.
+    pub rsp: u64,
+    /// The stack segment selector.
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// The exception which caused the trap.
+    pub fn exception(&self) -> CpuException {
+        CpuException::try_from(self.vector as u8).expect("Trap frame has an invalid vector")
+    }
+
+    /// The general-purpose registers of the interrupted code.
+    pub fn registers(&self) -> GeneralRegisters {
+        GeneralRegisters {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+        }
+    }
+
+    /// The part of the frame which the CPU pushed.
+    pub fn stack_frame(&self) -> StackFrame {
+        StackFrame {
+            instruction_pointer: VirtAddr::new(self.rip),
+            code_segment: self.cs,
+            cpu_flags: self.rflags,
+            stack_pointer: VirtAddr::new(self.rsp),
+            stack_segment: self.ss,
+        }
+    }
+}
+
+// Each entry pushes a zero error code if the CPU doesn't push one and then its vector, so that
+// every trap has the same frame layout. The common path saves the general-purpose registers and
+// passes a pointer to the resulting `TrapFrame` to `rust_os_handle_trap`. The CPU aligns the stack
+// to 16 bytes before pushing its five words and the error code, and the frame is twenty-two words
+// long, so the stack is aligned again at the call.
+global_asm!(
+    "
+    .intel_syntax noprefix
+    .global rust_os_divide_by_zero_entry
+    rust_os_divide_by_zero_entry:
+        push 0
+        push 0
+        jmp rust_os_trap_common
+    .global rust_os_debug_entry
+    rust_os_debug_entry:
+        push 0
+        push 1
+        jmp rust_os_trap_common
+    .global rust_os_non_maskable_interrupt_entry
+    rust_os_non_maskable_interrupt_entry:
+        push 0
+        push 2
+        jmp rust_os_trap_common
+    .global rust_os_breakpoint_entry
+    rust_os_breakpoint_entry:
+        push 0
+        push 3
+        jmp rust_os_trap_common
+    .global rust_os_overflow_entry
+    rust_os_overflow_entry:
+        push 0
+        push 4
+        jmp rust_os_trap_common
+    .global rust_os_bound_range_exceeded_entry
+    rust_os_bound_range_exceeded_entry:
+        push 0
+        push 5
+        jmp rust_os_trap_common
+    .global rust_os_invalid_opcode_entry
+    rust_os_invalid_opcode_entry:
+        push 0
+        push 6
+        jmp rust_os_trap_common
+    .global rust_os_device_not_available_entry
+    rust_os_device_not_available_entry:
+        push 0
+        push 7
+        jmp rust_os_trap_common
+    .global rust_os_double_fault_entry
+    rust_os_double_fault_entry:
+        push 8
+        jmp rust_os_trap_common
+    .global rust_os_coprocessor_segment_overrun_entry
+    rust_os_coprocessor_segment_overrun_entry:
+        push 0
+        push 9
+        jmp rust_os_trap_common
+    .global rust_os_invalid_tss_entry
+    rust_os_invalid_tss_entry:
+        push 10
+        jmp rust_os_trap_common
+    .global rust_os_segment_not_present_entry
+    rust_os_segment_not_present_entry:
+        push 11
+        jmp rust_os_trap_common
+    .global rust_os_stack_segment_fault_entry
+    rust_os_stack_segment_fault_entry:
+        push 12
+        jmp rust_os_trap_common
+    .global rust_os_general_protection_fault_entry
+    rust_os_general_protection_fault_entry:
+        push 13
+        jmp rust_os_trap_common
+    .global rust_os_page_fault_entry
+    rust_os_page_fault_entry:
+        push 14
+        jmp rust_os_trap_common
+    .global rust_os_x87_floating_point_entry
+    rust_os_x87_floating_point_entry:
+        push 0
+        push 16
+        jmp rust_os_trap_common
+    .global rust_os_alignment_check_entry
+    rust_os_alignment_check_entry:
+        push 17
+        jmp rust_os_trap_common
+    .global rust_os_machine_check_entry
+    rust_os_machine_check_entry:
+        push 0
+        push 18
+        jmp rust_os_trap_common
+    .global rust_os_simd_floating_point_entry
+    rust_os_simd_floating_point_entry:
+        push 0
+        push 19
+        jmp rust_os_trap_common
+    .global rust_os_virtualization_entry
+    rust_os_virtualization_entry:
+        push 0
+        push 20
+        jmp rust_os_trap_common
+    .global rust_os_security_exception_entry
+    rust_os_security_exception_entry:
+        push 30
+        jmp rust_os_trap_common
+    rust_os_trap_common:
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        mov rdi, rsp
+        cld
+        call rust_os_handle_trap
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        add rsp, 16
+        iretq
+    .att_syntax prefix
+    "
+);
+
+extern "C" {
+    fn rust_os_divide_by_zero_entry();
+    fn rust_os_debug_entry();
+    fn rust_os_non_maskable_interrupt_entry();
+    fn rust_os_breakpoint_entry();
+    fn rust_os_overflow_entry();
+    fn rust_os_bound_range_exceeded_entry();
+    fn rust_os_invalid_opcode_entry();
+    fn rust_os_device_not_available_entry();
+    fn rust_os_double_fault_entry();
+    fn rust_os_coprocessor_segment_overrun_entry();
+    fn rust_os_invalid_tss_entry();
+    fn rust_os_segment_not_present_entry();
+    fn rust_os_stack_segment_fault_entry();
+    fn rust_os_general_protection_fault_entry();
+    fn rust_os_page_fault_entry();
+    fn rust_os_x87_floating_point_entry();
+    fn rust_os_alignment_check_entry();
+    fn rust_os_machine_check_entry();
+    fn rust_os_simd_floating_point_entry();
+    fn rust_os_virtualization_entry();
+    fn rust_os_security_exception_entry();
+}
+
+#[no_mangle]
+extern "C" fn rust_os_handle_trap(frame: &mut TrapFrame) {
+    super::handle_trap(frame)
+}
+
+/// The entry stub for `exception`.
+fn stub(exception: CpuException) -> unsafe extern "C" fn() {
+    match exception {
+        CpuException::DivideByZero => rust_os_divide_by_zero_entry,
+        CpuException::Debug => rust_os_debug_entry,
+        CpuException::NonMaskableInterrupt => rust_os_non_maskable_interrupt_entry,
+        CpuException::Breakpoint => rust_os_breakpoint_entry,
+        CpuException::Overflow => rust_os_overflow_entry,
+        CpuException::BoundRangeExceeded => rust_os_bound_range_exceeded_entry,
+        CpuException::InvalidOpcode => rust_os_invalid_opcode_entry,
+        CpuException::DeviceNotAvailable => rust_os_device_not_available_entry,
+        CpuException::DoubleFault => rust_os_double_fault_entry,
+        #[allow(deprecated)]
+        CpuException::CoprocessorSegmentOverrun => rust_os_coprocessor_segment_overrun_entry,
+        CpuException::InvalidTss => rust_os_invalid_tss_entry,
+        CpuException::SegmentNotPresent => rust_os_segment_not_present_entry,
+        CpuException::StackSegmentFault => rust_os_stack_segment_fault_entry,
+        CpuException::GeneralProtectionFault => rust_os_general_protection_fault_entry,
+        CpuException::PageFault => rust_os_page_fault_entry,
+        CpuException::X87FloatingPointException => rust_os_x87_floating_point_entry,
+        CpuException::AlignmentCheck => rust_os_alignment_check_entry,
+        CpuException::MachineCheck => rust_os_machine_check_entry,
+        CpuException::SimdFloatingPointException => rust_os_simd_floating_point_entry,
+        CpuException::VirtualizationException => rust_os_virtualization_entry,
+        CpuException::SecurityException => rust_os_security_exception_entry,
+    }
+}
+
+/// The entry stub for `exception`, typed as whichever handler its entry in the IDT expects. The
+/// stubs don't follow the `x86-interrupt` calling convention, but they don't need to: only the CPU
+/// calls them.
+pub(super) fn entry<F: Copy>(exception: CpuException) -> F {
+    assert_eq!(
+        mem::size_of::<F>(),
+        mem::size_of::<unsafe extern "C" fn()>(),
+        "Handler types must be function pointers"
+    );
+    unsafe { mem::transmute_copy(&stub(exception)) }
+}
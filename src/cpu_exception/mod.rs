@@ -1,8 +1,17 @@
 use core::convert::TryFrom;
 
+/// Tools for setting hardware watchpoints with the debug registers.
+pub mod debug;
+
+/// An exception table which lets marked instructions fault without panicking.
+pub mod fixup;
+
 /// Tools related to handling interrupts.
 pub mod interrupts;
 
+/// Tools for describing the state of the processor when an exception is thrown.
+pub mod report;
+
 /// An exception thrown by the CPU.
 /// The documentation on the various exceptions is based on the page at
 /// [the OSDev Wiki](https://wiki.osdev.org/Exceptions).
@@ -18,7 +27,7 @@ pub mod interrupts;
 /// exception is related to a descriptor in the Global Descriptor Table.
 /// * Bits 15-3 are the index into the appropriate Descriptor Table as determined by bits 2-1.
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CpuException {
     /// The result of executing `DIV` or `IDIV` with 0 as the denominator. Sometimes also thrown
     /// when the result of the instruction is too large to fit in the destination.
@@ -245,6 +254,101 @@ pub enum CpuException {
 }
 
 impl CpuException {
+    /// Get an iterator over every exception that can be thrown by the CPU, in order of vector
+    /// number.
+    pub fn exceptions() -> impl Iterator<Item = Self> {
+        #[allow(deprecated)]
+        static EXCEPTIONS: [CpuException; 21] = [
+            CpuException::DivideByZero,
+            CpuException::Debug,
+            CpuException::NonMaskableInterrupt,
+            CpuException::Breakpoint,
+            CpuException::Overflow,
+            CpuException::BoundRangeExceeded,
+            CpuException::InvalidOpcode,
+            CpuException::DeviceNotAvailable,
+            CpuException::DoubleFault,
+            CpuException::CoprocessorSegmentOverrun,
+            CpuException::InvalidTss,
+            CpuException::SegmentNotPresent,
+            CpuException::StackSegmentFault,
+            CpuException::GeneralProtectionFault,
+            CpuException::PageFault,
+            CpuException::X87FloatingPointException,
+            CpuException::AlignmentCheck,
+            CpuException::MachineCheck,
+            CpuException::SimdFloatingPointException,
+            CpuException::VirtualizationException,
+            CpuException::SecurityException,
+        ];
+        EXCEPTIONS.iter().copied()
+    }
+
+    /// The vector number of the exception in the Interrupt Descriptor Table.
+    pub fn vector(&self) -> u8 {
+        *self as u8
+    }
+
+    /// The CPU pushes an error code onto the stack before calling the handler for some exceptions.
+    /// The exceptions which push an error code are [`DoubleFault`], [`InvalidTss`],
+    /// [`SegmentNotPresent`], [`StackSegmentFault`], [`GeneralProtectionFault`], [`PageFault`],
+    /// [`AlignmentCheck`], and [`SecurityException`].
+    ///
+    /// [`AlignmentCheck`]: #variant.AlignmentCheck
+    /// [`DoubleFault`]: #variant.DoubleFault
+    /// [`GeneralProtectionFault`]: #variant.GeneralProtectionFault
+    /// [`InvalidTss`]: #variant.InvalidTss
+    /// [`PageFault`]: #variant.PageFault
+    /// [`SecurityException`]: #variant.SecurityException
+    /// [`SegmentNotPresent`]: #variant.SegmentNotPresent
+    /// [`StackSegmentFault`]: #variant.StackSegmentFault
+    pub fn has_error_code(&self) -> bool {
+        match self {
+            Self::DivideByZero => false,
+            Self::Debug => false,
+            Self::NonMaskableInterrupt => false,
+            Self::Breakpoint => false,
+            Self::Overflow => false,
+            Self::BoundRangeExceeded => false,
+            Self::InvalidOpcode => false,
+            Self::DeviceNotAvailable => false,
+            Self::DoubleFault => true,
+            #[allow(deprecated)]
+            Self::CoprocessorSegmentOverrun => false,
+            Self::InvalidTss => true,
+            Self::SegmentNotPresent => true,
+            Self::StackSegmentFault => true,
+            Self::GeneralProtectionFault => true,
+            Self::PageFault => true,
+            Self::X87FloatingPointException => false,
+            Self::AlignmentCheck => true,
+            Self::MachineCheck => false,
+            Self::SimdFloatingPointException => false,
+            Self::VirtualizationException => false,
+            Self::SecurityException => true,
+        }
+    }
+
+    /// A short description of the class of the exception as determined by [`is_abort`],
+    /// [`is_fault`], [`is_interrupt`], and [`is_trap`]. Exceptions which can be more than one
+    /// class, such as [`Debug`], list every class that applies.
+    ///
+    /// [`Debug`]: #variant.Debug
+    /// [`is_abort`]: #method.is_abort
+    /// [`is_fault`]: #method.is_fault
+    /// [`is_interrupt`]: #method.is_interrupt
+    /// [`is_trap`]: #method.is_trap
+    pub fn class(&self) -> &'static str {
+        match (self.is_abort(), self.is_fault(), self.is_interrupt(), self.is_trap()) {
+            (true, _, _, _) => "abort",
+            (false, true, _, true) => "fault/trap",
+            (false, true, _, false) => "fault",
+            (false, false, _, true) => "trap",
+            (false, false, true, false) => "interrupt",
+            (false, false, false, false) => "unclassified",
+        }
+    }
+
     /// An exception is an *abort* if the process that threw it cannot be meaningfully recovered
     /// under any circumstances. The only exceptions that are abort are [`DoubleFault`] and
     /// [`MachineCheck`] (and `TripleFault`, but that is only ever handled by resetting the
@@ -440,3 +544,36 @@ impl TryFrom<u8> for CpuException {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::cpu_exception]";
+
+    #[test_case]
+    fn test_exceptions_match_try_from() {
+        serial_print!("{} test_exceptions_match_try_from... ", TEST_PREFIX);
+        for code in 0x00..=0xFFu8 {
+            if let Ok(exception) = CpuException::try_from(code) {
+                assert!(CpuException::exceptions().any(|e| e == exception));
+                assert_eq!(exception.vector(), code);
+            }
+        }
+        for exception in CpuException::exceptions() {
+            assert_eq!(CpuException::try_from(exception.vector()), Ok(exception));
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_exception_class() {
+        serial_print!("{} test_exception_class... ", TEST_PREFIX);
+        assert_eq!(CpuException::DoubleFault.class(), "abort");
+        assert_eq!(CpuException::PageFault.class(), "fault");
+        assert_eq!(CpuException::Debug.class(), "fault/trap");
+        assert_eq!(CpuException::Breakpoint.class(), "trap");
+        assert_eq!(CpuException::NonMaskableInterrupt.class(), "interrupt");
+        serial_println!("[ok]");
+    }
+}
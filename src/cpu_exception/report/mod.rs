@@ -0,0 +1,419 @@
+use core::fmt::{self, Display, Formatter};
+
+use spin::Mutex;
+
+use x86_64::{
+    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use super::{interrupts::trap::TrapFrame, CpuException};
+use crate::backtrace::Backtrace;
+
+/// The values of the general-purpose registers other than `RSP`, which is saved in the interrupt
+/// stack frame instead.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    /// The value of `RAX`.
+    pub rax: u64,
+    /// The value of `RBX`.
+    pub rbx: u64,
+    /// The value of `RCX`.
+    pub rcx: u64,
+    /// The value of `RDX`.
+    pub rdx: u64,
+    /// The value of `RSI`.
+    pub rsi: u64,
+    /// The value of `RDI`.
+    pub rdi: u64,
+    /// The value of `RBP`.
+    pub rbp: u64,
+    /// The value of `R8`.
+    pub r8: u64,
+    /// The value of `R9`.
+    pub r9: u64,
+    /// The value of `R10`.
+    pub r10: u64,
+    /// The value of `R11`.
+    pub r11: u64,
+    /// The value of `R12`.
+    pub r12: u64,
+    /// The value of `R13`.
+    pub r13: u64,
+    /// The value of `R14`.
+    pub r14: u64,
+    /// The value of `R15`.
+    pub r15: u64,
+}
+
+impl Display for GeneralRegisters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX={:016X} RBX={:016X} RCX={:016X}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX={:016X} RSI={:016X} RDI={:016X}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP={:016X} R8 ={:016X} R9 ={:016X}",
+            self.rbp, self.r8, self.r9
+        )?;
+        writeln!(
+            f,
+            "R10={:016X} R11={:016X} R12={:016X}",
+            self.r10, self.r11, self.r12
+        )?;
+        write!(
+            f,
+            "R13={:016X} R14={:016X} R15={:016X}",
+            self.r13, self.r14, self.r15
+        )
+    }
+}
+
+/// The values of the control registers that are relevant to exceptions.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ControlRegisters {
+    /// The value of `CR0`.
+    pub cr0: u64,
+    /// The value of `CR2`, the address which caused the most recent page fault.
+    pub cr2: u64,
+    /// The value of `CR3`, the physical address of the level 4 page table and its flags.
+    pub cr3: u64,
+    /// The value of `CR4`.
+    pub cr4: u64,
+}
+
+impl ControlRegisters {
+    /// Read the current values of the control registers.
+    pub fn read() -> Self {
+        let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+        unsafe {
+            asm!(
+                "mov {0}, cr0",
+                "mov {1}, cr2",
+                "mov {2}, cr3",
+                "mov {3}, cr4",
+                out(reg) cr0,
+                out(reg) cr2,
+                out(reg) cr3,
+                out(reg) cr4,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Self { cr0, cr2, cr3, cr4 }
+    }
+}
+
+impl Display for ControlRegisters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "CR0={:016X} CR2={:016X}", self.cr0, self.cr2)?;
+        write!(f, "CR3={:016X} CR4={:016X}", self.cr3, self.cr4)
+    }
+}
+
+/// The values the CPU pushed onto the stack before calling the exception handler.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackFrame {
+    /// The address of the saved instruction.
+    pub instruction_pointer: VirtAddr,
+    /// The code segment selector at the time of the exception.
+    pub code_segment: u64,
+    /// The value of `RFLAGS` at the time of the exception.
+    pub cpu_flags: u64,
+    /// The value of `RSP` at the time of the exception.
+    pub stack_pointer: VirtAddr,
+    /// The stack segment selector at the time of the exception.
+    pub stack_segment: u64,
+}
+
+impl From<&InterruptStackFrame> for StackFrame {
+    fn from(frame: &InterruptStackFrame) -> Self {
+        Self {
+            instruction_pointer: frame.instruction_pointer,
+            code_segment: frame.code_segment,
+            cpu_flags: frame.cpu_flags,
+            stack_pointer: frame.stack_pointer,
+            stack_segment: frame.stack_segment,
+        }
+    }
+}
+
+impl Display for StackFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP={:016X} CS={:04X} RFLAGS={:016X}",
+            self.instruction_pointer.as_u64(),
+            self.code_segment,
+            self.cpu_flags,
+        )?;
+        write!(
+            f,
+            "RSP={:016X} SS={:04X}",
+            self.stack_pointer.as_u64(),
+            self.stack_segment,
+        )
+    }
+}
+
+/// A descriptor table that can be referenced by a selector error code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DescriptorTable {
+    /// The Global Descriptor Table.
+    Gdt,
+    /// The Interrupt Descriptor Table.
+    Idt,
+    /// The Local Descriptor Table.
+    Ldt,
+}
+
+/// An error code which represents a segment selector. See the documentation of [`CpuException`]
+/// for the meaning of each bit.
+///
+/// [`CpuException`]: ../enum.CpuException.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct SelectorErrorCode(pub u16);
+
+impl SelectorErrorCode {
+    /// Whether the exception originated externally to the processor.
+    pub fn is_external(&self) -> bool {
+        self.0 & 0b001 != 0
+    }
+
+    /// The descriptor table that contains the descriptor which caused the exception.
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 & 0b110) >> 1 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// The index of the descriptor which caused the exception in `self.table()`.
+    pub fn index(&self) -> u16 {
+        self.0 >> 3
+    }
+
+    /// Whether the error code is 0, which means that the exception was not caused by a
+    /// descriptor.
+    pub fn is_null(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_null() {
+            write!(f, "not caused by a selector")
+        } else {
+            write!(
+                f,
+                "{:?}[{}]{}",
+                self.table(),
+                self.index(),
+                if self.is_external() {
+                    " (external)"
+                } else {
+                    ""
+                },
+            )
+        }
+    }
+}
+
+/// The meaning of the error code pushed by an exception.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// The exception does not push an error code.
+    None,
+    /// The error code has no further meaning that can be decoded.
+    Raw(u64),
+    /// The error code is a segment selector.
+    Selector(SelectorErrorCode),
+    /// The error code of a [`PageFault`] along with the faulting address from `CR2`.
+    ///
+    /// [`PageFault`]: ../enum.CpuException.html#variant.PageFault
+    PageFault {
+        /// The decoded error code.
+        flags: PageFaultErrorCode,
+        /// The virtual address which caused the page fault.
+        address: VirtAddr,
+    },
+}
+
+impl ErrorCode {
+    /// Decode the error code `code` pushed by `exception`. The faulting address of a page fault
+    /// is taken from `cr2`.
+    pub fn decode(exception: CpuException, code: Option<u64>, cr2: u64) -> Self {
+        match (exception, code) {
+            (_, None) => Self::None,
+            (CpuException::InvalidTss, Some(code))
+            | (CpuException::SegmentNotPresent, Some(code))
+            | (CpuException::StackSegmentFault, Some(code))
+            | (CpuException::GeneralProtectionFault, Some(code)) => {
+                Self::Selector(SelectorErrorCode(code as u16))
+            }
+            (CpuException::PageFault, Some(code)) => Self::PageFault {
+                flags: PageFaultErrorCode::from_bits_truncate(code),
+                address: VirtAddr::new_truncate(cr2),
+            },
+            (_, Some(code)) => Self::Raw(code),
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Raw(code) => write!(f, "{:#X}", code),
+            Self::Selector(selector) => write!(f, "{:#06X} ({})", selector.0, selector),
+            Self::PageFault { flags, address } => {
+                write!(
+                    f,
+                    "{:#X} {:?} at {:#X}",
+                    flags.bits(),
+                    flags,
+                    address.as_u64()
+                )
+            }
+        }
+    }
+}
+
+/// A description of the state of the processor when an exception was thrown.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExceptionReport {
+    /// The exception that was thrown.
+    pub exception: CpuException,
+    /// The decoded error code of the exception.
+    pub error_code: ErrorCode,
+    /// The stack frame pushed by the CPU.
+    pub frame: StackFrame,
+    /// The general-purpose registers of the interrupted code.
+    pub registers: GeneralRegisters,
+    /// The control registers on entry to the handler.
+    pub control_registers: ControlRegisters,
+}
+
+static LAST_REPORT: Mutex<Option<ExceptionReport>> = Mutex::new(None);
+
+impl ExceptionReport {
+    /// Create a report for the exception which caused the trap described by `frame`.
+    pub fn from_trap_frame(frame: &TrapFrame) -> Self {
+        let exception = frame.exception();
+        let error_code = if exception.has_error_code() {
+            Some(frame.error_code)
+        } else {
+            None
+        };
+        let control_registers = ControlRegisters::read();
+        Self {
+            exception,
+            error_code: ErrorCode::decode(exception, error_code, control_registers.cr2),
+            frame: frame.stack_frame(),
+            registers: frame.registers(),
+            control_registers,
+        }
+    }
+
+    /// The stack of the code that caused the exception, starting from the interrupted instruction
+    /// and frame pointer. Only the frames of functions which were compiled with frame pointers can
+    /// be found.
+    pub fn backtrace(&self) -> Backtrace {
+        Backtrace::from_exception(self.frame.instruction_pointer.as_u64(), self.registers.rbp)
+    }
+
+    /// Write the report to both the VGA text buffer and the first serial port.
+    pub fn render(&self) {
+        vga_println!("{}", self);
+        serial_println!("{}", self);
+    }
+
+    /// Remember the report so that it can be retrieved with [`last_report`].
+    ///
+    /// [`last_report`]: fn.last_report.html
+    pub fn record(&self) {
+        *LAST_REPORT.lock() = Some(*self);
+    }
+}
+
+impl Display for ExceptionReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "EXCEPTION: {:?} ({}, vector {:#04X})",
+            self.exception,
+            self.exception.class(),
+            self.exception.vector(),
+        )?;
+        writeln!(f, "Error code: {}", self.error_code)?;
+        writeln!(f, "{}", self.frame)?;
+        writeln!(f, "{}", self.registers)?;
+        writeln!(f, "{}", self.control_registers)?;
+        write!(f, "{}", self.backtrace())
+    }
+}
+
+/// Get the report of the most recent exception that has been handled.
+pub fn last_report() -> Option<ExceptionReport> {
+    *LAST_REPORT.lock()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::cpu_exception::report]";
+
+    #[test_case]
+    fn test_selector_error_code() {
+        serial_print!("{} test_selector_error_code... ", TEST_PREFIX);
+        let selector = SelectorErrorCode(0x0013);
+        assert!(selector.is_external());
+        assert_eq!(selector.table(), DescriptorTable::Idt);
+        assert_eq!(selector.index(), 2);
+        let selector = SelectorErrorCode(0x002C);
+        assert!(!selector.is_external());
+        assert_eq!(selector.table(), DescriptorTable::Ldt);
+        assert_eq!(selector.index(), 5);
+        let selector = SelectorErrorCode(0x0010);
+        assert_eq!(selector.table(), DescriptorTable::Gdt);
+        assert_eq!(selector.index(), 2);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_decode_error_code() {
+        serial_print!("{} test_decode_error_code... ", TEST_PREFIX);
+        assert_eq!(
+            ErrorCode::decode(CpuException::Breakpoint, None, 0),
+            ErrorCode::None
+        );
+        assert_eq!(
+            ErrorCode::decode(CpuException::GeneralProtectionFault, Some(0x10), 0),
+            ErrorCode::Selector(SelectorErrorCode(0x10))
+        );
+        assert_eq!(
+            ErrorCode::decode(CpuException::PageFault, Some(0b11), 0xDEAD_B000),
+            ErrorCode::PageFault {
+                flags: PageFaultErrorCode::PROTECTION_VIOLATION
+                    | PageFaultErrorCode::CAUSED_BY_WRITE,
+                address: VirtAddr::new(0xDEAD_B000),
+            }
+        );
+        assert_eq!(
+            ErrorCode::decode(CpuException::DoubleFault, Some(0), 0),
+            ErrorCode::Raw(0)
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,672 @@
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+
+use uart_16550::SerialPort;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::control::{Cr0, Cr0Flags},
+};
+
+use crate::{
+    cpu_exception::{
+        fixup::{self, Fault},
+        interrupts::trap::{TrapFrame, TRAP_FLAG},
+    },
+    io::serial::SERIAL2,
+    qemu::{self, QemuExitCode},
+};
+
+/// The maximum number of software breakpoints that can be inserted at once.
+pub const MAX_BREAKPOINTS: usize = 32;
+
+/// The largest packet the stub accepts or sends, not counting the framing characters.
+const PACKET_SIZE: usize = 0x400;
+
+/// The signal reported to the debugger for every stop.
+const SIGTRAP: u8 = 5;
+
+/// The opcode of `INT3`.
+const INT3: u8 = 0xCC;
+
+/// The number of registers the stub provides, in GDB's numbering for x86-64: `RAX`, `RBX`, `RCX`,
+/// `RDX`, `RSI`, `RDI`, `RBP`, `RSP`, `R8` through `R15`, `RIP`, `EFLAGS`, `CS`, `SS`, `DS`,
+/// `ES`, `FS` and `GS`. The floating-point and vector registers are not available.
+const REGISTER_COUNT: usize = 24;
+
+/// A software breakpoint inserted by the debugger.
+#[derive(Clone, Copy, Debug)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+struct Stub {
+    /// Whether traps are handed to the stub.
+    enabled: bool,
+    /// Whether the debugger resumed the kernel and is waiting for a stop reply.
+    running: bool,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+/// The state of the stub. It is only locked with interrupts disabled.
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    enabled: false,
+    running: false,
+    breakpoints: [None; MAX_BREAKPOINTS],
+});
+
+/// Hand every [`Breakpoint`] and [`Debug`] trap to the stub from now on. Nothing is sent to the
+/// debugger until the next trap, so a session is usually started with [`breakpoint`] instead.
+///
+/// The stub only talks to the debugger while the kernel is stopped in a trap, so the debugger
+/// can't interrupt a running kernel.
+///
+/// [`Breakpoint`]: ../cpu_exception/enum.CpuException.html#variant.Breakpoint
+/// [`Debug`]: ../cpu_exception/enum.CpuException.html#variant.Debug
+/// [`breakpoint`]: fn.breakpoint.html
+pub fn enable() {
+    without_interrupts(|| STUB.lock().enabled = true);
+}
+
+/// Whether traps are handed to the stub. The stub is disabled again when the debugger detaches.
+pub fn is_enabled() -> bool {
+    without_interrupts(|| STUB.lock().enabled)
+}
+
+/// Enable the stub and stop in it, waiting for a debugger to connect to the second serial port.
+pub fn breakpoint() {
+    enable();
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Talk to the debugger until it resumes the kernel or detaches. This is called with interrupts
+/// disabled for every [`Breakpoint`] and [`Debug`] trap while the stub is enabled.
+///
+/// [`Breakpoint`]: ../cpu_exception/enum.CpuException.html#variant.Breakpoint
+/// [`Debug`]: ../cpu_exception/enum.CpuException.html#variant.Debug
+pub(crate) fn handle_trap(frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+    let mut port = SERIAL2.lock();
+    frame.rflags &= !TRAP_FLAG;
+    Session {
+        stub: &mut stub,
+        port: &mut port,
+        frame,
+    }
+    .run();
+}
+
+/// A packet being built to send to the debugger.
+struct Response {
+    buffer: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    fn new() -> Self {
+        Self {
+            buffer: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xF) as usize]);
+    }
+
+    /// Push the low `size` bytes of `value` in target (little-endian) byte order.
+    fn push_register(&mut self, value: u64, size: usize) {
+        value.to_le_bytes()[..size]
+            .iter()
+            .for_each(|&byte| self.push_hex(byte));
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.push(byte));
+        Ok(())
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a big-endian hexadecimal number, as used for addresses and lengths.
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | hex_digit(digit)? as u64)
+    })
+}
+
+/// Parse a pair of hexadecimal digits.
+fn parse_byte(digits: &[u8]) -> Option<u8> {
+    match digits {
+        [high, low] => Some(hex_digit(*high)? << 4 | hex_digit(*low)?),
+        _ => None,
+    }
+}
+
+/// Parse a register value sent in target (little-endian) byte order.
+fn parse_register(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || digits.len() % 2 != 0 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .rev()
+        .try_fold(0, |value, pair| Some(value << 8 | parse_byte(pair)? as u64))
+}
+
+/// Split `bytes` at the first `separator`.
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..position], &bytes[position + 1..]))
+}
+
+/// The size in bytes of register `n`.
+fn register_size(n: usize) -> usize {
+    if n <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+fn read_register(frame: &TrapFrame, n: usize) -> Option<u64> {
+    let value = match n {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        20..=23 => read_data_segment(n),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Read one of `DS`, `ES`, `FS` or `GS`. The trap stubs don't change them, so their current
+/// values are the values of the interrupted code.
+fn read_data_segment(n: usize) -> u64 {
+    let selector: u16;
+    unsafe {
+        match n {
+            20 => asm!("mov {:x}, ds", out(reg) selector),
+            21 => asm!("mov {:x}, es", out(reg) selector),
+            22 => asm!("mov {:x}, fs", out(reg) selector),
+            _ => asm!("mov {:x}, gs", out(reg) selector),
+        }
+    }
+    selector as u64
+}
+
+/// Write `value` to register `n`. Writes to the segment registers are ignored, since changing
+/// them under the kernel is never what the debugger wants. Only the low 32 bits of `RFLAGS` can
+/// be written.
+fn write_register(frame: &mut TrapFrame, n: usize, value: u64) -> bool {
+    let register = match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => {
+            frame.rflags = frame.rflags & !0xFFFF_FFFF | value & 0xFFFF_FFFF;
+            return true;
+        }
+        18..=23 => return true,
+        _ => return false,
+    };
+    *register = value;
+    true
+}
+
+/// Read a byte of kernel memory, or return the fault if it isn't mapped.
+fn read_byte(address: u64) -> Result<u8, Fault> {
+    unsafe { fixup::probe_read(address) }
+}
+
+/// Write a byte of kernel memory, even if the page holding it is read-only, or return the fault if
+/// it isn't mapped.
+///
+/// # Safety
+/// The write must not break the kernel.
+unsafe fn write_byte(address: u64, value: u8) -> Result<(), Fault> {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    let written = fixup::probe_write(address, value);
+    Cr0::write(cr0);
+    written
+}
+
+/// A conversation with the debugger during a single trap.
+struct Session<'a> {
+    stub: &'a mut Stub,
+    port: &'a mut SerialPort,
+    frame: &'a mut TrapFrame,
+}
+
+impl Session<'_> {
+    fn run(&mut self) {
+        if self.stub.running {
+            self.send_stop_reply();
+        }
+        let mut buffer = [0; PACKET_SIZE];
+        loop {
+            let len = self.receive_packet(&mut buffer);
+            let packet = &buffer[..len];
+            let mut response = Response::new();
+            match packet.split_first() {
+                Some((b'?', _)) => write!(response, "S{:02x}", SIGTRAP).unwrap(),
+                Some((b'g', _)) => self.read_registers(&mut response),
+                Some((b'G', data)) => self.write_registers(data, &mut response),
+                Some((b'p', data)) => self.read_register(data, &mut response),
+                Some((b'P', data)) => self.write_register(data, &mut response),
+                Some((b'm', data)) => self.read_memory(data, &mut response),
+                Some((b'M', data)) => self.write_memory(data, &mut response),
+                Some((b'Z', data)) => self.insert_breakpoint(data, &mut response),
+                Some((b'z', data)) => self.remove_breakpoint(data, &mut response),
+                Some((b'c', data)) => return self.resume(data, false),
+                Some((b's', data)) => return self.resume(data, true),
+                Some((b'D', _)) => {
+                    self.detach();
+                    return self.send_packet(b"OK");
+                }
+                Some((b'k', _)) => {
+                    self.detach();
+                    qemu::exit_qemu(QemuExitCode::Success);
+                }
+                Some((b'H', _)) | Some((b'T', _)) => response.write_str("OK").unwrap(),
+                Some((b'q', query)) => self.query(query, &mut response),
+                // An empty response tells the debugger that the packet isn't supported.
+                _ => {}
+            }
+            self.send_packet(response.as_bytes());
+        }
+    }
+
+    /// Wait for a packet with a valid checksum, acknowledge it and copy its contents into
+    /// `buffer`. Returns the length of the contents.
+    fn receive_packet(&mut self, buffer: &mut [u8; PACKET_SIZE]) -> usize {
+        loop {
+            while self.port.receive() != b'$' {}
+            let mut len = 0;
+            let mut checksum = 0u8;
+            loop {
+                let byte = self.port.receive();
+                if byte == b'#' {
+                    break;
+                }
+                if len < PACKET_SIZE {
+                    buffer[len] = byte;
+                }
+                len += 1;
+                checksum = checksum.wrapping_add(byte);
+            }
+            let expected = [self.port.receive(), self.port.receive()];
+            if len <= PACKET_SIZE && parse_byte(&expected) == Some(checksum) {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    /// Send a packet and wait for the debugger to acknowledge it, resending it as often as the
+    /// debugger asks.
+    fn send_packet(&mut self, data: &[u8]) {
+        loop {
+            let mut checksum = 0u8;
+            let mut send = |port: &mut SerialPort, byte: u8| {
+                port.send(byte);
+                checksum = checksum.wrapping_add(byte);
+            };
+            self.port.send(b'$');
+            for &byte in data {
+                match byte {
+                    b'$' | b'#' | b'}' | b'*' => {
+                        send(self.port, b'}');
+                        send(self.port, byte ^ 0x20);
+                    }
+                    _ => send(self.port, byte),
+                }
+            }
+            self.port.send(b'#');
+            self.port.send(HEX_DIGITS[(checksum >> 4) as usize]);
+            self.port.send(HEX_DIGITS[(checksum & 0xF) as usize]);
+            loop {
+                match self.port.receive() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn send_stop_reply(&mut self) {
+        let mut response = Response::new();
+        write!(response, "S{:02x}", SIGTRAP).unwrap();
+        self.stub.running = false;
+        self.send_packet(response.as_bytes());
+    }
+
+    fn read_registers(&self, response: &mut Response) {
+        (0..REGISTER_COUNT).for_each(|n| {
+            let value = read_register(self.frame, n).unwrap();
+            response.push_register(value, register_size(n));
+        });
+    }
+
+    fn write_registers(&mut self, data: &[u8], response: &mut Response) {
+        let mut data = data;
+        for n in 0..REGISTER_COUNT {
+            let size = 2 * register_size(n);
+            if data.len() < size {
+                break;
+            }
+            let (digits, rest) = data.split_at(size);
+            match parse_register(digits) {
+                Some(value) => {
+                    write_register(self.frame, n, value);
+                }
+                None => return response.write_str("E01").unwrap(),
+            }
+            data = rest;
+        }
+        response.write_str("OK").unwrap();
+    }
+
+    fn read_register(&self, data: &[u8], response: &mut Response) {
+        let value = parse_hex(data)
+            .and_then(|n| Some((n as usize, read_register(self.frame, n as usize)?)));
+        match value {
+            Some((n, value)) => response.push_register(value, register_size(n)),
+            None => response.write_str("E01").unwrap(),
+        }
+    }
+
+    fn write_register(&mut self, data: &[u8], response: &mut Response) {
+        let written = split(data, b'=')
+            .and_then(|(n, value)| Some((parse_hex(n)? as usize, parse_register(value)?)))
+            .map_or(false, |(n, value)| write_register(self.frame, n, value));
+        response
+            .write_str(if written { "OK" } else { "E01" })
+            .unwrap();
+    }
+
+    /// Read kernel memory. If any of it can't be read, the reply is an error instead.
+    fn read_memory(&self, data: &[u8], response: &mut Response) {
+        let range = split(data, b',')
+            .and_then(|(address, len)| Some((parse_hex(address)?, parse_hex(len)?)));
+        match range {
+            Some((address, len)) => {
+                let len = len.min(PACKET_SIZE as u64 / 2);
+                let read = (address..address.wrapping_add(len))
+                    .try_for_each(|address| read_byte(address).map(|byte| response.push_hex(byte)));
+                if read.is_err() {
+                    *response = Response::new();
+                    response.write_str("E03").unwrap();
+                }
+            }
+            None => response.write_str("E01").unwrap(),
+        }
+    }
+
+    /// Write kernel memory. Read-only pages are written too, so that the debugger can patch code.
+    fn write_memory(&mut self, data: &[u8], response: &mut Response) {
+        let parsed = split(data, b',').and_then(|(address, rest)| {
+            let (len, bytes) = split(rest, b':')?;
+            Some((parse_hex(address)?, parse_hex(len)?, bytes))
+        });
+        match parsed {
+            Some((address, len, bytes)) if bytes.len() as u64 == 2 * len => {
+                if bytes.chunks(2).any(|pair| parse_byte(pair).is_none()) {
+                    return response.write_str("E01").unwrap();
+                }
+                let written = bytes
+                    .chunks(2)
+                    .enumerate()
+                    .try_for_each(|(offset, pair)| unsafe {
+                        write_byte(address + offset as u64, parse_byte(pair).unwrap())
+                    });
+                response
+                    .write_str(if written.is_ok() { "OK" } else { "E03" })
+                    .unwrap();
+            }
+            _ => response.write_str("E01").unwrap(),
+        }
+    }
+
+    /// Parse the type and address of a `Z` or `z` packet. Only software breakpoints (type 0) are
+    /// supported.
+    fn parse_breakpoint(data: &[u8]) -> Option<Result<u64, ()>> {
+        let (kind, rest) = split(data, b',')?;
+        let (address, _) = split(rest, b',')?;
+        if kind != b"0" {
+            return None;
+        }
+        Some(parse_hex(address).ok_or(()))
+    }
+
+    fn insert_breakpoint(&mut self, data: &[u8], response: &mut Response) {
+        let address = match Self::parse_breakpoint(data) {
+            Some(Ok(address)) => address,
+            Some(Err(())) => return response.write_str("E01").unwrap(),
+            None => return,
+        };
+        let breakpoints = &mut self.stub.breakpoints;
+        if breakpoints.iter().flatten().any(|bp| bp.address == address) {
+            return response.write_str("OK").unwrap();
+        }
+        match breakpoints.iter_mut().find(|bp| bp.is_none()) {
+            Some(slot) => {
+                let inserted = read_byte(address)
+                    .and_then(|original| unsafe { write_byte(address, INT3).map(|()| original) });
+                match inserted {
+                    Ok(original) => {
+                        *slot = Some(Breakpoint { address, original });
+                        response.write_str("OK").unwrap();
+                    }
+                    Err(_) => response.write_str("E03").unwrap(),
+                }
+            }
+            None => response.write_str("E02").unwrap(),
+        }
+    }
+
+    fn remove_breakpoint(&mut self, data: &[u8], response: &mut Response) {
+        let address = match Self::parse_breakpoint(data) {
+            Some(Ok(address)) => address,
+            Some(Err(())) => return response.write_str("E01").unwrap(),
+            None => return,
+        };
+        let slot = self
+            .stub
+            .breakpoints
+            .iter_mut()
+            .find(|bp| bp.map_or(false, |bp| bp.address == address));
+        match slot {
+            Some(slot) => {
+                let breakpoint = slot.take().unwrap();
+                let restored = unsafe { write_byte(breakpoint.address, breakpoint.original) };
+                response
+                    .write_str(if restored.is_ok() { "OK" } else { "E03" })
+                    .unwrap();
+            }
+            None => response.write_str("E01").unwrap(),
+        }
+    }
+
+    /// Return to the kernel, optionally at a new address and optionally for only one instruction.
+    fn resume(&mut self, data: &[u8], step: bool) {
+        if let Some(address) = parse_hex(data) {
+            self.frame.rip = address;
+        }
+        if step {
+            self.frame.rflags |= TRAP_FLAG;
+        }
+        self.stub.running = true;
+    }
+
+    /// Remove every breakpoint and stop handling traps.
+    fn detach(&mut self) {
+        for breakpoint in self.stub.breakpoints.iter_mut().filter_map(Option::take) {
+            // The byte was written when the breakpoint was inserted, so this can't fault.
+            let _ = unsafe { write_byte(breakpoint.address, breakpoint.original) };
+        }
+        self.stub.enabled = false;
+        self.stub.running = false;
+    }
+
+    fn query(&self, query: &[u8], response: &mut Response) {
+        let name = split(query, b':').map_or(query, |(name, _)| name);
+        let reply = match name {
+            b"Supported" => "PacketSize=400",
+            b"Attached" => "1",
+            b"C" => "QC1",
+            b"fThreadInfo" => "m1",
+            b"sThreadInfo" => "l",
+            _ => "",
+        };
+        response.write_str(reply).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::gdb]";
+
+    #[test_case]
+    fn test_parse_hex() {
+        serial_print!("{} test_parse_hex... ", TEST_PREFIX);
+        assert_eq!(parse_hex(b"ffff80000000beef"), Some(0xFFFF_8000_0000_BEEF));
+        assert_eq!(parse_hex(b"1A"), Some(0x1A));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"12g4"), None);
+        assert_eq!(parse_byte(b"c3"), Some(0xC3));
+        assert_eq!(parse_register(b"efbeadde"), Some(0xDEAD_BEEF));
+        assert_eq!(parse_register(b"efb"), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_register_round_trip() {
+        serial_print!("{} test_register_round_trip... ", TEST_PREFIX);
+        let mut frame = TrapFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: 0,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            vector: 3,
+            error_code: 0,
+            rip: 0,
+            cs: 0x08,
+            rflags: 0x1_0000_0202,
+            rsp: 0,
+            ss: 0,
+        };
+        (0..=16).for_each(|n| assert!(write_register(&mut frame, n, n as u64 + 1)));
+        (0..=16).for_each(|n| assert_eq!(read_register(&frame, n), Some(n as u64 + 1)));
+        assert_eq!(frame.rsp, 8);
+        assert_eq!(frame.rip, 17);
+        assert!(write_register(&mut frame, 17, 0x246));
+        assert_eq!(frame.rflags, 0x1_0000_0246);
+        assert!(write_register(&mut frame, 18, 0x10));
+        assert_eq!(frame.cs, 0x08);
+        assert!(!write_register(&mut frame, REGISTER_COUNT, 0));
+        assert_eq!(read_register(&frame, REGISTER_COUNT), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_response_encoding() {
+        serial_print!("{} test_response_encoding... ", TEST_PREFIX);
+        let mut response = Response::new();
+        response.push_register(0x1234, 8);
+        response.push_register(0x202, 4);
+        assert_eq!(response.as_bytes(), b"341200000000000002020000" as &[u8]);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_memory_faults() {
+        serial_print!("{} test_memory_faults... ", TEST_PREFIX);
+        let mut value = 0x5Au8;
+        let address = &mut value as *mut u8 as u64;
+        assert_eq!(read_byte(address), Ok(0x5A));
+        assert_eq!(unsafe { write_byte(address, INT3) }, Ok(()));
+        assert_eq!(value, INT3);
+        // The guard page below the kernel stack is never mapped.
+        let guard = crate::gdt::KERNEL_STACK_ADDRESS;
+        assert!(read_byte(guard).is_err());
+        assert!(unsafe { write_byte(guard, INT3) }.is_err());
+        serial_println!("[ok]");
+    }
+}
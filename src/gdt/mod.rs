@@ -1,9 +1,26 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+
 use x86_64::{
-    instructions::{segmentation, tables},
-    structures::{gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector}, tss::TaskStateSegment},
+    instructions::{interrupts::without_interrupts, segmentation, tables},
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        paging::{
+            mapper::{MapToError, UnmapError},
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+        },
+        tss::TaskStateSegment,
+    },
     VirtAddr,
 };
 
+use crate::memory;
+
 /// The index of the stack for handling [`DoubleFault`] in the Interrupt Stack Table.
 ///
 /// [`DoubleFault`]: ../cpu_exception/enum.CpuException.html#variant.DoubleFault
@@ -19,25 +36,246 @@ pub const STACK_SEGMENT_FAULT_IST_INDEX: u16 = 1;
 /// [`PageFault`]: ../cpu_exception/enum.CpuException.html#variant.PageFault
 pub const PAGE_FAULT_IST_INDEX: u16 = 2;
 
+/// The lowest address of the kernel stack set up by the bootloader. This must match
+/// `kernel-stack-address` in `[package.metadata.bootloader]` in `Cargo.toml`.
+pub const KERNEL_STACK_ADDRESS: u64 = 0xFFFF_FF80_0000_0000;
+
+/// The number of pages in the kernel stack set up by the bootloader, including its guard page.
+/// This must match `kernel-stack-size` in `[package.metadata.bootloader]` in `Cargo.toml`.
+pub const KERNEL_STACK_PAGES: u64 = 512;
+
+/// The size of the unmapped page below each stack.
+pub const GUARD_PAGE_SIZE: u64 = 4096;
+
+/// A stack with an unmapped guard page immediately below it, so that overflowing the stack causes
+/// a page fault instead of silently overwriting whatever is below it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GuardedStack {
+    /// The name of the stack, used when reporting an overflow.
+    pub name: &'static str,
+    /// The lowest address of the guard page.
+    pub guard: VirtAddr,
+    /// The address one past the highest byte of the stack, which is its initial stack pointer.
+    pub top: VirtAddr,
+}
+
+impl GuardedStack {
+    /// The lowest address of the usable stack, which is the address just past the guard page.
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard + GUARD_PAGE_SIZE
+    }
+
+    /// The number of usable bytes in the stack.
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom()
+    }
+
+    /// Whether a fault at `fault_address` with the stack pointer at `stack_pointer` was caused by
+    /// overflowing this stack: either the access hit the guard page or the stack pointer is in it.
+    /// A push that faults leaves the stack pointer at the bottom of the stack, so that counts too.
+    pub fn is_overflow(&self, stack_pointer: VirtAddr, fault_address: VirtAddr) -> bool {
+        let in_guard = |address: VirtAddr| address >= self.guard && address < self.bottom();
+        in_guard(fault_address) || in_guard(stack_pointer) || stack_pointer == self.bottom()
+    }
+}
+
 macro_rules! make_stack {
-    ($size:expr) => {{
-        static mut STACK: [u8; $size] = [0; $size];
+    ($name:expr, $size:expr) => {{
+        #[repr(C, align(4096))]
+        struct Stack([u8; GUARD_PAGE_SIZE as usize + $size]);
+
+        static mut STACK: Stack = Stack([0; GUARD_PAGE_SIZE as usize + $size]);
+
+        let guard = VirtAddr::from_ptr(unsafe { &STACK });
+        GuardedStack {
+            name: $name,
+            guard,
+            top: guard + GUARD_PAGE_SIZE + $size as u64,
+        }
+    }};
+}
+
+/// The index of the kernel stack in the table of stacks. The stack for IST index `n` is at index
+/// `n + 1`.
+const KERNEL_STACK_INDEX: usize = 0;
+
+/// The number of stacks in the table of stacks: the kernel stack and one per used IST index.
+const STACK_COUNT: usize = 4;
+
+/// The size of each of the stacks that the Interrupt Stack Table starts out with. They are only
+/// meant to last until [`init_stacks`] replaces them.
+///
+/// [`init_stacks`]: fn.init_stacks.html
+pub const BOOTSTRAP_STACK_SIZE: usize = 4096;
+
+/// The start of the virtual memory region holding the stacks created by [`init_stacks`]. Each
+/// IST index gets a window of [`IST_STACK_WINDOW`] bytes starting with its guard page.
+///
+/// [`init_stacks`]: fn.init_stacks.html
+/// [`IST_STACK_WINDOW`]: constant.IST_STACK_WINDOW.html
+pub const IST_STACK_REGION: u64 = 0xFFFF_FE00_0000_0000;
+
+/// The size of the window of virtual memory reserved for each stack created by [`init_stacks`].
+///
+/// [`init_stacks`]: fn.init_stacks.html
+pub const IST_STACK_WINDOW: u64 = 0x10_0000;
+
+/// The largest stack [`init_stacks`] can create.
+///
+/// [`init_stacks`]: fn.init_stacks.html
+pub const MAX_IST_STACK_SIZE: usize = (IST_STACK_WINDOW - GUARD_PAGE_SIZE) as usize;
+
+/// The value every unused word of a stack is filled with, so that the deepest point the stack has
+/// reached can be found by looking for the first word that was overwritten.
+pub const STACK_PAINT: u64 = 0x57AC_57AC_57AC_57AC;
+
+/// How far below the current stack pointer painting of the kernel stack stops, so that the frames
+/// of the code doing the painting are left alone.
+const KERNEL_STACK_PAINT_MARGIN: u64 = 4096;
 
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        let stack_end = stack_start + $size;
-        stack_end
-    }}
+/// The sizes of the stacks in the Interrupt Stack Table. Each size must be a non-zero multiple of
+/// 4096 no larger than [`MAX_IST_STACK_SIZE`].
+///
+/// [`MAX_IST_STACK_SIZE`]: constant.MAX_IST_STACK_SIZE.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackSizes {
+    /// The size of the stack at [`DOUBLE_FAULT_IST_INDEX`].
+    ///
+    /// [`DOUBLE_FAULT_IST_INDEX`]: constant.DOUBLE_FAULT_IST_INDEX.html
+    pub double_fault: usize,
+    /// The size of the stack at [`STACK_SEGMENT_FAULT_IST_INDEX`].
+    ///
+    /// [`STACK_SEGMENT_FAULT_IST_INDEX`]: constant.STACK_SEGMENT_FAULT_IST_INDEX.html
+    pub stack_segment_fault: usize,
+    /// The size of the stack at [`PAGE_FAULT_IST_INDEX`].
+    ///
+    /// [`PAGE_FAULT_IST_INDEX`]: constant.PAGE_FAULT_IST_INDEX.html
+    pub page_fault: usize,
+}
+
+impl StackSizes {
+    /// Sizes which leave room for the handlers to render a full exception report.
+    pub const DEFAULT: Self = Self {
+        double_fault: 0x4000,
+        stack_segment_fault: 0x4000,
+        page_fault: 0x4000,
+    };
+
+    /// The size of the stack at IST index `index`.
+    fn get(&self, index: u16) -> usize {
+        match index {
+            DOUBLE_FAULT_IST_INDEX => self.double_fault,
+            STACK_SEGMENT_FAULT_IST_INDEX => self.stack_segment_fault,
+            PAGE_FAULT_IST_INDEX => self.page_fault,
+            _ => unreachable!("IST index {} is not used", index),
+        }
+    }
+}
+
+impl Default for StackSizes {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The reason the stacks in the Interrupt Stack Table could not be replaced.
+#[derive(Debug)]
+pub enum StackError {
+    /// The stacks have already been replaced.
+    AlreadyInitialized,
+    /// The size of the stack at the IST index is not a non-zero multiple of 4096 or is too large.
+    InvalidSize {
+        /// The IST index of the stack.
+        index: u16,
+        /// The requested size.
+        size: usize,
+    },
+    /// The frame allocator ran out of frames.
+    OutOfFrames,
+    /// A page of a stack could not be mapped.
+    Map(MapToError<Size4KiB>),
+}
+
+/// How much of a stack has been used.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackUsage {
+    /// The name of the stack.
+    pub name: &'static str,
+    /// The number of usable bytes in the stack.
+    pub size: u64,
+    /// The largest number of bytes that have been in use at once, as far as the stack's paint
+    /// shows.
+    pub high_water_mark: u64,
+}
+
+impl Display for StackUsage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of {} bytes used ({}%)",
+            self.name,
+            self.high_water_mark,
+            self.size,
+            self.high_water_mark * 100 / self.size.max(1),
+        )
+    }
+}
+
+/// The name of the stack at IST index `index`.
+fn ist_stack_name(index: u16) -> &'static str {
+    match index {
+        DOUBLE_FAULT_IST_INDEX => "double fault stack",
+        STACK_SEGMENT_FAULT_IST_INDEX => "stack segment fault stack",
+        PAGE_FAULT_IST_INDEX => "page fault stack",
+        _ => unreachable!("IST index {} is not used", index),
+    }
+}
+
+/// The IST indices in use, in order.
+fn ist_indices() -> impl Iterator<Item = u16> {
+    [
+        DOUBLE_FAULT_IST_INDEX,
+        STACK_SEGMENT_FAULT_IST_INDEX,
+        PAGE_FAULT_IST_INDEX,
+    ]
+    .iter()
+    .copied()
 }
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        const STACK_SIZE: usize = 4096;
+    /// The kernel stack and the stack at each used IST index. The fault handlers read the table,
+    /// so interrupts must be disabled while it is locked.
+    static ref STACKS: Mutex<[GuardedStack; STACK_COUNT]> = Mutex::new([
+        GuardedStack {
+            name: "kernel stack",
+            guard: VirtAddr::new(KERNEL_STACK_ADDRESS),
+            top: VirtAddr::new(KERNEL_STACK_ADDRESS + KERNEL_STACK_PAGES * 4096),
+        },
+        make_stack!(ist_stack_name(DOUBLE_FAULT_IST_INDEX), BOOTSTRAP_STACK_SIZE),
+        make_stack!(ist_stack_name(STACK_SEGMENT_FAULT_IST_INDEX), BOOTSTRAP_STACK_SIZE),
+        make_stack!(ist_stack_name(PAGE_FAULT_IST_INDEX), BOOTSTRAP_STACK_SIZE),
+    ]);
+}
+
+/// Whether [`init_stacks`] has succeeded or is running.
+///
+/// [`init_stacks`]: fn.init_stacks.html
+static DYNAMIC_STACKS: AtomicBool = AtomicBool::new(false);
 
+/// The Task State Segment. The CPU reads the Interrupt Stack Table from it directly, so the
+/// entries can be replaced after it has been loaded.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+lazy_static! {
+    static ref TSS: Tss = {
+        let stacks = STACKS.lock();
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = make_stack!(STACK_SIZE);
-        tss.interrupt_stack_table[STACK_SEGMENT_FAULT_IST_INDEX as usize] = make_stack!(STACK_SIZE);
-        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = make_stack!(STACK_SIZE);
-        tss
+        for index in ist_indices() {
+            tss.interrupt_stack_table[index as usize] = stacks[index as usize + 1].top;
+        }
+        Tss(UnsafeCell::new(tss))
     };
 }
 
@@ -45,7 +283,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -55,11 +293,415 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-/// Set up the Global Descriptor Table.
+/// Set up the Global Descriptor Table, unmap the guard page below every stack and paint the
+/// stacks.
 pub fn init() {
     GDT.0.load();
     unsafe {
         segmentation::set_cs(GDT.1.code_selector);
         tables::load_tss(GDT.1.tss_selector);
     }
+    let stacks = without_interrupts(|| *STACKS.lock());
+    unmap_guard_pages(&stacks);
+    let stack_pointer: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) stack_pointer, options(nomem, nostack));
+        let kernel_stack = stacks[KERNEL_STACK_INDEX];
+        paint(
+            kernel_stack.bottom(),
+            VirtAddr::new(stack_pointer - KERNEL_STACK_PAINT_MARGIN),
+        );
+        for stack in &stacks[KERNEL_STACK_INDEX + 1..] {
+            paint(stack.bottom(), stack.top);
+        }
+    }
+}
+
+fn unmap_guard_pages(stacks: &[GuardedStack]) {
+    let mut page_table = unsafe { memory::active_page_table() };
+    for stack in stacks {
+        let page: Page<Size4KiB> = Page::containing_address(stack.guard);
+        match page_table.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            // The bootloader may already have left the page below its stack unmapped.
+            Err(UnmapError::PageNotMapped) => {}
+            Err(e) => panic!(
+                "Failed to unmap the guard page of the {}: {:?}",
+                stack.name, e
+            ),
+        }
+    }
+}
+
+/// Fill every word from `bottom` up to `top` with [`STACK_PAINT`].
+///
+/// [`STACK_PAINT`]: constant.STACK_PAINT.html
+///
+/// # Safety
+/// The memory must be mapped and must not be in use.
+unsafe fn paint(bottom: VirtAddr, top: VirtAddr) {
+    let mut word = bottom.as_mut_ptr::<u64>();
+    while (word as u64) < top.as_u64() {
+        word.write_volatile(STACK_PAINT);
+        word = word.add(1);
+    }
+}
+
+/// The number of bytes between `top` and the lowest word from `bottom` up which no longer holds
+/// [`STACK_PAINT`].
+///
+/// [`STACK_PAINT`]: constant.STACK_PAINT.html
+///
+/// # Safety
+/// The memory from `bottom` up to `top` must be mapped.
+unsafe fn high_water_mark(bottom: VirtAddr, top: VirtAddr) -> u64 {
+    let mut word = bottom.as_ptr::<u64>();
+    while (word as u64) < top.as_u64() && word.read_volatile() == STACK_PAINT {
+        word = word.add(1);
+    }
+    top.as_u64() - (word as u64).min(top.as_u64())
+}
+
+/// Replace the stacks in the Interrupt Stack Table with stacks of the given sizes. Their memory
+/// is taken from `allocator` and mapped at [`IST_STACK_REGION`] with an unmapped guard page below
+/// each stack, and they are painted so that [`stack_usage`] can report how much of them is used.
+///
+/// This can only be done once. If it fails, whatever was mapped is unmapped and its frames are
+/// returned to `allocator`, the stacks the Interrupt Stack Table started out with stay in use, and
+/// it can be tried again.
+///
+/// [`IST_STACK_REGION`]: constant.IST_STACK_REGION.html
+/// [`stack_usage`]: fn.stack_usage.html
+pub fn init_stacks<A>(sizes: &StackSizes, allocator: &mut A) -> Result<(), StackError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    for index in ist_indices() {
+        let size = sizes.get(index);
+        if size == 0 || size % GUARD_PAGE_SIZE as usize != 0 || size > MAX_IST_STACK_SIZE {
+            return Err(StackError::InvalidSize { index, size });
+        }
+    }
+    if DYNAMIC_STACKS.swap(true, Ordering::SeqCst) {
+        return Err(StackError::AlreadyInitialized);
+    }
+    let new_stacks = match map_stacks(sizes, IST_STACK_REGION, allocator) {
+        Ok(stacks) => stacks,
+        Err(e) => {
+            DYNAMIC_STACKS.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+    };
+    without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        for index in ist_indices() {
+            let stack = new_stacks[index as usize + 1].unwrap();
+            stacks[index as usize + 1] = stack;
+            unsafe {
+                (*TSS.0.get()).interrupt_stack_table[index as usize] = stack.top;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Map and paint a stack of the given size for every used IST index, each in its window of the
+/// region starting at `region`. If any of them can't be mapped, the ones that were are unmapped
+/// again.
+fn map_stacks<A>(
+    sizes: &StackSizes,
+    region: u64,
+    allocator: &mut A,
+) -> Result<[Option<GuardedStack>; STACK_COUNT], StackError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let mut page_table = unsafe { memory::active_page_table() };
+    let mut stacks = [None; STACK_COUNT];
+    for index in ist_indices() {
+        let guard = VirtAddr::new(region + index as u64 * IST_STACK_WINDOW);
+        let stack = GuardedStack {
+            name: ist_stack_name(index),
+            guard,
+            top: guard + GUARD_PAGE_SIZE + sizes.get(index) as u64,
+        };
+        if let Err(e) = map_stack(&mut page_table, &stack, allocator) {
+            for stack in stacks.iter().flatten() {
+                unmap_stack(&mut page_table, stack, allocator);
+            }
+            return Err(e);
+        }
+        stacks[index as usize + 1] = Some(stack);
+    }
+    Ok(stacks)
+}
+
+/// Map every page of `stack` above its guard page to a frame from `allocator` and paint it. If a
+/// page can't be mapped, the pages that were are unmapped again.
+fn map_stack<M, A>(
+    page_table: &mut M,
+    stack: &GuardedStack,
+    allocator: &mut A,
+) -> Result<(), StackError>
+where
+    M: Mapper<Size4KiB>,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let pages = Page::range(
+        Page::containing_address(stack.bottom()),
+        Page::containing_address(stack.top),
+    );
+    for page in pages {
+        let mapped = match allocator.allocate_frame() {
+            Some(frame) => unsafe { page_table.map_to(page, frame, flags, allocator) }
+                .map(|flush| flush.flush())
+                .map_err(|e| {
+                    allocator.deallocate_frame(frame);
+                    StackError::Map(e)
+                }),
+            None => Err(StackError::OutOfFrames),
+        };
+        if let Err(e) = mapped {
+            unmap_stack(page_table, stack, allocator);
+            return Err(e);
+        }
+    }
+    unsafe {
+        paint(stack.bottom(), stack.top);
+    }
+    Ok(())
+}
+
+/// Unmap whichever pages of `stack` above its guard page are mapped and return their frames to
+/// `deallocator`.
+fn unmap_stack<M, D>(page_table: &mut M, stack: &GuardedStack, deallocator: &mut D)
+where
+    M: Mapper<Size4KiB>,
+    D: FrameDeallocator<Size4KiB>,
+{
+    let pages = Page::range(
+        Page::containing_address(stack.bottom()),
+        Page::containing_address(stack.top),
+    );
+    for page in pages {
+        if let Ok((frame, flush)) = page_table.unmap(page) {
+            flush.flush();
+            deallocator.deallocate_frame(frame);
+        }
+    }
+}
+
+/// Get an iterator over every stack with a guard page.
+pub fn stacks() -> impl Iterator<Item = GuardedStack> {
+    let stacks = without_interrupts(|| *STACKS.lock());
+    (0..STACK_COUNT).map(move |index| stacks[index])
+}
+
+/// Report the high-water mark of the kernel stack and of every stack in the Interrupt Stack
+/// Table. Only the part of the kernel stack that was unused when [`init`] ran is painted, so its
+/// high-water mark is at least as deep as the stack was at that point.
+///
+/// [`init`]: fn.init.html
+pub fn stack_usage() -> [StackUsage; STACK_COUNT] {
+    let stacks = without_interrupts(|| *STACKS.lock());
+    let usage = |stack: &GuardedStack| StackUsage {
+        name: stack.name,
+        size: stack.size(),
+        high_water_mark: unsafe { high_water_mark(stack.bottom(), stack.top) },
+    };
+    [
+        usage(&stacks[0]),
+        usage(&stacks[1]),
+        usage(&stacks[2]),
+        usage(&stacks[3]),
+    ]
+}
+
+/// The stack which overflowed to cause a fault at `fault_address` with the stack pointer at
+/// `stack_pointer`, if the fault was a stack overflow. This is called from fault handlers, so if
+/// the table of stacks is locked the overflow can't be diagnosed.
+pub fn overflowed_stack(stack_pointer: VirtAddr, fault_address: VirtAddr) -> Option<GuardedStack> {
+    let stacks = *STACKS.try_lock()?;
+    stacks
+        .iter()
+        .copied()
+        .find(|stack| stack.is_overflow(stack_pointer, fault_address))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use x86_64::structures::paging::{MapperAllSizes, PhysFrame};
+
+    use crate::memory::frame::GlobalFrameAllocator;
+
+    const TEST_PREFIX: &'static str = "[rust_os::gdt]";
+
+    /// A frame allocator which hands out at most `remaining` frames from the buddy allocator and
+    /// counts how many it has handed out and taken back.
+    struct LimitedFrames {
+        remaining: usize,
+        allocated: usize,
+        deallocated: usize,
+    }
+
+    impl LimitedFrames {
+        fn new(remaining: usize) -> Self {
+            LimitedFrames {
+                remaining,
+                allocated: 0,
+                deallocated: 0,
+            }
+        }
+    }
+
+    unsafe impl FrameAllocator<Size4KiB> for LimitedFrames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            if self.remaining == 0 {
+                return None;
+            }
+            let frame = GlobalFrameAllocator.allocate_frame()?;
+            self.remaining -= 1;
+            self.allocated += 1;
+            Some(frame)
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for LimitedFrames {
+        fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+            self.deallocated += 1;
+            GlobalFrameAllocator.deallocate_frame(frame);
+        }
+    }
+
+    #[test_case]
+    fn test_guard_pages_unmapped() {
+        serial_print!("{} test_guard_pages_unmapped... ", TEST_PREFIX);
+        let page_table = unsafe { memory::active_page_table() };
+        for stack in stacks() {
+            assert_eq!(page_table.translate_addr(stack.guard), None);
+            assert!(page_table.translate_addr(stack.bottom()).is_some());
+            assert_eq!(stack.bottom().as_u64() % GUARD_PAGE_SIZE, 0);
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_overflow_detection() {
+        serial_print!("{} test_overflow_detection... ", TEST_PREFIX);
+        let stack = stacks().nth(1).unwrap();
+        let unrelated = VirtAddr::new(0x1000);
+        assert!(stack.is_overflow(stack.bottom(), unrelated));
+        assert!(stack.is_overflow(stack.bottom() - 8u64, unrelated));
+        assert!(stack.is_overflow(stack.top, stack.guard + 16u64));
+        assert!(!stack.is_overflow(stack.top - 8u64, unrelated));
+        assert_eq!(
+            overflowed_stack(stack.bottom() - 8u64, unrelated).map(|s| s.name),
+            Some("double fault stack")
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_high_water_mark() {
+        serial_print!("{} test_high_water_mark... ", TEST_PREFIX);
+        let mut words = [0u64; 8];
+        let bottom = VirtAddr::from_ptr(words.as_ptr());
+        let top = bottom + 8 * 8u64;
+        unsafe {
+            paint(bottom, top);
+            assert_eq!(high_water_mark(bottom, top), 0);
+            words[5] = 0;
+            assert_eq!(high_water_mark(bottom, top), 3 * 8);
+            words[0] = 0;
+            assert_eq!(high_water_mark(bottom, top), 8 * 8);
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_init_stacks() {
+        serial_print!("{} test_init_stacks... ", TEST_PREFIX);
+        let invalid = StackSizes {
+            page_fault: 0x1234,
+            ..StackSizes::DEFAULT
+        };
+        assert!(matches!(
+            init_stacks(&invalid, &mut GlobalFrameAllocator),
+            Err(StackError::InvalidSize {
+                index: PAGE_FAULT_IST_INDEX,
+                size: 0x1234,
+            })
+        ));
+        // `crate::init` has already replaced the stacks.
+        assert!(matches!(
+            init_stacks(&StackSizes::DEFAULT, &mut GlobalFrameAllocator),
+            Err(StackError::AlreadyInitialized)
+        ));
+        let usage = stack_usage();
+        assert_eq!(usage[1].size, StackSizes::DEFAULT.double_fault as u64);
+        assert_eq!(
+            usage[2].size,
+            StackSizes::DEFAULT.stack_segment_fault as u64
+        );
+        assert_eq!(usage[3].size, StackSizes::DEFAULT.page_fault as u64);
+        assert!(usage[1..]
+            .iter()
+            .all(|usage| usage.high_water_mark < usage.size));
+        let page_table = unsafe { memory::active_page_table() };
+        for stack in stacks().skip(1) {
+            assert!(stack.guard.as_u64() >= IST_STACK_REGION);
+            assert_eq!(page_table.translate_addr(stack.guard), None);
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_map_stacks() {
+        serial_print!("{} test_map_stacks... ", TEST_PREFIX);
+        // A region past the one `crate::init` put the stacks in.
+        let region = IST_STACK_REGION + STACK_COUNT as u64 * IST_STACK_WINDOW;
+        let sizes = StackSizes {
+            double_fault: 0x2000,
+            stack_segment_fault: 0x1000,
+            page_fault: 0x3000,
+        };
+        let mut allocator = LimitedFrames::new(usize::MAX);
+        let stacks = map_stacks(&sizes, region, &mut allocator).expect("Failed to map the stacks");
+        let mut page_table = unsafe { memory::active_page_table() };
+        for index in ist_indices() {
+            let stack = stacks[index as usize + 1].unwrap();
+            assert_eq!(stack.size(), sizes.get(index) as u64);
+            assert_eq!(
+                stack.guard.as_u64(),
+                region + index as u64 * IST_STACK_WINDOW
+            );
+            assert_eq!(unsafe { high_water_mark(stack.bottom(), stack.top) }, 0);
+            assert_eq!(page_table.translate_addr(stack.guard), None);
+            assert!(page_table.translate_addr(stack.bottom()).is_some());
+            assert!(page_table.translate_addr(stack.top - 1u64).is_some());
+        }
+        for stack in stacks.iter().flatten() {
+            unmap_stack(&mut page_table, stack, &mut allocator);
+            assert_eq!(page_table.translate_addr(stack.bottom()), None);
+        }
+        assert_eq!(allocator.deallocated, 6);
+
+        // The page tables for the region exist now, so every frame allocated below backs a page
+        // of a stack. The stacks need 6 of them.
+        let mut allocator = LimitedFrames::new(4);
+        assert!(matches!(
+            map_stacks(&sizes, region, &mut allocator),
+            Err(StackError::OutOfFrames)
+        ));
+        assert_eq!(allocator.allocated, 4);
+        assert_eq!(allocator.deallocated, 4);
+        for index in ist_indices() {
+            let bottom = VirtAddr::new(region + index as u64 * IST_STACK_WINDOW) + GUARD_PAGE_SIZE;
+            assert_eq!(page_table.translate_addr(bottom), None);
+        }
+        serial_println!("[ok]");
+    }
 }
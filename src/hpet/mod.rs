@@ -0,0 +1,438 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Mutex;
+
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+use crate::{
+    acpi::{Acpi, AcpiError, Polarity, TriggerMode},
+    apic::{self, ApicError},
+    memory::paging::{self, PagingError},
+};
+
+/// The size of the register block of an HPET with the maximum of 32 timers.
+const REGISTERS_SIZE: u64 = 0x400;
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+/// The offset of the registers of the first timer. Each timer has 0x20 bytes of registers.
+const TIMERS: usize = 0x100;
+const TIMER_CONFIGURATION: usize = 0x00;
+const TIMER_COMPARATOR: usize = 0x08;
+const TIMER_FSB_ROUTE: usize = 0x10;
+
+/// The general capabilities bit which is set if the main counter is 64 bits wide.
+const COUNTER_64_BIT: u64 = 1 << 13;
+
+/// The general configuration bit which starts the main counter and allows timer interrupts.
+const ENABLE: u64 = 1 << 0;
+/// The general configuration bit which routes timers 0 and 1 to IRQs 0 and 8 instead.
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+/// The timer configuration bit which makes the interrupt level triggered.
+const LEVEL_TRIGGERED: u64 = 1 << 1;
+/// The timer configuration bit which enables the interrupt.
+const INTERRUPT_ENABLE: u64 = 1 << 2;
+/// The timer configuration bit which makes the timer periodic.
+const PERIODIC: u64 = 1 << 3;
+/// The timer capability bit which is set if the timer can be periodic.
+const PERIODIC_CAPABLE: u64 = 1 << 4;
+/// The timer capability bit which is set if the comparator is 64 bits wide.
+const COMPARATOR_64_BIT: u64 = 1 << 5;
+/// The timer configuration bit which lets the next write set the comparator of a periodic timer.
+const SET_VALUE: u64 = 1 << 6;
+/// The shift of the timer configuration bits which select the I/O APIC input.
+const ROUTE_SHIFT: u64 = 9;
+/// The timer configuration bits which select the I/O APIC input.
+const ROUTE_MASK: u64 = 0x1F << ROUTE_SHIFT;
+/// The timer configuration bit which delivers the interrupt as a message on the front side bus.
+const FSB_ENABLE: u64 = 1 << 14;
+/// The timer capability bit which is set if the timer can deliver its interrupt on the front side
+/// bus.
+const FSB_CAPABLE: u64 = 1 << 15;
+
+/// The number of femtoseconds in a second.
+const FEMTOSECONDS: u128 = 1_000_000_000_000_000;
+
+/// The reason the HPET could not be set up or programmed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HpetError {
+    /// The HPET table could not be read.
+    Acpi(AcpiError),
+    /// The registers could not be mapped.
+    Map(PagingError),
+    /// The HPET has not been set up.
+    NotInitialized,
+    /// The HPET doesn't have the timer.
+    NoSuchTimer(u8),
+    /// The timer can't be periodic.
+    PeriodicUnsupported(u8),
+    /// The timer can't deliver its interrupt on the front side bus.
+    FsbUnsupported(u8),
+    /// The timer can't be routed to the I/O APIC input.
+    GsiUnsupported {
+        /// The timer.
+        timer: u8,
+        /// The I/O APIC input.
+        gsi: u32,
+    },
+    /// The interrupt could not be routed through the APICs.
+    Apic(ApicError),
+}
+
+impl From<AcpiError> for HpetError {
+    fn from(e: AcpiError) -> Self {
+        Self::Acpi(e)
+    }
+}
+
+impl From<PagingError> for HpetError {
+    fn from(e: PagingError) -> Self {
+        Self::Map(e)
+    }
+}
+
+impl From<ApicError> for HpetError {
+    fn from(e: ApicError) -> Self {
+        Self::Apic(e)
+    }
+}
+
+/// Whether a timer raises a single interrupt or one every interval.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimerMode {
+    /// Raise a single interrupt once the interval has passed.
+    OneShot,
+    /// Raise an interrupt every time the interval passes.
+    Periodic,
+}
+
+/// How the interrupt of a timer reaches the processor. Both routes need the APICs to be active.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// Through an input of the I/O APIC, as an edge triggered interrupt.
+    IoApic {
+        /// The Global System Interrupt the timer is connected to.
+        gsi: u32,
+    },
+    /// As a message written directly to the local APIC over the front side bus.
+    Fsb,
+}
+
+/// What a timer of the HPET can do.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimerCapabilities {
+    /// Whether the timer can be periodic.
+    pub periodic: bool,
+    /// Whether the comparator is 64 bits wide.
+    pub comparator_64_bit: bool,
+    /// Whether the timer can deliver its interrupt on the front side bus.
+    pub fsb: bool,
+    /// The I/O APIC inputs that the timer can be routed to, as a bit for each of the first 32
+    /// Global System Interrupts.
+    pub gsis: u32,
+}
+
+impl TimerCapabilities {
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            periodic: bits & PERIODIC_CAPABLE != 0,
+            comparator_64_bit: bits & COMPARATOR_64_BIT != 0,
+            fsb: bits & FSB_CAPABLE != 0,
+            gsis: (bits >> 32) as u32,
+        }
+    }
+
+    /// Whether the timer can be routed to the I/O APIC input `gsi`.
+    pub fn can_route_to(&self, gsi: u32) -> bool {
+        gsi < 32 && self.gsis & (1 << gsi) != 0
+    }
+}
+
+/// A High Precision Event Timer: a main counter and up to 32 timers which raise an interrupt when
+/// the counter reaches their comparator.
+pub struct Hpet {
+    base: VirtAddr,
+}
+
+impl Hpet {
+    /// Get a handle to the HPET whose registers are mapped at `base`.
+    ///
+    /// # Safety
+    /// The registers of the HPET must be mapped at `base` and there must not be any other handle
+    /// to the HPET.
+    pub unsafe fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    fn read(&self, register: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + register).as_ptr()) }
+    }
+
+    fn write(&mut self, register: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + register).as_mut_ptr(), value) }
+    }
+
+    fn timer_register(&self, timer: u8, register: usize) -> Result<usize, HpetError> {
+        if timer < self.timers() {
+            Ok(TIMERS + timer as usize * 0x20 + register)
+        } else {
+            Err(HpetError::NoSuchTimer(timer))
+        }
+    }
+
+    /// Start the main counter in the standard routing mode and disable every timer.
+    pub fn enable(&mut self) {
+        for timer in 0..self.timers() {
+            // Every timer exists, so this can't fail.
+            let _ = self.stop_timer(timer);
+        }
+        let configuration = self.read(CONFIGURATION) & !LEGACY_REPLACEMENT;
+        self.write(CONFIGURATION, configuration | ENABLE);
+    }
+
+    /// The number of femtoseconds between increments of the main counter.
+    pub fn period(&self) -> u32 {
+        (self.read(CAPABILITIES) >> 32) as u32
+    }
+
+    /// The number of times the main counter is incremented each second.
+    pub fn frequency(&self) -> u64 {
+        (FEMTOSECONDS / self.period() as u128) as u64
+    }
+
+    /// Whether the main counter is 64 bits wide. A 32 bit counter wraps around every few minutes
+    /// at most.
+    pub fn is_64_bit(&self) -> bool {
+        self.read(CAPABILITIES) & COUNTER_64_BIT != 0
+    }
+
+    /// The number of timers.
+    pub fn timers(&self) -> u8 {
+        ((self.read(CAPABILITIES) >> 8) & 0x1F) as u8 + 1
+    }
+
+    /// The current value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// The number of increments of the main counter which last `duration`, rounded up.
+    pub fn counts(&self, duration: Duration) -> u64 {
+        let femtoseconds = duration.as_nanos() * 1_000_000;
+        let period = self.period() as u128;
+        ((femtoseconds + period - 1) / period) as u64
+    }
+
+    /// What `timer` can do.
+    pub fn capabilities(&self, timer: u8) -> Result<TimerCapabilities, HpetError> {
+        let register = self.timer_register(timer, TIMER_CONFIGURATION)?;
+        Ok(TimerCapabilities::from_bits(self.read(register)))
+    }
+
+    /// Make `timer` raise an interrupt on `vector` after `interval`, and again every `interval`
+    /// if `mode` is periodic. The APICs must be active.
+    pub fn start_timer(
+        &mut self,
+        timer: u8,
+        mode: TimerMode,
+        interval: Duration,
+        vector: u8,
+        route: Route,
+    ) -> Result<(), HpetError> {
+        let capabilities = self.capabilities(timer)?;
+        let configuration_register = self.timer_register(timer, TIMER_CONFIGURATION)?;
+        let comparator_register = self.timer_register(timer, TIMER_COMPARATOR)?;
+        let mut configuration = self.read(configuration_register)
+            & !(LEVEL_TRIGGERED | INTERRUPT_ENABLE | PERIODIC | ROUTE_MASK | FSB_ENABLE);
+        if mode == TimerMode::Periodic {
+            if !capabilities.periodic {
+                return Err(HpetError::PeriodicUnsupported(timer));
+            }
+            configuration |= PERIODIC | SET_VALUE;
+        }
+        match route {
+            Route::IoApic { gsi } => {
+                if !capabilities.can_route_to(gsi) {
+                    return Err(HpetError::GsiUnsupported { timer, gsi });
+                }
+                without_interrupts(|| {
+                    apic::route_gsi(gsi, vector, Polarity::ActiveHigh, TriggerMode::Edge)
+                })?;
+                configuration |= (gsi as u64) << ROUTE_SHIFT;
+            }
+            Route::Fsb => {
+                if !capabilities.fsb {
+                    return Err(HpetError::FsbUnsupported(timer));
+                }
+                let (address, data) = without_interrupts(|| apic::msi_message(vector))?;
+                let fsb_register = self.timer_register(timer, TIMER_FSB_ROUTE)?;
+                self.write(fsb_register, (address as u64) << 32 | data as u64);
+                configuration |= FSB_ENABLE;
+            }
+        }
+        let counts = self.counts(interval).max(1);
+        self.write(configuration_register, configuration);
+        // With `SET_VALUE`, the first write to the comparator of a periodic timer sets the time of
+        // the first interrupt and the second sets the interval.
+        self.write(comparator_register, self.counter().wrapping_add(counts));
+        if mode == TimerMode::Periodic {
+            self.write(comparator_register, counts);
+        }
+        self.write(configuration_register, configuration | INTERRUPT_ENABLE);
+        Ok(())
+    }
+
+    /// Stop `timer` from raising interrupts.
+    pub fn stop_timer(&mut self, timer: u8) -> Result<(), HpetError> {
+        let register = self.timer_register(timer, TIMER_CONFIGURATION)?;
+        let configuration = self.read(register);
+        self.write(register, configuration & !(INTERRUPT_ENABLE | PERIODIC));
+        Ok(())
+    }
+}
+
+static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+
+/// The virtual address of the main counter, or 0 if there is no usable 64 bit main counter.
+static MAIN_COUNTER_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+/// The frequency of the main counter, or 0 if there is no usable 64 bit main counter.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Find the HPET described by the ACPI tables, map its registers and start its main counter. Does
+/// nothing if the HPET is already set up, so its registers are only mapped once.
+///
+/// # Safety
+/// The HPET table must describe the registers of a real HPET.
+pub unsafe fn init(acpi: &Acpi) -> Result<(), HpetError> {
+    if without_interrupts(|| HPET.lock().is_some()) {
+        return Ok(());
+    }
+    let info = acpi.hpet()?;
+    let base = paging::map_mmio(info.address, REGISTERS_SIZE)?;
+    let mut hpet = Hpet::new(base);
+    hpet.enable();
+    let (address, frequency) = if hpet.is_64_bit() {
+        ((base + MAIN_COUNTER).as_u64(), hpet.frequency())
+    } else {
+        (0, 0)
+    };
+    without_interrupts(|| {
+        *HPET.lock() = Some(hpet);
+        MAIN_COUNTER_ADDRESS.store(address, Ordering::SeqCst);
+        FREQUENCY.store(frequency, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// The current value of the main counter, if the HPET has been set up and its main counter is 64
+/// bits wide. This doesn't lock the HPET, so it can be used as a clocksource.
+pub fn counter() -> Option<u64> {
+    match MAIN_COUNTER_ADDRESS.load(Ordering::SeqCst) {
+        0 => None,
+        address => Some(unsafe { ptr::read_volatile(address as *const u64) }),
+    }
+}
+
+/// The frequency of the main counter, if [`counter`] returns it.
+///
+/// [`counter`]: fn.counter.html
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::SeqCst) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Run `f` with the HPET with interrupts disabled, if it has been set up.
+pub fn with_hpet<F, T>(f: F) -> Result<T, HpetError>
+where
+    F: FnOnce(&mut Hpet) -> T,
+{
+    without_interrupts(|| HPET.lock().as_mut().map(f).ok_or(HpetError::NotInitialized))
+}
+
+/// Make `timer` raise an interrupt on `vector` after `interval`, and again every `interval` if
+/// `mode` is periodic. The APICs must be active.
+pub fn start_timer(
+    timer: u8,
+    mode: TimerMode,
+    interval: Duration,
+    vector: u8,
+    route: Route,
+) -> Result<(), HpetError> {
+    with_hpet(|hpet| hpet.start_timer(timer, mode, interval, vector, route))?
+}
+
+/// Stop `timer` from raising interrupts.
+pub fn stop_timer(timer: u8) -> Result<(), HpetError> {
+    with_hpet(|hpet| hpet.stop_timer(timer))?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::memory;
+
+    const TEST_PREFIX: &'static str = "[rust_os::hpet]";
+
+    #[test_case]
+    fn test_capabilities() {
+        serial_print!("{} test_capabilities... ", TEST_PREFIX);
+        let capabilities = TimerCapabilities::from_bits(0x00F0_0000_0000_8030);
+        assert!(capabilities.periodic);
+        assert!(capabilities.comparator_64_bit);
+        assert!(capabilities.fsb);
+        assert!(!capabilities.can_route_to(19));
+        assert!(capabilities.can_route_to(20));
+        assert!(capabilities.can_route_to(23));
+        assert!(!capabilities.can_route_to(24));
+        assert!(!capabilities.can_route_to(40));
+        serial_println!("[ok]");
+    }
+
+    fn check_main_counter(hpet: &mut Hpet) {
+        // QEMU's HPET has a 64 bit counter which is incremented every 10 ns.
+        assert!(hpet.is_64_bit());
+        assert_eq!(hpet.frequency(), 100_000_000);
+        assert_eq!(hpet.period(), 10_000_000);
+        assert_eq!(hpet.counts(Duration::from_micros(1)), 100);
+        let first = hpet.counter();
+        while hpet.counter() == first {}
+        assert!(hpet.timers() >= 3);
+        assert_eq!(
+            hpet.start_timer(
+                32,
+                TimerMode::OneShot,
+                Duration::from_millis(1),
+                0x60,
+                Route::Fsb
+            ),
+            Err(HpetError::NoSuchTimer(32))
+        );
+    }
+
+    #[test_case]
+    fn test_main_counter() {
+        serial_print!("{} test_main_counter... ", TEST_PREFIX);
+        if with_hpet(check_main_counter).is_err() {
+            // Use a handle of its own rather than `init`, which would make the HPET the reference
+            // clocksource for the tests which follow.
+            let acpi = unsafe { Acpi::new(memory::physical_memory_offset()) }.unwrap();
+            let info = acpi.hpet().unwrap();
+            let base = unsafe { paging::map_mmio(info.address, REGISTERS_SIZE) }.unwrap();
+            let mut hpet = unsafe { Hpet::new(base) };
+            hpet.enable();
+            check_main_counter(&mut hpet);
+        }
+        serial_println!("[ok]");
+    }
+}
@@ -2,6 +2,8 @@ use core::fmt::{Arguments, Write};
 
 use spin::MutexGuard;
 
+use x86_64::instructions::interrupts::without_interrupts;
+
 /// Various tools for writing to the serial port.
 #[macro_use]
 pub mod serial;
@@ -61,12 +63,17 @@ pub fn print_to(out: &mut dyn Write, args: Arguments, name: &str) {
         .unwrap_or_else(|_| panic!("Failed to write to {}: {}", name, args));
 }
 
+/// Write a formatted string to stdout. Interrupts are disabled while stdout is locked so that an
+/// interrupt handler which prints can't deadlock.
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    without_interrupts(|| print_to(&mut *stdout(), args, "stdout"));
+}
+
 /// Write a formatted string to stdout.
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => {
-        $crate::io::print_to(&mut *$crate::io::stdout(), format_args!($($arg)*), "stdout")
-    };
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
 }
 
 /// Write a formatted string to stdout and terminate with a newline.
@@ -0,0 +1,217 @@
+use core::str;
+
+use spin::Mutex;
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    cpu_exception::interrupts::{register_irq, set_irq_masked, RegisterError},
+    pic::Irq,
+};
+
+/// The maximum number of commands that can be registered.
+pub const MAX_COMMANDS: usize = 16;
+
+/// The longest line that is kept. The rest of a longer line is dropped.
+pub const MAX_LINE_LENGTH: usize = 32;
+
+/// The data register of the first serial port.
+const DATA_PORT: u16 = 0x3F8;
+/// The interrupt enable register of the first serial port.
+const INTERRUPT_ENABLE_PORT: u16 = 0x3F9;
+/// The line status register of the first serial port.
+const LINE_STATUS_PORT: u16 = 0x3FD;
+
+/// The interrupt enable bit which raises an interrupt when a byte is received.
+const DATA_AVAILABLE_INTERRUPT: u8 = 1 << 0;
+/// The line status bit which is set while a received byte is waiting to be read.
+const DATA_READY: u8 = 1 << 0;
+
+/// The reason a command could not be registered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandError {
+    /// A command with the name is already registered.
+    AlreadyRegistered(&'static str),
+    /// [`MAX_COMMANDS`] commands are already registered.
+    ///
+    /// [`MAX_COMMANDS`]: constant.MAX_COMMANDS.html
+    TooManyCommands,
+}
+
+#[derive(Clone, Copy)]
+struct Command {
+    name: &'static str,
+    run: fn(),
+}
+
+/// A line of input which hasn't been ended yet.
+struct Line {
+    bytes: [u8; MAX_LINE_LENGTH],
+    length: usize,
+}
+
+impl Line {
+    /// Add `byte` to the line. If it ends the line, return the line and start a new one.
+    fn push(&mut self, byte: u8) -> Option<([u8; MAX_LINE_LENGTH], usize)> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = (self.bytes, self.length);
+                self.length = 0;
+                Some(line)
+            }
+            byte => {
+                if self.length < MAX_LINE_LENGTH {
+                    self.bytes[self.length] = byte;
+                    self.length += 1;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// The registered commands. The serial interrupt looks them up, so interrupts must be disabled
+/// while it is locked.
+static COMMANDS: Mutex<[Option<Command>; MAX_COMMANDS]> = Mutex::new([None; MAX_COMMANDS]);
+
+/// The line being typed. Interrupts must be disabled while it is locked.
+static LINE: Mutex<Line> = Mutex::new(Line {
+    bytes: [0; MAX_LINE_LENGTH],
+    length: 0,
+});
+
+/// Run `run` whenever a line consisting of `name` is typed into the first serial port. Commands
+/// run in the interrupt handler of the port, so they must not allocate or take locks without
+/// disabling interrupts.
+pub fn register(name: &'static str, run: fn()) -> Result<(), CommandError> {
+    without_interrupts(|| {
+        let mut commands = COMMANDS.lock();
+        if commands
+            .iter()
+            .flatten()
+            .any(|command| command.name == name)
+        {
+            return Err(CommandError::AlreadyRegistered(name));
+        }
+        let slot = commands
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(CommandError::TooManyCommands)?;
+        *slot = Some(Command { name, run });
+        Ok(())
+    })
+}
+
+/// Run the command named by `line`, ignoring surrounding whitespace. Returns whether there was
+/// one.
+fn run(line: &[u8]) -> bool {
+    let name = match str::from_utf8(line) {
+        Ok(name) => name.trim(),
+        Err(_) => return false,
+    };
+    if name.is_empty() {
+        return false;
+    }
+    let command = without_interrupts(|| {
+        COMMANDS
+            .lock()
+            .iter()
+            .flatten()
+            .copied()
+            .find(|command| command.name == name)
+    });
+    match command {
+        Some(command) => {
+            (command.run)();
+            true
+        }
+        None => {
+            serial_println!("Unknown command: {}", name);
+            false
+        }
+    }
+}
+
+/// Handle a byte received by the first serial port.
+fn receive(byte: u8) {
+    if let Some((bytes, length)) = without_interrupts(|| LINE.lock().push(byte)) {
+        run(&bytes[..length]);
+    }
+}
+
+fn serial_interrupt(_: u8) -> bool {
+    let mut line_status = Port::<u8>::new(LINE_STATUS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    let mut received = false;
+    unsafe {
+        while line_status.read() & DATA_READY != 0 {
+            receive(data.read());
+            received = true;
+        }
+    }
+    received
+}
+
+/// Start running the commands typed into the first serial port.
+pub fn init() {
+    lazy_static::initialize(&super::SERIAL1);
+    match register_irq(Irq::Com1.vector(), serial_interrupt) {
+        Ok(()) | Err(RegisterError::AlreadyRegistered(_)) => {}
+        Err(e) => panic!("Failed to register the serial handler: {:?}", e),
+    }
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(INTERRUPT_ENABLE_PORT).write(DATA_AVAILABLE_INTERRUPT);
+    });
+    set_irq_masked(Irq::Com1, false);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const TEST_PREFIX: &'static str = "[rust_os::io::serial::command]";
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_run() {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn test_commands() {
+        serial_print!("{} test_commands... ", TEST_PREFIX);
+        assert_eq!(register("test count", count_run), Ok(()));
+        assert_eq!(
+            register("test count", count_run),
+            Err(CommandError::AlreadyRegistered("test count"))
+        );
+        let runs = RUNS.load(Ordering::SeqCst);
+        for &byte in b" test count \r\n" {
+            receive(byte);
+        }
+        assert_eq!(RUNS.load(Ordering::SeqCst), runs + 1);
+        assert!(run(b"test count"));
+        assert!(!run(b"  "));
+        assert_eq!(RUNS.load(Ordering::SeqCst), runs + 2);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_long_line() {
+        serial_print!("{} test_long_line... ", TEST_PREFIX);
+        let mut line = Line {
+            bytes: [0; MAX_LINE_LENGTH],
+            length: 0,
+        };
+        for _ in 0..MAX_LINE_LENGTH + 8 {
+            assert_eq!(line.push(b'x'), None);
+        }
+        let (bytes, length) = line.push(b'\n').unwrap();
+        assert_eq!(length, MAX_LINE_LENGTH);
+        assert!(bytes.iter().all(|&byte| byte == b'x'));
+        assert_eq!(line.push(b'\n').map(|(_, length)| length), Some(0));
+        serial_println!("[ok]");
+    }
+}
@@ -4,6 +4,11 @@ use spin::Mutex;
 
 use uart_16550::SerialPort;
 
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Commands which can be typed into the first serial port.
+pub mod command;
+
 lazy_static! {
     /// A reference to the serial port at address `0x03F8`.
     pub static ref SERIAL1: Mutex<SerialPort> = {
@@ -11,11 +16,18 @@ lazy_static! {
         serial_port.init();
         Mutex::new(serial_port)
     };
+
+    /// A reference to the serial port at address `0x02F8`. It is reserved for the GDB stub.
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
 }
 
 #[doc(hidden)]
 pub fn _print(args: Arguments) {
-    super::print_to(&mut *SERIAL1.lock(), args, "SERIAL1");
+    without_interrupts(|| super::print_to(&mut *SERIAL1.lock(), args, "SERIAL1"));
 }
 
 /// Write a formatted string to the first serial port.
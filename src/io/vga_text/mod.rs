@@ -7,6 +7,8 @@ use spin::Mutex;
 
 use volatile::Volatile;
 
+use x86_64::instructions::interrupts::without_interrupts;
+
 /// The base for two colors that can be used in CGA text mode.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
@@ -384,7 +386,7 @@ impl Write for Writer {
 
 #[doc(hidden)]
 pub fn _print(args: Arguments) {
-    super::print_to(&mut *WRITER.lock(), args, "VGA port");
+    without_interrupts(|| super::print_to(&mut *WRITER.lock(), args, "VGA port"));
 }
 
 /// Print a formatted string to the VGA text buffer with the current color.
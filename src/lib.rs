@@ -5,10 +5,20 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(asm)]
+#![feature(const_fn)]
 #![feature(custom_test_frameworks)]
+#![feature(global_asm)]
 
 use core::panic::PanicInfo;
 
+use bootloader::BootInfo;
+#[cfg(test)]
+use bootloader::entry_point;
+
+extern crate alloc;
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -17,17 +27,49 @@ extern crate lazy_static;
 pub mod io;
 use io::vga_text::{BackgroundColor, TextColor, Writer};
 
+/// Tools for reading the ACPI tables provided by the firmware.
+pub mod acpi;
+
+/// Tools for walking the stack and resolving addresses to kernel functions.
+pub mod backtrace;
+use backtrace::Backtrace;
+
+/// Tools for handling the local APIC and I/O APICs.
+pub mod apic;
+
 /// Tools for handling CPU exceptions.
 pub mod cpu_exception;
 use cpu_exception::interrupts;
 
+/// A GDB Remote Serial Protocol stub on the second serial port.
+pub mod gdb;
+
 /// Tools for handling the Global Descriptor Table.
 pub mod gdt;
 
+/// Tools for programming the High Precision Event Timer. It can be a clocksource and raise
+/// interrupts of its own, but it doesn't replace the PIT as the tick of the kernel.
+pub mod hpet;
+
+/// Tools for managing physical and virtual memory.
+pub mod memory;
+
+/// Tools for handling the chained 8259 Programmable Interrupt Controllers.
+pub mod pic;
+
+/// Tools for programming the 8253/8254 Programmable Interval Timer.
+pub mod pit;
+
 /// QEMU-specific functionality.
 pub mod qemu;
 use qemu::QemuExitCode;
 
+/// Tools for reading and setting the wall-clock time kept by the CMOS real-time clock.
+pub mod rtc;
+
+/// Tools for measuring the time since boot and waiting for time to pass.
+pub mod time;
+
 /// Draws the available pairs of background and text colors.
 pub fn draw_vga_test() {
     let old_color = io::vga_text::WRITER.lock().color();
@@ -41,17 +83,47 @@ pub fn draw_vga_test() {
     set_vga_color!(old_color);
 }
 
-/// Initialize various parts of the OS.
-pub fn init() {
+/// Initialize various parts of the OS from the information the bootloader passed to the kernel.
+pub fn init(boot_info: &'static BootInfo) {
+    memory::init(boot_info);
+    memory::heap::init().expect("Failed to map the kernel heap");
     gdt::init();
+    gdt::init_stacks(
+        &gdt::StackSizes::DEFAULT,
+        &mut memory::frame::GlobalFrameAllocator,
+    )
+    .expect("Failed to allocate the interrupt stacks");
     interrupts::init_idt();
+    interrupts::enable();
+    time::init(time::DEFAULT_FREQUENCY).expect("Failed to program the PIT");
+    rtc::init();
+    io::serial::command::init();
+    match io::serial::command::register("slabs", memory::slab::dump) {
+        Ok(()) | Err(io::serial::command::CommandError::AlreadyRegistered(_)) => {}
+        Err(e) => panic!("Failed to register the slabs command: {:?}", e),
+    }
 }
 
 /// The function to run the tests.
 pub fn test_runner(tests: &[&dyn Fn()]) {
     serial_println!("Running {} tests", tests.len());
-    tests.iter().for_each(|test| test());
-    serial_println!("All tests succeeded");
+    let start = time::Instant::now();
+    for test in tests {
+        #[cfg(feature = "debug-allocator")]
+        let mark = memory::heap::allocation_mark();
+        let test_start = time::Instant::now();
+        test();
+        serial_println!("    took {:?}", test_start.elapsed());
+        #[cfg(feature = "debug-allocator")]
+        {
+            let leaks = memory::heap::report_leaks(mark);
+            assert_eq!(leaks, 0, "The test leaked {} allocations", leaks);
+        }
+    }
+    serial_println!("All tests succeeded in {:?}", start.elapsed());
+    if memory::slab::caches().next().is_some() {
+        memory::slab::dump();
+    }
 
     qemu::exit_qemu(QemuExitCode::Success);
 }
@@ -60,6 +132,7 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
 pub fn test_panic(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", Backtrace::current());
 
     qemu::exit_qemu(QemuExitCode::Failure)
 }
@@ -68,6 +141,7 @@ pub fn test_panic(info: &PanicInfo) -> ! {
 pub fn no_test_panic(info: &PanicInfo) -> ! {
     set_stdout_color!(Writer::DEFAULT_COLOR_PAIR);
     println!("{}\n", info);
+    println!("{}", Backtrace::current());
 
     qemu::exit_qemu(QemuExitCode::Failure)
 }
@@ -78,11 +152,13 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     test_panic(info)
 }
 
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
 /// Entry point for `cargo xtest` for the library.
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     loop {}
 }
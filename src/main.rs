@@ -9,12 +9,19 @@
 #![test_runner(rust_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use core::panic::PanicInfo;
+use core::{panic::PanicInfo, time::Duration};
+
+use bootloader::{entry_point, BootInfo};
 
 #[macro_use]
 extern crate rust_os;
 
-use rust_os::qemu::{self, QemuExitCode};
+use rust_os::{
+    acpi::Acpi,
+    cpu_exception::interrupts,
+    hpet, memory, rtc,
+    time::{self, timer::Timer},
+};
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -25,10 +32,32 @@ fn panic(info: &PanicInfo) -> ! {
     }
 }
 
+entry_point!(kernel_main);
+
 /// The entry point for the binary.
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    rust_os::init();
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
+    println!("{}", memory::memory_map());
+    println!("{}", memory::frame::stats());
+    let acpi = unsafe { Acpi::new(memory::physical_memory_offset()) };
+    println!("Booted at {}", rtc::now());
+    let apic = acpi
+        .map_err(Into::into)
+        .and_then(|acpi| unsafe { interrupts::enable_apic(&acpi) });
+    if let Err(e) = apic {
+        println!("Using the 8259 PICs: {:?}", e);
+    }
+    let hpet = acpi
+        .map_err(Into::into)
+        .and_then(|acpi| unsafe { hpet::init(&acpi) });
+    let clocksource = match hpet {
+        Ok(()) => time::clocksource::init(),
+        Err(e) => {
+            println!("No HPET: {:?}", e);
+            time::clocksource::current()
+        }
+    };
+    println!("Measuring time with the {}", clocksource);
     rust_os::draw_vga_test();
 
     #[cfg(test)]
@@ -36,7 +65,11 @@ pub extern "C" fn _start() -> ! {
 
     println!("It did not crash!");
 
-    // TODO: event loop
-
-    qemu::exit_qemu(QemuExitCode::Success)
+    Timer::periodic(Duration::from_secs(10), || {
+        println!("Up for {}s", time::uptime().as_secs())
+    });
+    loop {
+        time::timer::run_pending();
+        x86_64::instructions::hlt();
+    }
 }
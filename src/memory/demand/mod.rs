@@ -0,0 +1,334 @@
+use core::{
+    fmt::{self, Display, Formatter},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{Page, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use crate::memory::{
+    self,
+    frame::{self, Zone},
+    paging::{self, PagingError},
+};
+
+/// The maximum number of regions that can be reserved at once.
+pub const MAX_REGIONS: usize = 32;
+
+/// A range of virtual memory which is only backed by frames once it is used. Every page starts
+/// out unmapped, and the first access to it maps a zeroed frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LazyRegion {
+    /// The name of the region, for diagnostics.
+    pub name: &'static str,
+    /// The first address in the region.
+    pub start: VirtAddr,
+    /// The address one past the end of the region.
+    pub end: VirtAddr,
+    /// The flags that pages of the region are mapped with.
+    pub flags: PageTableFlags,
+}
+
+impl LazyRegion {
+    /// Whether `addr` is in the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+}
+
+/// The reason a region could not be reserved or released.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReserveError {
+    /// The start or size of the region is not a non-zero multiple of 4096.
+    Unaligned,
+    /// The region overlaps the reserved region with the given name.
+    Overlaps(&'static str),
+    /// A page in the range is already mapped.
+    AlreadyMapped(VirtAddr),
+    /// [`MAX_REGIONS`] regions are already reserved.
+    ///
+    /// [`MAX_REGIONS`]: constant.MAX_REGIONS.html
+    TooManyRegions,
+    /// No reserved region starts at the address.
+    NotReserved(VirtAddr),
+    /// A page of the region could not be unmapped.
+    Unmap(PagingError),
+}
+
+/// The reason a page fault could not be resolved by backing a reserved region.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DemandError {
+    /// The page is present, so the fault was caused by a protection violation.
+    ProtectionViolation,
+    /// The address is not in a reserved region.
+    NotReserved,
+    /// No frame was free to back the page.
+    OutOfFrames(&'static str),
+    /// The fault interrupted code which holds the frame allocator, so no frame could be taken
+    /// from it.
+    AllocatorLocked(&'static str),
+    /// The page could not be mapped.
+    Map(&'static str, PagingError),
+}
+
+impl Display for DemandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ProtectionViolation => write!(f, "the access is not allowed by the page"),
+            Self::NotReserved => write!(f, "the page is not mapped or reserved"),
+            Self::OutOfFrames(name) => write!(f, "no frame is free to back {}", name),
+            Self::AllocatorLocked(name) => write!(
+                f,
+                "the frame allocator was locked when {} was touched",
+                name
+            ),
+            Self::Map(name, e) => write!(f, "a page of {} could not be mapped: {:?}", name, e),
+        }
+    }
+}
+
+/// The reserved regions. Page faults look regions up, so interrupts must be disabled while it is
+/// locked.
+static REGIONS: Mutex<[Option<LazyRegion>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// The number of page faults resolved by mapping a frame.
+static RESOLVED_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Reserve the `size` bytes of virtual memory from `start` as a region named `name`, to be backed
+/// by zeroed frames mapped with `flags` as they are touched. No page in the range may be mapped.
+pub fn reserve(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<LazyRegion, ReserveError> {
+    if size == 0 || !start.is_aligned(4096u64) || size % 4096 != 0 {
+        return Err(ReserveError::Unaligned);
+    }
+    let region = LazyRegion {
+        name,
+        start,
+        end: start + size,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if let Some(other) = regions
+            .iter()
+            .flatten()
+            .find(|other| other.start < region.end && region.start < other.end)
+        {
+            return Err(ReserveError::Overlaps(other.name));
+        }
+        if let Some(page) = region
+            .pages()
+            .find(|page| paging::translate(page.start_address()).is_some())
+        {
+            return Err(ReserveError::AlreadyMapped(page.start_address()));
+        }
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ReserveError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(region)
+    })
+}
+
+/// Remove the reserved region which starts at `start`, unmapping every page of it that was
+/// backed and giving the frames back to the frame allocator.
+///
+/// # Safety
+/// Nothing may use the memory of the region afterwards.
+pub unsafe fn release(start: VirtAddr) -> Result<LazyRegion, ReserveError> {
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |region| region.start == start))
+            .ok_or(ReserveError::NotReserved(start))?;
+        let region = slot.take().unwrap();
+        for page in region.pages() {
+            match paging::unmap(page) {
+                Ok(frame) => frame::free(frame, 0),
+                Err(PagingError::PageNotMapped) => {}
+                Err(e) => return Err(ReserveError::Unmap(e)),
+            }
+        }
+        Ok(region)
+    })
+}
+
+/// The reserved region containing `addr`, if any.
+pub fn region_containing(addr: VirtAddr) -> Option<LazyRegion> {
+    without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter()
+            .flatten()
+            .copied()
+            .find(|region| region.contains(addr))
+    })
+}
+
+/// The number of page faults which have been resolved by backing a page of a reserved region.
+pub fn resolved_faults() -> usize {
+    RESOLVED_FAULTS.load(Ordering::SeqCst)
+}
+
+/// Try to resolve a page fault at `addr` by backing the page with a zeroed frame. This is called
+/// by the page fault handler with interrupts disabled.
+pub(crate) fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), DemandError> {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(DemandError::ProtectionViolation);
+    }
+    // The lock is only held with interrupts disabled, so if it is taken the fault happened
+    // while it was held and the region can't be looked up.
+    let region = REGIONS
+        .try_lock()
+        .and_then(|regions| {
+            regions
+                .iter()
+                .flatten()
+                .copied()
+                .find(|region| region.contains(addr))
+        })
+        .ok_or(DemandError::NotReserved)?;
+    // Likewise, the frame allocator may be held by the code which faulted. The frame and any page
+    // tables are taken from it under a single lock.
+    frame::try_with_allocator(|allocator| {
+        let frame = allocator
+            .allocate(0, Zone::Normal)
+            .ok_or(DemandError::OutOfFrames(region.name))?;
+        unsafe {
+            let zeroed = memory::physical_memory_offset() + frame.start_address().as_u64();
+            ptr::write_bytes(zeroed.as_mut_ptr::<u8>(), 0, 4096);
+            let page = Page::containing_address(addr);
+            if let Err(e) = paging::map_with(page, frame, region.flags, allocator) {
+                allocator.free(frame, 0);
+                return Err(DemandError::Map(region.name, e));
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(DemandError::AllocatorLocked(region.name)))?;
+    RESOLVED_FAULTS.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::demand]";
+
+    /// Virtual addresses which the tests reserve.
+    const TEST_REGION: u64 = 0xFFFF_FD80_4000_0000;
+
+    #[test_case]
+    fn test_reserve_errors() {
+        serial_print!("{} test_reserve_errors... ", TEST_PREFIX);
+        let start = VirtAddr::new(TEST_REGION);
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        assert_eq!(
+            reserve("test", start + 8u64, 4096, flags),
+            Err(ReserveError::Unaligned)
+        );
+        assert_eq!(
+            reserve("test", start, 0, flags),
+            Err(ReserveError::Unaligned)
+        );
+        let region = reserve("test", start, 0x4000, flags).unwrap();
+        assert_eq!(region.end, start + 0x4000u64);
+        assert_eq!(
+            reserve("other", start + 0x3000u64, 0x2000, flags),
+            Err(ReserveError::Overlaps("test"))
+        );
+        let mapped = VirtAddr::from_ptr(&TEST_PREFIX).align_down(4096u64);
+        assert_eq!(
+            reserve("mapped", mapped, 4096, flags),
+            Err(ReserveError::AlreadyMapped(mapped))
+        );
+        assert_eq!(region_containing(start + 0x3FFFu64), Some(region));
+        unsafe {
+            assert_eq!(release(start), Ok(region));
+            assert_eq!(release(start), Err(ReserveError::NotReserved(start)));
+        }
+        assert_eq!(region_containing(start), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_lazy_backing() {
+        serial_print!("{} test_lazy_backing... ", TEST_PREFIX);
+        let start = VirtAddr::new(TEST_REGION + 0x10_0000);
+        let size = 0x10_0000;
+        let free_frames = frame::stats().free_frames();
+        let faults = resolved_faults();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        reserve("test lazy", start, size, flags).unwrap();
+        assert_eq!(paging::translate(start), None);
+        let first = start.as_mut_ptr::<u64>();
+        let last = (start + size - 8u64).as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(first.read_volatile(), 0);
+            first.write_volatile(0x1234);
+            last.write_volatile(0x5678);
+            assert_eq!(first.read_volatile(), 0x1234);
+            assert_eq!(last.read_volatile(), 0x5678);
+        }
+        // Only the two touched pages are backed.
+        assert_eq!(resolved_faults(), faults + 2);
+        assert!(paging::translate(start + 0x1000u64).is_none());
+        let translation = paging::translate(start).unwrap();
+        assert!(translation.flags.contains(flags));
+        unsafe {
+            release(start).unwrap();
+        }
+        assert_eq!(paging::translate(start), None);
+        // The page tables created for the region are kept.
+        assert!(frame::stats().free_frames() <= free_frames);
+        assert!(frame::stats().free_frames() + 4 >= free_frames);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_allocator_locked() {
+        serial_print!("{} test_allocator_locked... ", TEST_PREFIX);
+        let start = VirtAddr::new(TEST_REGION + 0x20_0000);
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        reserve("test locked", start, 0x1000, flags).unwrap();
+        // A fault while the frame allocator is held is reported rather than deadlocking.
+        let result = frame::try_with_allocator(|_| {
+            handle_page_fault(start, PageFaultErrorCode::CAUSED_BY_WRITE)
+        });
+        assert_eq!(
+            result,
+            Some(Err(DemandError::AllocatorLocked("test locked")))
+        );
+        assert_eq!(paging::translate(start), None);
+        unsafe {
+            release(start).unwrap();
+        }
+        serial_println!("[ok]");
+    }
+}
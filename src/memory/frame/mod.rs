@@ -0,0 +1,657 @@
+use core::fmt::{self, Display, Formatter};
+
+use spin::Mutex;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory::{MemoryMap, RegionKind};
+
+/// The size of a physical frame in bytes.
+pub const FRAME_SIZE: u64 = 4096;
+
+/// The largest order of block the allocator hands out. A block of order `n` is `2^n` contiguous
+/// frames aligned to its own size, so the largest block is 4 MiB.
+pub const MAX_ORDER: usize = 10;
+
+/// The number of orders of block.
+pub const ORDERS: usize = MAX_ORDER + 1;
+
+/// The number of zones.
+pub const ZONE_COUNT: usize = 3;
+
+/// A range of physical memory that allocations can be restricted to, for devices which can't
+/// address all of physical memory.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Zone {
+    /// Memory below 16 MiB, which ISA DMA can reach.
+    Dma,
+    /// Memory from 16 MiB to 4 GiB, which devices with 32-bit addresses can reach.
+    Dma32,
+    /// Memory above 4 GiB.
+    Normal,
+}
+
+impl Zone {
+    /// Get an iterator over every zone, from the lowest addresses up.
+    pub fn zones() -> impl Iterator<Item = Self> {
+        [Self::Dma, Self::Dma32, Self::Normal].iter().copied()
+    }
+
+    /// The address one past the end of the zone.
+    pub fn end(self) -> u64 {
+        match self {
+            Self::Dma => 16 << 20,
+            Self::Dma32 => 4 << 30,
+            Self::Normal => u64::max_value(),
+        }
+    }
+
+    /// The zone which contains `addr`.
+    pub fn containing(addr: PhysAddr) -> Self {
+        Self::zones()
+            .find(|zone| addr.as_u64() < zone.end())
+            .unwrap_or(Self::Normal)
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Display for Zone {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Dma => "DMA",
+            Self::Dma32 => "DMA32",
+            Self::Normal => "normal",
+        };
+        f.pad(name)
+    }
+}
+
+/// The header written into the first frame of every free block to link it into the free list of
+/// its zone and order.
+#[repr(C)]
+struct FreeBlock {
+    next: Option<PhysAddr>,
+    previous: Option<PhysAddr>,
+    order: usize,
+}
+
+/// The free blocks of a single zone and order.
+#[derive(Clone, Copy, Debug, Default)]
+struct FreeList {
+    head: Option<PhysAddr>,
+    len: usize,
+}
+
+/// A buddy allocator over physical frames. Every free block is aligned to its size, and when a
+/// block is freed it is merged with its buddy, the other half of the block of the next order up,
+/// for as long as the buddy is also free.
+///
+/// The free lists are threaded through the free blocks themselves, which are reached through the
+/// mapping of all of physical memory. A bitmap with a bit for every frame records which frames
+/// start a free block, so that the allocator can tell whether a buddy is free without trusting
+/// the contents of memory that may be in use.
+pub struct BuddyAllocator<'a> {
+    free_heads: &'a mut [u64],
+    base_frame: u64,
+    physical_memory_offset: VirtAddr,
+    free_lists: [[FreeList; ORDERS]; ZONE_COUNT],
+    total_frames: [u64; ZONE_COUNT],
+}
+
+impl<'a> BuddyAllocator<'a> {
+    /// Create an allocator without any free frames which can manage the frames from `base` up,
+    /// one for each bit of `free_heads`.
+    ///
+    /// # Safety
+    /// All of physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn new(
+        free_heads: &'a mut [u64],
+        base: PhysAddr,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        for word in free_heads.iter_mut() {
+            *word = 0;
+        }
+        Self {
+            free_heads,
+            base_frame: base.as_u64() / FRAME_SIZE,
+            physical_memory_offset,
+            free_lists: [[FreeList::default(); ORDERS]; ZONE_COUNT],
+            total_frames: [0; ZONE_COUNT],
+        }
+    }
+
+    /// Hand the whole frames from `start` to `end` to the allocator.
+    ///
+    /// # Safety
+    /// The frames must be unused, must not already belong to the allocator and must be in the
+    /// range the allocator manages.
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut start = start.align_up(FRAME_SIZE).as_u64();
+        let end = end.align_down(FRAME_SIZE).as_u64();
+        while start < end {
+            // Free the largest block which starts at `start`, is aligned to its size and fits.
+            // Zones start at multiples of the largest block, so no block crosses into another
+            // zone.
+            let mut order = MAX_ORDER;
+            while start % (FRAME_SIZE << order) != 0 || start + (FRAME_SIZE << order) > end {
+                order -= 1;
+            }
+            let zone = Zone::containing(PhysAddr::new(start));
+            self.total_frames[zone.index()] += 1 << order;
+            self.free(PhysFrame::containing_address(PhysAddr::new(start)), order);
+            start += FRAME_SIZE << order;
+        }
+    }
+
+    /// Allocate `2^order` contiguous frames aligned to their size from `zone` or, if it has no
+    /// block large enough, from the zones below it.
+    pub fn allocate(&mut self, order: usize, zone: Zone) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        Zone::zones()
+            .filter(|other| *other <= zone)
+            .rev()
+            .find_map(|zone| self.allocate_from(zone, order))
+    }
+
+    fn allocate_from(&mut self, zone: Zone, order: usize) -> Option<PhysFrame> {
+        let mut current = (order..ORDERS).find(|&order| self.list(zone, order).head.is_some())?;
+        let block = self.list(zone, current).head.unwrap();
+        unsafe {
+            self.remove(block);
+        }
+        // Split the block in half until it is the right size, freeing the upper halves.
+        while current > order {
+            current -= 1;
+            unsafe {
+                self.push(block + (FRAME_SIZE << current), current);
+            }
+        }
+        Some(PhysFrame::containing_address(block))
+    }
+
+    /// Return the `2^order` frames starting at `frame` to the allocator and merge them with their
+    /// buddies.
+    ///
+    /// # Safety
+    /// The frames must have been allocated from this allocator with the same order and must no
+    /// longer be in use.
+    pub unsafe fn free(&mut self, frame: PhysFrame, order: usize) {
+        let mut block = frame.start_address();
+        let mut order = order;
+        assert!(order <= MAX_ORDER, "Block order {} is too large", order);
+        assert!(
+            block.is_aligned(FRAME_SIZE << order),
+            "Block at {:?} is not aligned to its order {}",
+            block,
+            order
+        );
+        assert!(!self.is_head(block), "Block at {:?} is already free", block);
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(block.as_u64() ^ (FRAME_SIZE << order));
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove(buddy);
+            block = PhysAddr::new(block.as_u64().min(buddy.as_u64()));
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    /// The current number of free and used frames in each zone.
+    pub fn stats(&self) -> FrameStats {
+        let zone_stats = |zone: Zone| {
+            let mut free_blocks = [0; ORDERS];
+            for (order, count) in free_blocks.iter_mut().enumerate() {
+                *count = self.list(zone, order).len;
+            }
+            let free_frames = free_blocks
+                .iter()
+                .enumerate()
+                .map(|(order, count)| (*count as u64) << order)
+                .sum();
+            ZoneStats {
+                zone,
+                total_frames: self.total_frames[zone.index()],
+                free_frames,
+                free_blocks,
+            }
+        };
+        FrameStats {
+            zones: [
+                zone_stats(Zone::Dma),
+                zone_stats(Zone::Dma32),
+                zone_stats(Zone::Normal),
+            ],
+        }
+    }
+
+    fn list(&self, zone: Zone, order: usize) -> &FreeList {
+        &self.free_lists[zone.index()][order]
+    }
+
+    fn list_mut(&mut self, zone: Zone, order: usize) -> &mut FreeList {
+        &mut self.free_lists[zone.index()][order]
+    }
+
+    /// The bit in `free_heads` for the frame at `addr`, if the allocator manages it.
+    fn bit(&self, addr: PhysAddr) -> Option<(usize, u64)> {
+        let index = (addr.as_u64() / FRAME_SIZE).checked_sub(self.base_frame)? as usize;
+        if index < self.free_heads.len() * 64 {
+            Some((index / 64, 1 << (index % 64)))
+        } else {
+            None
+        }
+    }
+
+    fn is_head(&self, addr: PhysAddr) -> bool {
+        self.bit(addr)
+            .map_or(false, |(word, bit)| self.free_heads[word] & bit != 0)
+    }
+
+    fn set_head(&mut self, addr: PhysAddr, free: bool) {
+        let (word, bit) = self
+            .bit(addr)
+            .unwrap_or_else(|| panic!("Frame at {:?} is not managed by the allocator", addr));
+        if free {
+            self.free_heads[word] |= bit;
+        } else {
+            self.free_heads[word] &= !bit;
+        }
+    }
+
+    fn header(&self, addr: PhysAddr) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr.as_u64()).as_mut_ptr()
+    }
+
+    /// Whether a free block of order `order` starts at `addr`.
+    fn is_free_block(&self, addr: PhysAddr, order: usize) -> bool {
+        self.is_head(addr) && unsafe { (*self.header(addr)).order == order }
+    }
+
+    /// Add the free block at `addr` to the front of its free list.
+    unsafe fn push(&mut self, addr: PhysAddr, order: usize) {
+        let zone = Zone::containing(addr);
+        let next = self.list(zone, order).head;
+        if let Some(next) = next {
+            (*self.header(next)).previous = Some(addr);
+        }
+        self.header(addr).write(FreeBlock {
+            next,
+            previous: None,
+            order,
+        });
+        let list = self.list_mut(zone, order);
+        list.head = Some(addr);
+        list.len += 1;
+        self.set_head(addr, true);
+    }
+
+    /// Take the free block at `addr` out of its free list.
+    unsafe fn remove(&mut self, addr: PhysAddr) {
+        let FreeBlock {
+            next,
+            previous,
+            order,
+        } = self.header(addr).read();
+        let zone = Zone::containing(addr);
+        match previous {
+            Some(previous) => (*self.header(previous)).next = next,
+            None => self.list_mut(zone, order).head = next,
+        }
+        if let Some(next) = next {
+            (*self.header(next)).previous = previous;
+        }
+        self.list_mut(zone, order).len -= 1;
+        self.set_head(addr, false);
+    }
+}
+
+/// The free and used frames of a single zone.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ZoneStats {
+    /// The zone.
+    pub zone: Zone,
+    /// The number of frames in the zone which the allocator manages.
+    pub total_frames: u64,
+    /// The number of frames in the zone which are free.
+    pub free_frames: u64,
+    /// The number of free blocks of each order.
+    pub free_blocks: [usize; ORDERS],
+}
+
+impl ZoneStats {
+    /// The number of frames in the zone which are allocated.
+    pub fn used_frames(&self) -> u64 {
+        self.total_frames - self.free_frames
+    }
+}
+
+impl Display for ZoneStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<6} {:>8} of {:>8} frames free, free blocks by order {:?}",
+            self.zone, self.free_frames, self.total_frames, self.free_blocks,
+        )
+    }
+}
+
+/// The free and used frames of every zone.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameStats {
+    /// The statistics of each zone, from the lowest addresses up.
+    pub zones: [ZoneStats; ZONE_COUNT],
+}
+
+impl FrameStats {
+    /// The number of frames the allocator manages.
+    pub fn total_frames(&self) -> u64 {
+        self.zones.iter().map(|zone| zone.total_frames).sum()
+    }
+
+    /// The number of free frames.
+    pub fn free_frames(&self) -> u64 {
+        self.zones.iter().map(|zone| zone.free_frames).sum()
+    }
+
+    /// The number of allocated frames.
+    pub fn used_frames(&self) -> u64 {
+        self.total_frames() - self.free_frames()
+    }
+}
+
+impl Display for FrameStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Physical frames: {} KiB used, {} KiB free",
+            self.used_frames() * FRAME_SIZE / 1024,
+            self.free_frames() * FRAME_SIZE / 1024,
+        )?;
+        for zone in &self.zones {
+            write!(f, "\n  {}", zone)?;
+        }
+        Ok(())
+    }
+}
+
+/// The allocator for every usable frame in the memory map. Page faults may allocate frames, so
+/// interrupts must be disabled while it is locked.
+static ALLOCATOR: Mutex<Option<BuddyAllocator<'static>>> = Mutex::new(None);
+
+fn with_allocator<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut BuddyAllocator<'static>) -> T,
+{
+    without_interrupts(|| {
+        let mut allocator = ALLOCATOR.lock();
+        f(allocator
+            .as_mut()
+            .expect("memory::frame::init has not been called"))
+    })
+}
+
+/// Run `f` on the frame allocator unless it is locked or hasn't been set up, for code which can
+/// interrupt a holder of the lock, like the page fault handler.
+pub(crate) fn try_with_allocator<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&mut BuddyAllocator<'static>) -> T,
+{
+    without_interrupts(|| {
+        let mut allocator = ALLOCATOR.try_lock()?;
+        allocator.as_mut().map(f)
+    })
+}
+
+/// Hand every usable frame in `map` to the frame allocator. The bitmap of free blocks is taken
+/// from the highest usable region large enough to hold it. Does nothing if the allocator has
+/// already been set up.
+///
+/// # Safety
+/// The usable regions of `map` must really be unused and all of physical memory must be mapped
+/// at `physical_memory_offset`.
+pub unsafe fn init(map: &MemoryMap, physical_memory_offset: VirtAddr) {
+    if without_interrupts(|| ALLOCATOR.lock().is_some()) {
+        return;
+    }
+    let end = map
+        .regions_of(RegionKind::Usable)
+        .map(|region| region.end.as_u64())
+        .max()
+        .unwrap_or(0);
+    let words = ((end / FRAME_SIZE + 63) / 64) as usize;
+    let bitmap_size = (words as u64 * 8 + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+    let bitmap_start = map
+        .regions_of(RegionKind::Usable)
+        .map(|region| (region.start.align_up(FRAME_SIZE), region.end))
+        .filter(|(start, end)| end.as_u64() >= start.as_u64() + bitmap_size)
+        .map(|(start, _)| start)
+        .last()
+        .expect("No usable region can hold the frame bitmap");
+    let bitmap_end = bitmap_start + bitmap_size;
+    let bitmap = core::slice::from_raw_parts_mut(
+        (physical_memory_offset + bitmap_start.as_u64()).as_mut_ptr::<u64>(),
+        words,
+    );
+    let mut allocator = BuddyAllocator::new(bitmap, PhysAddr::new(0), physical_memory_offset);
+    for region in map.regions_of(RegionKind::Usable) {
+        if region.contains(bitmap_start) {
+            allocator.add_region(region.start, bitmap_start);
+            allocator.add_region(bitmap_end, region.end);
+        } else {
+            allocator.add_region(region.start, region.end);
+        }
+    }
+    without_interrupts(|| *ALLOCATOR.lock() = Some(allocator));
+}
+
+/// Allocate `2^order` contiguous frames aligned to their size from `zone` or a zone below it.
+pub fn allocate(order: usize, zone: Zone) -> Option<PhysFrame> {
+    with_allocator(|allocator| allocator.allocate(order, zone))
+}
+
+/// Allocate a single frame from any zone, preferring the highest.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    allocate(0, Zone::Normal)
+}
+
+/// Return `2^order` frames starting at `frame` to the frame allocator.
+///
+/// # Safety
+/// The frames must have been allocated with [`allocate`] with the same order and must no longer
+/// be in use.
+///
+/// [`allocate`]: fn.allocate.html
+pub unsafe fn free(frame: PhysFrame, order: usize) {
+    with_allocator(|allocator| allocator.free(frame, order))
+}
+
+/// The current number of free and used frames in each zone.
+pub fn stats() -> FrameStats {
+    with_allocator(|allocator| allocator.stats())
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0, Zone::Normal)
+    }
+}
+
+/// A handle to the kernel's frame allocator, for use with the page table mappers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { free(frame, 0) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::memory;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::frame]";
+
+    /// Run `f` with a private allocator that manages a block of `2^order` frames taken from the
+    /// kernel's allocator, then give the block back.
+    fn with_private_allocator<F>(order: usize, f: F)
+    where
+        F: FnOnce(&mut BuddyAllocator, PhysAddr),
+    {
+        let block = allocate(order, Zone::Normal).expect("Failed to allocate a test block");
+        let mut bitmap = [0; 1];
+        unsafe {
+            let mut allocator = BuddyAllocator::new(
+                &mut bitmap,
+                block.start_address(),
+                memory::physical_memory_offset(),
+            );
+            f(&mut allocator, block.start_address());
+            free(block, order);
+        }
+    }
+
+    #[test_case]
+    fn test_split_and_coalesce() {
+        serial_print!("{} test_split_and_coalesce... ", TEST_PREFIX);
+        with_private_allocator(4, |allocator, base| {
+            unsafe {
+                allocator.add_region(base, base + 16 * FRAME_SIZE);
+            }
+            let zone = Zone::containing(base).index();
+            assert_eq!(allocator.stats().zones[zone].free_blocks[4], 1);
+            let first = allocator.allocate(0, Zone::Normal).unwrap();
+            assert_eq!(first.start_address(), base);
+            let stats = allocator.stats().zones[zone];
+            assert_eq!(stats.free_frames, 15);
+            assert_eq!(&stats.free_blocks[..5], &[1, 1, 1, 1, 0]);
+            let pair = allocator.allocate(1, Zone::Normal).unwrap();
+            assert_eq!(pair.start_address(), base + 2 * FRAME_SIZE);
+            unsafe {
+                allocator.free(first, 0);
+                assert_eq!(&allocator.stats().zones[zone].free_blocks[..2], &[0, 1]);
+                allocator.free(pair, 1);
+            }
+            let stats = allocator.stats().zones[zone];
+            assert_eq!(stats.free_frames, 16);
+            assert_eq!(stats.used_frames(), 0);
+            assert_eq!(&stats.free_blocks[..5], &[0, 0, 0, 0, 1]);
+            assert_eq!(allocator.allocate(5, Zone::Normal), None);
+        });
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_exhaustion() {
+        serial_print!("{} test_exhaustion... ", TEST_PREFIX);
+        with_private_allocator(5, |allocator, base| {
+            unsafe {
+                allocator.add_region(base, base + 32 * FRAME_SIZE);
+            }
+            let zone = Zone::containing(base).index();
+            let mut blocks = [None; 8];
+            for (i, block) in blocks.iter_mut().enumerate() {
+                let frame = allocator.allocate(2, Zone::Normal).unwrap();
+                assert_eq!(frame.start_address(), base + i as u64 * 4 * FRAME_SIZE);
+                *block = Some(frame);
+            }
+            assert_eq!(allocator.allocate(2, Zone::Normal), None);
+            assert_eq!(allocator.allocate(0, Zone::Normal), None);
+            assert_eq!(allocator.stats().zones[zone].free_frames, 0);
+            unsafe {
+                // No two of the odd blocks are buddies, so none of them merge.
+                for block in blocks.iter().skip(1).step_by(2) {
+                    allocator.free(block.unwrap(), 2);
+                }
+                assert_eq!(
+                    &allocator.stats().zones[zone].free_blocks[..6],
+                    &[0, 0, 4, 0, 0, 0]
+                );
+                assert_eq!(allocator.allocate(3, Zone::Normal), None);
+                // Each even block merges with its buddy, and the merged blocks with theirs.
+                for block in blocks.iter().step_by(2) {
+                    allocator.free(block.unwrap(), 2);
+                }
+            }
+            let stats = allocator.stats().zones[zone];
+            assert_eq!(stats.free_frames, 32);
+            assert_eq!(&stats.free_blocks[..6], &[0, 0, 0, 0, 0, 1]);
+            let whole = allocator.allocate(5, Zone::Normal).unwrap();
+            assert_eq!(whole.start_address(), base);
+        });
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_unaligned_region() {
+        serial_print!("{} test_unaligned_region... ", TEST_PREFIX);
+        with_private_allocator(3, |allocator, base| {
+            unsafe {
+                allocator.add_region(base + 100u64, base + 7 * FRAME_SIZE + 100);
+            }
+            let zone = Zone::containing(base).index();
+            let stats = allocator.stats().zones[zone];
+            assert_eq!(stats.total_frames, 6);
+            assert_eq!(&stats.free_blocks[..3], &[2, 2, 0]);
+            assert_eq!(allocator.allocate(2, Zone::Normal), None);
+            let pair = allocator.allocate(1, Zone::Normal).unwrap();
+            assert_eq!(pair.start_address(), base + 4 * FRAME_SIZE);
+        });
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_zones() {
+        serial_print!("{} test_zones... ", TEST_PREFIX);
+        assert_eq!(Zone::containing(PhysAddr::new(0xF_FFFF)), Zone::Dma);
+        assert_eq!(Zone::containing(PhysAddr::new(16 << 20)), Zone::Dma32);
+        assert_eq!(Zone::containing(PhysAddr::new(4 << 30)), Zone::Normal);
+        let before = stats();
+        let dma = allocate(2, Zone::Dma).expect("No memory below 16 MiB");
+        assert!(dma.start_address().as_u64() + 4 * FRAME_SIZE <= Zone::Dma.end());
+        assert!(dma.start_address().is_aligned(4 * FRAME_SIZE));
+        let frame = allocate(0, Zone::Dma32).expect("No memory below 4 GiB");
+        assert!(frame.start_address().as_u64() < Zone::Dma32.end());
+        assert_eq!(stats().used_frames(), before.used_frames() + 5);
+        unsafe {
+            free(dma, 2);
+            free(frame, 0);
+        }
+        assert_eq!(stats(), before);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_boot_frames() {
+        serial_print!("{} test_boot_frames... ", TEST_PREFIX);
+        let stats = stats();
+        let usable = memory::memory_map().total(RegionKind::Usable) / FRAME_SIZE;
+        assert!(stats.total_frames() > 0);
+        assert!(stats.total_frames() <= usable);
+        let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+        let region = memory::memory_map().region_containing(frame.start_address());
+        assert_eq!(region.map(|region| region.kind), Some(RegionKind::Usable));
+        GlobalFrameAllocator.deallocate_frame(frame);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,91 @@
+use core::{alloc::Layout, ptr};
+
+use super::{align_up, HeapBackend};
+
+/// A heap which hands out memory in order of address. Freed memory is only reused once every
+/// allocation has been freed, so it is fast but wastes memory under most workloads.
+#[derive(Debug)]
+pub struct BumpAllocator {
+    start: usize,
+    end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    /// Create a heap without any memory.
+    pub const fn new() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl HeapBackend for BumpAllocator {
+    const NAME: &'static str = "bump";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.start = start;
+        self.end = start + size;
+        self.next = start;
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let start = align_up(self.next, layout.align());
+        match start.checked_add(layout.size()) {
+            Some(end) if end <= self.end => {
+                self.next = end;
+                self.allocations += 1;
+                start as *mut u8
+            }
+            _ => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.start;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::memory::heap::Arena;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::heap::bump]";
+
+    #[test_case]
+    fn test_bump_allocator() {
+        serial_print!("{} test_bump_allocator... ", TEST_PREFIX);
+        let mut arena = Arena::new();
+        let start = arena.start();
+        let mut heap = BumpAllocator::new();
+        unsafe {
+            heap.init(start, 256);
+        }
+        let byte = Layout::from_size_align(1, 1).unwrap();
+        let word = Layout::from_size_align(8, 8).unwrap();
+        let first = heap.allocate(byte);
+        let second = heap.allocate(word);
+        assert_eq!(first as usize, start);
+        assert_eq!(second as usize, start + 8);
+        assert!(heap
+            .allocate(Layout::from_size_align(256, 1).unwrap())
+            .is_null());
+        unsafe {
+            heap.deallocate(first, byte);
+            assert_eq!(heap.allocate(byte) as usize, start + 16);
+            heap.deallocate(second, word);
+            heap.deallocate((start + 16) as *mut u8, byte);
+        }
+        assert_eq!(heap.allocate(word) as usize, start);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,382 @@
+use core::{
+    alloc::Layout,
+    fmt::{self, Display, Formatter},
+    mem::{self, align_of, size_of},
+    ptr, slice,
+};
+
+use super::{align_up, HeapBackend};
+use crate::backtrace::{Backtrace, Frame};
+
+/// The number of return addresses recorded for each allocation.
+pub const SITE_FRAMES: usize = 4;
+
+/// The number of bytes of red zone on each side of an allocation.
+pub const RED_ZONE_SIZE: usize = 16;
+
+/// The byte that red zones are filled with.
+pub const RED_ZONE_BYTE: u8 = 0xFD;
+
+/// The byte that freed memory is filled with.
+pub const POISON_BYTE: u8 = 0xDD;
+
+/// The value of [`Header::magic`] while an allocation is live.
+///
+/// [`Header::magic`]: struct.Header.html#structfield.magic
+const LIVE_MAGIC: u64 = 0xA110_CA7E_D0B1_0C4E;
+
+/// The prefixes of the functions which are part of allocating memory rather than the code which
+/// asked for it. They are skipped when recording where an allocation was made.
+const ALLOCATOR_FUNCTIONS: [&str; 6] = [
+    "alloc::",
+    "<alloc::",
+    "__rust_",
+    "__rg_",
+    "<rust_os::memory::heap::KernelHeap",
+    "<rust_os::memory::heap::debug::DebugAllocator",
+];
+
+/// The bookkeeping at the start of every block, followed by padding and the red zone before the
+/// allocation.
+#[repr(C)]
+struct Header {
+    next: *mut Header,
+    previous: *mut Header,
+    magic: u64,
+    id: u64,
+    size: usize,
+    align: usize,
+    leak_checked: bool,
+    site: [u64; SITE_FRAMES],
+}
+
+/// A live allocation made through a [`DebugAllocator`].
+///
+/// [`DebugAllocator`]: struct.DebugAllocator.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Allocation {
+    /// The sequence number of the allocation. Later allocations have larger numbers.
+    pub id: u64,
+    /// The address of the allocated memory.
+    pub address: usize,
+    /// The size of the allocation in bytes.
+    pub size: usize,
+    /// Whether the allocation counts as a leak if it outlives the code which made it.
+    pub leak_checked: bool,
+    /// The return addresses of the innermost calls outside the allocator when the allocation was
+    /// made, or 0 where the stack ended.
+    pub site: [u64; SITE_FRAMES],
+}
+
+impl Display for Allocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Allocation {} of {} bytes at {:#X}, made from:",
+            self.id, self.size, self.address
+        )?;
+        for &address in self.site.iter().take_while(|&&address| address != 0) {
+            write!(f, "\n    {}", Frame::from_return_address(address))?;
+        }
+        Ok(())
+    }
+}
+
+/// Damage which was found when an allocation was freed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Corruption {
+    /// The block doesn't hold a live allocation, so it was already freed or was never allocated.
+    NotAllocated,
+    /// The allocation was freed with a different size than it was made with.
+    SizeMismatch(usize),
+    /// The red zone before the allocation was overwritten the given number of bytes before it.
+    Underflow(usize),
+    /// The red zone after the allocation was overwritten the given number of bytes after its end.
+    Overflow(usize),
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAllocated => write!(f, "it was already freed or was never allocated"),
+            Self::SizeMismatch(size) => write!(f, "it was allocated with {} bytes", size),
+            Self::Underflow(offset) => {
+                write!(f, "it was written {} bytes before its start", offset)
+            }
+            Self::Overflow(offset) => write!(f, "it was written {} bytes after its end", offset),
+        }
+    }
+}
+
+/// A heap backend which wraps another to find memory bugs. Every block is surrounded by red zones
+/// which are checked when it is freed, freed memory is filled with [`POISON_BYTE`], and every
+/// live allocation is kept in a list with the functions which made it so that leaks can be
+/// found.
+///
+/// [`POISON_BYTE`]: constant.POISON_BYTE.html
+#[derive(Debug)]
+pub struct DebugAllocator<B> {
+    backend: B,
+    head: *mut Header,
+    next_id: u64,
+    live: usize,
+    leak_checked: bool,
+}
+
+// The list of headers runs through blocks the allocator got from `backend`, so it is as safe to
+// move as `backend` is.
+unsafe impl<B: Send> Send for DebugAllocator<B> {}
+
+impl<B> DebugAllocator<B> {
+    /// Create a heap which checks the allocations made from `backend`.
+    pub const fn new(backend: B) -> Self {
+        Self {
+            backend,
+            head: ptr::null_mut(),
+            next_id: 0,
+            live: 0,
+            leak_checked: true,
+        }
+    }
+
+    /// The sequence number the next allocation will have. Allocations made after this is called
+    /// have numbers no smaller than it.
+    pub fn mark(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Set whether allocations made from now on count as leaks if they outlive the code which made
+    /// them, and return the previous setting.
+    pub fn set_leak_checked(&mut self, checked: bool) -> bool {
+        mem::replace(&mut self.leak_checked, checked)
+    }
+
+    /// The number of live allocations.
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    /// Every live allocation, from the most recent.
+    pub fn allocations(&self) -> impl Iterator<Item = Allocation> + '_ {
+        let mut header = self.head;
+        core::iter::from_fn(move || {
+            if header.is_null() {
+                return None;
+            }
+            unsafe {
+                let allocation = Allocation {
+                    id: (*header).id,
+                    address: header as usize + Self::front_size((*header).align),
+                    size: (*header).size,
+                    leak_checked: (*header).leak_checked,
+                    site: (*header).site,
+                };
+                header = (*header).next;
+                Some(allocation)
+            }
+        })
+    }
+
+    /// The layout of the block which holds an allocation with `layout`: the header and front red
+    /// zone, the allocation, and the back red zone.
+    fn block_layout(layout: Layout) -> Layout {
+        let align = layout.align().max(align_of::<Header>());
+        let size = Self::front_size(align) + layout.size() + RED_ZONE_SIZE;
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    /// The number of bytes in a block before an allocation aligned to `align`.
+    fn front_size(align: usize) -> usize {
+        align_up(size_of::<Header>() + RED_ZONE_SIZE, align)
+    }
+
+    /// Check the block of the allocation at `ptr`, which was made with `layout`, for damage.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by this allocator for `layout`, though it may have been freed.
+    pub unsafe fn check(&self, ptr: *mut u8, layout: Layout) -> Result<(), Corruption> {
+        let block_layout = Self::block_layout(layout);
+        let front_size = Self::front_size(block_layout.align());
+        let header = ptr.sub(front_size) as *mut Header;
+        if (*header).magic != LIVE_MAGIC {
+            return Err(Corruption::NotAllocated);
+        }
+        if (*header).size != layout.size() {
+            return Err(Corruption::SizeMismatch((*header).size));
+        }
+        let before = slice::from_raw_parts(
+            (header as *const u8).add(size_of::<Header>()),
+            front_size - size_of::<Header>(),
+        );
+        if let Some(offset) = before.iter().rposition(|&byte| byte != RED_ZONE_BYTE) {
+            return Err(Corruption::Underflow(before.len() - offset));
+        }
+        let after = slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE_SIZE);
+        if let Some(offset) = after.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+            return Err(Corruption::Overflow(offset));
+        }
+        Ok(())
+    }
+}
+
+/// The innermost return addresses on the stack outside the allocator.
+fn allocation_site() -> [u64; SITE_FRAMES] {
+    let mut site = [0; SITE_FRAMES];
+    let frames = Backtrace::current().skip_while(|frame| {
+        frame.symbol.map_or(false, |symbol| {
+            ALLOCATOR_FUNCTIONS
+                .iter()
+                .any(|prefix| symbol.name.starts_with(prefix))
+        })
+    });
+    for (address, frame) in site.iter_mut().zip(frames) {
+        *address = frame.address;
+    }
+    site
+}
+
+impl<B: HeapBackend> HeapBackend for DebugAllocator<B> {
+    const NAME: &'static str = "debug";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.backend.init(start, size);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let block_layout = Self::block_layout(layout);
+        let block = self.backend.allocate(block_layout);
+        if block.is_null() {
+            return block;
+        }
+        let front_size = Self::front_size(block_layout.align());
+        unsafe {
+            let header = block as *mut Header;
+            header.write(Header {
+                next: self.head,
+                previous: ptr::null_mut(),
+                magic: LIVE_MAGIC,
+                id: self.next_id,
+                size: layout.size(),
+                align: block_layout.align(),
+                leak_checked: self.leak_checked,
+                site: allocation_site(),
+            });
+            if !self.head.is_null() {
+                (*self.head).previous = header;
+            }
+            self.head = header;
+            let ptr = block.add(front_size);
+            let before = block.add(size_of::<Header>());
+            ptr::write_bytes(before, RED_ZONE_BYTE, front_size - size_of::<Header>());
+            ptr::write_bytes(ptr.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+            self.next_id += 1;
+            self.live += 1;
+            ptr
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Err(corruption) = self.check(ptr, layout) {
+            panic!("Corrupt heap allocation at {:p}: {}", ptr, corruption);
+        }
+        let block_layout = Self::block_layout(layout);
+        let header = ptr.sub(Self::front_size(block_layout.align())) as *mut Header;
+        let (next, previous) = ((*header).next, (*header).previous);
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+        if previous.is_null() {
+            self.head = next;
+        } else {
+            (*previous).next = next;
+        }
+        self.live -= 1;
+        ptr::write_bytes(header as *mut u8, POISON_BYTE, block_layout.size());
+        self.backend.deallocate(header as *mut u8, block_layout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        backtrace::symbols,
+        memory::heap::{linked_list::LinkedListAllocator, Arena},
+    };
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::heap::debug]";
+
+    fn with_heap<F: FnOnce(&mut DebugAllocator<LinkedListAllocator>)>(f: F) {
+        let mut arena = Arena::new();
+        let mut heap = DebugAllocator::new(LinkedListAllocator::new());
+        unsafe {
+            heap.init(arena.start(), 4096);
+        }
+        f(&mut heap);
+    }
+
+    #[test_case]
+    fn test_tracking() {
+        serial_print!("{} test_tracking... ", TEST_PREFIX);
+        with_heap(|heap| {
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let first = heap.allocate(layout);
+            let mark = heap.mark();
+            let second = heap.allocate(Layout::from_size_align(100, 64).unwrap());
+            assert_eq!(second as usize % 64, 0);
+            assert_eq!(heap.live(), 2);
+            let allocation = heap.allocations().next().unwrap();
+            assert_eq!((allocation.id, allocation.size), (mark, 100));
+            assert_eq!(allocation.address, second as usize);
+            if symbols::is_loaded() {
+                let caller = Frame::from_return_address(allocation.site[0]);
+                assert!(caller.symbol.unwrap().name.contains("test_tracking"));
+            }
+            unsafe {
+                heap.deallocate(first, layout);
+            }
+            let live: alloc::vec::Vec<_> = heap.allocations().map(|a| a.id).collect();
+            assert_eq!(live, [mark]);
+            assert!(heap.allocations().next().unwrap().leak_checked);
+            assert!(heap.set_leak_checked(false));
+            let third = heap.allocate(layout);
+            assert!(!heap.set_leak_checked(true));
+            assert!(!heap.allocations().next().unwrap().leak_checked);
+            unsafe {
+                heap.deallocate(third, layout);
+            }
+        });
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_red_zones_and_poison() {
+        serial_print!("{} test_red_zones_and_poison... ", TEST_PREFIX);
+        with_heap(|heap| {
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            let ptr = heap.allocate(layout);
+            unsafe {
+                assert_eq!(heap.check(ptr, layout), Ok(()));
+                assert_eq!(
+                    heap.check(ptr, Layout::from_size_align(16, 8).unwrap()),
+                    Err(Corruption::SizeMismatch(32))
+                );
+                ptr.add(33).write(0);
+                assert_eq!(heap.check(ptr, layout), Err(Corruption::Overflow(1)));
+                ptr.add(33).write(RED_ZONE_BYTE);
+                ptr.sub(2).write(0);
+                assert_eq!(heap.check(ptr, layout), Err(Corruption::Underflow(2)));
+                ptr.sub(2).write(RED_ZONE_BYTE);
+                heap.deallocate(ptr, layout);
+                // The backend reuses the start of the block, but the allocation itself stays
+                // poisoned.
+                let freed = slice::from_raw_parts(ptr, layout.size());
+                assert!(freed.iter().all(|&byte| byte == POISON_BYTE));
+                assert_eq!(heap.check(ptr, layout), Err(Corruption::NotAllocated));
+            }
+            assert_eq!(heap.live(), 0);
+        });
+        serial_println!("[ok]");
+    }
+}
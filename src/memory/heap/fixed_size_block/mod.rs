@@ -0,0 +1,140 @@
+use core::{alloc::Layout, ptr};
+
+use super::{linked_list::LinkedListAllocator, HeapBackend};
+
+/// The sizes of the blocks which have their own free lists. Each block is aligned to its size, so
+/// these must be powers of two no smaller than a pointer.
+pub const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The header of a free block, linking it to the next free block of the same size.
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+/// A heap which rounds every small allocation up to one of [`BLOCK_SIZES`] and keeps a list of
+/// free blocks of each size, so that most allocations and frees take constant time. Blocks are
+/// taken from a [`LinkedListAllocator`] when their list is empty and are never given back to it.
+/// Allocations larger than the largest block go straight to the linked list.
+///
+/// [`BLOCK_SIZES`]: constant.BLOCK_SIZES.html
+/// [`LinkedListAllocator`]: ../linked_list/struct.LinkedListAllocator.html
+#[derive(Debug)]
+pub struct FixedSizeBlockAllocator {
+    heads: [*mut FreeBlock; BLOCK_SIZES.len()],
+    fallback: LinkedListAllocator,
+}
+
+// Freed blocks are never handed back to the fallback heap, so each list in `heads` is only
+// reachable through the allocator until one of its blocks is allocated again.
+unsafe impl Send for FixedSizeBlockAllocator {}
+
+impl FixedSizeBlockAllocator {
+    /// Create a heap without any memory.
+    pub const fn new() -> Self {
+        Self {
+            heads: [ptr::null_mut(); BLOCK_SIZES.len()],
+            fallback: LinkedListAllocator::new(),
+        }
+    }
+
+    /// The index in [`BLOCK_SIZES`] of the smallest block which can hold `layout`, if any.
+    ///
+    /// [`BLOCK_SIZES`]: constant.BLOCK_SIZES.html
+    fn size_index(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        BLOCK_SIZES
+            .iter()
+            .position(|&block_size| block_size >= size)
+    }
+
+    /// The number of free blocks of each size.
+    pub fn free_blocks(&self) -> [usize; BLOCK_SIZES.len()] {
+        let mut counts = [0; BLOCK_SIZES.len()];
+        for (count, head) in counts.iter_mut().zip(self.heads.iter()) {
+            let mut block = *head;
+            while !block.is_null() {
+                *count += 1;
+                block = unsafe { (*block).next };
+            }
+        }
+        counts
+    }
+}
+
+impl HeapBackend for FixedSizeBlockAllocator {
+    const NAME: &'static str = "fixed-size block";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.fallback.init(start, size);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match Self::size_index(layout) {
+            Some(index) if !self.heads[index].is_null() => {
+                let block = self.heads[index];
+                self.heads[index] = unsafe { (*block).next };
+                block as *mut u8
+            }
+            Some(index) => {
+                let block_size = BLOCK_SIZES[index];
+                let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                self.fallback.allocate(layout)
+            }
+            None => self.fallback.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match Self::size_index(layout) {
+            Some(index) => {
+                let block = ptr as *mut FreeBlock;
+                block.write(FreeBlock {
+                    next: self.heads[index],
+                });
+                self.heads[index] = block;
+            }
+            None => self.fallback.deallocate(ptr, layout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::memory::heap::Arena;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::heap::fixed_size_block]";
+
+    #[test_case]
+    fn test_block_reuse() {
+        serial_print!("{} test_block_reuse... ", TEST_PREFIX);
+        let mut arena = Arena::new();
+        let start = arena.start();
+        let mut heap = FixedSizeBlockAllocator::new();
+        unsafe {
+            heap.init(start, 8192);
+        }
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let first = heap.allocate(small);
+        assert_eq!(first as usize % 32, 0);
+        unsafe {
+            heap.deallocate(first, small);
+        }
+        assert_eq!(heap.free_blocks()[2], 1);
+        // A 20 byte allocation also uses a 32 byte block, so it gets the one just freed.
+        let second = heap.allocate(Layout::from_size_align(20, 4).unwrap());
+        assert_eq!(second, first);
+        assert_eq!(heap.free_blocks(), [0; BLOCK_SIZES.len()]);
+        let large = Layout::from_size_align(4096, 4096).unwrap();
+        let page = heap.allocate(large);
+        assert!(!page.is_null());
+        assert_eq!(page as usize % 4096, 0);
+        assert!(heap.allocate(large).is_null());
+        unsafe {
+            heap.deallocate(page, large);
+        }
+        assert_eq!(heap.allocate(large), page);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,236 @@
+use core::{
+    alloc::Layout,
+    mem::{align_of, size_of},
+    ptr,
+};
+
+use super::{align_up, HeapBackend};
+
+/// The header at the start of every free region, linking it to the next free region.
+struct FreeRegion {
+    size: usize,
+    next: *mut FreeRegion,
+}
+
+/// The smallest region the allocator can keep track of.
+const MIN_REGION_SIZE: usize = size_of::<FreeRegion>();
+
+/// A heap which keeps a list of free regions sorted by address. An allocation takes the first
+/// region it fits in, and freed memory is merged with the free regions next to it so that the
+/// heap doesn't fragment into pieces too small to use.
+#[derive(Debug)]
+pub struct LinkedListAllocator {
+    head: *mut FreeRegion,
+}
+
+// `head` only points into the memory handed to `init`, which nothing but the allocator touches
+// until it is allocated, so no other thread can hold a reference to a free region.
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    /// Create a heap without any memory.
+    pub const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+        }
+    }
+
+    /// The size and alignment actually used for `layout`, which leave room for a free region's
+    /// header when the memory is freed.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let align = layout.align().max(align_of::<FreeRegion>());
+        let size = align_up(layout.size(), align_of::<FreeRegion>()).max(MIN_REGION_SIZE);
+        (size, align)
+    }
+
+    /// Add the `size` bytes from `addr` to the list of free regions, merging them with the
+    /// regions on either side if they touch.
+    ///
+    /// # Safety
+    /// The memory must be unused and must not overlap any free region. `addr` must be aligned for
+    /// a free region and `size` must be a multiple of its alignment no smaller than
+    /// `MIN_REGION_SIZE`.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        let mut previous: *mut FreeRegion = ptr::null_mut();
+        let mut link: *mut *mut FreeRegion = &mut self.head;
+        while !(*link).is_null() && (*link as usize) < addr {
+            previous = *link;
+            link = &mut (*previous).next;
+        }
+        let next = *link;
+        let region = addr as *mut FreeRegion;
+        region.write(FreeRegion { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*region).size += (*next).size;
+            (*region).next = (*next).next;
+        }
+        if !previous.is_null() && previous as usize + (*previous).size == addr {
+            (*previous).size += (*region).size;
+            (*previous).next = (*region).next;
+        } else {
+            *link = region;
+        }
+    }
+
+    /// The number of free regions and the number of free bytes in them.
+    pub fn free_space(&self) -> (usize, usize) {
+        let (mut regions, mut bytes) = (0, 0);
+        let mut region = self.head;
+        while !region.is_null() {
+            unsafe {
+                regions += 1;
+                bytes += (*region).size;
+                region = (*region).next;
+            }
+        }
+        (regions, bytes)
+    }
+}
+
+impl HeapBackend for LinkedListAllocator {
+    const NAME: &'static str = "linked list";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        let aligned = align_up(start, align_of::<FreeRegion>());
+        let size = (start + size).saturating_sub(aligned) & !(align_of::<FreeRegion>() - 1);
+        if size >= MIN_REGION_SIZE {
+            self.add_free_region(aligned, size);
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+        let mut link: *mut *mut FreeRegion = &mut self.head;
+        unsafe {
+            while !(*link).is_null() {
+                let region = *link;
+                let region_start = region as usize;
+                let region_end = region_start + (*region).size;
+                let mut start = align_up(region_start, align);
+                if start != region_start && start - region_start < MIN_REGION_SIZE {
+                    // The gap in front is too small to be a free region, so leave room for one.
+                    start = align_up(region_start + MIN_REGION_SIZE, align);
+                }
+                let end = start.saturating_add(size);
+                let front = start - region_start;
+                let back = region_end.saturating_sub(end);
+                // Whatever is left behind must be big enough to be a free region as well.
+                let fits = end <= region_end && (back == 0 || back >= MIN_REGION_SIZE);
+                if !fits {
+                    link = &mut (*region).next;
+                    continue;
+                }
+                let mut rest = (*region).next;
+                if back != 0 {
+                    let back_region = end as *mut FreeRegion;
+                    back_region.write(FreeRegion {
+                        size: back,
+                        next: rest,
+                    });
+                    rest = back_region;
+                }
+                if front != 0 {
+                    (*region).size = front;
+                    (*region).next = rest;
+                } else {
+                    *link = rest;
+                }
+                return start as *mut u8;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::memory::heap::Arena;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::heap::linked_list]";
+
+    #[test_case]
+    fn test_reuse_and_merge() {
+        serial_print!("{} test_reuse_and_merge... ", TEST_PREFIX);
+        let mut arena = Arena::new();
+        let start = arena.start();
+        let mut heap = LinkedListAllocator::new();
+        unsafe {
+            heap.init(start, 1024);
+        }
+        assert_eq!(heap.free_space(), (1, 1024));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let blocks = [
+            heap.allocate(layout),
+            heap.allocate(layout),
+            heap.allocate(layout),
+        ];
+        assert_eq!(blocks[0] as usize, start);
+        assert_eq!(blocks[1] as usize, start + 104);
+        unsafe {
+            heap.deallocate(blocks[1], layout);
+            assert_eq!(heap.free_space().0, 2);
+            assert_eq!(heap.allocate(layout), blocks[1]);
+            heap.deallocate(blocks[0], layout);
+            heap.deallocate(blocks[2], layout);
+            heap.deallocate(blocks[1], layout);
+        }
+        assert_eq!(heap.free_space(), (1, 1024));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_alignment() {
+        serial_print!("{} test_alignment... ", TEST_PREFIX);
+        let mut arena = Arena::new();
+        let start = arena.start();
+        let mut heap = LinkedListAllocator::new();
+        unsafe {
+            heap.init(start + 8, 1016);
+        }
+        let aligned = Layout::from_size_align(16, 256).unwrap();
+        let block = heap.allocate(aligned);
+        assert_eq!(block as usize, start + 256);
+        assert_eq!(heap.free_space(), (2, 1016 - 16));
+        assert!(heap
+            .allocate(Layout::from_size_align(1024, 8).unwrap())
+            .is_null());
+        unsafe {
+            heap.deallocate(block, aligned);
+        }
+        assert_eq!(heap.free_space(), (1, 1016));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_small_front_gap() {
+        serial_print!("{} test_small_front_gap... ", TEST_PREFIX);
+        let mut arena = Arena::new();
+        let start = arena.start();
+        let mut heap = LinkedListAllocator::new();
+        unsafe {
+            heap.init(start, 4096);
+        }
+        // The first allocation leaves the free region 8 bytes short of 16-byte alignment, which is
+        // too small a gap to keep in front of the second.
+        let large = Layout::from_size_align(3000, 8).unwrap();
+        let small = Layout::from_size_align(16, 16).unwrap();
+        let first = heap.allocate(large);
+        let second = heap.allocate(small);
+        assert_eq!(first as usize, start);
+        assert_eq!(second as usize, start + 3024);
+        assert_eq!(heap.free_space(), (2, 4096 - 3000 - 16));
+        unsafe {
+            heap.deallocate(second, small);
+            heap.deallocate(first, large);
+        }
+        assert_eq!(heap.free_space(), (1, 4096));
+        serial_println!("[ok]");
+    }
+}
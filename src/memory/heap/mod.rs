@@ -0,0 +1,392 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt::{self, Display, Formatter},
+};
+
+use spin::Mutex;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::memory::{
+    frame,
+    paging::{self, PagingError},
+};
+
+/// A heap which hands out memory from the top of a region and only reuses it once everything has
+/// been freed.
+pub mod bump;
+
+/// A heap which wraps another to catch overflows, uses after free and leaks.
+#[cfg(feature = "debug-allocator")]
+pub mod debug;
+
+/// A heap which keeps a list of free blocks for each of a few sizes and passes larger
+/// allocations to a linked list heap.
+pub mod fixed_size_block;
+
+/// A heap which keeps a list of free regions sorted by address.
+pub mod linked_list;
+
+/// The virtual address of the start of the kernel heap.
+pub const HEAP_START: u64 = 0xFFFF_FA00_0000_0000;
+
+/// The size of the kernel heap in bytes.
+pub const HEAP_SIZE: u64 = 4 << 20;
+
+#[cfg(not(any(
+    feature = "bump-allocator",
+    feature = "linked-list-allocator",
+    feature = "fixed-size-block-allocator",
+)))]
+compile_error!(
+    "One of the features `bump-allocator`, `linked-list-allocator` and \
+     `fixed-size-block-allocator` must be enabled to choose the kernel heap"
+);
+
+#[cfg(any(
+    all(feature = "bump-allocator", feature = "linked-list-allocator"),
+    all(feature = "bump-allocator", feature = "fixed-size-block-allocator"),
+    all(
+        feature = "linked-list-allocator",
+        feature = "fixed-size-block-allocator"
+    ),
+))]
+compile_error!(
+    "Only one of the features `bump-allocator`, `linked-list-allocator` and \
+     `fixed-size-block-allocator` may be enabled. Disable the default features to choose another \
+     kernel heap"
+);
+
+/// The heap which manages the memory of the kernel heap, chosen by the `bump-allocator` feature.
+#[cfg(feature = "bump-allocator")]
+pub type BaseBackend = bump::BumpAllocator;
+
+/// The heap which manages the memory of the kernel heap, chosen by the `linked-list-allocator`
+/// feature.
+#[cfg(feature = "linked-list-allocator")]
+pub type BaseBackend = linked_list::LinkedListAllocator;
+
+/// The heap which manages the memory of the kernel heap, chosen by the
+/// `fixed-size-block-allocator` feature.
+#[cfg(feature = "fixed-size-block-allocator")]
+pub type BaseBackend = fixed_size_block::FixedSizeBlockAllocator;
+
+/// The backend of the kernel heap.
+#[cfg(not(feature = "debug-allocator"))]
+pub type ActiveBackend = BaseBackend;
+
+/// The backend of the kernel heap, which checks the allocations of the base backend because the
+/// `debug-allocator` feature is enabled.
+#[cfg(feature = "debug-allocator")]
+pub type ActiveBackend = debug::DebugAllocator<BaseBackend>;
+
+#[cfg(not(feature = "debug-allocator"))]
+const fn active_backend() -> ActiveBackend {
+    BaseBackend::new()
+}
+
+#[cfg(feature = "debug-allocator")]
+const fn active_backend() -> ActiveBackend {
+    debug::DebugAllocator::new(BaseBackend::new())
+}
+
+/// A way of managing the memory of a heap.
+pub trait HeapBackend {
+    /// The name of the backend, for reports.
+    const NAME: &'static str;
+
+    /// Hand the `size` bytes from `start` to the backend.
+    ///
+    /// # Safety
+    /// The memory must be mapped and unused, and this must only be called once.
+    unsafe fn init(&mut self, start: usize, size: usize);
+
+    /// Allocate memory for `layout`, or return a null pointer if there isn't enough free memory.
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// Free memory which was allocated with `layout`.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`allocate`] on this backend with the same `layout`, and
+    /// must not be used afterwards.
+    ///
+    /// [`allocate`]: #tymethod.allocate
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+/// The usage of the kernel heap.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HeapStats {
+    /// The name of the backend managing the heap.
+    pub backend: &'static str,
+    /// The size of the heap in bytes.
+    pub size: usize,
+    /// The number of bytes currently allocated.
+    pub used: usize,
+    /// The largest number of bytes that have been allocated at once.
+    pub peak: usize,
+    /// The number of successful allocations.
+    pub allocations: usize,
+    /// The number of frees.
+    pub frees: usize,
+    /// The number of allocations which failed.
+    pub failures: usize,
+}
+
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Kernel heap ({}): {} of {} bytes used, {} at peak, {} allocations, {} frees, {} failed",
+            self.backend,
+            self.used,
+            self.size,
+            self.peak,
+            self.allocations,
+            self.frees,
+            self.failures,
+        )
+    }
+}
+
+struct Inner<B> {
+    backend: B,
+    stats: HeapStats,
+}
+
+/// A [`GlobalAlloc`] which passes allocations to a [`HeapBackend`] and keeps statistics. The heap
+/// may be used by interrupt handlers, so interrupts are disabled while it is locked.
+///
+/// [`GlobalAlloc`]: https://doc.rust-lang.org/core/alloc/trait.GlobalAlloc.html
+/// [`HeapBackend`]: trait.HeapBackend.html
+pub struct KernelHeap<B> {
+    inner: Mutex<Inner<B>>,
+}
+
+impl<B> KernelHeap<B> {
+    /// Create a heap without any memory which passes allocations to `backend`.
+    pub const fn new(backend: B, name: &'static str) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                backend,
+                stats: HeapStats {
+                    backend: name,
+                    size: 0,
+                    used: 0,
+                    peak: 0,
+                    allocations: 0,
+                    frees: 0,
+                    failures: 0,
+                },
+            }),
+        }
+    }
+}
+
+impl<B: HeapBackend> KernelHeap<B> {
+    /// Hand the `size` bytes from `start` to the heap. Does nothing if the heap already has
+    /// memory.
+    ///
+    /// # Safety
+    /// The memory must be mapped and unused.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            if inner.stats.size == 0 {
+                inner.backend.init(start, size);
+                inner.stats.size = size;
+            }
+        })
+    }
+
+    /// The current usage of the heap.
+    pub fn stats(&self) -> HeapStats {
+        without_interrupts(|| self.inner.lock().stats)
+    }
+
+    /// Run `f` on the backend of the heap. The heap is locked meanwhile, so `f` must not allocate.
+    pub fn with_backend<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut B) -> T,
+    {
+        without_interrupts(|| f(&mut self.inner.lock().backend))
+    }
+}
+
+unsafe impl<B: HeapBackend> GlobalAlloc for KernelHeap<B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let ptr = inner.backend.allocate(layout);
+            let stats = &mut inner.stats;
+            if ptr.is_null() {
+                stats.failures += 1;
+            } else {
+                stats.allocations += 1;
+                stats.used += layout.size();
+                stats.peak = stats.peak.max(stats.used);
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            inner.backend.deallocate(ptr, layout);
+            inner.stats.frees += 1;
+            inner.stats.used -= layout.size();
+        })
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap<ActiveBackend> =
+    KernelHeap::new(active_backend(), ActiveBackend::NAME);
+
+/// Map the kernel heap at [`HEAP_START`] with frames from the frame allocator and hand it to the
+/// global allocator. Does nothing if the heap is already set up.
+///
+/// [`HEAP_START`]: constant.HEAP_START.html
+pub fn init() -> Result<(), PagingError> {
+    if ALLOCATOR.stats().size != 0 {
+        return Ok(());
+    }
+    let start = VirtAddr::new(HEAP_START);
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(start),
+        Page::containing_address(start + HEAP_SIZE),
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in pages {
+        let frame = frame::allocate_frame().ok_or(PagingError::FrameAllocationFailed)?;
+        unsafe {
+            paging::map(page, frame, flags)?;
+        }
+    }
+    unsafe {
+        ALLOCATOR.init(HEAP_START as usize, HEAP_SIZE as usize);
+    }
+    Ok(())
+}
+
+/// The current usage of the kernel heap.
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// The sequence number that the next allocation from the kernel heap will have, to be passed to
+/// [`report_leaks`].
+///
+/// [`report_leaks`]: fn.report_leaks.html
+#[cfg(feature = "debug-allocator")]
+pub fn allocation_mark() -> u64 {
+    ALLOCATOR.with_backend(|backend| backend.mark())
+}
+
+/// Print every allocation from the kernel heap made since `mark` was taken which is still live to
+/// the serial port, and return how many there are.
+#[cfg(feature = "debug-allocator")]
+pub fn report_leaks(mark: u64) -> usize {
+    ALLOCATOR.with_backend(|backend| {
+        backend
+            .allocations()
+            .take_while(|allocation| allocation.id >= mark)
+            .filter(|allocation| allocation.leak_checked)
+            .inspect(|allocation| serial_println!("Leaked: {}", allocation))
+            .count()
+    })
+}
+
+/// Run `f`, leaving the allocations it makes out of [`report_leaks`]. This is for structures
+/// which keep memory between uses, like the timer wheel.
+///
+/// [`report_leaks`]: fn.report_leaks.html
+pub fn without_leak_checks<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    #[cfg(feature = "debug-allocator")]
+    let checked = ALLOCATOR.with_backend(|backend| backend.set_leak_checked(false));
+    let result = f();
+    #[cfg(feature = "debug-allocator")]
+    ALLOCATOR.with_backend(|backend| backend.set_leak_checked(checked));
+    result
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("Failed to allocate {:?}\n{}", layout, stats())
+}
+
+/// Page-aligned memory on the stack for the tests of the heap backends to hand to them.
+#[cfg(test)]
+#[repr(C, align(4096))]
+pub(crate) struct Arena([u8; Arena::SIZE]);
+
+#[cfg(test)]
+impl Arena {
+    /// The number of bytes in an arena.
+    pub(crate) const SIZE: usize = 8192;
+
+    pub(crate) fn new() -> Self {
+        Self([0; Self::SIZE])
+    }
+
+    /// The address of the first byte of the arena.
+    pub(crate) fn start(&mut self) -> usize {
+        self.0.as_mut_ptr() as usize
+    }
+}
+
+/// Round `addr` up to the next multiple of `align`, which must be a power of two.
+pub(crate) fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use alloc::{boxed::Box, vec::Vec};
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::heap]";
+
+    #[test_case]
+    fn test_stats() {
+        serial_print!("{} test_stats... ", TEST_PREFIX);
+        let before = stats();
+        assert_eq!(before.size, HEAP_SIZE as usize);
+        assert_eq!(before.backend, ActiveBackend::NAME);
+        let value = Box::new([0u8; 100]);
+        let during = stats();
+        assert_eq!(during.used, before.used + 100);
+        assert_eq!(during.allocations, before.allocations + 1);
+        assert!(during.peak >= during.used);
+        drop(value);
+        let after = stats();
+        assert_eq!(after.used, before.used);
+        assert_eq!(after.frees, before.frees + 1);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_heap_mapped() {
+        serial_print!("{} test_heap_mapped... ", TEST_PREFIX);
+        let values: Vec<u64> = (0..1000).collect();
+        let addr = VirtAddr::from_ptr(values.as_ptr());
+        assert!(addr.as_u64() >= HEAP_START && addr.as_u64() < HEAP_START + HEAP_SIZE);
+        assert_eq!(values.iter().sum::<u64>(), 999 * 1000 / 2);
+        let translation = paging::translate(VirtAddr::new(HEAP_START + HEAP_SIZE - 1));
+        assert!(translation.is_some());
+        assert_eq!(
+            paging::translate(VirtAddr::new(HEAP_START + HEAP_SIZE)),
+            None
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,272 @@
+use core::fmt::{self, Display, Formatter};
+
+use bootloader::bootinfo::{self, MemoryRegionType};
+
+use x86_64::PhysAddr;
+
+/// The maximum number of regions in a [`MemoryMap`]. The bootloader's memory map has the same
+/// limit.
+///
+/// [`MemoryMap`]: struct.MemoryMap.html
+pub const MAX_REGIONS: usize = 64;
+
+/// What a region of physical memory is used for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegionKind {
+    /// Free memory which the kernel can allocate.
+    Usable,
+    /// Memory which must not be used, such as memory used by the firmware, ACPI tables, memory
+    /// mapped devices or bad memory.
+    Reserved,
+    /// The kernel image and the kernel stack.
+    Kernel,
+    /// The page tables the bootloader set up for the kernel.
+    PageTable,
+    /// The bootloader itself and the boot information it passes to the kernel.
+    Bootloader,
+}
+
+impl RegionKind {
+    /// Get an iterator over every kind of region.
+    pub fn kinds() -> impl Iterator<Item = Self> {
+        [
+            Self::Usable,
+            Self::Reserved,
+            Self::Kernel,
+            Self::PageTable,
+            Self::Bootloader,
+        ]
+        .iter()
+        .copied()
+    }
+
+    /// Classify a region of the bootloader's memory map.
+    fn classify(region_type: MemoryRegionType) -> Self {
+        match region_type {
+            MemoryRegionType::Usable => Self::Usable,
+            MemoryRegionType::Kernel | MemoryRegionType::KernelStack => Self::Kernel,
+            MemoryRegionType::PageTable => Self::PageTable,
+            MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package => Self::Bootloader,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+impl Display for RegionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Usable => "usable",
+            Self::Reserved => "reserved",
+            Self::Kernel => "kernel",
+            Self::PageTable => "page table",
+            Self::Bootloader => "bootloader",
+        };
+        f.pad(name)
+    }
+}
+
+/// A contiguous range of physical memory with a single use.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Region {
+    /// The first address in the region.
+    pub start: PhysAddr,
+    /// The address one past the end of the region.
+    pub end: PhysAddr,
+    /// What the region is used for.
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// The size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Whether `addr` is in the region.
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+/// The physical memory of the machine, as a list of non-overlapping regions in order of address.
+/// Adjacent regions of the same kind are merged.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMap {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+}
+
+impl MemoryMap {
+    /// Create a memory map from the one the bootloader passed to the kernel.
+    pub fn from_boot_info(boot_map: &bootinfo::MemoryMap) -> Self {
+        let empty = Region {
+            start: PhysAddr::new(0),
+            end: PhysAddr::new(0),
+            kind: RegionKind::Reserved,
+        };
+        let mut map = Self {
+            regions: [empty; MAX_REGIONS],
+            len: 0,
+        };
+        for region in boot_map.iter() {
+            map.insert(Region {
+                start: PhysAddr::new(region.range.start_addr()),
+                end: PhysAddr::new(region.range.end_addr()),
+                kind: RegionKind::classify(region.region_type),
+            });
+        }
+        map
+    }
+
+    /// Insert `region` in order of address, merging it with its neighbours if they are adjacent
+    /// and of the same kind.
+    fn insert(&mut self, region: Region) {
+        if region.start >= region.end {
+            return;
+        }
+        let index = self.regions[..self.len]
+            .iter()
+            .position(|other| other.start > region.start)
+            .unwrap_or(self.len);
+        if index > 0 {
+            let previous = &mut self.regions[index - 1];
+            if previous.kind == region.kind && previous.end == region.start {
+                previous.end = region.end;
+                self.merge_next(index - 1);
+                return;
+            }
+        }
+        if index < self.len {
+            let next = &mut self.regions[index];
+            if next.kind == region.kind && next.start == region.end {
+                next.start = region.start;
+                return;
+            }
+        }
+        assert!(self.len < MAX_REGIONS, "Too many memory regions");
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = region;
+        self.len += 1;
+    }
+
+    /// Merge the region after `index` into the region at `index` if they are adjacent and of the
+    /// same kind.
+    fn merge_next(&mut self, index: usize) {
+        if index + 1 < self.len {
+            let next = self.regions[index + 1];
+            let region = &mut self.regions[index];
+            if next.kind == region.kind && next.start == region.end {
+                region.end = next.end;
+                self.regions[index + 1..self.len].rotate_left(1);
+                self.len -= 1;
+            }
+        }
+    }
+
+    /// The regions of memory, in order of address.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+
+    /// Get an iterator over the regions of the given kind.
+    pub fn regions_of(&self, kind: RegionKind) -> impl Iterator<Item = &Region> {
+        self.regions()
+            .iter()
+            .filter(move |region| region.kind == kind)
+    }
+
+    /// The total size in bytes of the regions of the given kind.
+    pub fn total(&self, kind: RegionKind) -> u64 {
+        self.regions_of(kind).map(Region::size).sum()
+    }
+
+    /// The region containing `addr`, if any.
+    pub fn region_containing(&self, addr: PhysAddr) -> Option<Region> {
+        self.regions()
+            .iter()
+            .copied()
+            .find(|region| region.contains(addr))
+    }
+}
+
+impl Display for MemoryMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Physical memory map:")?;
+        for kind in RegionKind::kinds() {
+            write!(
+                f,
+                "\n  {:<10} {:>8} KiB in {} regions",
+                kind,
+                self.total(kind) / 1024,
+                self.regions_of(kind).count(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::map]";
+
+    fn boot_region(start: u64, end: u64, region_type: MemoryRegionType) -> MemoryRegion {
+        MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        }
+    }
+
+    #[test_case]
+    fn test_classify_and_merge() {
+        serial_print!("{} test_classify_and_merge... ", TEST_PREFIX);
+        let mut boot_map = bootinfo::MemoryMap::new();
+        boot_map.add_region(boot_region(0x0000, 0x1000, MemoryRegionType::FrameZero));
+        boot_map.add_region(boot_region(0x1000, 0x5000, MemoryRegionType::Bootloader));
+        boot_map.add_region(boot_region(0x5000, 0x6000, MemoryRegionType::BootInfo));
+        boot_map.add_region(boot_region(0x6000, 0x9000, MemoryRegionType::Usable));
+        boot_map.add_region(boot_region(0x9000, 0xA000, MemoryRegionType::PageTable));
+        boot_map.add_region(boot_region(0xA000, 0xC000, MemoryRegionType::Kernel));
+        boot_map.add_region(boot_region(0xC000, 0xD000, MemoryRegionType::KernelStack));
+        boot_map.add_region(boot_region(0xD000, 0xF000, MemoryRegionType::Usable));
+        let map = MemoryMap::from_boot_info(&boot_map);
+        let kinds = [
+            RegionKind::Reserved,
+            RegionKind::Bootloader,
+            RegionKind::Usable,
+            RegionKind::PageTable,
+            RegionKind::Kernel,
+            RegionKind::Usable,
+        ];
+        assert_eq!(map.regions().len(), kinds.len());
+        for (region, kind) in map.regions().iter().zip(kinds.iter()) {
+            assert_eq!(region.kind, *kind);
+        }
+        assert_eq!(map.total(RegionKind::Usable), 0x5000);
+        assert_eq!(map.total(RegionKind::Kernel), 0x3000);
+        assert_eq!(
+            map.region_containing(PhysAddr::new(0x5800)).map(|r| r.kind),
+            Some(RegionKind::Bootloader)
+        );
+        assert_eq!(map.region_containing(PhysAddr::new(0xF000)), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_boot_memory_map() {
+        serial_print!("{} test_boot_memory_map... ", TEST_PREFIX);
+        let map = crate::memory::memory_map();
+        assert!(map.total(RegionKind::Usable) > 0);
+        assert!(map.total(RegionKind::Kernel) > 0);
+        assert!(map
+            .regions()
+            .windows(2)
+            .all(|pair| pair[0].end <= pair[1].start));
+        serial_println!("[ok]");
+    }
+}
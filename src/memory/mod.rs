@@ -0,0 +1,83 @@
+use bootloader::BootInfo;
+
+use spin::Once;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{OffsetPageTable, PageTable},
+    VirtAddr,
+};
+
+/// Virtual memory regions which are backed by frames only once they are touched.
+pub mod demand;
+
+/// Tools for allocating physical frames.
+pub mod frame;
+
+/// The kernel heap, which backs the `alloc` crate.
+pub mod heap;
+
+/// Tools for describing the physical memory of the machine.
+pub mod map;
+pub use map::{MemoryMap, Region, RegionKind};
+
+/// Tools for changing the mappings of the active page table.
+pub mod paging;
+
+/// W^X protection of the kernel image.
+pub mod protection;
+
+/// Caches of fixed-size kernel objects.
+pub mod slab;
+
+/// Checked access to user memory, and protection of user memory from the kernel.
+pub mod user;
+
+/// The virtual address at which the bootloader mapped all of physical memory.
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// The physical memory map the bootloader passed to the kernel.
+static MEMORY_MAP: Once<MemoryMap> = Once::new();
+
+/// Take the physical memory offset and the memory map from the information the bootloader passed
+/// to the kernel, hand the usable memory to the frame allocator, remap the kernel image so that
+/// none of it is both writable and executable and stop the kernel from touching user pages. This
+/// must be called before anything that reads or maps physical memory.
+pub fn init(boot_info: &'static BootInfo) {
+    protection::enable_nx();
+    PHYSICAL_MEMORY_OFFSET.call_once(|| VirtAddr::new(boot_info.physical_memory_offset));
+    MEMORY_MAP.call_once(|| MemoryMap::from_boot_info(&boot_info.memory_map));
+    unsafe {
+        frame::init(memory_map(), physical_memory_offset());
+    }
+    protection::protect_kernel().expect("Failed to protect the kernel image");
+    user::init();
+}
+
+/// The virtual address at which physical address 0 is mapped.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .r#try()
+        .expect("memory::init has not been called")
+}
+
+/// The physical memory map of the machine.
+pub fn memory_map() -> &'static MemoryMap {
+    MEMORY_MAP
+        .r#try()
+        .expect("memory::init has not been called")
+}
+
+/// Get a mapper for the active level 4 page table.
+///
+/// # Safety
+/// There must not be any other reference to the active page table while the returned mapper is
+/// alive.
+pub unsafe fn active_page_table() -> OffsetPageTable<'static> {
+    let (frame, _) = Cr3::read();
+    let table = physical_memory_offset() + frame.start_address().as_u64();
+    OffsetPageTable::new(
+        &mut *table.as_mut_ptr::<PageTable>(),
+        physical_memory_offset(),
+    )
+}
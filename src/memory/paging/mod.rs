@@ -0,0 +1,499 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    instructions::{interrupts::without_interrupts, tlb},
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableEntry,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory::{self, frame::GlobalFrameAllocator};
+
+/// The start of the virtual addresses that [`map_mmio`] maps device memory at.
+///
+/// [`map_mmio`]: fn.map_mmio.html
+pub const MMIO_REGION: u64 = 0xFFFF_FD00_0000_0000;
+
+/// The size of the virtual address range that [`map_mmio`] maps device memory in.
+///
+/// [`map_mmio`]: fn.map_mmio.html
+pub const MMIO_REGION_SIZE: u64 = 0x80_0000_0000;
+
+/// The flags which [`protect`] changes. Every other flag of a mapping is left alone.
+///
+/// [`protect`]: fn.protect.html
+pub const PROTECTION_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits()
+        | PageTableFlags::NO_EXECUTE.bits(),
+);
+
+/// The reason a mapping could not be changed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PagingError {
+    /// A frame for a new page table could not be allocated.
+    FrameAllocationFailed,
+    /// The address is already mapped, to the given frame.
+    PageAlreadyMapped(PhysAddr),
+    /// The address is not mapped.
+    PageNotMapped,
+    /// The address is part of a larger page than the operation works on.
+    ParentEntryHugePage,
+    /// A page table entry holds an address which is not a valid frame.
+    InvalidFrameAddress(PhysAddr),
+    /// The address or size is not aligned to the page size.
+    Unaligned(u64),
+    /// There is no room left in the virtual address range for the mapping.
+    OutOfAddressSpace,
+}
+
+impl<S: PageSize> From<MapToError<S>> for PagingError {
+    fn from(e: MapToError<S>) -> Self {
+        match e {
+            MapToError::FrameAllocationFailed => Self::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => Self::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => Self::PageAlreadyMapped(frame.start_address()),
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(e: UnmapError) -> Self {
+        match e {
+            UnmapError::ParentEntryHugePage => Self::ParentEntryHugePage,
+            UnmapError::PageNotMapped => Self::PageNotMapped,
+            UnmapError::InvalidFrameAddress(addr) => Self::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(e: FlagUpdateError) -> Self {
+        match e {
+            FlagUpdateError::PageNotMapped => Self::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage => Self::ParentEntryHugePage,
+        }
+    }
+}
+
+/// Where a virtual address is mapped to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Translation {
+    /// The physical address the virtual address is mapped to.
+    pub address: PhysAddr,
+    /// The size of the page containing the address: 4 KiB, 2 MiB or 1 GiB.
+    pub page_size: u64,
+    /// The flags of the page table entry which maps the page.
+    pub flags: PageTableFlags,
+}
+
+impl Translation {
+    /// The start of the frame the page is mapped to.
+    pub fn frame_address(&self) -> PhysAddr {
+        self.address.align_down(self.page_size)
+    }
+}
+
+/// Get the page table at `addr` through the mapping of all of physical memory.
+fn table_at(addr: PhysAddr) -> *mut PageTable {
+    (memory::physical_memory_offset() + addr.as_u64()).as_mut_ptr()
+}
+
+/// Walk the page tables to the entry which maps `addr`, calling `visit_parent` on every entry
+/// which points to a lower table on the way. Returns the entry with the size of the page it maps.
+unsafe fn walk<F>(
+    addr: VirtAddr,
+    mut visit_parent: F,
+) -> Result<(&'static mut PageTableEntry, u64), PagingError>
+where
+    F: FnMut(&mut PageTableEntry),
+{
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table = table_at(Cr3::read().0.start_address());
+    // The size of the page which an entry maps at each level, or 0 if it can only point to a
+    // lower table.
+    let page_sizes = [0, Size2MiB::SIZE * 512, Size2MiB::SIZE, Size4KiB::SIZE];
+    for (level, (index, page_size)) in indices.iter().zip(page_sizes.iter()).enumerate() {
+        let entry = &mut (*table)[*index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(PagingError::PageNotMapped);
+        }
+        if level == 3 || (*page_size != 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+            return Ok((entry, *page_size));
+        }
+        visit_parent(entry);
+        table = table_at(entry.addr());
+    }
+    unreachable!("Every page table walk ends at level 1")
+}
+
+/// Find where `addr` is mapped to in the active page table, if it is mapped.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    without_interrupts(|| unsafe {
+        let (entry, page_size) = walk(addr, |_| {}).ok()?;
+        Some(Translation {
+            address: entry.addr() + (addr.as_u64() & (page_size - 1)),
+            page_size,
+            flags: entry.flags(),
+        })
+    })
+}
+
+/// Set `flags` on every entry above the one which maps `addr`, so that they don't mask
+/// permissions given to the page itself.
+unsafe fn add_parent_flags(addr: VirtAddr, flags: PageTableFlags) -> Result<(), PagingError> {
+    walk(addr, |entry| {
+        entry.set_flags(entry.flags() | flags);
+    })?;
+    Ok(())
+}
+
+/// Map `page` to `frame` with `flags` in the active page table and flush it from the TLB. Page
+/// tables are allocated from the frame allocator when needed. Pages can be 4 KiB or 2 MiB.
+///
+/// # Safety
+/// Mapping the frame must not create a second mutable alias of memory which is in use.
+pub unsafe fn map<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), PagingError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    map_with(page, frame, flags, &mut GlobalFrameAllocator)
+}
+
+/// Like [`map`], but page tables are allocated from `allocator`, so that the frame allocator
+/// doesn't have to be locked again by code which already holds it.
+///
+/// # Safety
+/// Mapping the frame must not create a second mutable alias of memory which is in use.
+///
+/// [`map`]: fn.map.html
+pub unsafe fn map_with<S, A>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    allocator: &mut A,
+) -> Result<(), PagingError>
+where
+    S: PageSize,
+    A: FrameAllocator<Size4KiB>,
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let flags = flags | PageTableFlags::PRESENT;
+    without_interrupts(|| -> Result<(), PagingError> {
+        let mut page_table = memory::active_page_table();
+        page_table.map_to(page, frame, flags, allocator)?.flush();
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            add_parent_flags(page.start_address(), PageTableFlags::USER_ACCESSIBLE)?;
+        }
+        Ok(())
+    })
+}
+
+/// Remove the mapping of `page` from the active page table and flush it from the TLB. Returns the
+/// frame it was mapped to. The page tables themselves are kept even if they become empty.
+///
+/// # Safety
+/// Nothing may use the page after it is unmapped.
+pub unsafe fn unmap<S: PageSize>(page: Page<S>) -> Result<PhysFrame<S>, PagingError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    without_interrupts(|| -> Result<PhysFrame<S>, PagingError> {
+        let mut page_table = memory::active_page_table();
+        let (frame, flush) = page_table.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Map the `size` bytes of virtual memory from `start` to the physical memory from `frame_start`
+/// with `flags`. Wherever both addresses are aligned to 2 MiB and at least 2 MiB are left, a
+/// huge page is used instead of 512 small ones. If a page can't be mapped, the pages mapped
+/// before it stay mapped.
+///
+/// # Safety
+/// Mapping the frames must not create a second mutable alias of memory which is in use.
+pub unsafe fn map_range(
+    start: VirtAddr,
+    frame_start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    check_aligned(start.as_u64() | frame_start.as_u64() | size)?;
+    let mut offset = 0;
+    while offset < size {
+        let virt = start + offset;
+        let phys = frame_start + offset;
+        if virt.is_aligned(Size2MiB::SIZE)
+            && phys.is_aligned(Size2MiB::SIZE)
+            && size - offset >= Size2MiB::SIZE
+        {
+            map::<Size2MiB>(
+                Page::containing_address(virt),
+                PhysFrame::containing_address(phys),
+                flags,
+            )?;
+            offset += Size2MiB::SIZE;
+        } else {
+            map::<Size4KiB>(
+                Page::containing_address(virt),
+                PhysFrame::containing_address(phys),
+                flags,
+            )?;
+            offset += Size4KiB::SIZE;
+        }
+    }
+    Ok(())
+}
+
+/// Remove every mapping in the `size` bytes of virtual memory from `start`, whatever size its
+/// pages are. Pages which aren't mapped are skipped.
+///
+/// # Safety
+/// Nothing may use the memory after it is unmapped.
+pub unsafe fn unmap_range(start: VirtAddr, size: u64) -> Result<(), PagingError> {
+    check_aligned(start.as_u64() | size)?;
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        addr += match translate(addr).map(|translation| translation.page_size) {
+            Some(Size4KiB::SIZE) => {
+                unmap::<Size4KiB>(Page::containing_address(addr))?;
+                Size4KiB::SIZE
+            }
+            Some(Size2MiB::SIZE)
+                if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE =>
+            {
+                unmap::<Size2MiB>(Page::containing_address(addr))?;
+                Size2MiB::SIZE
+            }
+            // Only part of a huge page is in the range.
+            Some(_) => return Err(PagingError::ParentEntryHugePage),
+            None => Size4KiB::SIZE,
+        };
+    }
+    Ok(())
+}
+
+/// Change the writable, user accessible and no-execute flags of the page containing `addr`,
+/// whatever its size, to the ones in `flags`, and flush it from the TLB. The other flags are
+/// left alone.
+///
+/// # Safety
+/// Taking away permissions from memory which is in use will make the next access fault, and
+/// giving them may let code break the kernel's invariants.
+pub unsafe fn protect(addr: VirtAddr, flags: PageTableFlags) -> Result<(), PagingError> {
+    let flags = flags & PROTECTION_FLAGS;
+    without_interrupts(|| -> Result<(), PagingError> {
+        let (entry, _) = walk(addr, |_| {})?;
+        entry.set_flags(entry.flags() - PROTECTION_FLAGS | flags);
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            add_parent_flags(addr, PageTableFlags::USER_ACCESSIBLE)?;
+        }
+        flush(addr);
+        Ok(())
+    })
+}
+
+/// Change the protection flags of every page in the `size` bytes from `start` as [`protect`]
+/// does. A huge page is changed as a whole if any of it is in the range.
+///
+/// [`protect`]: fn.protect.html
+///
+/// # Safety
+/// See [`protect`].
+pub unsafe fn protect_range(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    check_aligned(start.as_u64() | size)?;
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        protect(addr, flags)?;
+        let page_size = translate(addr).map_or(Size4KiB::SIZE, |translation| translation.page_size);
+        addr = addr.align_down(page_size) + page_size;
+    }
+    Ok(())
+}
+
+/// Map `size` bytes of device memory from `start` into the MMIO region with caching disabled and
+/// return the virtual address of `start`. The virtual addresses are never reused.
+///
+/// # Safety
+/// The memory must belong to a device, or otherwise be safe to access without caching.
+pub unsafe fn map_mmio(start: PhysAddr, size: u64) -> Result<VirtAddr, PagingError> {
+    static NEXT: AtomicU64 = AtomicU64::new(MMIO_REGION);
+    let frame_start = start.align_down(Size4KiB::SIZE);
+    let size = (start + size).align_up(Size4KiB::SIZE) - frame_start;
+    let base = NEXT.fetch_add(size, Ordering::SeqCst);
+    if base + size > MMIO_REGION + MMIO_REGION_SIZE {
+        return Err(PagingError::OutOfAddressSpace);
+    }
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    map_range(VirtAddr::new(base), frame_start, size, flags)?;
+    Ok(VirtAddr::new(base) + (start - frame_start))
+}
+
+fn check_aligned(value: u64) -> Result<(), PagingError> {
+    if value % Size4KiB::SIZE == 0 {
+        Ok(())
+    } else {
+        Err(PagingError::Unaligned(value))
+    }
+}
+
+/// Flush the page containing `addr` from the TLB.
+pub fn flush(addr: VirtAddr) {
+    tlb::flush(addr);
+}
+
+/// Flush every page which isn't global from the TLB.
+pub fn flush_all() {
+    tlb::flush_all();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::memory::frame::{self, Zone};
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::paging]";
+
+    /// Virtual addresses which the tests map and unmap.
+    const TEST_REGION: u64 = 0xFFFF_FD80_0000_0000;
+
+    #[test_case]
+    fn test_translate() {
+        serial_print!("{} test_translate... ", TEST_PREFIX);
+        static VALUE: u64 = 0x1234_5678;
+        let addr = VirtAddr::from_ptr(&VALUE);
+        let translation = translate(addr).expect("A static is not mapped");
+        assert!(translation.flags.contains(PageTableFlags::PRESENT));
+        let through_offset = memory::physical_memory_offset() + translation.address.as_u64();
+        assert_eq!(unsafe { *through_offset.as_ptr::<u64>() }, VALUE);
+        assert_eq!(translate(VirtAddr::new(TEST_REGION - 0x1000)), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_map_protect_unmap() {
+        serial_print!("{} test_map_protect_unmap... ", TEST_PREFIX);
+        let frame = frame::allocate_frame().unwrap();
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_REGION));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            map(page, frame, flags).expect("Failed to map the test page");
+            assert_eq!(
+                map(page, frame, flags),
+                Err(PagingError::PageAlreadyMapped(frame.start_address()))
+            );
+            page.start_address().as_mut_ptr::<u64>().write_volatile(42);
+            let through_offset = memory::physical_memory_offset() + frame.start_address().as_u64();
+            assert_eq!(through_offset.as_ptr::<u64>().read_volatile(), 42);
+
+            protect(page.start_address(), PageTableFlags::empty()).unwrap();
+            let translation = translate(page.start_address() + 8u64).unwrap();
+            assert_eq!(translation.address, frame.start_address() + 8u64);
+            assert_eq!(translation.page_size, 4096);
+            assert!(!translation.flags.contains(PageTableFlags::WRITABLE));
+            protect(page.start_address(), PageTableFlags::WRITABLE).unwrap();
+            assert!(translate(page.start_address())
+                .unwrap()
+                .flags
+                .contains(PageTableFlags::WRITABLE));
+
+            assert_eq!(unmap(page), Ok(frame));
+            assert_eq!(unmap(page), Err(PagingError::PageNotMapped));
+            assert_eq!(translate(page.start_address()), None);
+            frame::free(frame, 0);
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_huge_pages() {
+        serial_print!("{} test_huge_pages... ", TEST_PREFIX);
+        // A 4 MiB block from the buddy allocator is aligned to 4 MiB, so its upper half is a 2 MiB
+        // aligned frame with a frame of the block just below it.
+        let block = frame::allocate(10, Zone::Normal).expect("No 4 MiB block is free");
+        let huge_frame = block.start_address() + Size2MiB::SIZE;
+        let start = VirtAddr::new(TEST_REGION + Size2MiB::SIZE);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            // Start a page early so that the first page is small and the rest is huge.
+            map_range(
+                start - 4096u64,
+                huge_frame - 4096u64,
+                Size2MiB::SIZE + 4096,
+                flags,
+            )
+            .unwrap();
+            assert_eq!(translate(start - 4096u64).unwrap().page_size, 4096);
+            let translation = translate(start + 0x1234u64).unwrap();
+            assert_eq!(translation.page_size, Size2MiB::SIZE);
+            assert_eq!(translation.address, huge_frame + 0x1234u64);
+            assert!(translation.flags.contains(PageTableFlags::HUGE_PAGE));
+            assert_eq!(protect(start + 0x1000u64, PageTableFlags::empty()), Ok(()));
+            assert!(!translate(start)
+                .unwrap()
+                .flags
+                .contains(PageTableFlags::WRITABLE));
+            unmap_range(start - 4096u64, Size2MiB::SIZE + 4096).unwrap();
+            assert_eq!(translate(start - 4096u64), None);
+            assert_eq!(translate(start), None);
+            frame::free(block, 10);
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_map_mmio() {
+        serial_print!("{} test_map_mmio... ", TEST_PREFIX);
+        let vga = PhysAddr::new(0xb_8000);
+        let mapped = unsafe { map_mmio(vga + 2u64, 2) }.expect("Failed to map the VGA buffer");
+        assert!(mapped.as_u64() >= MMIO_REGION);
+        assert_eq!(mapped.as_u64() % 4096, 2);
+        let translation = translate(mapped).unwrap();
+        assert_eq!(translation.address, vga + 2u64);
+        assert!(translation.flags.contains(PageTableFlags::NO_CACHE));
+        let through_offset = memory::physical_memory_offset() + vga.as_u64() + 2u64;
+        unsafe {
+            assert_eq!(
+                mapped.as_ptr::<u16>().read_volatile(),
+                through_offset.as_ptr::<u16>().read_volatile()
+            );
+        }
+        assert_eq!(
+            unsafe {
+                map_range(
+                    VirtAddr::new(TEST_REGION + 8),
+                    vga,
+                    4096,
+                    PageTableFlags::empty(),
+                )
+            },
+            Err(PagingError::Unaligned(TEST_REGION + 8 | 0xb_8000 | 4096))
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,391 @@
+use core::{
+    convert::TryInto,
+    fmt::{self, Display, Formatter},
+    slice,
+};
+
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{Page, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use crate::{
+    gdt,
+    memory::paging::{self, PagingError},
+};
+
+/// The type of a loadable ELF program header.
+const PT_LOAD: u32 = 1;
+
+/// The ELF program header flag which marks a segment as executable.
+const PF_X: u32 = 1;
+
+/// The ELF program header flag which marks a segment as writable.
+const PF_W: u32 = 2;
+
+/// The magic number at the start of every ELF file.
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+
+/// The offset of the start of the program headers in the ELF header.
+const E_PHOFF: usize = 0x20;
+
+/// The offset of the size of each program header in the ELF header.
+const E_PHENTSIZE: usize = 0x36;
+
+/// The offset of the number of program headers in the ELF header.
+const E_PHNUM: usize = 0x38;
+
+extern "C" {
+    /// The ELF header of the kernel, which the linker places at the start of the first loadable
+    /// segment.
+    static __ehdr_start: u8;
+}
+
+/// The reason the kernel image could not be protected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProtectionError {
+    /// A page of the kernel image could not be remapped.
+    Paging(PagingError),
+    /// The page at the address holds both code and writable data, so it can't be mapped without
+    /// being both writable and executable.
+    WritableAndExecutable(VirtAddr),
+}
+
+impl From<PagingError> for ProtectionError {
+    fn from(e: PagingError) -> Self {
+        Self::Paging(e)
+    }
+}
+
+/// What a segment of the kernel image holds, which decides how it is protected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SegmentKind {
+    /// `.text`, which is read-only and executable.
+    Code,
+    /// `.rodata`, which is read-only and not executable.
+    ReadOnlyData,
+    /// `.data` and `.bss`, which are writable and not executable.
+    Data,
+}
+
+impl SegmentKind {
+    /// The flags the pages of a segment of this kind are mapped with.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            Self::Code => PageTableFlags::empty(),
+            Self::ReadOnlyData => PageTableFlags::NO_EXECUTE,
+            Self::Data => PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        }
+    }
+}
+
+impl Display for SegmentKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Code => write!(f, "kernel code (.text)"),
+            Self::ReadOnlyData => write!(f, "kernel read-only data (.rodata)"),
+            Self::Data => write!(f, "kernel data (.data/.bss)"),
+        }
+    }
+}
+
+/// A loadable segment of the kernel image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KernelSegment {
+    /// What the segment holds.
+    pub kind: SegmentKind,
+    /// The first address in the segment.
+    pub start: VirtAddr,
+    /// The address one past the end of the segment.
+    pub end: VirtAddr,
+}
+
+impl KernelSegment {
+    /// Whether `addr` is in the segment.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Whether any of `page` is in the segment.
+    fn overlaps(&self, page: Page<Size4KiB>) -> bool {
+        page.start_address() < self.end && self.start < page.start_address() + page.size()
+    }
+}
+
+/// The ELF header of the kernel and the program headers which follow it.
+fn elf_header() -> &'static [u8] {
+    unsafe {
+        let start = &__ehdr_start as *const u8;
+        let header = slice::from_raw_parts(start, E_PHNUM + 2);
+        let program_headers = read_u64(header, E_PHOFF) as usize;
+        let size = read_u16(header, E_PHENTSIZE) * read_u16(header, E_PHNUM);
+        slice::from_raw_parts(start, program_headers + size)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap()) as usize
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// The loadable segments of the kernel image, read from its program headers.
+pub fn kernel_segments() -> impl Iterator<Item = KernelSegment> {
+    let header = elf_header();
+    assert_eq!(
+        header[..4],
+        ELF_MAGIC,
+        "The kernel's ELF header is not mapped"
+    );
+    let program_headers = read_u64(header, E_PHOFF) as usize;
+    let entry_size = read_u16(header, E_PHENTSIZE);
+    (0..read_u16(header, E_PHNUM)).filter_map(move |index| {
+        let entry = &header[program_headers + index * entry_size..];
+        let memory_size = read_u64(entry, 0x28);
+        if read_u32(entry, 0) != PT_LOAD || memory_size == 0 {
+            return None;
+        }
+        let flags = read_u32(entry, 4);
+        let kind = if flags & PF_X != 0 {
+            SegmentKind::Code
+        } else if flags & PF_W != 0 {
+            SegmentKind::Data
+        } else {
+            SegmentKind::ReadOnlyData
+        };
+        let start = VirtAddr::new(read_u64(entry, 0x10));
+        Some(KernelSegment {
+            kind,
+            start,
+            end: start + memory_size,
+        })
+    })
+}
+
+/// The segment of the kernel image containing `addr`, if any.
+pub fn segment_containing(addr: VirtAddr) -> Option<KernelSegment> {
+    kernel_segments().find(|segment| segment.contains(addr))
+}
+
+/// The flags of `page`, which holds parts of segments of the given kinds: writable if any of them
+/// is writable, and executable if any of them is executable. A page which would have to be both is
+/// refused.
+fn combined_flags(
+    page: Page<Size4KiB>,
+    kinds: impl Iterator<Item = SegmentKind>,
+) -> Result<PageTableFlags, ProtectionError> {
+    let mut flags = PageTableFlags::NO_EXECUTE;
+    for kind in kinds {
+        flags |= kind.flags() & PageTableFlags::WRITABLE;
+        if kind == SegmentKind::Code {
+            flags -= PageTableFlags::NO_EXECUTE;
+        }
+    }
+    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+        Err(ProtectionError::WritableAndExecutable(page.start_address()))
+    } else {
+        Ok(flags)
+    }
+}
+
+/// The flags of a page of the kernel image.
+fn page_flags(page: Page<Size4KiB>) -> Result<PageTableFlags, ProtectionError> {
+    let kinds = kernel_segments()
+        .filter(|segment| segment.overlaps(page))
+        .map(|segment| segment.kind);
+    combined_flags(page, kinds)
+}
+
+/// Make the CPU honour the no-execute flag in page table entries and stop the kernel from writing
+/// to read-only pages. This must be done before any entry has the no-execute flag set.
+pub fn enable_nx() {
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::write(Cr0::read() | Cr0Flags::WRITE_PROTECT);
+    }
+}
+
+/// Remap the kernel image so that no page of it is both writable and executable: code is
+/// read-only, read-only data is neither writable nor executable, and data is writable but not
+/// executable. The kernel stack is made non-executable as well.
+///
+/// A page shared by code and read-only data is executable. A page shared by code and writable data
+/// would have to be writable and executable, so it is an error instead.
+pub fn protect_kernel() -> Result<(), ProtectionError> {
+    for segment in kernel_segments() {
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(segment.start),
+            Page::containing_address(segment.end - 1u64) + 1,
+        );
+        for page in pages {
+            let flags = page_flags(page)?;
+            unsafe {
+                paging::protect(page.start_address(), flags)?;
+            }
+        }
+    }
+    // The page at the bottom of the kernel stack is its guard page, which may already be unmapped.
+    let stack_bottom = gdt::KERNEL_STACK_ADDRESS + gdt::GUARD_PAGE_SIZE;
+    let stack_size = gdt::KERNEL_STACK_PAGES * 4096 - gdt::GUARD_PAGE_SIZE;
+    unsafe {
+        paging::protect_range(
+            VirtAddr::new(stack_bottom),
+            stack_size,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )?;
+    }
+    Ok(())
+}
+
+/// An access which was refused because it would have written to a read-only page or executed a
+/// non-executable one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// A write to a present, read-only page.
+    Write(Option<SegmentKind>),
+    /// An instruction fetch from a present, non-executable page.
+    Execute(Option<SegmentKind>),
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (action, segment) = match self {
+            Self::Write(segment) => ("write to read-only", segment),
+            Self::Execute(segment) => ("instruction fetch from non-executable", segment),
+        };
+        match segment {
+            Some(segment) => write!(f, "W^X violation: {} {}", action, segment),
+            None => write!(f, "W^X violation: {} memory", action),
+        }
+    }
+}
+
+/// The W^X violation which caused a page fault at `addr` with `error_code`, if it was caused by
+/// one.
+pub fn violation(addr: VirtAddr, error_code: PageFaultErrorCode) -> Option<Violation> {
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return None;
+    }
+    let segment = segment_containing(addr).map(|segment| segment.kind);
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Some(Violation::Execute(segment))
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Some(Violation::Write(segment))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::protection]";
+
+    static mut DATA: u64 = 1;
+
+    fn flags_of(addr: VirtAddr) -> PageTableFlags {
+        paging::translate(addr).unwrap().flags
+    }
+
+    #[test_case]
+    fn test_nx_enabled() {
+        serial_print!("{} test_nx_enabled... ", TEST_PREFIX);
+        assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+        assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_kernel_segments() {
+        serial_print!("{} test_kernel_segments... ", TEST_PREFIX);
+        let code = VirtAddr::new(test_kernel_segments as usize as u64);
+        let rodata = VirtAddr::from_ptr(TEST_PREFIX.as_ptr());
+        let data = VirtAddr::from_ptr(unsafe { &DATA });
+        let segment_kind = |addr| segment_containing(addr).map(|segment| segment.kind);
+        assert_eq!(segment_kind(code), Some(SegmentKind::Code));
+        assert_eq!(segment_kind(rodata), Some(SegmentKind::ReadOnlyData));
+        assert_eq!(segment_kind(data), Some(SegmentKind::Data));
+        let stack = 0u64;
+        assert_eq!(segment_kind(VirtAddr::from_ptr(&stack)), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_w_xor_x() {
+        serial_print!("{} test_w_xor_x... ", TEST_PREFIX);
+        let writable = PageTableFlags::WRITABLE;
+        let no_execute = PageTableFlags::NO_EXECUTE;
+        let code = flags_of(VirtAddr::new(test_w_xor_x as usize as u64));
+        assert!(!code.contains(writable) && !code.contains(no_execute));
+        let rodata = flags_of(VirtAddr::from_ptr(TEST_PREFIX.as_ptr()));
+        assert!(!rodata.contains(writable) && rodata.contains(no_execute));
+        let data = flags_of(VirtAddr::from_ptr(unsafe { &DATA }));
+        assert!(data.contains(writable | no_execute));
+        let stack = 0u64;
+        assert!(flags_of(VirtAddr::from_ptr(&stack)).contains(writable | no_execute));
+        for segment in kernel_segments() {
+            let mut addr = segment.start;
+            while addr < segment.end {
+                let flags = flags_of(addr);
+                assert!(!flags.contains(writable) || flags.contains(no_execute));
+                addr = addr.align_down(4096u64) + 4096u64;
+            }
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_combined_flags() {
+        serial_print!("{} test_combined_flags... ", TEST_PREFIX);
+        let page = Page::containing_address(VirtAddr::new(0x20_0000));
+        let kinds = [SegmentKind::Code, SegmentKind::ReadOnlyData];
+        assert_eq!(
+            combined_flags(page, kinds.iter().copied()),
+            Ok(PageTableFlags::empty())
+        );
+        let kinds = [SegmentKind::ReadOnlyData, SegmentKind::Data];
+        assert_eq!(
+            combined_flags(page, kinds.iter().copied()),
+            Ok(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        );
+        let kinds = [SegmentKind::Code, SegmentKind::Data];
+        assert_eq!(
+            combined_flags(page, kinds.iter().copied()),
+            Err(ProtectionError::WritableAndExecutable(page.start_address()))
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_violation() {
+        serial_print!("{} test_violation... ", TEST_PREFIX);
+        let code = VirtAddr::new(test_violation as usize as u64);
+        let data = VirtAddr::from_ptr(unsafe { &DATA });
+        let present = PageFaultErrorCode::PROTECTION_VIOLATION;
+        assert_eq!(
+            violation(code, present | PageFaultErrorCode::CAUSED_BY_WRITE),
+            Some(Violation::Write(Some(SegmentKind::Code)))
+        );
+        assert_eq!(
+            violation(data, present | PageFaultErrorCode::INSTRUCTION_FETCH),
+            Some(Violation::Execute(Some(SegmentKind::Data)))
+        );
+        assert_eq!(violation(code, PageFaultErrorCode::CAUSED_BY_WRITE), None);
+        assert_eq!(violation(data, present), None);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,701 @@
+use core::{
+    fmt::{self, Display, Formatter},
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+    slice,
+};
+
+use spin::Mutex;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts, structures::paging::PhysFrame, PhysAddr, VirtAddr,
+};
+
+use crate::memory::{
+    self,
+    frame::{self, Zone, FRAME_SIZE},
+    heap::align_up,
+};
+
+/// The maximum number of caches that are listed by [`dump`]. Caches created after the limit is
+/// reached still work but aren't listed.
+///
+/// [`dump`]: fn.dump.html
+pub const MAX_CACHES: usize = 32;
+
+/// The number of bytes of red zone on each side of an object in a cache with red zones.
+pub const RED_ZONE_SIZE: usize = 8;
+
+/// The byte that red zones are filled with.
+pub const RED_ZONE_BYTE: u8 = 0xBB;
+
+/// The byte that free objects are filled with in a cache with poisoning.
+pub const POISON_BYTE: u8 = 0x6B;
+
+/// The largest order of block from the frame allocator that a slab can be.
+const MAX_SLAB_ORDER: usize = 3;
+
+/// The number of objects a slab should hold. Slabs are made larger until they hold this many
+/// objects or reach [`MAX_SLAB_ORDER`].
+///
+/// [`MAX_SLAB_ORDER`]: constant.MAX_SLAB_ORDER.html
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// A function which puts a new object into its initial state.
+pub type Constructor = fn(*mut u8);
+
+/// The checks a cache makes to catch misuse of its objects.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DebugOptions {
+    /// Surround every object with bytes of [`RED_ZONE_BYTE`] and check that they are unchanged
+    /// when the object is freed, to catch writes past either end of the object.
+    ///
+    /// [`RED_ZONE_BYTE`]: constant.RED_ZONE_BYTE.html
+    pub red_zone: bool,
+    /// Fill every freed object with [`POISON_BYTE`] and check that it is unchanged when the
+    /// object is allocated again, to catch writes to freed objects. The constructor of the cache
+    /// is run again on every allocation, since the poison overwrites the constructed state.
+    ///
+    /// [`POISON_BYTE`]: constant.POISON_BYTE.html
+    pub poison: bool,
+}
+
+impl DebugOptions {
+    /// No checks.
+    pub const NONE: Self = Self {
+        red_zone: false,
+        poison: false,
+    };
+
+    /// Every check.
+    pub const ALL: Self = Self {
+        red_zone: true,
+        poison: true,
+    };
+
+    fn any(self) -> bool {
+        self.red_zone || self.poison
+    }
+}
+
+/// The header at the start of every slab. It is followed by a stack of the indices of the free
+/// objects in the slab, and then by the objects.
+#[repr(C)]
+struct SlabHeader {
+    next: *mut SlabHeader,
+    previous: *mut SlabHeader,
+    in_use: usize,
+}
+
+/// How a cache lays out its objects in a slab.
+#[derive(Clone, Copy, Debug)]
+struct Geometry {
+    order: usize,
+    capacity: usize,
+    /// The offset of the first slot from the start of the slab.
+    first_slot: usize,
+    /// The distance from the start of one slot to the start of the next.
+    slot_size: usize,
+    /// The offset of the object from the start of its slot, which leaves room for the red zone.
+    object_offset: usize,
+}
+
+impl Geometry {
+    fn new(size: usize, align: usize, debug: DebugOptions) -> Self {
+        let red_zone = if debug.red_zone { RED_ZONE_SIZE } else { 0 };
+        let align = align.max(align_of::<u16>());
+        let object_offset = align_up(red_zone, align);
+        let slot_size = align_up(object_offset + size.max(1) + red_zone, align);
+        for order in 0..=MAX_SLAB_ORDER {
+            let slab_size = (FRAME_SIZE as usize) << order;
+            let first_slot =
+                |capacity| align_up(size_of::<SlabHeader>() + capacity * size_of::<u16>(), align);
+            let mut capacity = slab_size / slot_size;
+            while capacity > 0 && first_slot(capacity) + capacity * slot_size > slab_size {
+                capacity -= 1;
+            }
+            if capacity >= MIN_OBJECTS_PER_SLAB || (capacity > 0 && order == MAX_SLAB_ORDER) {
+                return Self {
+                    order,
+                    capacity: capacity.min(u16::max_value() as usize),
+                    first_slot: first_slot(capacity),
+                    slot_size,
+                    object_offset,
+                };
+            }
+        }
+        panic!("Objects of {} bytes are too large for a slab", size)
+    }
+
+    fn slab_size(&self) -> usize {
+        (FRAME_SIZE as usize) << self.order
+    }
+}
+
+/// The usage of a single cache.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CacheStats {
+    /// The name of the cache.
+    pub name: &'static str,
+    /// The size of each object in bytes.
+    pub object_size: usize,
+    /// The size of each slab in bytes.
+    pub slab_size: usize,
+    /// The number of objects each slab holds.
+    pub objects_per_slab: usize,
+    /// The number of slabs whose objects are all in use.
+    pub full_slabs: usize,
+    /// The number of slabs with some objects in use.
+    pub partial_slabs: usize,
+    /// The number of slabs with no objects in use.
+    pub empty_slabs: usize,
+    /// The number of objects in use.
+    pub objects_in_use: usize,
+    /// The number of successful allocations.
+    pub allocations: usize,
+    /// The number of frees.
+    pub frees: usize,
+    /// The number of allocations which failed because no frames were free.
+    pub failures: usize,
+}
+
+impl CacheStats {
+    /// The total number of slabs in the cache.
+    pub fn slabs(&self) -> usize {
+        self.full_slabs + self.partial_slabs + self.empty_slabs
+    }
+
+    /// The number of objects the slabs of the cache can hold.
+    pub fn total_objects(&self) -> usize {
+        self.slabs() * self.objects_per_slab
+    }
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<20} {:>6} {:>5} {:>5}/{:<5} {:>3}/{:>3}/{:>3} {:>9} {:>9} {:>5}",
+            self.name,
+            self.object_size,
+            self.objects_per_slab,
+            self.objects_in_use,
+            self.total_objects(),
+            self.full_slabs,
+            self.partial_slabs,
+            self.empty_slabs,
+            self.allocations,
+            self.frees,
+            self.failures,
+        )
+    }
+}
+
+struct CacheState {
+    geometry: Option<Geometry>,
+    partial: *mut SlabHeader,
+    full: *mut SlabHeader,
+    empty: *mut SlabHeader,
+    registered: bool,
+    stats: CacheStats,
+}
+
+// The slab lists point into frames that the cache took from the frame allocator for itself, and
+// the state is only used behind the cache's lock.
+unsafe impl Send for CacheState {}
+
+/// A cache of objects of a single size and alignment. Objects are carved out of slabs of
+/// contiguous frames taken from the frame allocator, so allocating and freeing them is cheap and
+/// doesn't fragment the kernel heap.
+///
+/// Slabs with free objects are preferred over empty ones so that objects are packed together,
+/// and at most one empty slab is kept, the rest going back to the frame allocator.
+pub struct SlabCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    constructor: Option<Constructor>,
+    debug: DebugOptions,
+    state: Mutex<CacheState>,
+}
+
+impl SlabCache {
+    /// Create a cache named `name` of objects of `size` bytes aligned to `align`, which must be a
+    /// power of two. If there is a `constructor`, every object is passed to it before it is
+    /// first allocated. No memory is taken until the first allocation.
+    pub const fn new(
+        name: &'static str,
+        size: usize,
+        align: usize,
+        constructor: Option<Constructor>,
+        debug: DebugOptions,
+    ) -> Self {
+        Self {
+            name,
+            size,
+            align,
+            constructor,
+            debug,
+            state: Mutex::new(CacheState {
+                geometry: None,
+                partial: ptr::null_mut(),
+                full: ptr::null_mut(),
+                empty: ptr::null_mut(),
+                registered: false,
+                stats: CacheStats {
+                    name,
+                    object_size: size,
+                    slab_size: 0,
+                    objects_per_slab: 0,
+                    full_slabs: 0,
+                    partial_slabs: 0,
+                    empty_slabs: 0,
+                    objects_in_use: 0,
+                    allocations: 0,
+                    frees: 0,
+                    failures: 0,
+                },
+            }),
+        }
+    }
+
+    /// Create a cache named `name` of objects of type `T`.
+    pub const fn for_type<T>(
+        name: &'static str,
+        constructor: Option<Constructor>,
+        debug: DebugOptions,
+    ) -> Self {
+        Self::new(name, size_of::<T>(), align_of::<T>(), constructor, debug)
+    }
+
+    /// The name of the cache.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Allocate an object, or return `None` if a new slab is needed and no frames are free.
+    pub fn allocate(&'static self) -> Option<NonNull<u8>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if !state.registered {
+                register(self);
+                state.registered = true;
+            }
+            let geometry = *state
+                .geometry
+                .get_or_insert_with(|| Geometry::new(self.size, self.align, self.debug));
+            let slab = match self.slab_with_free_object(&mut state, &geometry) {
+                Some(slab) => slab,
+                None => {
+                    state.stats.failures += 1;
+                    return None;
+                }
+            };
+            unsafe {
+                let old_in_use = (*slab).in_use;
+                let index = *free_stack(slab).add(geometry.capacity - old_in_use - 1) as usize;
+                self.move_slab(&mut state, &geometry, slab, old_in_use, old_in_use + 1);
+                let object = object_at(slab, &geometry, index);
+                if self.debug.poison {
+                    self.check_poison(object);
+                    if let Some(constructor) = self.constructor {
+                        constructor(object);
+                    }
+                }
+                state.stats.allocations += 1;
+                state.stats.objects_in_use += 1;
+                Some(NonNull::new_unchecked(object))
+            }
+        })
+    }
+
+    /// Return an object to the cache.
+    ///
+    /// # Safety
+    /// `object` must have been allocated from this cache and must not be used afterwards.
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let geometry = state
+                .geometry
+                .expect("Freed an object to a cache which never allocated");
+            let object = object.as_ptr();
+            let slab = (object as usize & !(geometry.slab_size() - 1)) as *mut SlabHeader;
+            let offset = (object as usize - slab as usize)
+                .checked_sub(geometry.first_slot + geometry.object_offset)
+                .filter(|offset| offset % geometry.slot_size == 0)
+                .filter(|offset| offset / geometry.slot_size < geometry.capacity);
+            let index = match offset {
+                Some(offset) => offset / geometry.slot_size,
+                None => panic!("{:p} is not an object of the {} cache", object, self.name),
+            };
+            let in_use = (*slab).in_use;
+            if self.debug.any() {
+                let free = slice::from_raw_parts(free_stack(slab), geometry.capacity - in_use);
+                assert!(
+                    !free.contains(&(index as u16)),
+                    "Object {:p} of the {} cache was freed twice",
+                    object,
+                    self.name
+                );
+            }
+            if self.debug.red_zone {
+                self.check_red_zones(&geometry, object);
+            }
+            if self.debug.poison {
+                ptr::write_bytes(object, POISON_BYTE, self.size);
+            }
+            *free_stack(slab).add(geometry.capacity - in_use) = index as u16;
+            self.move_slab(&mut state, &geometry, slab, in_use, in_use - 1);
+            state.stats.frees += 1;
+            state.stats.objects_in_use -= 1;
+            // Keep one empty slab for the next allocation and give the rest back.
+            if in_use == 1 && !(*slab).next.is_null() {
+                self.release_slab(&mut state, &geometry, slab);
+            }
+        })
+    }
+
+    /// Give every empty slab back to the frame allocator. Returns the number of slabs released.
+    pub fn shrink(&self) -> usize {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let geometry = match state.geometry {
+                Some(geometry) => geometry,
+                None => return 0,
+            };
+            let mut released = 0;
+            while !state.empty.is_null() {
+                let slab = state.empty;
+                unsafe {
+                    self.release_slab(&mut state, &geometry, slab);
+                }
+                released += 1;
+            }
+            released
+        })
+    }
+
+    /// The current usage of the cache.
+    pub fn stats(&self) -> CacheStats {
+        without_interrupts(|| self.state.lock().stats)
+    }
+
+    /// Find a slab with a free object, preferring partially used slabs, and creating a slab if
+    /// there is none.
+    fn slab_with_free_object(
+        &self,
+        state: &mut CacheState,
+        geometry: &Geometry,
+    ) -> Option<*mut SlabHeader> {
+        if !state.partial.is_null() {
+            return Some(state.partial);
+        }
+        if !state.empty.is_null() {
+            return Some(state.empty);
+        }
+        let frame = frame::allocate(geometry.order, Zone::Normal)?;
+        let slab = (memory::physical_memory_offset() + frame.start_address().as_u64())
+            .as_mut_ptr::<SlabHeader>();
+        assert_eq!(
+            slab as usize % geometry.slab_size(),
+            0,
+            "Physical memory is not mapped at an offset aligned to the size of a slab"
+        );
+        unsafe {
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                previous: ptr::null_mut(),
+                in_use: 0,
+            });
+            for index in 0..geometry.capacity {
+                // Hand out the lowest objects first.
+                *free_stack(slab).add(geometry.capacity - index - 1) = index as u16;
+                let object = object_at(slab, geometry, index);
+                if self.debug.red_zone {
+                    let slot = object.sub(geometry.object_offset);
+                    ptr::write_bytes(slot, RED_ZONE_BYTE, geometry.slot_size);
+                }
+                if self.debug.poison {
+                    ptr::write_bytes(object, POISON_BYTE, self.size);
+                } else if let Some(constructor) = self.constructor {
+                    constructor(object);
+                }
+            }
+            push(&mut state.empty, slab);
+        }
+        state.stats.empty_slabs += 1;
+        state.stats.slab_size = geometry.slab_size();
+        state.stats.objects_per_slab = geometry.capacity;
+        Some(slab)
+    }
+
+    /// Move `slab` to the list for its new number of objects in use.
+    unsafe fn move_slab(
+        &self,
+        state: &mut CacheState,
+        geometry: &Geometry,
+        slab: *mut SlabHeader,
+        old_in_use: usize,
+        new_in_use: usize,
+    ) {
+        let old_list = Self::list(state, geometry, old_in_use);
+        unlink(old_list, slab);
+        *Self::count(state, geometry, old_in_use) -= 1;
+        (*slab).in_use = new_in_use;
+        let new_list = Self::list(state, geometry, new_in_use);
+        push(new_list, slab);
+        *Self::count(state, geometry, new_in_use) += 1;
+    }
+
+    fn list<'a>(
+        state: &'a mut CacheState,
+        geometry: &Geometry,
+        in_use: usize,
+    ) -> &'a mut *mut SlabHeader {
+        match in_use {
+            0 => &mut state.empty,
+            _ if in_use == geometry.capacity => &mut state.full,
+            _ => &mut state.partial,
+        }
+    }
+
+    fn count<'a>(state: &'a mut CacheState, geometry: &Geometry, in_use: usize) -> &'a mut usize {
+        match in_use {
+            0 => &mut state.stats.empty_slabs,
+            _ if in_use == geometry.capacity => &mut state.stats.full_slabs,
+            _ => &mut state.stats.partial_slabs,
+        }
+    }
+
+    /// Take the empty slab `slab` out of the cache and give it back to the frame allocator.
+    unsafe fn release_slab(
+        &self,
+        state: &mut CacheState,
+        geometry: &Geometry,
+        slab: *mut SlabHeader,
+    ) {
+        unlink(&mut state.empty, slab);
+        state.stats.empty_slabs -= 1;
+        let address = VirtAddr::from_ptr(slab) - memory::physical_memory_offset();
+        frame::free(
+            PhysFrame::containing_address(PhysAddr::new(address)),
+            geometry.order,
+        );
+    }
+
+    unsafe fn check_red_zones(&self, geometry: &Geometry, object: *mut u8) {
+        let slot = object.sub(geometry.object_offset);
+        let before = slice::from_raw_parts(slot, geometry.object_offset);
+        let after = slice::from_raw_parts(
+            object.add(self.size),
+            geometry.slot_size - geometry.object_offset - self.size,
+        );
+        if let Some(offset) = before.iter().rposition(|&byte| byte != RED_ZONE_BYTE) {
+            panic!(
+                "Red zone before object {:p} of the {} cache was overwritten {} bytes before it",
+                object,
+                self.name,
+                geometry.object_offset - offset,
+            );
+        }
+        if let Some(offset) = after.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+            panic!(
+                "Red zone after object {:p} of the {} cache was overwritten {} bytes after it",
+                object, self.name, offset,
+            );
+        }
+    }
+
+    unsafe fn check_poison(&self, object: *mut u8) {
+        let bytes = slice::from_raw_parts(object, self.size);
+        if let Some(offset) = bytes.iter().position(|&byte| byte != POISON_BYTE) {
+            panic!(
+                "Object {:p} of the {} cache was written at offset {} after it was freed",
+                object, self.name, offset,
+            );
+        }
+    }
+}
+
+/// The stack of the indices of the free objects in `slab`. The top of the stack is at index
+/// `capacity - in_use - 1`.
+unsafe fn free_stack(slab: *mut SlabHeader) -> *mut u16 {
+    slab.add(1) as *mut u16
+}
+
+/// The `index`th object in `slab`.
+unsafe fn object_at(slab: *mut SlabHeader, geometry: &Geometry, index: usize) -> *mut u8 {
+    (slab as *mut u8).add(geometry.first_slot + index * geometry.slot_size + geometry.object_offset)
+}
+
+/// Add `slab` to the front of the list starting at `head`.
+unsafe fn push(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    (*slab).previous = ptr::null_mut();
+    (*slab).next = *head;
+    if !head.is_null() {
+        (**head).previous = slab;
+    }
+    *head = slab;
+}
+
+/// Take `slab` out of the list starting at `head`.
+unsafe fn unlink(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    if (*slab).previous.is_null() {
+        *head = (*slab).next;
+    } else {
+        (*(*slab).previous).next = (*slab).next;
+    }
+    if !(*slab).next.is_null() {
+        (*(*slab).next).previous = (*slab).previous;
+    }
+}
+
+/// Every cache which has allocated an object, in the order they first did so.
+static CACHES: Mutex<[Option<&'static SlabCache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+/// Add `cache` to the list of caches if there is room for it.
+fn register(cache: &'static SlabCache) {
+    let mut caches = CACHES.lock();
+    if let Some(slot) = caches.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(cache);
+    }
+}
+
+/// Get an iterator over every cache which has allocated an object.
+pub fn caches() -> impl Iterator<Item = &'static SlabCache> {
+    let caches = without_interrupts(|| *CACHES.lock());
+    (0..MAX_CACHES).filter_map(move |index| caches[index])
+}
+
+/// Write the statistics of every cache to the first serial port, one line per cache. The used and
+/// total object counts together with the number of partially used slabs show how fragmented
+/// each cache is. Typing `slabs` into the first serial port runs this.
+pub fn dump() {
+    serial_println!(
+        "{:<20} {:>6} {:>5} {:>11} {:>11} {:>9} {:>9} {:>5}",
+        "cache",
+        "size",
+        "/slab",
+        "used/total",
+        "full/part/e",
+        "allocs",
+        "frees",
+        "fails",
+    );
+    for cache in caches() {
+        serial_println!("{}", cache.stats());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use alloc::vec::Vec;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::slab]";
+
+    fn construct(object: *mut u8) {
+        unsafe { (object as *mut u64).write_unaligned(0xC0FF_EE00) }
+    }
+
+    static WORDS: SlabCache =
+        SlabCache::for_type::<u64>("test words", Some(construct), DebugOptions::NONE);
+
+    static PAGES: SlabCache = SlabCache::new("test pages", 1024, 1024, None, DebugOptions::NONE);
+
+    static CHECKED: SlabCache =
+        SlabCache::new("test checked", 20, 4, Some(construct), DebugOptions::ALL);
+
+    #[test_case]
+    fn test_allocate_and_free() {
+        serial_print!("{} test_allocate_and_free... ", TEST_PREFIX);
+        let first = WORDS.allocate().unwrap();
+        let second = WORDS.allocate().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.as_ptr() as usize % align_of::<u64>(), 0);
+        unsafe {
+            assert_eq!(*(first.as_ptr() as *const u64), 0xC0FF_EE00);
+            assert_eq!(*(second.as_ptr() as *const u64), 0xC0FF_EE00);
+        }
+        let stats = WORDS.stats();
+        assert_eq!(stats.objects_in_use, 2);
+        assert_eq!(stats.partial_slabs, 1);
+        assert_eq!(stats.slab_size, FRAME_SIZE as usize);
+        unsafe {
+            WORDS.free(first);
+        }
+        // The most recently freed object is handed out first.
+        assert_eq!(WORDS.allocate(), Some(first));
+        unsafe {
+            WORDS.free(first);
+            WORDS.free(second);
+        }
+        let stats = WORDS.stats();
+        assert_eq!(
+            (stats.objects_in_use, stats.empty_slabs, stats.slabs()),
+            (0, 1, 1)
+        );
+        assert!(caches().any(|cache| cache.name() == "test words"));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_grow_and_shrink() {
+        serial_print!("{} test_grow_and_shrink... ", TEST_PREFIX);
+        let mut objects = Vec::new();
+        objects.push(PAGES.allocate().unwrap());
+        let per_slab = PAGES.stats().objects_per_slab;
+        assert!(per_slab >= MIN_OBJECTS_PER_SLAB);
+        while objects.len() <= per_slab {
+            objects.push(PAGES.allocate().unwrap());
+        }
+        assert!(objects
+            .iter()
+            .all(|object| object.as_ptr() as usize % 1024 == 0));
+        let stats = PAGES.stats();
+        assert_eq!((stats.full_slabs, stats.partial_slabs), (1, 1));
+        for object in objects {
+            unsafe {
+                PAGES.free(object);
+            }
+        }
+        // One empty slab is kept and the other is given back.
+        assert_eq!(PAGES.stats().slabs(), 1);
+        let free_frames = frame::stats().free_frames();
+        assert_eq!(PAGES.shrink(), 1);
+        assert_eq!(PAGES.stats().slabs(), 0);
+        assert_eq!(
+            frame::stats().free_frames(),
+            free_frames + stats.slab_size as u64 / FRAME_SIZE
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_red_zones_and_poison() {
+        serial_print!("{} test_red_zones_and_poison... ", TEST_PREFIX);
+        let object = CHECKED.allocate().unwrap().as_ptr();
+        unsafe {
+            assert_eq!((object as *const u64).read_unaligned(), 0xC0FF_EE00);
+            let before = slice::from_raw_parts(object.sub(RED_ZONE_SIZE), RED_ZONE_SIZE);
+            let after = slice::from_raw_parts(object.add(20), RED_ZONE_SIZE);
+            assert!(before
+                .iter()
+                .chain(after)
+                .all(|&byte| byte == RED_ZONE_BYTE));
+            // Writing all of the object is fine.
+            ptr::write_bytes(object, 0, 20);
+            CHECKED.free(NonNull::new_unchecked(object));
+            let freed = slice::from_raw_parts(object, 20);
+            assert!(freed.iter().all(|&byte| byte == POISON_BYTE));
+            // The constructor runs again once the poison has been checked.
+            let again = CHECKED.allocate().unwrap().as_ptr();
+            assert_eq!(again, object);
+            assert_eq!((again as *const u64).read_unaligned(), 0xC0FF_EE00);
+            CHECKED.free(NonNull::new_unchecked(again));
+        }
+        serial_println!("[ok]");
+    }
+}
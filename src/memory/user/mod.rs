@@ -0,0 +1,390 @@
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    fmt::{self, Display, Formatter},
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    ptr, slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::control::{Cr4, Cr4Flags},
+    structures::{idt::PageFaultErrorCode, paging::PageTableFlags},
+    VirtAddr,
+};
+
+use crate::memory::paging;
+
+/// The address one past the end of user space, which is the lower half of the address space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// The bit of `ebx` in CPUID leaf 7 which is set if SMEP is supported.
+const CPUID_SMEP: u32 = 1 << 7;
+
+/// The bit of `ebx` in CPUID leaf 7 which is set if SMAP is supported.
+const CPUID_SMAP: u32 = 1 << 20;
+
+/// Whether SMAP has been enabled, so that `stac` and `clac` can be used.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The protections against the kernel touching user memory which the CPU supports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Support {
+    /// Supervisor Mode Execution Prevention, which stops the kernel from executing user pages.
+    pub smep: bool,
+    /// Supervisor Mode Access Prevention, which stops the kernel from reading or writing user
+    /// pages unless it sets the AC flag.
+    pub smap: bool,
+}
+
+/// Detect which of SMEP and SMAP the CPU supports.
+pub fn support() -> Support {
+    unsafe {
+        if __cpuid(0).eax < 7 {
+            return Support {
+                smep: false,
+                smap: false,
+            };
+        }
+        let features = __cpuid_count(7, 0).ebx;
+        Support {
+            smep: features & CPUID_SMEP != 0,
+            smap: features & CPUID_SMAP != 0,
+        }
+    }
+}
+
+/// Enable SMEP and SMAP if the CPU supports them and return which were enabled.
+pub fn init() -> Support {
+    let support = support();
+    let mut flags = Cr4::read();
+    if support.smep {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if support.smap {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    unsafe {
+        Cr4::write(flags);
+    }
+    SMAP_ENABLED.store(support.smap, Ordering::SeqCst);
+    support
+}
+
+/// Which protections against touching user memory are enabled.
+pub fn enabled() -> Support {
+    let flags = Cr4::read();
+    Support {
+        smep: flags.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+        smap: flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+    }
+}
+
+/// Run `f` with the AC flag set, so that it may touch user pages even with SMAP enabled.
+/// Interrupts are disabled meanwhile so that no handler runs with the flag set.
+fn with_user_access<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    if !SMAP_ENABLED.load(Ordering::SeqCst) {
+        return f();
+    }
+    without_interrupts(|| unsafe {
+        asm!("stac", options(nostack));
+        let result = f();
+        asm!("clac", options(nostack));
+        result
+    })
+}
+
+/// The reason an access to user memory was refused.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UserAccessError {
+    /// The address is not in user space.
+    NotUserAddress(VirtAddr),
+    /// The address is not aligned for the type it points to.
+    Unaligned(VirtAddr),
+    /// The page containing the address is not mapped as user accessible.
+    NotMapped(VirtAddr),
+    /// The page containing the address is not writable.
+    ReadOnly(VirtAddr),
+}
+
+/// Check that every page of the `size` bytes from `addr` is in user space and is mapped for user
+/// access, and writable if `write` is set.
+fn check_range(addr: VirtAddr, size: usize, write: bool) -> Result<(), UserAccessError> {
+    let end = addr
+        .as_u64()
+        .checked_add(size as u64)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(UserAccessError::NotUserAddress(addr))?;
+    let mut page = addr.align_down(4096u64);
+    while page.as_u64() < end {
+        let fault_address = page.max(addr);
+        let flags = paging::translate(page)
+            .map(|translation| translation.flags)
+            .filter(|flags| flags.contains(PageTableFlags::USER_ACCESSIBLE))
+            .ok_or(UserAccessError::NotMapped(fault_address))?;
+        if write && !flags.contains(PageTableFlags::WRITABLE) {
+            return Err(UserAccessError::ReadOnly(fault_address));
+        }
+        page += 4096u64;
+    }
+    Ok(())
+}
+
+/// Copy `dest.len()` bytes from user memory at `src` into `dest`.
+pub fn copy_from_user(dest: &mut [u8], src: VirtAddr) -> Result<(), UserAccessError> {
+    check_range(src, dest.len(), false)?;
+    with_user_access(|| unsafe {
+        ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dest.as_mut_ptr(), dest.len());
+    });
+    Ok(())
+}
+
+/// Copy `src` into user memory at `dest`.
+pub fn copy_to_user(dest: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+    check_range(dest, src.len(), true)?;
+    with_user_access(|| unsafe {
+        ptr::copy_nonoverlapping(src.as_ptr(), dest.as_mut_ptr::<u8>(), src.len());
+    });
+    Ok(())
+}
+
+/// A pointer to a `T` in user memory. The pointer is only checked to be in user space when it is
+/// created; every access checks that the memory is mapped.
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    addr: VirtAddr,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Copy> UserPtr<T> {
+    /// A pointer to the `T` at `addr`, which must be in user space and aligned for `T`.
+    pub fn new(addr: VirtAddr) -> Result<Self, UserAccessError> {
+        let in_user_space = addr
+            .as_u64()
+            .checked_add(mem::size_of::<T>() as u64)
+            .map_or(false, |end| end <= USER_SPACE_END);
+        if !in_user_space {
+            Err(UserAccessError::NotUserAddress(addr))
+        } else if !addr.is_aligned(mem::align_of::<T>() as u64) {
+            Err(UserAccessError::Unaligned(addr))
+        } else {
+            Ok(Self {
+                addr,
+                _marker: PhantomData,
+            })
+        }
+    }
+
+    /// The address the pointer points to.
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    /// Copy the value out of user memory.
+    ///
+    /// # Safety
+    /// User code can write any bytes to its memory, so every bit pattern must be a valid `T`.
+    pub unsafe fn read(&self) -> Result<T, UserAccessError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>());
+        copy_from_user(bytes, self.addr)?;
+        Ok(value.assume_init())
+    }
+
+    /// Copy `value` into user memory.
+    pub fn write(&self, value: T) -> Result<(), UserAccessError> {
+        let bytes =
+            unsafe { slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>()) };
+        copy_to_user(self.addr, bytes)
+    }
+}
+
+/// An access by the kernel to a user page which SMEP or SMAP refused.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// The kernel fetched an instruction from a user page.
+    Smep,
+    /// The kernel read a user page without going through [`copy_from_user`].
+    ///
+    /// [`copy_from_user`]: fn.copy_from_user.html
+    SmapRead,
+    /// The kernel wrote to a user page without going through [`copy_to_user`].
+    ///
+    /// [`copy_to_user`]: fn.copy_to_user.html
+    SmapWrite,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Smep => write!(f, "SMEP violation: the kernel executed a user page"),
+            Self::SmapRead => write!(
+                f,
+                "SMAP violation: the kernel read a user page outside of copy_from_user"
+            ),
+            Self::SmapWrite => write!(
+                f,
+                "SMAP violation: the kernel wrote to a user page outside of copy_to_user"
+            ),
+        }
+    }
+}
+
+/// The SMEP or SMAP violation which caused a page fault at `addr` with `error_code`, if it was
+/// caused by one.
+pub fn violation(addr: VirtAddr, error_code: PageFaultErrorCode) -> Option<Violation> {
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        || !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        return None;
+    }
+    let flags = paging::translate(addr)?.flags;
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return None;
+    }
+    let enabled = enabled();
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Some(Violation::Smep).filter(|_| enabled.smep)
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Some(Violation::SmapWrite).filter(|_| enabled.smap)
+    } else {
+        Some(Violation::SmapRead).filter(|_| enabled.smap)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use x86_64::structures::paging::Page;
+
+    use crate::memory::frame;
+
+    const TEST_PREFIX: &'static str = "[rust_os::memory::user]";
+
+    /// A user address which the tests map.
+    const TEST_PAGE: u64 = 0x0000_4000_0000_0000;
+
+    /// Map a writable user page at [`TEST_PAGE`] and one read-only user page after it, run `f`
+    /// and unmap them.
+    ///
+    /// [`TEST_PAGE`]: constant.TEST_PAGE.html
+    fn with_user_pages<F: FnOnce(VirtAddr)>(f: F) {
+        let start = VirtAddr::new(TEST_PAGE);
+        let user = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        let pages = [
+            (start, user | PageTableFlags::WRITABLE),
+            (start + 4096u64, user),
+        ];
+        for &(addr, flags) in &pages {
+            let frame = frame::allocate_frame().unwrap();
+            unsafe {
+                paging::map(Page::containing_address(addr), frame, flags).unwrap();
+            }
+        }
+        f(start);
+        for &(addr, _) in &pages {
+            unsafe {
+                let frame = paging::unmap(Page::containing_address(addr)).unwrap();
+                frame::free(frame, 0);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_enabled() {
+        serial_print!("{} test_enabled... ", TEST_PREFIX);
+        assert_eq!(enabled(), support());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_copy() {
+        serial_print!("{} test_copy... ", TEST_PREFIX);
+        with_user_pages(|start| {
+            let data = *b"user data";
+            copy_to_user(start + 4090u64, &data[..6]).unwrap();
+            let mut read = [0; 9];
+            assert_eq!(
+                copy_to_user(start + 4090u64, &data),
+                Err(UserAccessError::ReadOnly(start + 4096u64))
+            );
+            copy_from_user(&mut read[..6], start + 4090u64).unwrap();
+            assert_eq!(read[..6], data[..6]);
+            assert_eq!(
+                copy_from_user(&mut read, start + 8189u64),
+                Err(UserAccessError::NotMapped(start + 8192u64))
+            );
+            let kernel = VirtAddr::from_ptr(&read);
+            assert_eq!(
+                copy_from_user(&mut read, kernel),
+                Err(UserAccessError::NotUserAddress(kernel))
+            );
+        });
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_user_ptr() {
+        serial_print!("{} test_user_ptr... ", TEST_PREFIX);
+        with_user_pages(|start| {
+            let pointer = UserPtr::<u64>::new(start + 8u64).unwrap();
+            pointer.write(0x1234_5678).unwrap();
+            let read_only = UserPtr::<u64>::new(start + 4096u64).unwrap();
+            unsafe {
+                assert_eq!(pointer.read(), Ok(0x1234_5678));
+                assert!(read_only.read().is_ok());
+            }
+            assert_eq!(
+                read_only.write(1),
+                Err(UserAccessError::ReadOnly(start + 4096u64))
+            );
+            assert_eq!(
+                UserPtr::<u64>::new(start + 4u64).map(|pointer| pointer.addr()),
+                Err(UserAccessError::Unaligned(start + 4u64))
+            );
+            let end = VirtAddr::new(USER_SPACE_END - 4);
+            assert_eq!(
+                UserPtr::<u64>::new(end).map(|pointer| pointer.addr()),
+                Err(UserAccessError::NotUserAddress(end))
+            );
+        });
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_violation() {
+        serial_print!("{} test_violation... ", TEST_PREFIX);
+        with_user_pages(|start| {
+            let present = PageFaultErrorCode::PROTECTION_VIOLATION;
+            let smap = if enabled().smap {
+                Some(Violation::SmapWrite)
+            } else {
+                None
+            };
+            assert_eq!(
+                violation(start, present | PageFaultErrorCode::CAUSED_BY_WRITE),
+                smap
+            );
+            assert_eq!(
+                violation(start, present | PageFaultErrorCode::USER_MODE),
+                None
+            );
+            let kernel = VirtAddr::new(test_violation as usize as u64);
+            assert_eq!(violation(kernel, present), None);
+        });
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,335 @@
+use core::convert::TryFrom;
+
+use spin::Mutex;
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// The vector that IRQ 0 is remapped to. The first 32 vectors are reserved for CPU exceptions, so
+/// the IRQs are placed immediately after them.
+pub const PIC_1_OFFSET: u8 = 0x20;
+
+/// The vector that IRQ 8 is remapped to.
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// A hardware interrupt line of the chained 8259 PICs. Lines 0 through 7 belong to the primary PIC
+/// and lines 8 through 15 belong to the secondary PIC, which is connected to line 2 of the primary
+/// PIC.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Irq {
+    /// The Programmable Interval Timer.
+    Timer = 0,
+    /// The PS/2 keyboard.
+    Keyboard = 1,
+    /// The secondary PIC. This line never raises an interrupt of its own.
+    Cascade = 2,
+    /// The second serial port (`COM2`), and usually also the fourth serial port (`COM4`).
+    Com2 = 3,
+    /// The first serial port (`COM1`), and usually also the third serial port (`COM3`).
+    Com1 = 4,
+    /// The second parallel port (`LPT2`), or a sound card.
+    Lpt2 = 5,
+    /// The floppy disk controller.
+    Floppy = 6,
+    /// The first parallel port (`LPT1`). Spurious interrupts from the primary PIC are also
+    /// delivered on this line.
+    Lpt1 = 7,
+    /// The CMOS real-time clock.
+    Rtc = 8,
+    /// A line left free for peripherals, often used by ACPI.
+    Free9 = 9,
+    /// A line left free for peripherals.
+    Free10 = 10,
+    /// A line left free for peripherals.
+    Free11 = 11,
+    /// The PS/2 mouse.
+    Mouse = 12,
+    /// The FPU, coprocessor, or inter-processor interrupts.
+    Fpu = 13,
+    /// The primary ATA channel.
+    PrimaryAta = 14,
+    /// The secondary ATA channel. Spurious interrupts from the secondary PIC are also delivered on
+    /// this line.
+    SecondaryAta = 15,
+}
+
+impl Irq {
+    /// Get an iterator over every interrupt line, in order of line number.
+    pub fn lines() -> impl Iterator<Item = Self> {
+        [
+            Self::Timer,
+            Self::Keyboard,
+            Self::Cascade,
+            Self::Com2,
+            Self::Com1,
+            Self::Lpt2,
+            Self::Floppy,
+            Self::Lpt1,
+            Self::Rtc,
+            Self::Free9,
+            Self::Free10,
+            Self::Free11,
+            Self::Mouse,
+            Self::Fpu,
+            Self::PrimaryAta,
+            Self::SecondaryAta,
+        ]
+        .iter()
+        .copied()
+    }
+
+    /// The line number of the interrupt.
+    pub fn line(&self) -> u8 {
+        *self as u8
+    }
+
+    /// The vector in the Interrupt Descriptor Table that the interrupt is delivered on once the
+    /// PICs have been remapped.
+    pub fn vector(&self) -> u8 {
+        PIC_1_OFFSET + self.line()
+    }
+
+    /// Whether the interrupt is handled by the secondary PIC.
+    pub fn is_secondary(&self) -> bool {
+        self.line() >= 8
+    }
+}
+
+impl TryFrom<u8> for Irq {
+    type Error = u8;
+
+    fn try_from(line: u8) -> Result<Self, Self::Error> {
+        Self::lines().nth(line as usize).ok_or(line)
+    }
+}
+
+/// The ICW1 bit that indicates that ICW4 will be sent.
+const ICW1_ICW4: u8 = 0x01;
+/// The ICW1 bit that starts the initialization sequence.
+const ICW1_INIT: u8 = 0x10;
+/// The ICW4 bit that puts the PIC in 8086/88 mode.
+const ICW4_8086: u8 = 0x01;
+/// The OCW2 command for a non-specific end of interrupt.
+const OCW2_EOI: u8 = 0x20;
+/// The OCW3 command to make the next read of the command port return the In-Service Register.
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// A single 8259 PIC.
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    fn new(offset: u8, command: u16, data: u16) -> Self {
+        Self {
+            offset,
+            command: Port::new(command),
+            data: Port::new(data),
+        }
+    }
+
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(OCW2_EOI);
+    }
+
+    fn in_service(&mut self) -> u8 {
+        unsafe {
+            self.command.write(OCW3_READ_ISR);
+            self.command.read()
+        }
+    }
+
+    fn mask(&mut self) -> u8 {
+        unsafe { self.data.read() }
+    }
+
+    unsafe fn set_mask(&mut self, mask: u8) {
+        self.data.write(mask);
+    }
+}
+
+/// A primary and secondary 8259 PIC chained together through line 2 of the primary PIC.
+pub struct ChainedPics {
+    primary: Pic,
+    secondary: Pic,
+}
+
+impl ChainedPics {
+    /// Create a handle to the PICs at the standard PC I/O ports which will remap IRQs 0-7 to
+    /// `primary_offset` and IRQs 8-15 to `secondary_offset` when initialized.
+    ///
+    /// # Safety
+    /// The offsets must not overlap the vectors used by CPU exceptions and there must not be any
+    /// other handle to the PICs.
+    pub unsafe fn new(primary_offset: u8, secondary_offset: u8) -> Self {
+        Self {
+            primary: Pic::new(primary_offset, 0x20, 0x21),
+            secondary: Pic::new(secondary_offset, 0xA0, 0xA1),
+        }
+    }
+
+    /// Remap the PICs to their offsets and mask every line except [`Cascade`]. Drivers must unmask
+    /// the lines they handle with [`set_masked`].
+    ///
+    /// [`Cascade`]: enum.Irq.html#variant.Cascade
+    /// [`set_masked`]: #method.set_masked
+    ///
+    /// # Safety
+    /// Interrupts must be disabled while the PICs are being initialized and the vectors from the
+    /// offsets must have handlers installed before interrupts are enabled.
+    pub unsafe fn initialize(&mut self) {
+        // Writing to an unused port gives the PICs time to process each command on older
+        // hardware.
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        let mut wait = || wait_port.write(0);
+
+        self.primary.command.write(ICW1_INIT | ICW1_ICW4);
+        wait();
+        self.secondary.command.write(ICW1_INIT | ICW1_ICW4);
+        wait();
+
+        self.primary.data.write(self.primary.offset);
+        wait();
+        self.secondary.data.write(self.secondary.offset);
+        wait();
+
+        // Tell the primary PIC that the secondary PIC is connected to line 2 and tell the
+        // secondary PIC its cascade identity.
+        self.primary.data.write(1 << Irq::Cascade.line());
+        wait();
+        self.secondary.data.write(Irq::Cascade.line());
+        wait();
+
+        self.primary.data.write(ICW4_8086);
+        wait();
+        self.secondary.data.write(ICW4_8086);
+        wait();
+
+        self.primary.set_mask(!(1 << Irq::Cascade.line()));
+        self.secondary.set_mask(0xFF);
+    }
+
+    /// Whether the interrupt delivered on the line for `irq` is spurious. A spurious interrupt is
+    /// one which was withdrawn before the PIC could report which line raised it, in which case the
+    /// PIC reports the lowest-priority line of the chip instead. Only [`Lpt1`] and
+    /// [`SecondaryAta`] can be spurious.
+    ///
+    /// [`Lpt1`]: enum.Irq.html#variant.Lpt1
+    /// [`SecondaryAta`]: enum.Irq.html#variant.SecondaryAta
+    pub fn is_spurious(&mut self, irq: Irq) -> bool {
+        match irq {
+            Irq::Lpt1 => self.primary.in_service() & 0x80 == 0,
+            Irq::SecondaryAta => self.secondary.in_service() & 0x80 == 0,
+            _ => false,
+        }
+    }
+
+    /// Acknowledge the interrupt on the line for `irq`. Spurious interrupts are acknowledged
+    /// correctly: a spurious [`Lpt1`] is not acknowledged at all and a spurious [`SecondaryAta`]
+    /// is only acknowledged to the primary PIC, which did see a real interrupt on [`Cascade`].
+    ///
+    /// [`Cascade`]: enum.Irq.html#variant.Cascade
+    /// [`Lpt1`]: enum.Irq.html#variant.Lpt1
+    /// [`SecondaryAta`]: enum.Irq.html#variant.SecondaryAta
+    ///
+    /// # Safety
+    /// Must only be called once per interrupt, at the end of the handler for `irq`.
+    pub unsafe fn notify_end_of_interrupt(&mut self, irq: Irq) {
+        let spurious = self.is_spurious(irq);
+        if irq.is_secondary() {
+            if !spurious {
+                self.secondary.end_of_interrupt();
+            }
+            self.primary.end_of_interrupt();
+        } else if !spurious {
+            self.primary.end_of_interrupt();
+        }
+    }
+
+    /// Whether the line for `irq` is masked.
+    pub fn is_masked(&mut self, irq: Irq) -> bool {
+        if irq.is_secondary() {
+            self.secondary.mask() & (1 << (irq.line() - 8)) != 0
+        } else {
+            self.primary.mask() & (1 << irq.line()) != 0
+        }
+    }
+
+    /// Mask or unmask the line for `irq`. While a line is masked, the PIC ignores any interrupt
+    /// raised on it.
+    pub fn set_masked(&mut self, irq: Irq, masked: bool) {
+        let (pic, bit) = if irq.is_secondary() {
+            (&mut self.secondary, 1 << (irq.line() - 8))
+        } else {
+            (&mut self.primary, 1 << irq.line())
+        };
+        let mask = pic.mask();
+        let mask = if masked { mask | bit } else { mask & !bit };
+        unsafe {
+            pic.set_mask(mask);
+        }
+    }
+
+    /// Mask every line on both PICs. This is used when another interrupt controller takes over
+    /// from the PICs.
+    pub fn mask_all(&mut self) {
+        unsafe {
+            self.primary.set_mask(0xFF);
+            self.secondary.set_mask(0xFF);
+        }
+    }
+}
+
+lazy_static! {
+    /// The PICs of the system, remapped to [`PIC_1_OFFSET`] and [`PIC_2_OFFSET`]. The IRQ handlers
+    /// lock the PICs to acknowledge interrupts, so interrupts must be disabled while the lock is
+    /// held.
+    ///
+    /// [`PIC_1_OFFSET`]: constant.PIC_1_OFFSET.html
+    /// [`PIC_2_OFFSET`]: constant.PIC_2_OFFSET.html
+    pub static ref PICS: Mutex<ChainedPics> =
+        Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+}
+
+/// Mask or unmask the line for `irq` with interrupts disabled.
+pub fn set_masked(irq: Irq, masked: bool) {
+    without_interrupts(|| PICS.lock().set_masked(irq, masked));
+}
+
+/// Whether the line for `irq` is masked.
+pub fn is_masked(irq: Irq) -> bool {
+    without_interrupts(|| PICS.lock().is_masked(irq))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::pic]";
+
+    #[test_case]
+    fn test_irq_vectors() {
+        serial_print!("{} test_irq_vectors... ", TEST_PREFIX);
+        assert_eq!(Irq::Timer.vector(), PIC_1_OFFSET);
+        assert_eq!(Irq::Rtc.vector(), PIC_2_OFFSET);
+        assert_eq!(Irq::try_from(15u8), Ok(Irq::SecondaryAta));
+        assert_eq!(Irq::try_from(16u8), Err(16));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_set_masked() {
+        serial_print!("{} test_set_masked... ", TEST_PREFIX);
+        set_masked(Irq::Lpt2, false);
+        assert!(!is_masked(Irq::Lpt2));
+        set_masked(Irq::Lpt2, true);
+        assert!(is_masked(Irq::Lpt2));
+        set_masked(Irq::Free11, false);
+        assert!(!is_masked(Irq::Free11));
+        set_masked(Irq::Free11, true);
+        assert!(is_masked(Irq::Free11));
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,196 @@
+use core::time::Duration;
+
+use spin::Mutex;
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// The frequency in Hz of the clock which drives every channel of the PIT.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The lowest whole frequency in Hz the PIT can be programmed to. The divisor is 16 bits wide.
+pub const MIN_FREQUENCY: u32 = BASE_FREQUENCY / 0x1_0000 + 1;
+
+/// The command bits which select channel 0.
+const CHANNEL_0: u8 = 0b00 << 6;
+/// The command bits which make the reload value be written low byte first, then high byte.
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+/// The command bits which latch the current count so that it can be read consistently.
+const ACCESS_LATCH: u8 = 0b00 << 4;
+/// The command bits which select mode 2, a rate generator which raises IRQ 0 once per period.
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+/// The reason the PIT could not be programmed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PitError {
+    /// The frequency is below [`MIN_FREQUENCY`], or so high that its divisor would be below 2,
+    /// which mode 2 doesn't allow.
+    ///
+    /// [`MIN_FREQUENCY`]: constant.MIN_FREQUENCY.html
+    UnsupportedFrequency(u32),
+}
+
+/// The divisor of [`BASE_FREQUENCY`] which comes closest to `frequency`. The rate generator
+/// needs a divisor of at least 2.
+///
+/// [`BASE_FREQUENCY`]: constant.BASE_FREQUENCY.html
+pub fn divisor_for(frequency: u32) -> Result<u32, PitError> {
+    if frequency < MIN_FREQUENCY {
+        return Err(PitError::UnsupportedFrequency(frequency));
+    }
+    match (BASE_FREQUENCY + frequency / 2) / frequency {
+        0 | 1 => Err(PitError::UnsupportedFrequency(frequency)),
+        divisor => Ok(divisor),
+    }
+}
+
+/// The length of `periods` periods of the PIT when it is programmed with `divisor`.
+pub fn periods_to_duration(periods: u64, divisor: u32) -> Duration {
+    let nanos = periods as u128 * divisor as u128 * 1_000_000_000 / BASE_FREQUENCY as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// The number of whole periods of the PIT programmed with `divisor` which last at least
+/// `duration`.
+pub fn duration_to_periods(duration: Duration, divisor: u32) -> u64 {
+    let cycles = duration.as_nanos() * BASE_FREQUENCY as u128;
+    let period = divisor as u128 * 1_000_000_000;
+    ((cycles + period - 1) / period) as u64
+}
+
+/// Channel 0 of the 8253/8254 Programmable Interval Timer, which is wired to IRQ 0.
+pub struct Pit {
+    channel_0: Port<u8>,
+    command: Port<u8>,
+    divisor: u32,
+}
+
+impl Pit {
+    /// Create a handle to the PIT at the standard PC I/O ports.
+    ///
+    /// # Safety
+    /// There must not be any other handle to the PIT.
+    pub unsafe fn new() -> Self {
+        Self {
+            channel_0: Port::new(0x40),
+            command: Port::new(0x43),
+            divisor: 0,
+        }
+    }
+
+    /// Make channel 0 raise IRQ 0 periodically at the frequency closest to `frequency` and return
+    /// the divisor of [`BASE_FREQUENCY`] which was programmed.
+    ///
+    /// [`BASE_FREQUENCY`]: constant.BASE_FREQUENCY.html
+    pub fn set_frequency(&mut self, frequency: u32) -> Result<u32, PitError> {
+        let divisor = divisor_for(frequency)?;
+        unsafe {
+            self.command
+                .write(CHANNEL_0 | ACCESS_LOW_HIGH | MODE_RATE_GENERATOR);
+            self.channel_0.write(divisor as u8);
+            self.channel_0.write((divisor >> 8) as u8);
+        }
+        self.divisor = divisor;
+        Ok(divisor)
+    }
+
+    /// The divisor channel 0 was last programmed with, or 0 if it hasn't been programmed.
+    pub fn divisor(&self) -> u32 {
+        self.divisor
+    }
+
+    /// The current count of channel 0, which counts down from the divisor once per cycle of
+    /// [`BASE_FREQUENCY`] and raises IRQ 0 when it reaches 1.
+    ///
+    /// [`BASE_FREQUENCY`]: constant.BASE_FREQUENCY.html
+    pub fn count(&mut self) -> u16 {
+        unsafe {
+            self.command.write(CHANNEL_0 | ACCESS_LATCH);
+            let low = self.channel_0.read();
+            let high = self.channel_0.read();
+            u16::from_le_bytes([low, high])
+        }
+    }
+}
+
+lazy_static! {
+    /// The PIT of the system. Interrupts must be disabled while the lock is held.
+    pub static ref PIT: Mutex<Pit> = Mutex::new(unsafe { Pit::new() });
+}
+
+/// Program the PIT to `frequency` with interrupts disabled and return the divisor used.
+pub fn set_frequency(frequency: u32) -> Result<u32, PitError> {
+    without_interrupts(|| PIT.lock().set_frequency(frequency))
+}
+
+/// The divisor the PIT was last programmed with, or 0 if it hasn't been programmed.
+pub fn divisor() -> u32 {
+    without_interrupts(|| PIT.lock().divisor())
+}
+
+/// The current count of channel 0 of the PIT.
+pub fn count() -> u16 {
+    without_interrupts(|| PIT.lock().count())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::pit]";
+
+    #[test_case]
+    fn test_divisor() {
+        serial_print!("{} test_divisor... ", TEST_PREFIX);
+        assert_eq!(divisor_for(1000), Ok(1193));
+        assert_eq!(divisor_for(100), Ok(11932));
+        assert_eq!(divisor_for(MIN_FREQUENCY), Ok(62799));
+        assert_eq!(divisor_for(BASE_FREQUENCY / 2), Ok(2));
+        assert_eq!(
+            divisor_for(BASE_FREQUENCY),
+            Err(PitError::UnsupportedFrequency(BASE_FREQUENCY))
+        );
+        assert_eq!(
+            divisor_for(MIN_FREQUENCY - 1),
+            Err(PitError::UnsupportedFrequency(MIN_FREQUENCY - 1))
+        );
+        assert_eq!(
+            divisor_for(BASE_FREQUENCY + 1),
+            Err(PitError::UnsupportedFrequency(BASE_FREQUENCY + 1))
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_conversions() {
+        serial_print!("{} test_conversions... ", TEST_PREFIX);
+        // 1193182 cycles take exactly one second.
+        assert_eq!(
+            periods_to_duration(BASE_FREQUENCY as u64, 1),
+            Duration::from_secs(1)
+        );
+        // A divisor of 1193 gives a period of 999.847 microseconds.
+        assert_eq!(
+            periods_to_duration(1000, 1193),
+            Duration::from_nanos(999_847_466)
+        );
+        assert_eq!(duration_to_periods(Duration::from_secs(1), 1193), 1001);
+        assert_eq!(
+            duration_to_periods(Duration::from_nanos(999_847_466), 1193),
+            1000
+        );
+        assert_eq!(duration_to_periods(Duration::from_secs(0), 1193), 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_count() {
+        serial_print!("{} test_count... ", TEST_PREFIX);
+        let divisor = divisor();
+        assert_ne!(divisor, 0);
+        // The count never goes above the reload value and keeps changing.
+        let first = count();
+        assert!(u32::from(first) <= divisor);
+        while count() == first {}
+        serial_println!("[ok]");
+    }
+}
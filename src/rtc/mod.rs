@@ -0,0 +1,780 @@
+use core::{
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    acpi::Acpi,
+    cpu_exception::interrupts::{register_irq, set_irq_masked, RegisterError},
+    memory,
+    pic::Irq,
+};
+
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const WEEKDAY: u8 = 0x06;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+
+/// The status A bit which is set while the clock is updating its registers.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// The status A bits which select the rate of the periodic interrupt.
+const RATE_MASK: u8 = 0x0F;
+/// The status B bit which is set if the hours are kept from 0 to 23 instead of from 1 to 12.
+const HOURS_24: u8 = 1 << 1;
+/// The status B bit which is set if the registers are in binary instead of BCD.
+const BINARY: u8 = 1 << 2;
+/// The status B bit which enables the alarm interrupt.
+const ALARM_INTERRUPT: u8 = 1 << 5;
+/// The status B bit which enables the periodic interrupt.
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+/// The status B bit which stops the clock from updating so that it can be set.
+const SET: u8 = 1 << 7;
+/// The status C bit which is set if the alarm interrupt was raised.
+const ALARM_FLAG: u8 = 1 << 5;
+/// The status C bit which is set if the periodic interrupt was raised.
+const PERIODIC_FLAG: u8 = 1 << 6;
+/// The status C bit which is set if any enabled interrupt was raised.
+const INTERRUPT_FLAG: u8 = 1 << 7;
+/// The bit of the hours which is set for the afternoon in 12-hour mode.
+const PM: u8 = 1 << 7;
+/// The value of an alarm register which matches every value of the time.
+const ALARM_ANY: u8 = 0xC0;
+
+/// The frequency in Hz of the oscillator which drives the periodic interrupt.
+const BASE_FREQUENCY: u32 = 32768;
+/// The lowest rate which gives a frequency of `BASE_FREQUENCY >> (rate - 1)`.
+const MIN_RATE: u8 = 3;
+
+/// The first year which can be kept without a century register. Years from `FIRST_YEAR` to 99
+/// years after it are stored as their last two digits.
+pub const FIRST_YEAR: u16 = 1970;
+
+/// The reason the clock could not be read or programmed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RtcError {
+    /// The date or time doesn't exist.
+    InvalidDateTime(DateTime),
+    /// The year can't be stored in the registers of the clock.
+    UnsupportedYear(u16),
+    /// The frequency isn't a power of two from 2 to 8192 Hz.
+    UnsupportedFrequency(u32),
+    /// A field of the alarm is out of range.
+    InvalidAlarm(Alarm),
+}
+
+/// A date and time in UTC. The fields are in order of significance, so comparing two values
+/// compares them chronologically.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct DateTime {
+    /// The year.
+    pub year: u16,
+    /// The month, from 1 to 12.
+    pub month: u8,
+    /// The day of the month, from 1.
+    pub day: u8,
+    /// The hour, from 0 to 23.
+    pub hour: u8,
+    /// The minute, from 0 to 59.
+    pub minute: u8,
+    /// The second, from 0 to 59.
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The start of the Unix epoch.
+    pub const UNIX_EPOCH: Self = Self {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    /// Whether the date and time exist.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// The number of days from the start of the Unix epoch to the date, which is negative for
+    /// dates before it.
+    fn days_since_epoch(&self) -> i64 {
+        // Count years from March so that the leap day is the last day of the year.
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month_from_march = (self.month as i64 + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        // 1970-01-01 is day 719468 counting from 0000-03-01.
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    /// The number of seconds from the start of the Unix epoch, which is negative for times before
+    /// it.
+    pub fn unix_timestamp(&self) -> i64 {
+        self.days_since_epoch() * 86400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    /// The date and time `timestamp` seconds after the start of the Unix epoch, which must be from
+    /// the year 0 to the year 65535.
+    pub fn from_unix_timestamp(timestamp: i64) -> Self {
+        let days = timestamp.div_euclid(86400) + 719_468;
+        let seconds = timestamp.rem_euclid(86400);
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let month = (month_from_march + 2) % 12 + 1;
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// The day of the week from 1 for Sunday to 7 for Saturday, as kept by the clock.
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday.
+        ((self.days_since_epoch() + 4).rem_euclid(7) + 1) as u8
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Whether `year` has a 29th of February.
+pub fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// The number of days in `month` of `year`, or 0 if `month` isn't from 1 to 12.
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => 0,
+    }
+}
+
+/// The time of day at which the alarm interrupt is raised. A field of `None` matches every value,
+/// so an alarm with only `second` set is raised once a minute.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Alarm {
+    /// The hour, from 0 to 23.
+    pub hour: Option<u8>,
+    /// The minute, from 0 to 59.
+    pub minute: Option<u8>,
+    /// The second, from 0 to 59.
+    pub second: Option<u8>,
+}
+
+impl Alarm {
+    fn is_valid(&self) -> bool {
+        self.hour.map_or(true, |hour| hour < 24)
+            && self.minute.map_or(true, |minute| minute < 60)
+            && self.second.map_or(true, |second| second < 60)
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// The format of the registers of the clock, as selected by status register B.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Format {
+    binary: bool,
+    hours_24: bool,
+}
+
+impl Format {
+    fn new(status_b: u8) -> Self {
+        Self {
+            binary: status_b & BINARY != 0,
+            hours_24: status_b & HOURS_24 != 0,
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.binary {
+            value
+        } else {
+            from_bcd(value)
+        }
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.binary {
+            value
+        } else {
+            to_bcd(value)
+        }
+    }
+
+    /// Convert the hours register to an hour from 0 to 23.
+    fn decode_hour(&self, value: u8) -> u8 {
+        if self.hours_24 {
+            self.decode(value)
+        } else {
+            // Midnight and noon are both 12.
+            let hour = self.decode(value & !PM) % 12;
+            if value & PM != 0 {
+                hour + 12
+            } else {
+                hour
+            }
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.hours_24 {
+            self.encode(hour)
+        } else {
+            let pm = if hour >= 12 { PM } else { 0 };
+            match hour % 12 {
+                0 => self.encode(12) | pm,
+                hour => self.encode(hour) | pm,
+            }
+        }
+    }
+}
+
+/// The registers which hold the date and time, as read from the clock.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+impl RawTime {
+    fn decode(&self, format: Format) -> DateTime {
+        let year = format.decode(self.year) as u16;
+        let year = match self.century {
+            Some(century) => format.decode(century) as u16 * 100 + year,
+            None if year < FIRST_YEAR % 100 => FIRST_YEAR - FIRST_YEAR % 100 + 100 + year,
+            None => FIRST_YEAR - FIRST_YEAR % 100 + year,
+        };
+        DateTime {
+            year,
+            month: format.decode(self.month),
+            day: format.decode(self.day),
+            hour: format.decode_hour(self.hour),
+            minute: format.decode(self.minute),
+            second: format.decode(self.second),
+        }
+    }
+}
+
+/// The CMOS real-time clock.
+pub struct Rtc {
+    index: Port<u8>,
+    data: Port<u8>,
+    century_register: Option<u8>,
+}
+
+impl Rtc {
+    /// Create a handle to the clock at the standard PC I/O ports. Until a century register is set,
+    /// years are assumed to be from [`FIRST_YEAR`] to 99 years after it.
+    ///
+    /// # Safety
+    /// There must not be any other handle to the clock.
+    ///
+    /// [`FIRST_YEAR`]: constant.FIRST_YEAR.html
+    pub unsafe fn new() -> Self {
+        Self {
+            index: Port::new(0x70),
+            data: Port::new(0x71),
+            century_register: None,
+        }
+    }
+
+    /// Keep the century in the CMOS register `register`, usually taken from the FADT, or in no
+    /// register.
+    pub fn set_century_register(&mut self, register: Option<u8>) {
+        self.century_register = register;
+    }
+
+    /// The CMOS register which keeps the century, if any.
+    pub fn century_register(&self) -> Option<u8> {
+        self.century_register
+    }
+
+    fn read_register(&mut self, register: u8) -> u8 {
+        // Bit 7 of the index disables NMIs, so it is left clear.
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    fn format(&mut self) -> Format {
+        Format::new(self.read_register(STATUS_B))
+    }
+
+    fn read_raw(&mut self) -> RawTime {
+        // The registers can be inconsistent while an update is in progress.
+        while self.read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+        RawTime {
+            second: self.read_register(SECONDS),
+            minute: self.read_register(MINUTES),
+            hour: self.read_register(HOURS),
+            day: self.read_register(DAY),
+            month: self.read_register(MONTH),
+            year: self.read_register(YEAR),
+            century: self
+                .century_register
+                .map(|register| self.read_register(register)),
+        }
+    }
+
+    /// The current date and time.
+    pub fn now(&mut self) -> DateTime {
+        // An update may start between checking the flag and reading the registers, so they are
+        // read until two reads in a row agree.
+        let mut raw = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        raw.decode(self.format())
+    }
+
+    /// Set the clock to `time`.
+    pub fn set_time(&mut self, time: DateTime) -> Result<(), RtcError> {
+        if !time.is_valid() {
+            return Err(RtcError::InvalidDateTime(time));
+        }
+        let century = match self.century_register {
+            Some(_) if time.year <= 9999 => (time.year / 100) as u8,
+            None if (FIRST_YEAR..FIRST_YEAR + 100).contains(&time.year) => 0,
+            _ => return Err(RtcError::UnsupportedYear(time.year)),
+        };
+        let status_b = self.read_register(STATUS_B);
+        let format = Format::new(status_b);
+        self.write_register(STATUS_B, status_b | SET);
+        self.write_register(SECONDS, format.encode(time.second));
+        self.write_register(MINUTES, format.encode(time.minute));
+        self.write_register(HOURS, format.encode_hour(time.hour));
+        self.write_register(WEEKDAY, format.encode(time.weekday()));
+        self.write_register(DAY, format.encode(time.day));
+        self.write_register(MONTH, format.encode(time.month));
+        self.write_register(YEAR, format.encode((time.year % 100) as u8));
+        if let Some(register) = self.century_register {
+            self.write_register(register, format.encode(century));
+        }
+        self.write_register(STATUS_B, status_b & !SET);
+        Ok(())
+    }
+
+    /// Raise IRQ 8 `frequency` times a second.
+    pub fn enable_periodic(&mut self, frequency: u32) -> Result<(), RtcError> {
+        let rate = (MIN_RATE..=RATE_MASK)
+            .find(|&rate| BASE_FREQUENCY >> (rate - 1) == frequency)
+            .ok_or(RtcError::UnsupportedFrequency(frequency))?;
+        let status_a = self.read_register(STATUS_A);
+        self.write_register(STATUS_A, (status_a & !RATE_MASK) | rate);
+        self.set_interrupt_enabled(PERIODIC_INTERRUPT, true);
+        Ok(())
+    }
+
+    /// Stop raising the periodic interrupt.
+    pub fn disable_periodic(&mut self) {
+        self.set_interrupt_enabled(PERIODIC_INTERRUPT, false);
+    }
+
+    /// Raise IRQ 8 whenever the time of day matches `alarm`.
+    pub fn set_alarm(&mut self, alarm: Alarm) -> Result<(), RtcError> {
+        if !alarm.is_valid() {
+            return Err(RtcError::InvalidAlarm(alarm));
+        }
+        let format = self.format();
+        let hour = alarm
+            .hour
+            .map_or(ALARM_ANY, |hour| format.encode_hour(hour));
+        let minute = alarm
+            .minute
+            .map_or(ALARM_ANY, |minute| format.encode(minute));
+        let second = alarm
+            .second
+            .map_or(ALARM_ANY, |second| format.encode(second));
+        self.write_register(HOURS_ALARM, hour);
+        self.write_register(MINUTES_ALARM, minute);
+        self.write_register(SECONDS_ALARM, second);
+        self.set_interrupt_enabled(ALARM_INTERRUPT, true);
+        Ok(())
+    }
+
+    /// Stop raising the alarm interrupt.
+    pub fn clear_alarm(&mut self) {
+        self.set_interrupt_enabled(ALARM_INTERRUPT, false);
+    }
+
+    fn set_interrupt_enabled(&mut self, interrupt: u8, enabled: bool) {
+        let status_b = self.read_register(STATUS_B);
+        let status_b = if enabled {
+            status_b | interrupt
+        } else {
+            status_b & !interrupt
+        };
+        self.write_register(STATUS_B, status_b);
+        // The clock doesn't raise another interrupt until the flags of the last one are read.
+        self.acknowledge();
+    }
+
+    /// Read and clear the flags of the interrupts which have been raised since the last call.
+    fn acknowledge(&mut self) -> u8 {
+        self.read_register(STATUS_C)
+    }
+}
+
+lazy_static! {
+    /// The real-time clock of the system. Interrupts must be disabled while the lock is held.
+    pub static ref RTC: Mutex<Rtc> = Mutex::new(unsafe { Rtc::new() });
+}
+
+/// The number of periodic interrupts raised since boot.
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of alarm interrupts raised since boot.
+static ALARM_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+fn rtc_interrupt(_: u8) -> bool {
+    let flags = RTC.lock().acknowledge();
+    if flags & PERIODIC_FLAG != 0 {
+        PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+    if flags & ALARM_FLAG != 0 {
+        ALARM_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+    flags & INTERRUPT_FLAG != 0
+}
+
+/// Keep the century in the CMOS register named by the FADT, if there is one, and start handling
+/// IRQ 8. The clock doesn't raise it until an interrupt is enabled with [`enable_periodic`] or
+/// [`set_alarm`].
+///
+/// [`enable_periodic`]: fn.enable_periodic.html
+/// [`set_alarm`]: fn.set_alarm.html
+pub fn init() {
+    let century_register = unsafe { Acpi::new(memory::physical_memory_offset()) }
+        .and_then(|acpi| acpi.fadt())
+        .ok()
+        .and_then(|fadt| fadt.century_register);
+    without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        rtc.set_century_register(century_register);
+        rtc.disable_periodic();
+        rtc.clear_alarm();
+    });
+    match register_irq(Irq::Rtc.vector(), rtc_interrupt) {
+        Ok(()) | Err(RegisterError::AlreadyRegistered(_)) => {}
+        Err(e) => panic!("Failed to register the RTC handler: {:?}", e),
+    }
+    set_irq_masked(Irq::Rtc, false);
+}
+
+/// Keep the century in the CMOS register `register`, usually taken from the FADT.
+pub fn set_century_register(register: Option<u8>) {
+    without_interrupts(|| RTC.lock().set_century_register(register))
+}
+
+/// The current date and time.
+pub fn now() -> DateTime {
+    without_interrupts(|| RTC.lock().now())
+}
+
+/// Set the clock to `time`.
+pub fn set_time(time: DateTime) -> Result<(), RtcError> {
+    without_interrupts(|| RTC.lock().set_time(time))
+}
+
+/// Raise the periodic interrupt `frequency` times a second.
+pub fn enable_periodic(frequency: u32) -> Result<(), RtcError> {
+    without_interrupts(|| RTC.lock().enable_periodic(frequency))
+}
+
+/// Stop raising the periodic interrupt.
+pub fn disable_periodic() {
+    without_interrupts(|| RTC.lock().disable_periodic())
+}
+
+/// Raise the alarm interrupt whenever the time of day matches `alarm`.
+pub fn set_alarm(alarm: Alarm) -> Result<(), RtcError> {
+    without_interrupts(|| RTC.lock().set_alarm(alarm))
+}
+
+/// Stop raising the alarm interrupt.
+pub fn clear_alarm() {
+    without_interrupts(|| RTC.lock().clear_alarm())
+}
+
+/// The number of periodic interrupts raised since boot.
+pub fn periodic_count() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// The number of alarm interrupts raised since boot.
+pub fn alarm_count() -> u64 {
+    ALARM_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::time::Duration;
+
+    use crate::time;
+
+    const TEST_PREFIX: &'static str = "[rust_os::rtc]";
+
+    /// The time QEMU's clock is started at by the test arguments in `Cargo.toml`.
+    const QEMU_BASE: DateTime = DateTime {
+        year: 2020,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 0,
+    };
+
+    #[test_case]
+    fn test_formats() {
+        serial_print!("{} test_formats... ", TEST_PREFIX);
+        let bcd_12 = Format::new(0);
+        assert_eq!(bcd_12.decode(0x59), 59);
+        assert_eq!(bcd_12.encode(59), 0x59);
+        assert_eq!(bcd_12.decode_hour(0x12), 0);
+        assert_eq!(bcd_12.decode_hour(0x12 | PM), 12);
+        assert_eq!(bcd_12.decode_hour(0x11 | PM), 23);
+        assert_eq!(bcd_12.encode_hour(0), 0x12);
+        assert_eq!(bcd_12.encode_hour(12), 0x12 | PM);
+        assert_eq!(bcd_12.encode_hour(23), 0x11 | PM);
+        let binary_24 = Format::new(BINARY | HOURS_24);
+        assert_eq!(binary_24.decode(59), 59);
+        assert_eq!(binary_24.decode_hour(23), 23);
+        assert_eq!(binary_24.encode_hour(0), 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_years() {
+        serial_print!("{} test_years... ", TEST_PREFIX);
+        let raw = |year, century| RawTime {
+            second: 0,
+            minute: 0,
+            hour: 0,
+            day: 1,
+            month: 1,
+            year,
+            century,
+        };
+        let format = Format::new(HOURS_24);
+        assert_eq!(raw(0x70, None).decode(format).year, 1970);
+        assert_eq!(raw(0x69, None).decode(format).year, 2069);
+        assert_eq!(raw(0x20, Some(0x21)).decode(format).year, 2120);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_date_time() {
+        serial_print!("{} test_date_time... ", TEST_PREFIX);
+        assert_eq!(DateTime::UNIX_EPOCH.unix_timestamp(), 0);
+        assert_eq!(DateTime::UNIX_EPOCH.weekday(), 5);
+        assert_eq!(QEMU_BASE.unix_timestamp(), 1_583_020_740);
+        assert_eq!(QEMU_BASE.weekday(), 7);
+        let y2038 = DateTime {
+            year: 2038,
+            month: 1,
+            day: 19,
+            hour: 3,
+            minute: 14,
+            second: 7,
+        };
+        assert_eq!(y2038.unix_timestamp(), i32::MAX as i64);
+        assert_eq!(DateTime::from_unix_timestamp(i32::MAX as i64), y2038);
+        assert_eq!(
+            DateTime::from_unix_timestamp(QEMU_BASE.unix_timestamp() + 60),
+            DateTime {
+                month: 3,
+                day: 1,
+                hour: 0,
+                minute: 0,
+                ..QEMU_BASE
+            }
+        );
+        assert_eq!(DateTime::from_unix_timestamp(0), DateTime::UNIX_EPOCH);
+        assert!(QEMU_BASE.is_valid());
+        assert!(!DateTime {
+            year: 2100,
+            ..QEMU_BASE
+        }
+        .is_valid());
+        assert!(DateTime::UNIX_EPOCH < QEMU_BASE);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_century_register() {
+        serial_print!("{} test_century_register... ", TEST_PREFIX);
+        let acpi = unsafe { Acpi::new(memory::physical_memory_offset()) }.unwrap();
+        // QEMU keeps the century in the standard register.
+        assert_eq!(acpi.fadt().unwrap().century_register, Some(0x32));
+        // `crate::init` has already taken it from the FADT.
+        assert_eq!(
+            without_interrupts(|| RTC.lock().century_register()),
+            Some(0x32)
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_now() {
+        serial_print!("{} test_now... ", TEST_PREFIX);
+        let now = now();
+        assert!(now.is_valid());
+        // The clock started at `QEMU_BASE` shortly before the PIT was programmed.
+        let elapsed = now.unix_timestamp() - QEMU_BASE.unix_timestamp();
+        let uptime = time::uptime().as_secs() as i64;
+        assert!(
+            elapsed >= uptime - 1 && elapsed <= uptime + 5,
+            "{} is not {}s after {}",
+            now,
+            uptime,
+            QEMU_BASE
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_set_time() {
+        serial_print!("{} test_set_time... ", TEST_PREFIX);
+        let original = now();
+        let start = time::uptime();
+        let leap_second = DateTime {
+            year: 2016,
+            month: 12,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert_eq!(
+            set_time(DateTime {
+                day: 32,
+                ..leap_second
+            }),
+            Err(RtcError::InvalidDateTime(DateTime {
+                day: 32,
+                ..leap_second
+            }))
+        );
+        set_time(leap_second).unwrap();
+        let read = now();
+        assert!(read == leap_second || read.year == 2017, "Read {}", read);
+        // Put the clock back where it would have been.
+        let elapsed = (time::uptime() - start).as_secs() as i64;
+        set_time(DateTime::from_unix_timestamp(
+            original.unix_timestamp() + elapsed,
+        ))
+        .unwrap();
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_periodic() {
+        serial_print!("{} test_periodic... ", TEST_PREFIX);
+        assert_eq!(
+            enable_periodic(1000),
+            Err(RtcError::UnsupportedFrequency(1000))
+        );
+        let start = periodic_count();
+        enable_periodic(1024).unwrap();
+        time::sleep(Duration::from_millis(100));
+        disable_periodic();
+        let count = periodic_count() - start;
+        // About 102 interrupts, allowing for how late QEMU delivers them.
+        assert!(count >= 50 && count <= 120, "{} periodic interrupts", count);
+        let stopped = periodic_count();
+        time::sleep(Duration::from_millis(10));
+        assert_eq!(periodic_count(), stopped);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_alarm() {
+        serial_print!("{} test_alarm... ", TEST_PREFIX);
+        let invalid = Alarm {
+            second: Some(60),
+            ..Alarm::default()
+        };
+        assert_eq!(set_alarm(invalid), Err(RtcError::InvalidAlarm(invalid)));
+        let start = alarm_count();
+        let second = (now().second + 1) % 60;
+        set_alarm(Alarm {
+            second: Some(second),
+            ..Alarm::default()
+        })
+        .unwrap();
+        time::sleep(Duration::from_millis(2100));
+        clear_alarm();
+        assert_eq!(alarm_count() - start, 1);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,213 @@
+use core::{
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Mutex;
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::tsc;
+use crate::{hpet, pit};
+
+/// How long the TSC is calibrated for at boot.
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+/// A free-running counter which can be used to measure time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Clocksource {
+    /// The ticks of the PIT combined with the count of channel 0, which has a resolution of a
+    /// single cycle of the PIT's 1.193182 MHz clock.
+    Pit,
+    /// The main counter of the HPET.
+    Hpet {
+        /// The frequency of the main counter in Hz.
+        frequency: u64,
+    },
+    /// The Time Stamp Counter, which ticks at the calibrated frequency.
+    Tsc {
+        /// The frequency of the TSC in Hz.
+        frequency: u64,
+    },
+}
+
+impl Clocksource {
+    /// The current value of the counter. Before the PIT is programmed, the PIT counter is always
+    /// 0.
+    pub fn read(&self) -> u64 {
+        match self {
+            Self::Pit => read_pit(),
+            Self::Hpet { .. } => hpet::counter().unwrap_or(0),
+            Self::Tsc { .. } => tsc::read(),
+        }
+    }
+
+    /// The number of times the counter is incremented each second.
+    pub fn frequency(&self) -> u64 {
+        match self {
+            Self::Pit => pit::BASE_FREQUENCY as u64,
+            Self::Hpet { frequency } => *frequency,
+            Self::Tsc { frequency } => *frequency,
+        }
+    }
+
+    /// The length of `counts` increments of the counter.
+    pub fn to_duration(&self, counts: u64) -> Duration {
+        Duration::from_nanos((counts as u128 * 1_000_000_000 / self.frequency() as u128) as u64)
+    }
+}
+
+impl Display for Clocksource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pit => write!(f, "PIT ({} Hz)", self.frequency()),
+            Self::Hpet { frequency } => write!(f, "HPET ({} Hz)", frequency),
+            Self::Tsc { frequency } => write!(f, "TSC ({} Hz)", frequency),
+        }
+    }
+}
+
+/// The largest value the PIT counter has returned, so that it never goes backwards.
+static LAST_PIT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+fn read_pit() -> u64 {
+    let divisor = pit::divisor() as u64;
+    if divisor == 0 {
+        return 0;
+    }
+    without_interrupts(|| {
+        // Channel 0 counts down from the divisor and raises a tick when it reaches 1. The tick is
+        // only counted once the interrupt is handled, so just after the count wraps around the
+        // combined value can briefly be a period behind. The last value is returned until the
+        // tick is counted.
+        let count = super::ticks() * divisor + divisor.saturating_sub(pit::count() as u64);
+        let last = LAST_PIT_COUNT.load(Ordering::Relaxed);
+        if count > last {
+            LAST_PIT_COUNT.store(count, Ordering::Relaxed);
+            count
+        } else {
+            last
+        }
+    })
+}
+
+struct State {
+    source: Clocksource,
+    /// The value of the counter when it was selected.
+    base_count: u64,
+    /// The number of nanoseconds since boot when the counter was selected.
+    base_nanos: u64,
+}
+
+impl State {
+    fn nanos(&self) -> u64 {
+        let elapsed = self.source.read().wrapping_sub(self.base_count);
+        self.base_nanos + self.source.to_duration(elapsed).as_nanos() as u64
+    }
+}
+
+/// The clocksource in use. Interrupts must be disabled while it is locked.
+static STATE: Mutex<State> = Mutex::new(State {
+    source: Clocksource::Pit,
+    base_count: 0,
+    base_nanos: 0,
+});
+
+/// The most precise clocksource which doesn't depend on the TSC: the HPET if it has been set up,
+/// and otherwise the PIT. Whichever is used, [`sleep`] and the timers still wait for the ticks of
+/// the PIT.
+///
+/// [`sleep`]: ../fn.sleep.html
+pub fn reference() -> Clocksource {
+    hpet::frequency().map_or(Clocksource::Pit, |frequency| Clocksource::Hpet {
+        frequency,
+    })
+}
+
+/// Use the TSC if it is invariant, calibrated against the [`reference`], and otherwise use the
+/// reference itself. The PIT must already be ticking and interrupts must be enabled. This is
+/// called again once the HPET has been set up. Returns the clocksource that was selected.
+///
+/// [`reference`]: fn.reference.html
+pub fn init() -> Clocksource {
+    let reference = reference();
+    let source = if tsc::is_invariant() {
+        Clocksource::Tsc {
+            frequency: tsc::calibrate(reference, CALIBRATION_TIME),
+        }
+    } else {
+        reference
+    };
+    select(source);
+    source
+}
+
+/// The clocksource in use.
+pub fn current() -> Clocksource {
+    without_interrupts(|| STATE.lock().source)
+}
+
+/// Measure time with `source` from now on. The time continues from where the previous
+/// clocksource left off.
+pub fn select(source: Clocksource) {
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        let nanos = state.nanos();
+        *state = State {
+            source,
+            base_count: source.read(),
+            base_nanos: nanos,
+        };
+    })
+}
+
+/// The number of nanoseconds since the PIT was first programmed, as measured by the clocksource
+/// in use.
+pub fn nanos() -> u64 {
+    without_interrupts(|| STATE.lock().nanos())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::time;
+
+    const TEST_PREFIX: &'static str = "[rust_os::time::clocksource]";
+
+    #[test_case]
+    fn test_pit_counter() {
+        serial_print!("{} test_pit_counter... ", TEST_PREFIX);
+        let start = Clocksource::Pit.read();
+        let mut last = start;
+        // Cross several ticks so that the count wraps around.
+        let end = time::ticks() + 3;
+        while time::ticks() < end {
+            let count = Clocksource::Pit.read();
+            assert!(count >= last);
+            last = count;
+        }
+        let elapsed = Clocksource::Pit.to_duration(last - start);
+        assert!(elapsed >= time::tick_length() * 2);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_select() {
+        serial_print!("{} test_select... ", TEST_PREFIX);
+        let original = current();
+        let frequency = tsc::calibrate(Clocksource::Pit, Duration::from_millis(10));
+        let before = nanos();
+        select(Clocksource::Tsc { frequency });
+        assert_eq!(current(), Clocksource::Tsc { frequency });
+        let during = nanos();
+        select(Clocksource::Pit);
+        let after = nanos();
+        select(original);
+        assert!(before <= during && during <= after);
+        // Switching clocksources doesn't lose or invent time.
+        assert!(after - before < 1_000_000);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,228 @@
+use core::{
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::{self, interrupts};
+
+use crate::{
+    cpu_exception::interrupts::{register_irq, set_irq_masked, RegisterError},
+    pic::Irq,
+    pit::{self, PitError},
+};
+
+/// Counters which measure time with a finer resolution than the ticks of the PIT.
+pub mod clocksource;
+
+/// Timers which call a function once or periodically whenever the kernel waits for an interrupt.
+pub mod timer;
+
+/// Tools for reading and calibrating the Time Stamp Counter.
+pub mod tsc;
+
+/// The frequency in Hz that [`init`] is called with during boot.
+///
+/// [`init`]: fn.init.html
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+/// The number of times the PIT has raised IRQ 0 since [`init`] was first called.
+///
+/// [`init`]: fn.init.html
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The divisor the PIT was programmed with by [`init`], or 0 before it is called.
+///
+/// [`init`]: fn.init.html
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+
+fn tick(_: u8) -> bool {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    timer::tick();
+    true
+}
+
+/// Program the PIT to tick at the frequency closest to `frequency`, start counting ticks and
+/// select the most precise reliable clocksource. Interrupts must be enabled. Returns the divisor
+/// of [`pit::BASE_FREQUENCY`] which was programmed.
+///
+/// Calling this again changes the length of every tick, including the ones already counted, so
+/// [`uptime`] is only monotonic while the frequency stays the same.
+///
+/// [`pit::BASE_FREQUENCY`]: ../pit/constant.BASE_FREQUENCY.html
+/// [`uptime`]: fn.uptime.html
+pub fn init(frequency: u32) -> Result<u32, PitError> {
+    let divisor = interrupts::without_interrupts(|| {
+        let divisor = pit::set_frequency(frequency)?;
+        DIVISOR.store(divisor, Ordering::Relaxed);
+        Ok(divisor)
+    })?;
+    match register_irq(Irq::Timer.vector(), tick) {
+        Ok(()) | Err(RegisterError::AlreadyRegistered(_)) => {}
+        Err(e) => panic!("Failed to register the timer handler: {:?}", e),
+    }
+    set_irq_masked(Irq::Timer, false);
+    clocksource::init();
+    timer::init();
+    Ok(divisor)
+}
+
+/// The number of ticks of the PIT since [`init`] was first called.
+///
+/// [`init`]: fn.init.html
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The length of a single tick, or zero if [`init`] hasn't been called.
+///
+/// [`init`]: fn.init.html
+pub fn tick_length() -> Duration {
+    pit::periods_to_duration(1, DIVISOR.load(Ordering::Relaxed))
+}
+
+/// The time since [`init`] was first called, with the resolution of a single tick.
+///
+/// [`init`]: fn.init.html
+pub fn uptime() -> Duration {
+    pit::periods_to_duration(ticks(), DIVISOR.load(Ordering::Relaxed))
+}
+
+/// Halt until at least `duration` has passed. Since the current tick may be nearly over when this
+/// is called, one tick more than `duration` is waited for. Timers which expire in the meantime are
+/// run after the tick that woke the CPU, so a slow one can make the sleep longer.
+///
+/// # Panics
+/// Panics if interrupts are disabled or [`init`] hasn't been called, since the ticks would never
+/// arrive.
+///
+/// [`init`]: fn.init.html
+pub fn sleep(duration: Duration) {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    assert_ne!(divisor, 0, "Tried to sleep before the PIT was programmed");
+    assert!(
+        interrupts::are_enabled(),
+        "Tried to sleep with interrupts disabled"
+    );
+    let target = ticks() + pit::duration_to_periods(duration, divisor) + 1;
+    while ticks() < target {
+        instructions::hlt();
+        timer::run_pending();
+    }
+}
+
+/// A point in time measured by the clocksource in use, with nanosecond resolution. Instants never
+/// go backwards.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time.
+    pub fn now() -> Self {
+        Self(clocksource::nanos())
+    }
+
+    /// The time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Self) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// The time since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    /// The time from when the PIT was first programmed to `self`.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        Self(self.0 + duration.as_nanos() as u64)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Self) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::time]";
+
+    #[test_case]
+    fn test_ticks_monotonic() {
+        serial_print!("{} test_ticks_monotonic... ", TEST_PREFIX);
+        // Count the periods of the PIT by watching its latched count get reloaded, which doesn't
+        // depend on the IRQs being delivered.
+        let first = ticks();
+        let mut periods = 0;
+        let mut last = pit::count();
+        while periods < 20 {
+            let count = pit::count();
+            if count > last {
+                periods += 1;
+            }
+            last = count;
+        }
+        let counted = ticks() - first;
+        // The IRQ of the period in progress at either end may or may not have been handled, and a
+        // reload can be missed if QEMU is descheduled between two reads.
+        assert!(
+            counted + 1 >= periods && counted <= periods + 3,
+            "{} ticks in {} periods",
+            counted,
+            periods
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_tick_length() {
+        serial_print!("{} test_tick_length... ", TEST_PREFIX);
+        // 1193 cycles of the 1.193182 MHz clock.
+        assert_eq!(pit::divisor(), 1193);
+        assert_eq!(tick_length(), Duration::from_nanos(999_847));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_instant() {
+        serial_print!("{} test_instant... ", TEST_PREFIX);
+        let start = Instant::now();
+        assert!(Instant::now() >= start);
+        sleep(Duration::from_millis(10));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(10));
+        assert!(
+            elapsed < Duration::from_millis(50),
+            "Slept for {:?}",
+            elapsed
+        );
+        assert!(start + elapsed <= Instant::now());
+        assert_eq!(start - Instant::now(), Duration::from_secs(0));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_sleep() {
+        serial_print!("{} test_sleep... ", TEST_PREFIX);
+        let duration = Duration::from_millis(100);
+        let start = Instant::now();
+        sleep(duration);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= duration, "Slept for {:?}", elapsed);
+        assert!(elapsed < duration * 5, "Slept for {:?}", elapsed);
+        serial_println!("[ok]");
+    }
+}
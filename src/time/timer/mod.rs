@@ -0,0 +1,530 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Mutex;
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::Instant;
+use crate::memory::heap;
+
+/// The length of a tick of the timer wheel. Timers expire on the first tick at or after their
+/// deadline.
+pub const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// How late a timer can run before it is reported.
+pub const LATE_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// The number of bits of the expiry tick which select a slot of a level.
+const SLOT_BITS: u32 = 6;
+
+/// The number of slots in each level of the wheel.
+const SLOTS: usize = 1 << SLOT_BITS;
+
+/// The number of levels of the wheel. Each level covers `SLOTS` times as many ticks as the level
+/// below it, so the wheel covers about 4.6 hours. Timers further in the future wait in the last
+/// slot they can reach and are moved down as the wheel turns.
+const LEVELS: usize = 4;
+
+/// The number of ticks covered by the whole wheel.
+const WHEEL_TICKS: u64 = 1 << (SLOT_BITS as usize * LEVELS);
+
+/// The function a timer calls when it expires.
+type Callback = Box<dyn FnMut() + Send>;
+
+struct Entry {
+    id: u64,
+    /// The tick at which the timer expires.
+    expires: u64,
+    /// The number of ticks between expiries of a periodic timer.
+    interval: Option<u64>,
+    callback: Callback,
+}
+
+/// Where a pending timer is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Location {
+    /// In the slot with the index into `Wheel::slots`.
+    Slot(usize),
+    /// Taken out of the wheel to have its callback run.
+    Running,
+}
+
+/// A hierarchical timer wheel. Level 0 has a slot for each of the next `SLOTS` ticks, and each
+/// slot of level `n` holds the timers expiring in a range of `SLOTS.pow(n)` ticks. Whenever the
+/// lower levels have gone all the way around, the next slot of the level above is emptied into
+/// them.
+struct Wheel {
+    /// The next tick to be processed.
+    current: u64,
+    /// The slots of every level, level 0 first.
+    slots: Vec<Vec<Entry>>,
+    locations: BTreeMap<u64, Location>,
+}
+
+impl Wheel {
+    fn new(current: u64) -> Self {
+        Self {
+            current,
+            slots: (0..SLOTS * LEVELS).map(|_| Vec::new()).collect(),
+            locations: BTreeMap::new(),
+        }
+    }
+
+    /// The index of the slot that a timer expiring at `expires` belongs in.
+    fn slot_for(&self, expires: u64) -> usize {
+        let expires = expires.max(self.current);
+        let delta = (expires - self.current).min(WHEEL_TICKS - 1);
+        let expires = self.current + delta;
+        let level = (0..LEVELS)
+            .find(|&level| delta < 1 << (SLOT_BITS as usize * (level + 1)))
+            .unwrap_or(LEVELS - 1);
+        let slot = (expires >> (SLOT_BITS as usize * level)) as usize % SLOTS;
+        level * SLOTS + slot
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        let index = self.slot_for(entry.expires);
+        self.locations.insert(entry.id, Location::Slot(index));
+        self.slots[index].push(entry);
+    }
+
+    /// Take the timer out of the wheel. Returns whether it was pending.
+    fn remove(&mut self, id: u64) -> bool {
+        match self.locations.remove(&id) {
+            Some(Location::Slot(index)) => {
+                self.slots[index].retain(|entry| entry.id != id);
+                true
+            }
+            Some(Location::Running) => true,
+            None => false,
+        }
+    }
+
+    /// Process every tick up to and including `now` and take out the timers which expired. They
+    /// stay pending until they are finished with.
+    fn advance(&mut self, now: u64) -> Vec<Entry> {
+        let mut expired = Vec::new();
+        while self.current <= now {
+            // Move the timers of the next slot of each level down once the level below it has
+            // gone all the way around.
+            for level in 1..LEVELS {
+                let below = self.current >> (SLOT_BITS as usize * (level - 1));
+                if below % SLOTS as u64 != 0 {
+                    break;
+                }
+                let slot = (self.current >> (SLOT_BITS as usize * level)) as usize % SLOTS;
+                for entry in mem::take(&mut self.slots[level * SLOTS + slot]) {
+                    self.insert(entry);
+                }
+            }
+            let index = self.current as usize % SLOTS;
+            for entry in mem::take(&mut self.slots[index]) {
+                self.locations.insert(entry.id, Location::Running);
+                expired.push(entry);
+            }
+            self.current += 1;
+        }
+        expired
+    }
+
+    /// Put a timer whose callback has run back in the wheel if it is periodic and hasn't been
+    /// cancelled, and otherwise forget it.
+    fn finish(&mut self, mut entry: Entry, now: u64) {
+        if self.locations.get(&entry.id) != Some(&Location::Running) {
+            return;
+        }
+        match entry.interval {
+            Some(interval) => {
+                // A timer which fell more than a period behind skips the expiries it missed.
+                entry.expires = (entry.expires + interval).max(now + 1);
+                self.insert(entry);
+            }
+            None => {
+                self.locations.remove(&entry.id);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// The timer wheel. Interrupts must be disabled while it is locked. Its slots and map keep
+    /// their memory when timers leave them, so it is allocated with
+    /// [`heap::without_leak_checks`].
+    ///
+    /// [`heap::without_leak_checks`]: ../../memory/heap/fn.without_leak_checks.html
+    static ref WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new(now_tick()));
+}
+
+/// Lock the wheel and run `f` on it, leaving the memory it allocates out of leak reports.
+fn with_wheel<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut Wheel) -> T,
+{
+    without_interrupts(|| heap::without_leak_checks(|| f(&mut WHEEL.lock())))
+}
+
+/// The ID of the next timer.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Whether [`run`] is running, so that a callback calling it doesn't run timers recursively.
+///
+/// [`run`]: fn.run.html
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the PIT has ticked since [`run_pending`] last ran the timers.
+///
+/// [`run_pending`]: fn.run_pending.html
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+static EXPIRED: AtomicU64 = AtomicU64::new(0);
+static LATE: AtomicU64 = AtomicU64::new(0);
+static MAX_LATENESS: AtomicU64 = AtomicU64::new(0);
+
+/// Set up the timer wheel, starting at the current time.
+pub fn init() {
+    heap::without_leak_checks(|| lazy_static::initialize(&WHEEL));
+}
+
+/// The current tick of the timer wheel, counted by the clocksource in use.
+fn now_tick() -> u64 {
+    (Instant::now().since_boot().as_nanos() / GRANULARITY.as_nanos()) as u64
+}
+
+/// The number of ticks which last at least `duration`.
+fn ticks_for(duration: Duration) -> u64 {
+    let granularity = GRANULARITY.as_nanos();
+    ((duration.as_nanos() + granularity - 1) / granularity) as u64
+}
+
+/// A handle to a timer which calls a function once or periodically. The function is called by
+/// [`run`] or [`run_pending`], never from an interrupt handler. Dropping the handle doesn't cancel
+/// the timer.
+///
+/// Timers must not be created or cancelled from interrupt handlers, since they allocate.
+///
+/// [`run`]: fn.run.html
+/// [`run_pending`]: fn.run_pending.html
+#[derive(Debug, Eq, PartialEq)]
+pub struct Timer {
+    id: u64,
+}
+
+impl Timer {
+    fn start(delay: Duration, interval: Option<Duration>, callback: Callback) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            id,
+            expires: now_tick() + ticks_for(delay),
+            interval: interval.map(|interval| ticks_for(interval).max(1)),
+            callback,
+        };
+        with_wheel(|wheel| wheel.insert(entry));
+        Self { id }
+    }
+
+    /// Call `callback` once `delay` has passed.
+    pub fn oneshot<F>(delay: Duration, callback: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut callback = Some(callback);
+        Self::start(
+            delay,
+            None,
+            Box::new(move || {
+                if let Some(callback) = callback.take() {
+                    callback()
+                }
+            }),
+        )
+    }
+
+    /// Call `callback` every `interval` until the timer is cancelled. Intervals are rounded up to
+    /// a whole number of ticks.
+    pub fn periodic<F>(interval: Duration, callback: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        Self::start(interval, Some(interval), Box::new(callback))
+    }
+
+    /// Stop the timer from calling its function again. If the function is running, it finishes.
+    /// Returns whether the timer was pending.
+    pub fn cancel(&self) -> bool {
+        with_wheel(|wheel| wheel.remove(self.id))
+    }
+
+    /// Whether the timer will call its function again.
+    pub fn is_pending(&self) -> bool {
+        with_wheel(|wheel| wheel.locations.contains_key(&self.id))
+    }
+}
+
+/// Note that the PIT has ticked, so that the next call to [`run_pending`] runs the timers. The
+/// tick IRQ can't run them itself, since their functions may allocate or take locks.
+///
+/// [`run_pending`]: fn.run_pending.html
+pub(super) fn tick() {
+    PENDING.store(true, Ordering::Release);
+}
+
+/// Run the timers with [`run`] if the PIT has ticked since they last ran. This is called wherever
+/// the kernel halts to wait for an interrupt: in [`sleep`] and in its main loop. Returns the
+/// number of functions which were called.
+///
+/// [`run`]: fn.run.html
+/// [`sleep`]: ../fn.sleep.html
+pub fn run_pending() -> usize {
+    if PENDING.swap(false, Ordering::Acquire) {
+        run()
+    } else {
+        0
+    }
+}
+
+/// Call the functions of every timer which has expired, and report any which run more than
+/// [`LATE_THRESHOLD`] after they expired. Returns the number of functions which were called.
+///
+/// [`LATE_THRESHOLD`]: constant.LATE_THRESHOLD.html
+pub fn run() -> usize {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    let now = now_tick();
+    let expired = with_wheel(|wheel| wheel.advance(now));
+    let count = expired.len();
+    for mut entry in expired {
+        let due = Duration::from_nanos(entry.expires * GRANULARITY.as_nanos() as u64);
+        let lateness = Instant::now().since_boot().checked_sub(due);
+        if let Some(lateness) = lateness.filter(|&lateness| lateness > LATE_THRESHOLD) {
+            let nanos = lateness.as_nanos() as u64;
+            LATE.fetch_add(1, Ordering::Relaxed);
+            if nanos > MAX_LATENESS.load(Ordering::Relaxed) {
+                MAX_LATENESS.store(nanos, Ordering::Relaxed);
+            }
+            serial_println!("Timer {} ran {:?} late", entry.id, lateness);
+        }
+        (entry.callback)();
+        EXPIRED.fetch_add(1, Ordering::Relaxed);
+        with_wheel(|wheel| wheel.finish(entry, now_tick()));
+    }
+    RUNNING.store(false, Ordering::Release);
+    count
+}
+
+/// How many timers have run, and how late they were.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Stats {
+    /// The number of times a timer's function has been called.
+    pub expired: u64,
+    /// The number of times a timer's function was called more than [`LATE_THRESHOLD`] after the
+    /// timer expired.
+    ///
+    /// [`LATE_THRESHOLD`]: constant.LATE_THRESHOLD.html
+    pub late: u64,
+    /// The latest that a timer's function has been called.
+    pub max_lateness: Duration,
+}
+
+/// How many timers have run since boot, and how late they were.
+pub fn stats() -> Stats {
+    Stats {
+        expired: EXPIRED.load(Ordering::Relaxed),
+        late: LATE.load(Ordering::Relaxed),
+        max_lateness: Duration::from_nanos(MAX_LATENESS.load(Ordering::Relaxed)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use alloc::sync::Arc;
+    use x86_64::instructions;
+
+    const TEST_PREFIX: &'static str = "[rust_os::time::timer]";
+
+    fn entry(id: u64, expires: u64) -> Entry {
+        Entry {
+            id,
+            expires,
+            interval: None,
+            callback: Box::new(|| {}),
+        }
+    }
+
+    /// Advance `wheel` one tick at a time and return the tick at which each timer expired.
+    fn expiries(wheel: &mut Wheel, until: u64) -> BTreeMap<u64, u64> {
+        let mut expiries = BTreeMap::new();
+        while wheel.current <= until {
+            let now = wheel.current;
+            for entry in wheel.advance(now) {
+                expiries.insert(entry.id, now);
+                wheel.finish(entry, now);
+            }
+        }
+        expiries
+    }
+
+    /// Run timers until `done` returns true or `timeout` passes.
+    fn run_until(timeout: Duration, done: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !done() && start.elapsed() < timeout {
+            run();
+            instructions::hlt();
+        }
+    }
+
+    #[test_case]
+    fn test_wheel_levels() {
+        serial_print!("{} test_wheel_levels... ", TEST_PREFIX);
+        let mut wheel = Wheel::new(100);
+        let deadlines = [100, 101, 163, 164, 200, 4195, 4196, 70_000, 300_000];
+        for (id, &expires) in deadlines.iter().enumerate() {
+            wheel.insert(entry(id as u64, expires));
+        }
+        // A timer which is already due expires on the next tick.
+        wheel.insert(entry(100, 50));
+        let expiries = expiries(&mut wheel, 300_000);
+        for (id, &expires) in deadlines.iter().enumerate() {
+            assert_eq!(expiries.get(&(id as u64)), Some(&expires));
+        }
+        assert_eq!(expiries.get(&100), Some(&100));
+        assert!(wheel.locations.is_empty());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_wheel_beyond_range() {
+        serial_print!("{} test_wheel_beyond_range... ", TEST_PREFIX);
+        let wheel = Wheel::new(1000);
+        // Timers past the end of the wheel wait in the last slot they can reach, which is emptied
+        // into the lower levels before the end of the wheel.
+        let last = wheel.slot_for(1000 + WHEEL_TICKS - 1);
+        assert_eq!(last / SLOTS, LEVELS - 1);
+        assert_eq!(wheel.slot_for(1000 + WHEEL_TICKS), last);
+        assert_eq!(wheel.slot_for(u64::MAX), last);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_wheel_remove() {
+        serial_print!("{} test_wheel_remove... ", TEST_PREFIX);
+        let mut wheel = Wheel::new(0);
+        wheel.insert(entry(0, 10));
+        wheel.insert(entry(1, 5000));
+        wheel.insert(Entry {
+            interval: Some(3),
+            ..entry(2, 3)
+        });
+        assert!(wheel.remove(1));
+        assert!(!wheel.remove(1));
+        let expiries = expiries(&mut wheel, 6000);
+        assert_eq!(expiries.get(&0), Some(&10));
+        assert_eq!(expiries.get(&1), None);
+        // The periodic timer is still pending after expiring many times.
+        assert!(wheel.remove(2));
+        assert!(wheel.locations.is_empty());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_oneshot() {
+        serial_print!("{} test_oneshot... ", TEST_PREFIX);
+        let fired = Arc::new(AtomicU64::new(0));
+        let start = Instant::now();
+        let timer = {
+            let fired = fired.clone();
+            Timer::oneshot(Duration::from_millis(5), move || {
+                fired.store(start.elapsed().as_nanos() as u64, Ordering::SeqCst);
+            })
+        };
+        assert!(timer.is_pending());
+        run_until(Duration::from_millis(100), || {
+            fired.load(Ordering::SeqCst) != 0
+        });
+        let elapsed = Duration::from_nanos(fired.load(Ordering::SeqCst));
+        assert!(elapsed >= Duration::from_millis(5));
+        assert!(
+            elapsed < Duration::from_millis(20),
+            "Ran after {:?}",
+            elapsed
+        );
+        assert!(!timer.is_pending());
+        assert!(!timer.cancel());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_periodic() {
+        serial_print!("{} test_periodic... ", TEST_PREFIX);
+        let count = Arc::new(AtomicU64::new(0));
+        let timer = {
+            let count = count.clone();
+            Timer::periodic(Duration::from_millis(2), move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        run_until(Duration::from_millis(50), || false);
+        assert!(timer.cancel());
+        let stopped = count.load(Ordering::SeqCst);
+        assert!(stopped >= 20 && stopped <= 26, "Ran {} times", stopped);
+        run_until(Duration::from_millis(10), || false);
+        assert_eq!(count.load(Ordering::SeqCst), stopped);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_cancel() {
+        serial_print!("{} test_cancel... ", TEST_PREFIX);
+        let fired = Arc::new(AtomicBool::new(false));
+        let timer = {
+            let fired = fired.clone();
+            Timer::oneshot(Duration::from_millis(2), move || {
+                fired.store(true, Ordering::SeqCst)
+            })
+        };
+        assert!(timer.cancel());
+        assert!(!timer.is_pending());
+        run_until(Duration::from_millis(10), || false);
+        assert!(!fired.load(Ordering::SeqCst));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_run_during_sleep() {
+        serial_print!("{} test_run_during_sleep... ", TEST_PREFIX);
+        let fired = Arc::new(AtomicBool::new(false));
+        let timer = {
+            let fired = fired.clone();
+            Timer::oneshot(Duration::from_millis(2), move || {
+                fired.store(true, Ordering::SeqCst)
+            })
+        };
+        crate::time::sleep(Duration::from_millis(10));
+        assert!(fired.load(Ordering::SeqCst));
+        assert!(!timer.is_pending());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_late() {
+        serial_print!("{} test_late... ", TEST_PREFIX);
+        let before = stats();
+        Timer::oneshot(Duration::from_millis(1), || {});
+        // Hog the CPU so that the timer can't run on time.
+        let start = Instant::now();
+        while start.elapsed() < LATE_THRESHOLD * 2 {}
+        assert_eq!(run(), 1);
+        let after = stats();
+        assert_eq!(after.expired, before.expired + 1);
+        assert_eq!(after.late, before.late + 1);
+        assert!(after.max_lateness >= LATE_THRESHOLD);
+        serial_println!("[ok]");
+    }
+}
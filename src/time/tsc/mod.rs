@@ -0,0 +1,93 @@
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
+
+use super::clocksource::Clocksource;
+
+/// The first extended CPUID leaf.
+const CPUID_EXTENDED: u32 = 0x8000_0000;
+
+/// The extended CPUID leaf which reports advanced power management features.
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+/// The bit of `edx` in CPUID leaf `0x8000_0007` which is set if the TSC ticks at a constant rate
+/// in every power state.
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Whether the TSC ticks at a constant rate regardless of frequency scaling and sleep states, so
+/// that it can be used to measure time.
+pub fn is_invariant() -> bool {
+    unsafe {
+        __cpuid(CPUID_EXTENDED).eax >= CPUID_POWER_MANAGEMENT
+            && __cpuid(CPUID_POWER_MANAGEMENT).edx & CPUID_INVARIANT_TSC != 0
+    }
+}
+
+/// The current value of the Time Stamp Counter.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measure the frequency of the TSC in Hz by counting its ticks while `reference` advances by
+/// `duration`.
+///
+/// # Panics
+/// Panics if `reference` is the PIT and interrupts are disabled, since the PIT would stop
+/// advancing after a single period.
+pub fn calibrate(reference: Clocksource, duration: Duration) -> u64 {
+    assert!(
+        reference != Clocksource::Pit || interrupts::are_enabled(),
+        "Tried to calibrate the TSC against the PIT with interrupts disabled"
+    );
+    let frequency = reference.frequency() as u128;
+    let counts = (duration.as_nanos() * frequency / 1_000_000_000) as u64;
+    let reference_start = reference.read();
+    let start = read();
+    loop {
+        let elapsed = reference.read() - reference_start;
+        if elapsed >= counts {
+            let ticks = (read() - start) as u128;
+            return (ticks * frequency / elapsed as u128) as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PREFIX: &'static str = "[rust_os::time::tsc]";
+
+    #[test_case]
+    fn test_read() {
+        serial_print!("{} test_read... ", TEST_PREFIX);
+        let first = read();
+        assert!(read() > first);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_calibrate() {
+        serial_print!("{} test_calibrate... ", TEST_PREFIX);
+        // QEMU doesn't emulate an invariant TSC, but its TSC still ticks steadily enough to be
+        // measured.
+        let first = calibrate(Clocksource::Pit, Duration::from_millis(20));
+        let second = calibrate(Clocksource::Pit, Duration::from_millis(20));
+        assert!(first > 100_000_000, "The TSC ticks at {} Hz", first);
+        let difference = if first > second {
+            first - second
+        } else {
+            second - first
+        };
+        assert!(
+            difference < first / 20,
+            "Calibrated the TSC at {} Hz and {} Hz",
+            first,
+            second
+        );
+        serial_println!("[ok]");
+    }
+}
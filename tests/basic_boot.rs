@@ -6,14 +6,17 @@
 
 use core::panic::PanicInfo;
 
+use bootloader::{entry_point, BootInfo};
+
 #[macro_use]
 extern crate rust_os;
 
 use rust_os::qemu::{self, QemuExitCode};
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    rust_os::init();
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
 
     test_main();
 
@@ -0,0 +1,93 @@
+//! A test that the report of a fault which pushes an error code describes the interrupted code:
+//! its registers are the ones it faulted with, and its backtrace starts at the faulting
+//! instruction and continues from the interrupted frame pointer.
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+
+#[macro_use]
+extern crate rust_os;
+use rust_os::{
+    cpu_exception::{report, CpuException},
+    qemu::{self, QemuExitCode},
+};
+
+/// The value `fault` puts in `R12` before faulting.
+const MARKER: u64 = 0x0123_4567_89AB_CDEF;
+
+/// The value of `RBP` in `fault`.
+static FRAME_POINTER: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("[exception_report]... ");
+    rust_os::init(boot_info);
+    fault();
+    serial_println!("[failed]\n");
+    serial_println!("Error: reading a non-canonical address did not fault\n");
+    qemu::exit_qemu(QemuExitCode::Failure)
+}
+
+/// Raise a general protection fault by reading a non-canonical address.
+#[inline(never)]
+fn fault() {
+    unsafe {
+        let frame_pointer: u64;
+        asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack));
+        FRAME_POINTER.store(frame_pointer, Ordering::SeqCst);
+        asm!(
+            "mov rax, qword ptr [rcx]",
+            in("rcx") 0x8000_0000_0000_0000u64,
+            in("r12") MARKER,
+            out("rax") _,
+            options(nostack),
+        );
+    }
+}
+
+fn fail(message: &str) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    qemu::exit_qemu(QemuExitCode::Failure)
+}
+
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    let report = match report::last_report() {
+        Some(report) if report.exception == CpuException::GeneralProtectionFault => report,
+        report => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: the last report was {:?}\n", report);
+            qemu::exit_qemu(QemuExitCode::Failure)
+        }
+    };
+    let frame_pointer = FRAME_POINTER.load(Ordering::SeqCst);
+    if report.registers.r12 != MARKER {
+        fail("R12 was not the value it faulted with");
+    }
+    if report.registers.rbp != frame_pointer {
+        fail("RBP was not the frame pointer of the faulting function");
+    }
+    let mut backtrace = report.backtrace();
+    if backtrace.next().map(|frame| frame.address)
+        != Some(report.frame.instruction_pointer.as_u64())
+    {
+        fail("the backtrace didn't start at the faulting instruction");
+    }
+    // The caller of `fault` saved its return address above the frame pointer.
+    let return_address = unsafe { *((frame_pointer + 8) as *const u64) };
+    if backtrace.next().map(|frame| frame.address) != Some(return_address) {
+        fail("the backtrace didn't continue from the interrupted frame pointer");
+    }
+    serial_println!("[ok]");
+    qemu::exit_qemu(QemuExitCode::Success)
+}
@@ -0,0 +1,57 @@
+//! A test that the kernel's data is not executable: jumping into it causes a page fault which the
+//! kernel's handler diagnoses as a W^X violation.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use core::{mem, panic::PanicInfo};
+
+use bootloader::{entry_point, BootInfo};
+
+#[macro_use]
+extern crate rust_os;
+use rust_os::{
+    cpu_exception::{report, CpuException},
+    memory::protection::{SegmentKind, Violation},
+    qemu::{self, QemuExitCode},
+};
+
+/// A `ret` instruction in writable data.
+static mut RET: [u8; 1] = [0xC3];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("[execute_data]... ");
+    rust_os::init(boot_info);
+    unsafe {
+        let function: extern "C" fn() = mem::transmute(RET.as_ptr());
+        function();
+    }
+    serial_println!("[failed]\n");
+    serial_println!("Error: executing data did not fault\n");
+    qemu::exit_qemu(QemuExitCode::Failure)
+}
+
+/// The kernel's page fault handler records a report and panics with its diagnosis.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = format!("{}", info);
+    let expected = format!("{}", Violation::Execute(Some(SegmentKind::Data)));
+    let reported = report::last_report().map(|report| report.exception);
+    if reported == Some(CpuException::PageFault) && message.contains(&expected) {
+        serial_println!("[ok]");
+        qemu::exit_qemu(QemuExitCode::Success)
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: expected a diagnosis of \"{}\", got {}\n",
+            expected,
+            message
+        );
+        qemu::exit_qemu(QemuExitCode::Failure)
+    }
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+
+#[macro_use]
+extern crate rust_os;
+
+use rust_os::{
+    memory::heap::{self, HEAP_SIZE},
+    qemu::{self, QemuExitCode},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
+
+    test_main();
+
+    qemu::exit_qemu(QemuExitCode::Success)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic(info)
+}
+
+const TEST_PREFIX: &'static str = "[heap_allocation]";
+
+/// Test that simple values can be boxed.
+#[test_case]
+fn test_simple_allocation() {
+    serial_print!("{} test_simple_allocation... ", TEST_PREFIX);
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+    let mut map = BTreeMap::new();
+    map.insert(String::from("answer"), 42);
+    assert_eq!(map.get("answer"), Some(&42));
+    serial_println!("[ok]");
+}
+
+/// Test that a vector can grow to a large size, reallocating on the way.
+#[test_case]
+fn test_large_vec() {
+    serial_print!("{} test_large_vec... ", TEST_PREFIX);
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    serial_println!("[ok]");
+}
+
+/// Test that a single allocation of a quarter of the heap succeeds and is writable throughout.
+#[test_case]
+fn test_large_allocation() {
+    serial_print!("{} test_large_allocation... ", TEST_PREFIX);
+    let size = HEAP_SIZE as usize / 4;
+    let mut buffer: Vec<u8> = Vec::with_capacity(size);
+    buffer.resize(size, 0xAB);
+    assert_eq!(buffer[0], 0xAB);
+    assert_eq!(buffer[size - 1], 0xAB);
+    assert!(heap::stats().used >= size);
+    serial_println!("[ok]");
+}
+
+/// Test that many small allocations which are freed straight away reuse the same memory. Together
+/// they allocate twice the size of the heap.
+#[test_case]
+fn test_many_boxes() {
+    serial_print!("{} test_many_boxes... ", TEST_PREFIX);
+    for i in 0..HEAP_SIZE / 4 {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    serial_println!("[ok]");
+}
+
+/// Test that memory is reused after it is freed even when other allocations are still alive.
+/// The bump allocator can't do this.
+#[cfg(not(feature = "bump-allocator"))]
+#[test_case]
+fn test_many_boxes_long_lived() {
+    serial_print!("{} test_many_boxes_long_lived... ", TEST_PREFIX);
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE / 4 {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+    serial_println!("[ok]");
+}
+
+/// Test that large allocations which together are bigger than the heap succeed when each is freed
+/// before the next.
+#[test_case]
+fn test_reuse_after_free() {
+    serial_print!("{} test_reuse_after_free... ", TEST_PREFIX);
+    let size = HEAP_SIZE as usize / 2;
+    for round in 0..8u8 {
+        let buffer = alloc::vec![round; size];
+        assert_eq!(buffer[size / 2], round);
+    }
+    let before = heap::stats();
+    drop(Box::new([0u64; 64]));
+    let after = heap::stats();
+    assert_eq!(after.used, before.used);
+    assert_eq!(after.failures, 0);
+    serial_println!("[ok]");
+}
+
+/// Test that small aligned allocations still succeed between large ones whose sizes aren't a
+/// multiple of the small ones' alignment.
+#[test_case]
+fn test_odd_sizes() {
+    serial_print!("{} test_odd_sizes... ", TEST_PREFIX);
+    let mut buffers = Vec::new();
+    let mut boxes = Vec::new();
+    for (i, &size) in [3000, 4104, 2056, 5000, 3080].iter().enumerate() {
+        buffers.push(alloc::vec![i as u8; size]);
+        boxes.push(Box::new([i as u64; 2]));
+        boxes.push(Box::new([i as u64; 2]));
+    }
+    for (i, buffer) in buffers.iter().enumerate() {
+        assert!(buffer.iter().all(|&byte| byte == i as u8));
+        assert_eq!(*boxes[2 * i], [i as u64; 2]);
+        assert_eq!(*boxes[2 * i + 1], [i as u64; 2]);
+    }
+    assert_eq!(heap::stats().failures, 0);
+    serial_println!("[ok]");
+}
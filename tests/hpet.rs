@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use bootloader::{entry_point, BootInfo};
+
+#[macro_use]
+extern crate rust_os;
+
+use rust_os::{
+    acpi::Acpi,
+    cpu_exception::interrupts,
+    hpet::{self, HpetError, Route, TimerMode},
+    memory,
+    qemu::{self, QemuExitCode},
+    time::{
+        self,
+        clocksource::{self, Clocksource},
+        tsc,
+    },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
+    unsafe {
+        let acpi = Acpi::new(memory::physical_memory_offset()).expect("No ACPI tables");
+        interrupts::enable_apic(&acpi).expect("Failed to enable the APICs");
+        hpet::init(&acpi).expect("Failed to set up the HPET");
+    }
+    interrupts::register_irq(VECTOR, count_interrupt).expect("Failed to register the handler");
+
+    test_main();
+
+    qemu::exit_qemu(QemuExitCode::Success)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic(info)
+}
+
+const TEST_PREFIX: &'static str = "[hpet]";
+
+/// The vector the timers raise their interrupts on.
+const VECTOR: u8 = 0x60;
+
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+fn count_interrupt(_: u8) -> bool {
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    true
+}
+
+/// The highest I/O APIC input which timer 0 can be routed to, which is above the ISA IRQs.
+fn gsi() -> u32 {
+    let capabilities = hpet::with_hpet(|hpet| hpet.capabilities(0))
+        .and_then(|capabilities| capabilities)
+        .unwrap();
+    (16..32)
+        .rev()
+        .find(|&gsi| capabilities.can_route_to(gsi))
+        .expect("Timer 0 can't be routed to the I/O APIC")
+}
+
+/// Test that the main counter runs at the frequency it reports.
+#[test_case]
+fn test_clocksource() {
+    serial_print!("{} test_clocksource... ", TEST_PREFIX);
+    let reference = clocksource::reference();
+    assert_eq!(
+        reference,
+        Clocksource::Hpet {
+            frequency: 100_000_000
+        }
+    );
+    let start = reference.read();
+    time::sleep(Duration::from_millis(20));
+    let elapsed = reference.to_duration(reference.read() - start);
+    assert!(elapsed >= Duration::from_millis(20) && elapsed < Duration::from_millis(30));
+    // The TSC calibrates to the same frequency against the HPET as against the PIT.
+    let hpet = tsc::calibrate(reference, Duration::from_millis(20));
+    let pit = tsc::calibrate(Clocksource::Pit, Duration::from_millis(20));
+    let difference = if hpet > pit { hpet - pit } else { pit - hpet };
+    assert!(difference < pit / 20, "{} Hz and {} Hz", hpet, pit);
+    serial_println!("[ok]");
+}
+
+/// Test that a one-shot timer routed through the I/O APIC raises a single interrupt.
+#[test_case]
+fn test_one_shot_io_apic() {
+    serial_print!("{} test_one_shot_io_apic... ", TEST_PREFIX);
+    let start = INTERRUPTS.load(Ordering::SeqCst);
+    let route = Route::IoApic { gsi: gsi() };
+    hpet::start_timer(
+        0,
+        TimerMode::OneShot,
+        Duration::from_millis(5),
+        VECTOR,
+        route,
+    )
+    .unwrap();
+    time::sleep(Duration::from_millis(20));
+    assert_eq!(INTERRUPTS.load(Ordering::SeqCst) - start, 1);
+    hpet::stop_timer(0).unwrap();
+    serial_println!("[ok]");
+}
+
+/// Test that a periodic timer raises an interrupt every interval until it is stopped.
+#[test_case]
+fn test_periodic_io_apic() {
+    serial_print!("{} test_periodic_io_apic... ", TEST_PREFIX);
+    let start = INTERRUPTS.load(Ordering::SeqCst);
+    let route = Route::IoApic { gsi: gsi() };
+    hpet::start_timer(
+        0,
+        TimerMode::Periodic,
+        Duration::from_millis(2),
+        VECTOR,
+        route,
+    )
+    .unwrap();
+    time::sleep(Duration::from_millis(100));
+    hpet::stop_timer(0).unwrap();
+    let count = INTERRUPTS.load(Ordering::SeqCst) - start;
+    assert!(count >= 30 && count <= 55, "{} interrupts", count);
+    let stopped = INTERRUPTS.load(Ordering::SeqCst);
+    time::sleep(Duration::from_millis(10));
+    assert_eq!(INTERRUPTS.load(Ordering::SeqCst), stopped);
+    serial_println!("[ok]");
+}
+
+/// Test that a timer delivers its interrupt on the front side bus if it can, and that timers
+/// which can't refuse to.
+#[test_case]
+fn test_one_shot_fsb() {
+    serial_print!("{} test_one_shot_fsb... ", TEST_PREFIX);
+    let fsb = hpet::with_hpet(|hpet| hpet.capabilities(0))
+        .and_then(|capabilities| capabilities)
+        .unwrap()
+        .fsb;
+    let start = INTERRUPTS.load(Ordering::SeqCst);
+    let result = hpet::start_timer(
+        0,
+        TimerMode::OneShot,
+        Duration::from_millis(5),
+        VECTOR,
+        Route::Fsb,
+    );
+    if fsb {
+        result.unwrap();
+        time::sleep(Duration::from_millis(20));
+        assert_eq!(INTERRUPTS.load(Ordering::SeqCst) - start, 1);
+        hpet::stop_timer(0).unwrap();
+    } else {
+        assert_eq!(result, Err(HpetError::FsbUnsupported(0)));
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+
+#[macro_use]
+extern crate rust_os;
+
+use rust_os::{
+    memory::slab::{DebugOptions, SlabCache},
+    qemu::{self, QemuExitCode},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
+    test_main();
+    qemu::exit_qemu(QemuExitCode::Failure)
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+        serial_println!("[test did not panic]");
+        qemu::exit_qemu(QemuExitCode::Failure);
+    }
+    qemu::exit_qemu(QemuExitCode::Success);
+}
+
+/// Freeing the overflowed object panics with a description of the red zone which was overwritten.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = format!("{}", info);
+    let expected = "Red zone after object";
+    if message.contains(expected) {
+        serial_println!("[ok]");
+        qemu::exit_qemu(QemuExitCode::Success)
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: expected \"{}\", got {}\n", expected, message);
+        qemu::exit_qemu(QemuExitCode::Failure)
+    }
+}
+
+const TEST_PREFIX: &'static str = "[slab_red_zone]";
+
+static CACHE: SlabCache = SlabCache::new("overflowed", 24, 8, None, DebugOptions::ALL);
+
+/// Test that writing one byte past the end of an object is caught when the object is freed.
+#[test_case]
+fn test_overflow_detected() {
+    serial_print!("{} test_overflow_detected... ", TEST_PREFIX);
+    let object = CACHE.allocate().unwrap();
+    unsafe {
+        object.as_ptr().add(24).write_volatile(0);
+        CACHE.free(object);
+    }
+}
@@ -0,0 +1,72 @@
+//! A test that SMAP stops the kernel from reading a user page directly and that the kernel's page
+//! fault handler diagnoses the fault as a SMAP violation.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use core::{panic::PanicInfo, ptr};
+
+use bootloader::{entry_point, BootInfo};
+
+#[macro_use]
+extern crate rust_os;
+use rust_os::{
+    cpu_exception::{report, CpuException},
+    memory::{
+        frame, paging,
+        user::{self, Violation},
+    },
+    qemu::{self, QemuExitCode},
+};
+
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// The user address the test maps and reads.
+const USER_PAGE: u64 = 0x0000_4000_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("[smap_violation]... ");
+    rust_os::init(boot_info);
+    if !user::enabled().smap {
+        serial_println!("[failed]\n");
+        serial_println!("Error: SMAP is not enabled\n");
+        qemu::exit_qemu(QemuExitCode::Failure);
+    }
+    let page = Page::containing_address(VirtAddr::new(USER_PAGE));
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    unsafe {
+        paging::map(page, frame::allocate_frame().unwrap(), flags).unwrap();
+        ptr::read_volatile(USER_PAGE as *const u64);
+    }
+    serial_println!("[failed]\n");
+    serial_println!("Error: reading a user page did not fault\n");
+    qemu::exit_qemu(QemuExitCode::Failure)
+}
+
+/// The kernel's page fault handler records a report and panics with its diagnosis.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = format!("{}", info);
+    let expected = format!("{}", Violation::SmapRead);
+    let reported = report::last_report().map(|report| report.exception);
+    if reported == Some(CpuException::PageFault) && message.contains(&expected) {
+        serial_println!("[ok]");
+        qemu::exit_qemu(QemuExitCode::Success)
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: expected a diagnosis of \"{}\", got {}\n",
+            expected,
+            message
+        );
+        qemu::exit_qemu(QemuExitCode::Failure)
+    }
+}
@@ -1,13 +1,15 @@
-//! A test that the GDT enables an exception handler to be called on stack overflow.
+//! A test that the GDT enables an exception handler to be called on stack overflow and that the
+//! kernel's handler diagnoses the overflow as an overflow of the kernel stack.
 
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)]
 
+extern crate alloc;
+
+use alloc::format;
 use core::panic::PanicInfo;
 
-#[macro_use]
-extern crate lazy_static;
+use bootloader::{entry_point, BootInfo};
 
 #[macro_use]
 extern crate rust_os;
@@ -15,36 +17,15 @@ use rust_os::qemu::{self, QemuExitCode};
 
 use volatile::Volatile;
 
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-
-lazy_static! {
-    static ref TEST_IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(rust_os::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt
-    };
-}
+entry_point!(main);
 
-#[no_mangle]
-extern "C" fn _start() -> ! {
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("[stack_overflow]... ");
-    rust_os::gdt::init();
-    init_test_idt();
+    rust_os::init(boot_info);
     stack_overflow();
-    panic!("Execution continued after stack overflow");
-}
-
-extern "x86-interrupt" fn double_fault_handler(_: &mut InterruptStackFrame, _: u64) -> ! {
-    serial_println!("[ok]");
-    qemu::exit_qemu(QemuExitCode::Success)
-}
-
-fn init_test_idt() {
-    TEST_IDT.load();
+    serial_println!("[failed]\n");
+    serial_println!("Error: execution continued after stack overflow\n");
+    qemu::exit_qemu(QemuExitCode::Failure)
 }
 
 #[allow(unconditional_recursion)]
@@ -56,7 +37,21 @@ fn stack_overflow() {
     Volatile::new(0).read();
 }
 
+/// The kernel's fault handler panics with its diagnosis.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    rust_os::test_panic(info)
+    let message = format!("{}", info);
+    let expected = "stack overflow on kernel stack";
+    if message.contains(expected) {
+        serial_println!("[ok]");
+        qemu::exit_qemu(QemuExitCode::Success)
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: expected a diagnosis of \"{}\", got {}\n",
+            expected,
+            message
+        );
+        qemu::exit_qemu(QemuExitCode::Failure)
+    }
 }
@@ -0,0 +1,53 @@
+//! A test that the kernel's code is read-only: writing to it causes a page fault which the
+//! kernel's handler diagnoses as a W^X violation.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use core::{panic::PanicInfo, ptr};
+
+use bootloader::{entry_point, BootInfo};
+
+#[macro_use]
+extern crate rust_os;
+use rust_os::{
+    cpu_exception::{report, CpuException},
+    memory::protection::{SegmentKind, Violation},
+    qemu::{self, QemuExitCode},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("[write_to_code]... ");
+    rust_os::init(boot_info);
+    unsafe {
+        ptr::write_volatile(main as *mut u8, 0xCC);
+    }
+    serial_println!("[failed]\n");
+    serial_println!("Error: writing to code did not fault\n");
+    qemu::exit_qemu(QemuExitCode::Failure)
+}
+
+/// The kernel's page fault handler records a report and panics with its diagnosis.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = format!("{}", info);
+    let expected = format!("{}", Violation::Write(Some(SegmentKind::Code)));
+    let reported = report::last_report().map(|report| report.exception);
+    if reported == Some(CpuException::PageFault) && message.contains(&expected) {
+        serial_println!("[ok]");
+        qemu::exit_qemu(QemuExitCode::Success)
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: expected a diagnosis of \"{}\", got {}\n",
+            expected,
+            message
+        );
+        qemu::exit_qemu(QemuExitCode::Failure)
+    }
+}
@@ -0,0 +1,132 @@
+#!/usr/bin/env python3
+"""Embed the function symbols of a linked kernel into its `.kernel_symbols` section.
+
+The layout of the table must match `src/backtrace/symbols/mod.rs`: a header holding the magic
+number `SYMTAB01`, the number of symbols and the offset of the names from the start of the
+table, then one entry per symbol sorted by address holding its address, size, and the offset
+and length of its name, then the names. Every number is little-endian.
+"""
+
+import re
+import struct
+import sys
+
+SECTION_NAME = b".kernel_symbols"
+MAGIC = b"SYMTAB01"
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+ESCAPES = {
+    "$SP$": "@",
+    "$BP$": "*",
+    "$RF$": "&",
+    "$LT$": "<",
+    "$GT$": ">",
+    "$LP$": "(",
+    "$RP$": ")",
+    "$C$": ",",
+}
+
+
+def demangle(name):
+    """Demangle a legacy Rust symbol name, dropping the hash. Other names are returned as-is."""
+    match = re.match(r"^_?_ZN(.*)E$", name)
+    if not match:
+        return name
+    rest = match.group(1)
+    parts = []
+    while rest:
+        length = re.match(r"^(\d+)", rest)
+        if not length:
+            return name
+        start = len(length.group(1))
+        end = start + int(length.group(1))
+        parts.append(rest[start:end])
+        rest = rest[end:]
+    if parts and re.match(r"^h[0-9a-f]{16}$", parts[-1]):
+        parts.pop()
+    demangled = []
+    for part in parts:
+        if part.startswith("_$"):
+            part = part[1:]
+        for escape, replacement in ESCAPES.items():
+            part = part.replace(escape, replacement)
+        part = re.sub(r"\$u([0-9a-f]{2})\$", lambda m: chr(int(m.group(1), 16)), part)
+        demangled.append(part.replace("..", "::"))
+    return "::".join(demangled)
+
+
+def read_sections(image):
+    (shoff,) = struct.unpack_from("<Q", image, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", image, 0x3A)
+    sections = []
+    for index in range(shnum):
+        fields = struct.unpack_from("<IIQQQQIIQQ", image, shoff + index * shentsize)
+        sections.append(
+            {
+                "name": fields[0],
+                "type": fields[1],
+                "offset": fields[4],
+                "size": fields[5],
+                "link": fields[6],
+                "entsize": fields[9],
+            }
+        )
+    names = sections[shstrndx]
+    for section in sections:
+        start = names["offset"] + section["name"]
+        section["name"] = image[start : image.index(b"\0", start)]
+    return sections
+
+
+def read_functions(image, sections):
+    symtab = next(section for section in sections if section["type"] == SHT_SYMTAB)
+    strtab = sections[symtab["link"]]
+    functions = {}
+    for offset in range(0, symtab["size"], symtab["entsize"]):
+        name, info, _, _, value, size = struct.unpack_from(
+            "<IBBHQQ", image, symtab["offset"] + offset
+        )
+        if info & 0xF != STT_FUNC or value == 0 or size == 0:
+            continue
+        start = strtab["offset"] + name
+        raw = image[start : image.index(b"\0", start)].decode("utf-8", "replace")
+        functions.setdefault(value, (size, demangle(raw)))
+    return sorted(functions.items())
+
+
+def build_table(functions):
+    names = bytearray()
+    entries = bytearray()
+    for address, (size, name) in functions:
+        encoded = name.encode("utf-8")
+        entries += struct.pack("<QQII", address, size, len(names), len(encoded))
+        names += encoded
+    header = MAGIC + struct.pack("<II", len(functions), 16 + len(entries))
+    return header + entries + names
+
+
+def main(path):
+    with open(path, "r+b") as kernel:
+        image = bytearray(kernel.read())
+        if image[:4] != b"\x7fELF" or image[4] != 2:
+            sys.exit("{} is not a 64-bit ELF file".format(path))
+        sections = read_sections(image)
+        target = next((s for s in sections if s["name"] == SECTION_NAME), None)
+        if target is None:
+            sys.exit("{} has no {} section".format(path, SECTION_NAME.decode()))
+        table = build_table(read_functions(image, sections))
+        if len(table) > target["size"]:
+            sys.exit(
+                "The symbol table needs {} bytes but only {} are reserved".format(
+                    len(table), target["size"]
+                )
+            )
+        kernel.seek(target["offset"])
+        kernel.write(table)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) < 2:
+        sys.exit("Usage: embed_symbols.py <kernel ELF file>")
+    main(sys.argv[1])
@@ -0,0 +1,6 @@
+#!/bin/sh
+# Cargo runner for the kernel: embed the symbol table used for backtraces into the linked
+# kernel, then boot it in QEMU.
+set -e
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+exec bootimage runner "$@"